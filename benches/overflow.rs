@@ -0,0 +1,67 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Lookups by overflow strategy
+//! Keys are looked up in tables of chained and robin hood buckets filled to increasing load factors.
+//! Run with cargo bench --bench overflow
+//!
+
+extern crate hammersbald;
+extern crate rand;
+
+use hammersbald::{persistent_with_config, Config, Overflow, Chained, RobinHood, HammersbaldAPI};
+
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+const KEYS: u32 = 100000;
+
+fn main () {
+    let dir = env::temp_dir().join(format!("hammersbald-bench-overflow-{}", rand::random::<u64>()));
+    println!("{:>12} {:>12} {:>16} {:>16}", "fill target", "strategy", "slots/lookup", "ns/lookup");
+    for fill_target in [2, 16, 64, 128].iter() {
+        let strategies: [(&str, Arc<dyn Overflow>); 2] = [("chained", Arc::new(Chained)), ("robin hood", Arc::new(RobinHood))];
+        for (name, overflow) in strategies.iter() {
+            let run = dir.join(format!("{}-{}", fill_target, name));
+            fs::create_dir_all(&run).unwrap();
+            let config = Config::default().directory(&run).bucket_fill_target(*fill_target)
+                .cached_data_pages(10000).overflow(overflow.clone());
+            let mut db = persistent_with_config("bench", &config).unwrap();
+            for i in 0 .. KEYS {
+                db.put_keyed(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+            }
+            db.batch().unwrap();
+            let (slots, nanos) = lookups(db.as_ref());
+            println!("{:>12} {:>12} {:>16.1} {:>16.0}", fill_target, name, slots, nanos);
+            db.shutdown();
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// slots examined and time of a lookup, on average, of keys stored and not stored
+fn lookups (db: &dyn HammersbaldAPI) -> (f64, f64) {
+    let keys = (0 .. 2 * KEYS).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+    let start = Instant::now();
+    for key in &keys {
+        db.get_keyed(key).unwrap();
+    }
+    let nanos = start.elapsed().as_nanos() as f64 / keys.len() as f64;
+    let slots = keys.iter().map(|key| db.probe(key).unwrap().slots).sum::<usize>();
+    (slots as f64 / keys.len() as f64, nanos)
+}
@@ -0,0 +1,43 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Workloads by batch size
+//! The tuner workloads on a persistent db with a fixed seed, batched at increasing intervals, so
+//! runs of different versions tell regressions of puts, gets and batches apart.
+//! Run with cargo bench --features bench --bench workloads
+//!
+
+extern crate hammersbald;
+extern crate rand;
+
+use hammersbald::{persistent_with_config, tune, write_report, Config, Workloads};
+
+use std::env;
+use std::fs;
+use std::io;
+
+fn main () {
+    let dir = env::temp_dir().join(format!("hammersbald-bench-workloads-{}", rand::random::<u64>()));
+    for batch_every in [100, 1000, 10000].iter() {
+        let run = dir.join(batch_every.to_string());
+        fs::create_dir_all(&run).unwrap();
+        let mut db = persistent_with_config("bench", &Config::default().directory(&run)).unwrap();
+        let report = tune(db.as_mut(), &Workloads { batch_every: *batch_every, seed: 1, ..Workloads::default() }).unwrap();
+        db.shutdown();
+        write_report(&mut io::stdout(), &format!("batch every {} puts", batch_every), &report).unwrap();
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
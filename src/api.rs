@@ -17,25 +17,111 @@
 //! # Hammersbald API
 //!
 use logfile::LogFile;
+use walfile::{WalFile, Redo};
+use bloomfile::BloomFile;
 use tablefile::TableFile;
-use datafile::{DataFile, EnvelopeIterator};
+use datafile;
+use datafile::{DataFile, EnvelopeIterator, EnvelopeCheck};
 use memtable::MemTable;
-use format::{Payload,Envelope};
+use format::{Payload, Envelope, DataAt, KeyedDataAt, KeyAt, EXTENDED, MAX_KEY_LEN, MAX_DATA_LEN, MAX_LINKS};
+use compression;
+use encryption::ENCRYPTED;
+use page::PAGE_SIZE;
 use persistent::Persistent;
 use transient::Transient;
 use pref::PRef;
-use error::Error;
+use error::{Error, FileKind};
+use index::{Index, IndexKeys, IndexFactory};
+use dedup::Dedup;
+use column::Column;
+use content::ContentId;
+use generationfile::GenerationFile;
+use pagedfile::PagedFile;
+use memcache::MemCache;
+use follower::Follower;
+use metrics::{Metrics, NoMetrics};
+use overflow::Overflow;
+use observer::{CommitObserver, Changes};
+use config::{Config, Throttle, AutoBatch, DEFAULT_MAX_FILE_SIZE};
+use stats;
+use dump;
+use backup;
+use stats::{VerifyLevel, VerifyReport, TableStats, Probe, EntryInfo};
 
+use bitcoin_hashes::{sha256, Hash};
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 
 use std::{
     io,
-    io::{Cursor, Read, Write}
+    iter,
+    io::{Cursor, Read, Write},
+    fs::File,
+    path::PathBuf,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant
 };
 
 /// Hammersbald
 pub struct Hammersbald {
-    mem: MemTable
+    // boxed as page buffers make it large and builder methods move the db
+    mem: Box<MemTable>,
+    indexes: HashMap<String, Index>,
+    index_factory: Option<IndexFactory>,
+    // boxed as its page buffer is large too
+    wal: Option<Box<WalFile>>,
+    // every put is logged, not only durable puts
+    log_puts: bool,
+    dedup: Option<Dedup>,
+    generations: Option<GenerationFile>,
+    metrics: Arc<dyn Metrics>,
+    changes: Changes,
+    throttle: Option<(u64, Throttle)>,
+    // most bytes of the files, 0 for no limit
+    quota: u64,
+    // free space of the disks of the files, if checked
+    free_space: Option<FreeSpace>,
+    // policy and start of the current batch
+    auto_batch: Option<(AutoBatch, Instant)>,
+    // position of the data file at the end of the last batch
+    batched: u64,
+    // held while open for writing
+    lock: Option<File>,
+    // chunk size of the files, snapshots are rolled alike
+    max_file_size: u64
+}
+
+// the free space of disks is queried again once the files may have grown within this margin of it
+const FREE_SPACE_MARGIN: u64 = 64 * 1024 * 1024;
+
+// free space of the disks of the files
+struct FreeSpace {
+    directories: Vec<PathBuf>,
+    // bytes left free
+    reserve: u64,
+    // least free bytes of the disks at the last query and the bytes of the files then
+    free: u64,
+    at: u64
+}
+
+impl FreeSpace {
+    // fail if writing the bytes would leave less than the reserve free
+    fn check(&mut self, file_bytes: u64, bytes: u64) -> Result<(), Error> {
+        // the files grew since the last query, the disks have at most that less free
+        let free = self.free.saturating_sub(file_bytes.saturating_sub(self.at));
+        if free < self.reserve + bytes + FREE_SPACE_MARGIN {
+            let mut least = u64::MAX;
+            for dir in &self.directories {
+                least = least.min(fs2::available_space(dir)?);
+            }
+            self.free = least;
+            self.at = file_bytes;
+            if least < self.reserve + bytes {
+                return Err(Error::OutOfSpace { bytes, source: None });
+            }
+        }
+        Ok(())
+    }
 }
 
 /// create or open a persistent db
@@ -43,45 +129,340 @@ pub fn persistent(name: &str, cached_data_pages: usize, bucket_fill_target: usiz
     Persistent::new_db(name, cached_data_pages,bucket_fill_target)
 }
 
+/// create or open a persistent db with configuration
+pub fn persistent_with_config(name: &str, config: &Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+    Persistent::with_config(name, config)
+}
+
+/// open a persistent db for read only
+/// the db is read as of its files at open, log recovery is not performed
+pub fn read_only(name: &str, cached_data_pages: usize) -> Result<Box<dyn HammersbaldRead>, Error> {
+    Persistent::read_only(name, &Config::default().cached_data_pages(cached_data_pages))
+}
+
+/// open a persistent db with configuration for read only
+pub fn read_only_with_config(name: &str, config: &Config) -> Result<Box<dyn HammersbaldRead>, Error> {
+    Persistent::read_only(name, config)
+}
+
+/// open a persistent db written by an other process for read only, Follower::refresh moves to its latest batch
+pub fn open_follower(name: &str, config: &Config) -> Result<Follower, Error> {
+    Follower::open(name, config)
+}
+
+/// upgrade the files of a persistent db created by an earlier version
+/// the db must not be open while migrating
+pub fn migrate(name: &str) -> Result<(), Error> {
+    Persistent::migrate(name)
+}
+
+/// recover a persistent db with lost or damaged hash table or link file by rebuilding them from
+/// the data file. Forgotten keys are restored and checkpoints are dropped.
+/// the db must not be open while repairing
+pub fn repair(name: &str, config: &Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+    Persistent::repair(name, config)
+}
+
+/// apply a delta of backup_incremental onto the snapshot with the name, taken at the checkpoint of
+/// the delta or restored up to it. Bloom filters and checkpoints of the snapshot are dropped.
+/// the snapshot must not be open while restoring
+/// returns the number of pages applied
+pub fn restore(name: &str, delta: &mut dyn Read) -> Result<u64, Error> {
+    backup::restore(name, delta)
+}
+
 /// create a transient db
 pub fn transient(bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
     Transient::new_db("",0,bucket_fill_target)
 }
 
+/// keep keyed data of a db in memory up to a budget of bytes, e.g. a transient tier in front of
+/// a persistent db. Puts are written through, gets check memory first.
+pub fn cached(db: Box<dyn HammersbaldAPI>, budget: usize) -> Box<dyn HammersbaldAPI> {
+    Box::new(MemCache::new(db, budget))
+}
+
+// check a size against a limit of the file format
+fn fits(what: &'static str, max: usize, got: u64) -> Result<(), Error> {
+    if got > max as u64 {
+        return Err(Error::DoesNotFit { what, max: max as u64, got });
+    }
+    Ok(())
+}
+
 /// public API to Hammersbald
-pub trait HammersbaldAPI : Send + Sync {
+pub trait HammersbaldAPI : HammersbaldRead {
     /// end current batch and start a new batch
     fn batch (&mut self)  -> Result<(), Error>;
 
-    /// stop background writer
+    /// bytes stored since the last batch, e.g. to schedule batches within a throttle limit
+    fn pending_bytes (&self) -> u64;
+
+    /// tell the observer the changes of each batch from the next batch on
+    /// changes dropped by rollback_to are not reported
+    fn observe (&mut self, observer: Arc<dyn CommitObserver>);
+
+    /// end current batch and record its boundary as a checkpoint with the id
+    fn batch_named (&mut self, id: u64) -> Result<(), Error>;
+
+    /// drop data and keys stored after the last checkpoint with the id, including the current batch,
+    /// later checkpoints are forgotten. Secondary indexes are rolled back with the data.
+    fn rollback_to (&mut self, id: u64) -> Result<(), Error>;
+
+    /// end current batch and write a consistent copy of the db as of the batch boundary
+    /// into new persistent files with the given name, that open with the configuration of the db.
+    /// Whole chunks of the data and link files are hard linked, only the hash table and the last
+    /// chunks are copied
+    fn snapshot (&mut self, name: &str) -> Result<(), Error>;
+
+    /// end current batch and write the pages changed since the last checkpoint with the id, to be
+    /// restored onto a snapshot of the checkpoint. A checkpoint recorded with batch_named right
+    /// before is the start of the next delta. Fails with Error::UnknownCheckpoint if the id is not known.
+    /// returns the number of pages written
+    fn backup_incremental (&mut self, since: u64, target: &mut dyn Write) -> Result<u64, Error>;
+
+    /// prepare for the insert of a large number of keys
+    /// the hash table is sized upfront instead of growing with each put. Puts of the load into buckets
+    /// not in memory do not read their links, the slots are merged in bucket order at the next batch
+    fn bulk_load (&mut self, expected_keys: usize) -> Result<(), Error>;
+
+    /// grow the hash table to at least the number of buckets, e.g. if needs_resize tells it is undersized
+    /// buckets are split incrementally by the next batches, so a batch does not stall
+    fn resize (&mut self, buckets: usize) -> Result<(), Error>;
+
+    /// end current batch and rebuild the hash table and link file from the data file
+    /// forgotten keys are restored and checkpoints are dropped
+    fn rebuild_index (&mut self) -> Result<(), Error>;
+
+    /// stop background writer and release the lock of the files
     fn shutdown (&mut self);
 
+    /// shutdown and close the files, so the db can be opened again
+    /// data stored after the last batch is not kept unless a write ahead log is used
+    fn close (self: Box<Self>);
+
     /// store data accessible with key
     /// returns a persistent reference to stored data
     fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error>;
 
+    /// store data accessible with key keeping data stored earlier with the key, as ingest with
+    /// Conflict::KeepBoth does. get_keyed returns the data stored first, get_filtered all.
+    /// returns a persistent reference to stored data
+    fn put_keyed_also(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error>;
+
+    /// store data accessible with key and sync a redo record of the put to the write ahead log before returning,
+    /// so the put survives a crash before the next batch while other puts stay on the fast path.
+    /// Replayed after a crash, the data is stored at the same persistent reference, data of puts that were not
+    /// durable before it is replaced with padding. A transient db does not make puts durable.
+    fn put_durable(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error>;
+
+    /// store data accessible with its SHA256 hash as key, e.g. a transaction with its id
+    /// data already stored with the hash is not stored again
+    /// returns the persistent reference and the hash
+    fn put_hashed(&mut self, data: &[u8]) -> Result<(PRef, [u8; 32]), Error>;
+
+    /// store data with a new content id, that resolves to it after compaction moved it
+    /// returns the persistent reference and the id
+    fn put_content_stable(&mut self, data: &[u8]) -> Result<(PRef, ContentId), Error>;
+
+    /// store data accessible with keys, the data of all entries is appended with one write
+    /// and the keys are inserted thereafter. Later entries of the same key win.
+    /// returns persistent references to stored data in the order of entries
+    fn put_batch(&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error>;
+
+    /// store data accessible with keys as put_batch, the group is atomic only with write_ahead_log(true):
+    /// it is logged as a single redo record, so all entries are recovered after a crash or none,
+    /// e.g. a block with the index entries of its transactions. Without, the group is stored as put_batch,
+    /// the data file has no marker of it and it is durable only with the batch as any put.
+    fn put_group(&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error>;
+
+    /// store data
+    /// returns a persistent reference, that of identical stored data if deduplication is on
+    fn put(&mut self, data: &[u8]) -> Result<PRef, Error>;
+
+    /// store len bytes of data read from reader without holding all of it in memory
+    /// returns a persistent reference, the data is not deduplicated and durable with the next batch.
+    /// The data is not written to the write ahead log, if every put is logged the batch is ended instead.
+    fn put_stream(&mut self, reader: &mut dyn Read, len: u64) -> Result<PRef, Error>;
+
+    /// store data with references to other stored data
+    /// returns a persistent reference
+    fn put_with_links(&mut self, data: &[u8], links: &[PRef]) -> Result<PRef, Error>;
+
+    /// copy live keyed data and data without key of the other db into this one, references stored with
+    /// put_with_links are rewritten, references serialized into data are not. The current batch is ended
+    /// before and the ingested data is durable once ingest returns.
+    fn ingest(&mut self, other: &dyn HammersbaldRead, conflict: Conflict) -> Result<Ingested, Error>;
+
+    /// store the records of a dump written by export, references between data are rewritten
+    /// the batch is ended thereafter, returns the number of records read
+    fn import(&mut self, reader: &mut dyn Read) -> Result<u64, Error>;
+
+    /// forget a key (if known)
+    /// This is not a real delete as data will be still accessible through its PRef, but contains hash table growth
+    fn forget(&mut self, key: &[u8]) -> Result<(), Error>;
+
+    /// define a secondary index of keyed data, keys extracts index keys from (key, data)
+    /// the index is stored in a db of its own, built from data stored earlier and updated with put_keyed
+    /// index definitions are not stored, define the index with the same name and keys after every open
+    fn create_index(&mut self, name: &str, keys: IndexKeys) -> Result<(), Error>;
+
+    /// retrieve keyed data with a key of a secondary index
+    /// returns Some(persistent reference, key, data) or None, also if the key of the data was since forgotten or overwritten
+    fn get_by_index(&self, name: &str, index_key: &[u8]) -> Result<Option<KeyedDataAt>, Error>;
+
+    /// tag data of the current batch with the generation instead of the batch sequence number,
+    /// e.g. a block height. Generations can not go back.
+    fn set_generation(&mut self, generation: u64) -> Result<(), Error>;
+
+    /// forget keys of data stored before the generation unless data of a later generation refers to it
+    /// returns the number of keys forgotten, compaction reclaims their space
+    fn prune_before(&mut self, generation: u64) -> Result<usize, Error>;
+
+    /// a named namespace of keys of the db, sharing its batches. Fails with Error::DoesNotFit
+    /// if the name is longer than MAX_COLUMN_NAME
+    fn column(&mut self, name: &str) -> Result<Column<'_>, Error>;
+}
+
+/// how ingest resolves a key stored in both dbs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Conflict {
+    /// keep the data of the later generation if both dbs keep generations, otherwise the ingested data
+    KeepNewest,
+    /// keep both, get_keyed returns the data stored first and get_filtered both
+    KeepBoth,
+    /// fail with Error::Conflict before anything is ingested
+    Error
+}
+
+/// result of ingest
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Ingested {
+    /// live keyed data of the other db
+    pub keys: usize,
+    /// data without key copied
+    pub referred: usize,
+    /// keys stored in both dbs
+    pub conflicts: usize
+}
+
+/// read only part of the public API to Hammersbald
+pub trait HammersbaldRead : Send + Sync {
     /// retrieve data with key
     /// returns Some(persistent reference, data) or None
     fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error>;
 
-    /// store data
-    /// returns a persistent reference
-    fn put(&mut self, data: &[u8]) -> Result<PRef, Error>;
+    /// retrieve data stored with put_hashed
+    /// returns Some(persistent reference, data) or None
+    fn get_hashed(&self, hash: &[u8; 32]) -> Result<Option<(PRef, Vec<u8>)>, Error>;
+
+    /// retrieve data stored with put_content_stable at its current persistent reference
+    /// returns Some(persistent reference, data) or None
+    fn resolve(&self, id: ContentId) -> Result<Option<(PRef, Vec<u8>)>, Error>;
+
+    /// retrieve data for a batch of keys, cheaper than one get_keyed for each as links and data are
+    /// read in the order of their position
+    /// returns Some(persistent reference, data) or None for each key in the order of keys
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<DataAt>>, Error>;
+
+    /// retrieve data stored with keys of the same hash as key, that the predicate on (key, data) accepts
+    /// a predicate not checking the key can select among hash collisions
+    /// returns (persistent reference, key, data) of accepted data
+    fn get_filtered(&self, key: &[u8], predicate: &dyn Fn(&[u8], &[u8]) -> bool) -> Result<Vec<KeyedDataAt>, Error>;
+
+    /// retrieve keys of the same hash as key without reading their data
+    /// returns (persistent reference, key) of each
+    fn get_keys_only(&self, key: &[u8]) -> Result<Vec<(PRef, Vec<u8>)>, Error>;
+
+    /// number of data stored with the key, more than one if ingested with Conflict::KeepBoth
+    /// reads the keys of hash collisions but no data
+    fn count(&self, key: &[u8]) -> Result<usize, Error>;
+
+    /// retrieve data stored with the key, n = 0 the most recent
+    /// returns Some(persistent reference, data) or None if less than n + 1 are stored with the key
+    fn get_nth(&self, key: &[u8], n: usize) -> Result<Option<(PRef, Vec<u8>)>, Error>;
+
+    /// iterate data stored with the key, the most recent first, rev() for the oldest first
+    /// the keys of hash collisions are read at the call, the data as it is iterated
+    fn get_all(&self, key: &[u8]) -> Result<GetIterator<'_>, Error>;
 
     /// retrieve data using a persistent reference
     /// returns (key, data)
     fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error>;
 
+    /// read data at a persistent reference without holding all of it in memory
+    fn get_stream(&self, pref: PRef) -> Result<Box<dyn Read + '_>, Error>;
+
+    /// retrieve data using a persistent reference without copying key and data out of the read buffer
+    fn get_ref(&self, pref: PRef) -> Result<ContentRef, Error>;
+
+    /// references stored with data at a persistent reference
+    /// returns an empty vector for data stored without references
+    fn get_links(&self, pref: PRef) -> Result<Vec<PRef>, Error>;
+
+    /// stored length, key length, links, page span and generation of data at a persistent reference,
+    /// only the head of its envelope is read
+    fn entry_info(&self, pref: PRef) -> Result<EntryInfo, Error>;
+
+    /// check that references stored with data point to stored data
+    /// returns (data, reference) pairs of dangling references
+    fn verify_links(&self) -> Result<Vec<(PRef, PRef)>, Error>;
+
+    /// check the consistency of hash table, links, data and the log
+    /// meant for a db at a batch boundary, e.g. opened read only after an unclean shutdown
+    fn verify(&self, level: VerifyLevel) -> Result<VerifyReport, Error>;
+
+    /// load factor and chain lengths of the hash table, all buckets are read
+    fn table_stats(&self) -> Result<TableStats, Error>;
+
+    /// the hash table is undersized and gets slow down, see table_stats
+    fn needs_resize(&self) -> Result<bool, Error>;
+
+    /// a random sample of up to n live keys with the length of their data, e.g. to estimate
+    /// the distribution of data sizes. Buckets are read in random order until the sample is complete.
+    fn sample_keys(&self, n: usize) -> Result<Vec<(Vec<u8>, usize)>, Error>;
+
+    /// look up the key as get_keyed does and report the link entries and data examined, so
+    /// a miss is told apart from hash collisions and pathological buckets for the keys are detected
+    fn probe(&self, key: &[u8]) -> Result<Probe, Error>;
+
     /// a quick (in-memory) check if the db may have the key
     /// this might return false positive, but if it is false key is definitely not used.
     fn may_have_key(&self, key: &[u8]) -> Result<bool, Error>;
 
-    /// forget a key (if known)
-    /// This is not a real delete as data will be still accessible through its PRef, but contains hash table growth
-    fn forget(&mut self, key: &[u8]) -> Result<(), Error>;
-
     /// iterator of data
-    fn iter(&self) -> HammersbaldIterator;
+    fn iter(&self) -> HammersbaldIterator<'_>;
+
+    /// iterator of data reading the pages ahead of it in the background, for full scans
+    /// of large dbs that would be read page by page otherwise
+    fn iter_prefetch(&self, pages: usize) -> HammersbaldIterator<'_>;
+
+    /// write live keyed data and data without key to a portable dump, independent of page layout and
+    /// file format version, e.g. to move a db between architectures or for test fixtures
+    /// returns the number of records written
+    fn export(&self, writer: &mut dyn Write) -> Result<u64, Error>;
+
+    /// iterator of data starting at a position of an earlier cursor, e.g. to resume a scan after a restart
+    /// PRef::from(0) starts at the first data. Returns Error::InvalidOffset if no data starts at the position.
+    fn cursor_at(&self, pos: PRef) -> Result<DataCursor<'_>, Error>;
+
+    /// iterator of data still accessible with its key
+    /// skips data without key and data of forgotten or overwritten keys
+    fn iter_live(&self) -> HammersbaldIterator<'_>;
+
+    /// iterator of keys still accessible, with the reference of their data
+    /// only the keys are read from the data file
+    fn iter_keys(&self) -> HammersbaldKeyIterator<'_>;
+
+    /// generation of the current batch, None if generations are not kept
+    fn generation(&self) -> Option<u64>;
+
+    /// generation of data at a persistent reference
+    /// None if generations are not kept or the data was stored before they were
+    fn generation_of(&self, pref: PRef) -> Result<Option<u64>, Error>;
+
+    /// length of the data stored, including the current batch, the position of the next data
+    fn data_len(&self) -> u64;
 }
 
 /// A helper to build Hammersbald data elements
@@ -89,6 +470,12 @@ pub struct HammersbaldDataWriter {
     data: Vec<u8>
 }
 
+impl Default for HammersbaldDataWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HammersbaldDataWriter {
     /// create a new builder
     pub fn new () -> HammersbaldDataWriter {
@@ -96,7 +483,7 @@ impl HammersbaldDataWriter {
     }
 
     /// serialized data
-    pub fn as_slice<'a> (&'a self) -> &'a [u8] {
+    pub fn as_slice (&self) -> &[u8] {
         self.data.as_slice()
     }
 
@@ -106,7 +493,7 @@ impl HammersbaldDataWriter {
     }
 
     /// return a reader
-    pub fn reader<'a>(&'a self) -> Cursor<&'a [u8]> {
+    pub fn reader(&self) -> Cursor<&[u8]> {
         Cursor::new(self.data.as_slice())
     }
 }
@@ -146,15 +533,333 @@ impl<'a> Read for HammersbaldDataReader<'a> {
 
 impl Hammersbald {
     /// create a new db with key and data file
-    pub fn new(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bucket_fill_target :usize) -> Result<Hammersbald, Error> {
-        let mem = MemTable::new(log, table, data, link, bucket_fill_target);
-        let mut db = Hammersbald { mem };
+    pub fn new(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bloom: BloomFile, bucket_fill_target :usize) -> Result<Hammersbald, Error> {
+        let mem = Box::new(MemTable::new(log, table, data, link, bloom, bucket_fill_target));
+        let mut db = Hammersbald { mem, indexes: HashMap::new(), index_factory: None, wal: None, log_puts: false, dedup: None, generations: None, metrics: Arc::new(NoMetrics),
+            changes: Changes::default(), throttle: None, quota: 0, free_space: None, auto_batch: None, batched: 0, lock: None, max_file_size: DEFAULT_MAX_FILE_SIZE };
         db.recover()?;
         db.load()?;
         db.batch()?;
         Ok(db)
     }
 
+    /// open a db for read only, skipping recovery
+    pub fn read_only(log: LogFile, table: TableFile, data: DataFile, link: DataFile, bloom: BloomFile) -> Result<Hammersbald, Error> {
+        let mem = Box::new(MemTable::new(log, table, data, link, bloom, 1));
+        let mut db = Hammersbald { mem, indexes: HashMap::new(), index_factory: None, wal: None, log_puts: false, dedup: None, generations: None, metrics: Arc::new(NoMetrics),
+            changes: Changes::default(), throttle: None, quota: 0, free_space: None, auto_batch: None, batched: 0, lock: None, max_file_size: DEFAULT_MAX_FILE_SIZE };
+        db.load()?;
+        db.batched = db.mem.data_position().as_u64();
+        Ok(db)
+    }
+
+    /// hold the lock of the files until shutdown
+    pub fn with_lock(mut self, lock: File) -> Hammersbald {
+        self.lock = Some(lock);
+        self
+    }
+
+    /// roll snapshots at the chunk size of the files of the db, so they share its whole chunks
+    pub fn with_max_file_size(mut self, bytes: u64) -> Hammersbald {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// set the factory that creates dbs of secondary indexes
+    pub fn with_index_factory(mut self, factory: IndexFactory) -> Hammersbald {
+        self.index_factory = Some(factory);
+        self
+    }
+
+    /// set the number of threads flushing the hash table at batch, 0 for the number of CPUs
+    pub fn with_flush_threads(mut self, threads: usize) -> Hammersbald {
+        self.mem.set_flush_threads(threads);
+        self
+    }
+
+    /// limit the bytes of hash table buckets kept in memory, 0 for no limit
+    pub fn with_memory_budget(mut self, bytes: usize) -> Hammersbald {
+        self.mem.set_memory_budget(bytes);
+        self
+    }
+
+    /// arrange the slots of hash table buckets with the overflow strategy
+    pub fn with_overflow(mut self, overflow: Arc<dyn Overflow>) -> Hammersbald {
+        self.mem.set_overflow(overflow);
+        self
+    }
+
+    /// store keys of the size only, without their length, and use their first 8 bytes as hash
+    /// a db with keys fails with Error::Corrupted unless it was created with the size
+    pub fn with_fixed_key_size(mut self, size: usize) -> Result<Hammersbald, Error> {
+        fits("fixed key size", MAX_KEY_LEN, size as u64)?;
+        self.mem.set_fixed_key_size(size)?;
+        Ok(self)
+    }
+
+    /// report puts, gets, batches and syncs to metrics
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Hammersbald {
+        self.mem.set_metrics(metrics.clone());
+        self.metrics = metrics;
+        self
+    }
+
+    /// limit the bytes stored between batches
+    pub fn with_throttle(mut self, max_pending_bytes: u64, throttle: Throttle) -> Hammersbald {
+        self.throttle = Some((max_pending_bytes, throttle));
+        self
+    }
+
+    /// limit the bytes of the files, puts and batches exceeding it fail with Error::OutOfSpace before writing
+    pub fn with_quota(mut self, bytes: u64) -> Hammersbald {
+        self.quota = bytes;
+        self
+    }
+
+    /// check the free space of the disks of the directories, puts and batches that would leave less than
+    /// reserve bytes free fail with Error::OutOfSpace before writing
+    pub fn with_free_space(mut self, directories: Vec<PathBuf>, reserve: u64) -> Hammersbald {
+        self.free_space = Some(FreeSpace { directories, reserve, free: 0, at: 0 });
+        self
+    }
+
+    /// end batches automatically as the policy tells
+    pub fn with_auto_batch(mut self, policy: AutoBatch) -> Hammersbald {
+        self.auto_batch = Some((policy, Instant::now()));
+        self
+    }
+
+    /// replay puts since the last batch from a write ahead log, then log further puts to it,
+    /// every put or only those of put_durable
+    pub fn with_write_ahead_log(mut self, mut wal: WalFile, every_put: bool) -> Result<Hammersbald, Error> {
+        let (base, records) = wal.records()?;
+        // records of a log not extending the last batch are already in the batch or lost with it
+        if base == Some(PRef::from(self.mem.data_len()?)) {
+            for redo in records {
+                match redo {
+                    Redo::Keyed(key, data) => { self.put_keyed(key.as_slice(), data.as_slice())?; },
+                    Redo::KeyedAlso(key, data) => { self.put_keyed_also(key.as_slice(), data.as_slice())?; },
+                    Redo::Durable(pref, key, data) => {
+                        // data lost before it is padded, so the data is stored where it was
+                        self.mem.pad_data_to(pref)?;
+                        self.put_keyed(key.as_slice(), data.as_slice())?;
+                    },
+                    Redo::Referred(data) => { self.put(data.as_slice())?; },
+                    Redo::Forget(key) => self.forget(key.as_slice())?,
+                    Redo::Linked(data, links) => { self.put_with_links(data.as_slice(), links.as_slice())?; },
+                    Redo::Group(entries) => {
+                        let entries = entries.iter().map(|(key, data)| (key.as_slice(), data.as_slice())).collect::<Vec<_>>();
+                        self.put_group(entries.as_slice())?;
+                    }
+                }
+            }
+            self.batch()?;
+        }
+        wal.reset(self.mem.data_len()?)?;
+        self.wal = Some(Box::new(wal));
+        self.log_puts = every_put;
+        Ok(self)
+    }
+
+    /// store identical referred data once, the content hash table is kept in the given db
+    pub fn with_dedup(mut self, db: Box<dyn HammersbaldAPI>) -> Result<Hammersbald, Error> {
+        let mut dedup = Dedup::new(db);
+        let from = dedup.built_until()?;
+        dedup.catch_up(self.mem.data_envelopes_from(from))?;
+        dedup.batch(PRef::from(self.mem.data_len()?))?;
+        self.dedup = Some(dedup);
+        Ok(self)
+    }
+
+    /// record the generation of data stored by each batch in the given file
+    pub fn with_generations(mut self, file: Box<dyn PagedFile>) -> Result<Hammersbald, Error> {
+        self.generations = Some(GenerationFile::new(file, self.mem.data_len()?)?);
+        Ok(self)
+    }
+
+    fn batch_generation(&mut self) -> Result<(), Error> {
+        if let Some(ref mut generations) = self.generations {
+            generations.batch(PRef::from(self.mem.data_len()?))?;
+        }
+        Ok(())
+    }
+
+    // reference of stored referred data identical to data
+    fn stored_duplicate(&self, data: &[u8]) -> Result<Option<PRef>, Error> {
+        if let Some(ref dedup) = self.dedup {
+            if let Some(pref) = dedup.get(data)? {
+                // the table might refer to data dropped by a rollback
+                if pref < self.mem.data_position() {
+                    if let Ok(Payload::Referred(referred)) = Payload::deserialize(self.mem.get_envelope(pref)?.payload()) {
+                        if referred.data == data {
+                            return Ok(Some(pref));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // check limits and throttle for keyed entries
+    fn check_entries(&mut self, entries: &[(&[u8], &[u8])]) -> Result<(), Error> {
+        for (key, data) in entries {
+            fits("key length", MAX_KEY_LEN, key.len() as u64)?;
+            fits("data length", MAX_DATA_LEN, data.len() as u64)?;
+            self.mem.check_key(key)?;
+        }
+        self.throttle(entries.iter().map(|(key, data)| (key.len() + data.len()) as u64).sum())
+    }
+
+    // limits, key size and throttle of a put of keyed data
+    fn check_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<(), Error> {
+        fits("key length", MAX_KEY_LEN, key.len() as u64)?;
+        fits("data length", MAX_DATA_LEN, data.len() as u64)?;
+        self.mem.check_key(key)?;
+        self.throttle((key.len() + data.len()) as u64)
+    }
+
+    // append keyed data and insert the key
+    fn store_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.metrics.put();
+        let data_offset = self.mem.append_data(key, data)?;
+        self.mem.put(key, data_offset)?;
+        for index in self.indexes.values_mut() {
+            index.add(data_offset, key, data)?;
+        }
+        self.changes.stored(key, data_offset);
+        Ok(data_offset)
+    }
+
+    // append data of keyed entries with one write, then insert the keys
+    fn store_entries(&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        let prefs = self.mem.append_data_batch(entries)?;
+        for ((key, data), pref) in entries.iter().zip(prefs.iter()) {
+            self.metrics.put();
+            self.mem.put(key, *pref)?;
+            for index in self.indexes.values_mut() {
+                index.add(*pref, key, data)?;
+            }
+            self.changes.stored(key, *pref);
+        }
+        self.auto_batch()?;
+        Ok(prefs)
+    }
+
+    // the write ahead log if every put is logged
+    fn logged_wal(&mut self) -> Option<&mut WalFile> {
+        if self.log_puts { self.wal.as_deref_mut() } else { None }
+    }
+
+    // copy data of the other db in the order it was stored, so data is copied before data referring to it
+    fn copy_from(&mut self, other: &dyn HammersbaldRead, conflict: Conflict) -> Result<Ingested, Error> {
+        let mut ingested = Ingested::default();
+        let mut copied = HashMap::new();
+        let mut scan = other.iter_prefetch(datafile::SCAN_PREFETCH);
+        for (pref, key, data) in &mut scan {
+            if key.is_empty() {
+                let mut links = Vec::new();
+                for link in other.get_links(pref)? {
+                    links.push(self.copy_link(other, link, &mut copied)?);
+                }
+                let to = if links.is_empty() { self.put(data.as_slice())? } else { self.put_with_links(data.as_slice(), links.as_slice())? };
+                copied.insert(pref, to);
+                ingested.referred += 1;
+            }
+            else if other.get_keyed(key.as_slice())?.map(|(live, _)| live) == Some(pref) {
+                let to = match self.get_keyed(key.as_slice())? {
+                    Some((existing, _)) => {
+                        ingested.conflicts += 1;
+                        match (conflict, self.generation_of(existing)?, other.generation_of(pref)?) {
+                            (Conflict::KeepBoth, _, _) => self.put_also(key.as_slice(), data.as_slice())?,
+                            (_, Some(mine), Some(theirs)) if mine > theirs => existing,
+                            _ => self.put_keyed(key.as_slice(), data.as_slice())?
+                        }
+                    },
+                    None => self.put_keyed(key.as_slice(), data.as_slice())?
+                };
+                copied.insert(pref, to);
+                ingested.keys += 1;
+            }
+        }
+        scan.error()?;
+        Ok(ingested)
+    }
+
+    // reference to the copy of linked data, data not copied as it is no longer live is copied without key
+    fn copy_link(&mut self, other: &dyn HammersbaldRead, link: PRef, copied: &mut HashMap<PRef, PRef>) -> Result<PRef, Error> {
+        if let Some(to) = copied.get(&link) {
+            return Ok(*to);
+        }
+        let (_, data) = other.get(link)?;
+        let to = self.put(data.as_slice())?;
+        copied.insert(link, to);
+        Ok(to)
+    }
+
+    // store keyed data keeping data stored earlier with the key
+    fn put_also(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.metrics.put();
+        let data_offset = self.mem.append_data(key, data)?;
+        self.mem.put_also(key, data_offset)?;
+        for index in self.indexes.values_mut() {
+            index.add(data_offset, key, data)?;
+        }
+        self.changes.stored_also(key, data_offset);
+        Ok(data_offset)
+    }
+
+    // a batch ended, the log restarts and pending bytes are counted from here
+    fn batch_ended(&mut self) -> Result<(), Error> {
+        self.batched = self.mem.data_position().as_u64();
+        if let Some((_, ref mut started)) = self.auto_batch {
+            *started = Instant::now();
+        }
+        if let Some(ref mut wal) = self.wal {
+            wal.reset(self.mem.data_len()?)?;
+        }
+        Ok(())
+    }
+
+    // end the batch after a write if it is due by the auto batch policy
+    fn auto_batch(&mut self) -> Result<(), Error> {
+        if let Some((policy, started)) = self.auto_batch {
+            if policy.is_due(started.elapsed(), self.pending_bytes()) {
+                self.batch()?;
+                self.changes.auto_committed();
+            }
+        }
+        Ok(())
+    }
+
+    // fail or end the batch if storing len bytes would exceed the throttle limit
+    fn throttle(&mut self, len: u64) -> Result<(), Error> {
+        // an envelope of at most 12 bytes and a page completed
+        self.check_quota(len + 12 + PAGE_SIZE as u64)?;
+        if let Some((max, throttle)) = self.throttle {
+            let pending = self.pending_bytes();
+            if pending > 0 && pending + len > max {
+                match throttle {
+                    Throttle::Fail => return Err(Error::WouldBlock(pending)),
+                    Throttle::Block => self.batch()?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the files have space for the bytes within the quota and on their disks
+    fn check_quota(&mut self, bytes: u64) -> Result<(), Error> {
+        let file_bytes = self.mem.file_bytes()?;
+        if self.quota > 0 && file_bytes + bytes > self.quota {
+            return Err(Error::OutOfSpace { bytes, source: None });
+        }
+        if let Some(ref mut free_space) = self.free_space {
+            free_space.check(file_bytes, bytes)?;
+        }
+        Ok(())
+    }
+
     /// load memtable
     fn load(&mut self) -> Result<(), Error> {
         self.mem.load()
@@ -164,26 +869,56 @@ impl Hammersbald {
         self.mem.recover()
     }
 
-    /// get hash table bucket iterator
-    pub fn slots<'a> (&'a self) -> impl Iterator<Item=Vec<(u32, PRef)>> +'a {
+    /// get hash table bucket iterator, iteration ends with the error of a bucket that can not be read
+    pub fn slots<'a> (&'a self) -> impl Iterator<Item=Result<Vec<(u32, PRef)>, Error>> +'a {
         self.mem.slots()
     }
 
+    /// slots of a hash table bucket
+    pub fn bucket_slots(&self, bucket_number: usize) -> Result<Vec<(u32, PRef)>, Error> {
+        self.mem.bucket_slots(bucket_number)
+    }
+
+    /// number of hash table buckets
+    pub fn n_buckets(&self) -> usize {
+        self.mem.n_buckets()
+    }
+
     /// get hash table pointers
     pub fn buckets<'a> (&'a self) -> impl Iterator<Item=PRef> +'a {
         self.mem.buckets()
     }
 
     /// return an iterator of all payloads
-    pub fn data_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+    pub fn data_envelopes<'a>(&'a self) -> impl Iterator<Item=Result<(PRef, Envelope), Error>> +'a {
         self.mem.data_envelopes()
     }
 
     /// return an iterator of all links
-    pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+    pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=Result<(PRef, Envelope), Error>> +'a {
         self.mem.link_envelopes()
     }
 
+    /// lengths of data, table and link files recorded in the log at the start of the batch
+    pub fn logged_lengths(&self) -> Result<Option<(u64, u64, u64)>, Error> {
+        self.mem.logged_lengths()
+    }
+
+    /// decode all envelopes of the data and the link file
+    pub fn check_envelopes(&self) -> (EnvelopeCheck, EnvelopeCheck) {
+        self.mem.check_envelopes()
+    }
+
+    /// average re-use of a hash table entry the table grows for
+    pub fn bucket_fill_target(&self) -> usize {
+        self.mem.bucket_fill_target()
+    }
+
+    /// size of all keys, 0 if keys are of any size
+    pub fn fixed_key_size(&self) -> usize {
+        self.mem.fixed_key_size()
+    }
+
     /// get db params
     pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
         self.mem.params()
@@ -193,91 +928,726 @@ impl Hammersbald {
 impl HammersbaldAPI for Hammersbald {
 
     fn batch (&mut self)  -> Result<(), Error> {
-        self.mem.batch()
+        // checked before the batch writes, so it can be retried once space is freed
+        self.check_quota(self.mem.batch_bytes()?)?;
+        self.mem.batch()?;
+        self.batch_generation()?;
+        // indexes are committed after the data they refer to
+        let until = PRef::from(self.mem.data_len()?);
+        for index in self.indexes.values_mut() {
+            index.batch(until)?;
+        }
+        if let Some(ref mut dedup) = self.dedup {
+            dedup.batch(until)?;
+        }
+        self.batch_ended()?;
+        self.changes.committed();
+        Ok(())
     }
 
-    fn shutdown (&mut self) {
-        self.mem.shutdown()
+    fn observe (&mut self, observer: Arc<dyn CommitObserver>) {
+        self.changes.observe(observer)
     }
 
-    fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
-        #[cfg(debug_assertions)]
-        {
-            if key.len() > 255 || data.len() >= 1 << 23 {
-                return Err(Error::KeyTooLong);
-            }
+    fn pending_bytes (&self) -> u64 {
+        self.mem.data_position().as_u64() - self.batched
+    }
+
+    fn batch_named (&mut self, id: u64) -> Result<(), Error> {
+        self.batch()?;
+        self.mem.checkpoint(id)?;
+        for index in self.indexes.values_mut() {
+            index.checkpoint(id)?;
         }
-        let data_offset = self.mem.append_data(key, data)?;
-        self.mem.put(key, data_offset)?;
-        Ok(data_offset)
+        Ok(())
     }
 
-    fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
-        self.mem.get(key)
+    fn rollback_to (&mut self, id: u64) -> Result<(), Error> {
+        self.mem.rollback_to(id)?;
+        self.changes.discard();
+        if let Some(ref mut generations) = self.generations {
+            generations.truncate(self.mem.data_len()?)?;
+        }
+        // an index created after the checkpoint is rebuilt from the data of the checkpoint
+        let until = PRef::from(self.mem.data_len()?);
+        for index in self.indexes.values_mut() {
+            index.rollback_to(id)?;
+            let from = index.built_until()?;
+            index.catch_up(self.mem.data_envelopes_from(from))?;
+            index.batch(until)?;
+        }
+        self.batch_ended()
     }
 
-    fn put(&mut self, data: &[u8]) -> Result<PRef, Error> {
-        let data_offset = self.mem.append_referred(data)?;
-        Ok(data_offset)
+    fn snapshot (&mut self, name: &str) -> Result<(), Error> {
+        Persistent::snapshot_free(name)?;
+        let snapshot = self.mem.snapshot_link(name, self.max_file_size)?;
+        let (mut log, mut table, mut data, mut link) = Persistent::snapshot_files(name, self.max_file_size)?;
+        self.mem.snapshot_copy(&snapshot, &mut log, &mut table, &mut data, &mut link)?;
+        Persistent::snapshot_key(name, self.mem.cipher(), self.max_file_size)?;
+        self.batch_generation()?;
+        self.batch_ended()
     }
 
-    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
-        let envelope = self.mem.get_envelope(pref)?;
-        match Payload::deserialize(envelope.payload())? {
-            Payload::Referred(referred) => return Ok((vec!(), referred.data.to_vec())),
-            Payload::Indexed(indexed) => return Ok((indexed.key.to_vec(), indexed.data.data.to_vec())),
-            _ => Err(Error::Corrupted("referred should point to data".to_string()))
-        }
+    fn backup_incremental (&mut self, since: u64, target: &mut dyn Write) -> Result<u64, Error> {
+        let pages = backup::backup_incremental(&mut self.mem, since, target)?;
+        self.batch_generation()?;
+        self.batch_ended()?;
+        Ok(pages)
     }
 
-    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
-        self.mem.may_have_key(key)
+    fn bulk_load (&mut self, expected_keys: usize) -> Result<(), Error> {
+        self.mem.bulk_load(expected_keys)
     }
 
-    fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
-        self.mem.forget(key)
+    fn resize (&mut self, buckets: usize) -> Result<(), Error> {
+        self.mem.resize(buckets);
+        Ok(())
     }
 
-    fn iter(&self) -> HammersbaldIterator {
-        HammersbaldIterator{ ei: self.mem.data_envelopes()}
+    fn rebuild_index (&mut self) -> Result<(), Error> {
+        self.mem.rebuild()?;
+        self.batch_generation()?;
+        self.batch_ended()
     }
-}
 
-/// iterate data content
-pub struct HammersbaldIterator<'a> {
-    ei: EnvelopeIterator<'a>
-}
+    fn shutdown (&mut self) {
+        for index in self.indexes.values_mut() {
+            index.shutdown();
+        }
+        if let Some(ref mut wal) = self.wal {
+            wal.shutdown();
+        }
+        if let Some(ref mut dedup) = self.dedup {
+            dedup.shutdown();
+        }
+        if let Some(ref mut generations) = self.generations {
+            generations.shutdown();
+        }
+        self.mem.shutdown();
+        // the lock is released with its file
+        self.lock = None;
+    }
 
-impl<'a> Iterator for HammersbaldIterator<'a> {
-    type Item = (PRef, Vec<u8>, Vec<u8>);
+    fn close (mut self: Box<Self>) {
+        self.shutdown()
+    }
 
-    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        if let Some((pref, envelope)) = self.ei.next() {
-            match Payload::deserialize(envelope.payload()).unwrap() {
-                Payload::Indexed(indexed) => {
-                    return Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec()))
-                },
-                Payload::Referred(referred) => {
-                    return Some((pref, vec!(), referred.data.to_vec()))
-                },
-                _ => return None
-            }
+    fn put_keyed(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.check_keyed(key, data)?;
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::Keyed(key.to_vec(), data.to_vec()))?;
         }
-        None
+        let data_offset = self.store_keyed(key, data)?;
+        self.auto_batch()?;
+        Ok(data_offset)
     }
-}
+
+    fn put_keyed_also(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        self.check_keyed(key, data)?;
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::KeyedAlso(key.to_vec(), data.to_vec()))?;
+        }
+        let data_offset = self.put_also(key, data)?;
+        self.auto_batch()?;
+        Ok(data_offset)
+    }
+
+    fn put_durable(&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        if self.log_puts || self.wal.is_none() {
+            return self.put_keyed(key, data);
+        }
+        self.check_keyed(key, data)?;
+        // logged once stored, so the record has the reference
+        let data_offset = self.store_keyed(key, data)?;
+        if let Some(ref mut wal) = self.wal {
+            wal.append(&Redo::Durable(data_offset, key.to_vec(), data.to_vec()))?;
+        }
+        self.auto_batch()?;
+        Ok(data_offset)
+    }
+
+    fn put_hashed(&mut self, data: &[u8]) -> Result<(PRef, [u8; 32]), Error> {
+        let hash = sha256::Hash::hash(data).into_inner();
+        // only the keys are read to find data stored earlier
+        if let Some((pref, _)) = self.get_keys_only(&hash)?.into_iter().find(|(_, key)| key[..] == hash[..]) {
+            return Ok((pref, hash));
+        }
+        Ok((self.put_keyed(&hash, data)?, hash))
+    }
+
+    fn put_content_stable(&mut self, data: &[u8]) -> Result<(PRef, ContentId), Error> {
+        let id = ContentId::unused(self)?;
+        Ok((self.put_keyed(&id.key(), data)?, id))
+    }
+
+    fn put_batch(&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        self.check_entries(entries)?;
+        if let Some(wal) = self.logged_wal() {
+            wal.append_all(entries.iter().map(|(key, data)| Redo::Keyed(key.to_vec(), data.to_vec())).collect::<Vec<_>>().as_slice())?;
+        }
+        self.store_entries(entries)
+    }
+
+    fn put_group(&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        self.check_entries(entries)?;
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::Group(entries.iter().map(|(key, data)| (key.to_vec(), data.to_vec())).collect()))?;
+        }
+        self.store_entries(entries)
+    }
+
+    fn put(&mut self, data: &[u8]) -> Result<PRef, Error> {
+        fits("data length", MAX_DATA_LEN, data.len() as u64)?;
+        self.metrics.put();
+        if let Some(pref) = self.stored_duplicate(data)? {
+            return Ok(pref);
+        }
+        self.throttle(data.len() as u64)?;
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::Referred(data.to_vec()))?;
+        }
+        let data_offset = self.mem.append_referred(data)?;
+        if let Some(ref mut dedup) = self.dedup {
+            dedup.add(data_offset, data)?;
+        }
+        self.changes.stored(&[], data_offset);
+        self.auto_batch()?;
+        Ok(data_offset)
+    }
+
+    fn put_stream(&mut self, reader: &mut dyn Read, len: u64) -> Result<PRef, Error> {
+        fits("data length", MAX_DATA_LEN, len)?;
+        self.throttle(len)?;
+        self.metrics.put();
+        let data_offset = self.mem.append_stream(reader, len)?;
+        self.changes.stored(&[], data_offset);
+        if self.log_puts {
+            // not logged, durable with the batch and later puts are replayed where they were stored
+            self.batch()?;
+        }
+        else {
+            self.auto_batch()?;
+        }
+        Ok(data_offset)
+    }
+
+    fn put_with_links(&mut self, data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        fits("data length", MAX_DATA_LEN, data.len() as u64)?;
+        fits("number of links", MAX_LINKS, links.len() as u64)?;
+        self.throttle((data.len() + 6 * links.len()) as u64)?;
+        self.metrics.put();
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::Linked(data.to_vec(), links.to_vec()))?;
+        }
+        let data_offset = self.mem.append_linked(data, links)?;
+        self.changes.stored(&[], data_offset);
+        self.auto_batch()?;
+        Ok(data_offset)
+    }
+
+    fn ingest(&mut self, other: &dyn HammersbaldRead, conflict: Conflict) -> Result<Ingested, Error> {
+        if conflict == Conflict::Error {
+            let mut keys = other.iter_keys();
+            for (_, key) in &mut keys {
+                if self.get_keyed(key.as_slice())?.is_some() {
+                    return Err(Error::Conflict(key));
+                }
+            }
+            keys.error()?;
+        }
+        self.batch()?;
+        // ingested data is durable with the next batch, it is not logged
+        let log_puts = self.log_puts;
+        self.log_puts = false;
+        let ingested = self.copy_from(other, conflict);
+        self.log_puts = log_puts;
+        let ingested = ingested?;
+        self.batch()?;
+        Ok(ingested)
+    }
+
+    fn import(&mut self, reader: &mut dyn Read) -> Result<u64, Error> {
+        dump::import(self, reader)
+    }
+
+    fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
+        if let Some(wal) = self.logged_wal() {
+            wal.append(&Redo::Forget(key.to_vec()))?;
+        }
+        self.mem.forget(key)?;
+        self.changes.forgotten(key);
+        self.auto_batch()
+    }
+
+    fn create_index(&mut self, name: &str, keys: IndexKeys) -> Result<(), Error> {
+        let db = match self.index_factory {
+            Some(ref factory) => factory(name)?,
+            None => return Err(Error::UnknownIndex(name.to_string()))
+        };
+        let mut index = Index::new(db, keys);
+        let from = index.built_until()?;
+        index.catch_up(self.mem.data_envelopes_from(from))?;
+        index.batch(PRef::from(self.mem.data_len()?))?;
+        if let Some(mut previous) = self.indexes.insert(name.to_string(), index) {
+            previous.shutdown();
+        }
+        Ok(())
+    }
+
+    fn get_by_index(&self, name: &str, index_key: &[u8]) -> Result<Option<KeyedDataAt>, Error> {
+        if let Some(index) = self.indexes.get(name) {
+            if let Some(pref) = index.get(index_key)? {
+                let (key, data) = self.get(pref)?;
+                // the index is not updated as keys are forgotten or overwritten
+                if self.mem.stored_with(key.as_slice())?.contains(&pref) {
+                    return Ok(Some((pref, key, data)));
+                }
+            }
+            return Ok(None);
+        }
+        Err(Error::UnknownIndex(name.to_string()))
+    }
+
+    fn set_generation(&mut self, generation: u64) -> Result<(), Error> {
+        match self.generations {
+            Some(ref mut generations) => generations.set_current(generation),
+            None => Err(Error::InvalidGeneration(generation))
+        }
+    }
+
+    fn prune_before(&mut self, generation: u64) -> Result<usize, Error> {
+        let start = match self.generations {
+            Some(ref generations) => generations.start_of(generation).unwrap_or_else(|| self.mem.data_position()),
+            None => return Err(Error::InvalidGeneration(generation))
+        };
+        // data of later generations might refer to older data
+        let mut referred = HashSet::new();
+        for envelope in self.mem.data_envelopes_from(start) {
+            if let Payload::Linked(linked) = Payload::deserialize(envelope?.1.payload())? {
+                referred.extend(linked.links());
+            }
+        }
+        let pruned = {
+            let mut live = self.iter_live();
+            let pruned = live.by_ref().filter(|(pref, _, _)| *pref < start && !referred.contains(pref))
+                .map(|(_, key, _)| key).collect::<Vec<_>>();
+            live.error()?;
+            pruned
+        };
+        for key in &pruned {
+            self.forget(key.as_slice())?;
+        }
+        Ok(pruned.len())
+    }
+
+    fn column(&mut self, name: &str) -> Result<Column<'_>, Error> {
+        Column::new(self, name)
+    }
+}
+
+impl HammersbaldRead for Hammersbald {
+    fn get_keyed(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.metrics.get();
+        self.mem.get(key)
+    }
+
+    fn get_hashed(&self, hash: &[u8; 32]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.get_keyed(hash)
+    }
+
+    fn resolve(&self, id: ContentId) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.get_keyed(&id.key())
+    }
+
+    fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<DataAt>>, Error> {
+        for _ in keys {
+            self.metrics.get();
+        }
+        self.mem.get_many(keys)
+    }
+
+    fn get_filtered(&self, key: &[u8], predicate: &dyn Fn(&[u8], &[u8]) -> bool) -> Result<Vec<KeyedDataAt>, Error> {
+        self.mem.get_filtered(key, predicate)
+    }
+
+    fn get_keys_only(&self, key: &[u8]) -> Result<Vec<(PRef, Vec<u8>)>, Error> {
+        self.mem.get_keys_only(key)
+    }
+
+    fn count(&self, key: &[u8]) -> Result<usize, Error> {
+        self.mem.count(key)
+    }
+
+    fn get_nth(&self, key: &[u8], n: usize) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.metrics.get();
+        self.mem.get_nth(key, n)
+    }
+
+    fn get_all(&self, key: &[u8]) -> Result<GetIterator<'_>, Error> {
+        Ok(GetIterator { db: self, prefs: self.mem.stored_with(key)?.into_iter() })
+    }
+
+    fn get(&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        self.metrics.get();
+        let envelope = self.mem.get_envelope(pref)?;
+        match Payload::deserialize(envelope.payload())? {
+            Payload::Referred(referred) => Ok((vec!(), referred.data.to_vec())),
+            Payload::Indexed(indexed) => Ok((indexed.key.to_vec(), indexed.data.data.to_vec())),
+            Payload::Linked(linked) => Ok((vec!(), linked.data.data.to_vec())),
+            _ => Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "referred should point to data".to_string() })
+        }
+    }
+
+    fn get_stream(&self, pref: PRef) -> Result<Box<dyn Read + '_>, Error> {
+        self.metrics.get();
+        Ok(Box::new(self.mem.get_stream(pref)?))
+    }
+
+    fn get_ref(&self, pref: PRef) -> Result<ContentRef, Error> {
+        self.metrics.get();
+        ContentRef::new(self.mem.get_envelope(pref)?)
+    }
+
+    fn get_links(&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        let envelope = self.mem.get_envelope(pref)?;
+        match Payload::deserialize(envelope.payload())? {
+            Payload::Linked(linked) => Ok(linked.links()),
+            Payload::Link(_) => Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "referred should point to data".to_string() }),
+            _ => Ok(vec!())
+        }
+    }
+
+    fn entry_info(&self, pref: PRef) -> Result<EntryInfo, Error> {
+        let (len, payload_type, count) = self.mem.get_head(pref)?;
+        let (key_len, links) = match payload_type & !(compression::CODEC_MASK | ENCRYPTED | EXTENDED) {
+            0 | 5 => (count, 0),
+            1 => (0, 0),
+            3 => (0, count),
+            _ => return Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "referred should point to data".to_string() })
+        };
+        // the length precedes the envelope
+        let end = pref.as_u64() + 3 + len as u64;
+        Ok(EntryInfo {
+            stored_len: len, key_len, links,
+            spans_pages: (end - 1) / PAGE_SIZE as u64 - pref.this_page().as_u64() / PAGE_SIZE as u64 + 1,
+            compressed: payload_type & compression::CODEC_MASK != 0,
+            encrypted: payload_type & ENCRYPTED != 0,
+            extended: payload_type & EXTENDED != 0,
+            generation: self.generation_of(pref)?
+        })
+    }
+
+    fn verify_links(&self) -> Result<Vec<(PRef, PRef)>, Error> {
+        let mut stored = HashSet::new();
+        let mut links = Vec::new();
+        for envelope in self.mem.data_envelopes() {
+            let (pref, envelope) = envelope?;
+            stored.insert(pref);
+            if let Payload::Linked(linked) = Payload::deserialize(envelope.payload())? {
+                links.extend(linked.links().into_iter().map(|link| (pref, link)));
+            }
+        }
+        Ok(links.into_iter().filter(|(_, link)| !stored.contains(link)).collect())
+    }
+
+    fn table_stats(&self) -> Result<TableStats, Error> {
+        stats::table_stats(self)
+    }
+
+    fn needs_resize(&self) -> Result<bool, Error> {
+        Ok(self.table_stats()?.needs_resize())
+    }
+
+    fn sample_keys(&self, n: usize) -> Result<Vec<(Vec<u8>, usize)>, Error> {
+        stats::sample_keys(self, n)
+    }
+
+    fn verify(&self, level: VerifyLevel) -> Result<VerifyReport, Error> {
+        stats::verify(self, level)
+    }
+
+    fn probe(&self, key: &[u8]) -> Result<Probe, Error> {
+        self.mem.probe(key)
+    }
+
+    fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
+        self.mem.may_have_key(key)
+    }
+
+    fn iter(&self) -> HammersbaldIterator<'_> {
+        HammersbaldIterator{ ei: Box::new(self.mem.data_envelopes()), error: None }
+    }
+
+    fn iter_prefetch(&self, pages: usize) -> HammersbaldIterator<'_> {
+        HammersbaldIterator{ ei: Box::new(self.mem.data_envelopes().prefetch(pages)), error: None }
+    }
+
+    fn export(&self, writer: &mut dyn Write) -> Result<u64, Error> {
+        dump::export(self, writer)
+    }
+
+    fn cursor_at(&self, pos: PRef) -> Result<DataCursor<'_>, Error> {
+        self.mem.check_data_boundary(pos)?;
+        Ok(DataCursor { ei: self.mem.data_envelopes_from(pos), error: None })
+    }
+
+    fn iter_live(&self) -> HammersbaldIterator<'_> {
+        HammersbaldIterator{ ei: Box::new(self.mem.live_envelopes()), error: None }
+    }
+
+    fn iter_keys(&self) -> HammersbaldKeyIterator<'_> {
+        HammersbaldKeyIterator{ ki: Box::new(self.mem.live_keys()), error: None }
+    }
+
+    fn generation(&self) -> Option<u64> {
+        self.generations.as_ref().map(|g| g.current())
+    }
+
+    fn generation_of(&self, pref: PRef) -> Result<Option<u64>, Error> {
+        if pref >= self.mem.data_position() {
+            return Err(Error::InvalidOffset);
+        }
+        Ok(self.generations.as_ref().and_then(|g| g.generation_of(pref)))
+    }
+
+    fn data_len(&self) -> u64 {
+        self.mem.data_position().as_u64()
+    }
+}
+
+/// data at a persistent reference, key and data are borrowed from the buffer it was read into
+pub struct ContentRef {
+    envelope: Envelope
+}
+
+impl ContentRef {
+    fn new (envelope: Envelope) -> Result<ContentRef, Error> {
+        match Payload::deserialize(envelope.payload())? {
+            Payload::Link(_) | Payload::Padding(_) => Err(Error::Corrupted("referred should point to data".to_string())),
+            _ => Ok(ContentRef { envelope })
+        }
+    }
+
+    /// key of the data, empty if not stored with a key
+    pub fn key (&self) -> &[u8] {
+        match Payload::deserialize(self.envelope.payload()) {
+            Ok(Payload::Indexed(indexed)) => indexed.key,
+            _ => &[]
+        }
+    }
+
+    /// the data
+    pub fn data (&self) -> &[u8] {
+        match Payload::deserialize(self.envelope.payload()) {
+            Ok(Payload::Indexed(indexed)) => indexed.data.data,
+            Ok(Payload::Referred(referred)) => referred.data,
+            Ok(Payload::Linked(linked)) => linked.data.data,
+            _ => &[]
+        }
+    }
+
+    /// copy of (key, data)
+    pub fn to_owned (&self) -> (Vec<u8>, Vec<u8>) {
+        (self.key().to_vec(), self.data().to_vec())
+    }
+}
+
+/// iterate data content, iteration ends early if data can not be read or decoded, see error
+pub struct HammersbaldIterator<'a> {
+    ei: Box<dyn Iterator<Item=Result<(PRef, Envelope), Error>> + 'a>,
+    error: Option<Error>
+}
+
+impl<'a> HammersbaldIterator<'a> {
+    /// persistent references of the data only, key and data are not copied
+    pub fn prefs<'s>(&'s mut self) -> Box<dyn Iterator<Item=PRef> + 's> {
+        Box::new(iter::from_fn(move || next_content(&mut self.ei, &mut self.error).map(|(pref, _)| pref)))
+    }
+
+    /// fail with the error that ended iteration before the end of the data, e.g. data encrypted
+    /// with an other key, compressed with a codec not compiled in or corrupted
+    pub fn error(&mut self) -> Result<(), Error> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl<'a> Iterator for HammersbaldIterator<'a> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        next_content(&mut self.ei, &mut self.error).and_then(|(pref, envelope)| content(pref, &envelope))
+    }
+}
+
+/// iterate data content from a position, iteration ends early if data can not be read or decoded, see error
+pub struct DataCursor<'a> {
+    ei: EnvelopeIterator<'a>,
+    error: Option<Error>
+}
+
+impl<'a> DataCursor<'a> {
+    /// position after the data returned last, pass it to cursor_at to resume
+    pub fn position(&self) -> PRef {
+        self.ei.position()
+    }
+
+    /// persistent references of the data only, key and data are not copied
+    pub fn prefs<'s>(&'s mut self) -> Box<dyn Iterator<Item=PRef> + 's> {
+        Box::new(iter::from_fn(move || next_content(&mut self.ei, &mut self.error).map(|(pref, _)| pref)))
+    }
+
+    /// fail with the error that ended iteration before the end of the data, see HammersbaldIterator::error
+    pub fn error(&mut self) -> Result<(), Error> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl<'a> Iterator for DataCursor<'a> {
+    type Item = (PRef, Vec<u8>, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        next_content(&mut self.ei, &mut self.error).and_then(|(pref, envelope)| content(pref, &envelope))
+    }
+}
+
+// next envelope of data, none after an error that is kept
+fn next_content<I: Iterator<Item=Result<(PRef, Envelope), Error>>> (ei: &mut I, error: &mut Option<Error>) -> Option<(PRef, Envelope)> {
+    if error.is_some() {
+        return None;
+    }
+    loop {
+        match ei.next()? {
+            Ok((pref, envelope)) => if is_content(&envelope) { return Some((pref, envelope)) },
+            Err(e) => {
+                *error = Some(e);
+                return None;
+            }
+        }
+    }
+}
+
+// (persistent reference, key, data) of an envelope of the data file
+fn content(pref: PRef, envelope: &Envelope) -> Option<(PRef, Vec<u8>, Vec<u8>)> {
+    match Payload::deserialize(envelope.payload()).unwrap() {
+        Payload::Indexed(indexed) => Some((pref, indexed.key.to_vec(), indexed.data.data.to_vec())),
+        Payload::Referred(referred) => Some((pref, vec!(), referred.data.to_vec())),
+        Payload::Linked(linked) => Some((pref, vec!(), linked.data.data.to_vec())),
+        _ => None
+    }
+}
+
+// the envelope holds indexed, referred or linked data
+fn is_content(envelope: &Envelope) -> bool {
+    matches!(Payload::deserialize(envelope.payload()), Ok(Payload::Indexed(_)) | Ok(Payload::Referred(_)) | Ok(Payload::Linked(_)))
+}
+
+/// iterate keys, iteration ends early if a key can not be read, see error
+pub struct HammersbaldKeyIterator<'a> {
+    ki: Box<dyn Iterator<Item=Result<KeyAt, Error>> + 'a>,
+    error: Option<Error>
+}
+
+impl<'a> HammersbaldKeyIterator<'a> {
+    /// fail with the error that ended iteration before the last key, see HammersbaldIterator::error
+    pub fn error(&mut self) -> Result<(), Error> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+impl<'a> Iterator for HammersbaldKeyIterator<'a> {
+    type Item = (PRef, Vec<u8>);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.ki.next()? {
+            Ok(key) => Some(key),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+/// iterate (persistent reference, data) stored with a key, of exact size as the references are found at the start
+pub struct GetIterator<'a> {
+    db: &'a dyn HammersbaldRead,
+    prefs: ::std::vec::IntoIter<PRef>
+}
+
+impl<'a> GetIterator<'a> {
+    /// persistent references of the data only, data is not read
+    pub fn prefs(self) -> ::std::vec::IntoIter<PRef> {
+        self.prefs
+    }
+
+    fn read(&self, pref: PRef) -> Result<DataAt, Error> {
+        self.db.get(pref).map(|(_, data)| (pref, data))
+    }
+}
+
+impl<'a> Iterator for GetIterator<'a> {
+    type Item = Result<DataAt, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.prefs.next().map(|pref| self.read(pref))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.prefs.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for GetIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.prefs.next_back().map(|pref| self.read(pref))
+    }
+}
+
+impl<'a> ExactSizeIterator for GetIterator<'a> {}
 
 #[cfg(test)]
-mod test {
+pub mod test {
     extern crate rand;
     extern crate hex;
 
     use transient::Transient;
+    use persistent::Persistent;
+    use pagedfile::PagedFileAppender;
+    use error::{Error, ErrorClass};
+    use super::{transient, persistent, persistent_with_config, read_only, read_only_with_config, migrate, repair, Conflict, Ingested, HammersbaldAPI, HammersbaldRead};
+    use config::{Config, SyncMode, Throttle, AutoBatch};
+    use index::IndexKeys;
+    use pref::PRef;
+    use metrics::Metrics;
+    use observer::{CommitObserver, Change};
+    use stats::VerifyLevel;
+    use model::{MemoryStore, conformance};
+    use overflow::{Overflow, Chained, RobinHood};
+    use page::{Page, PAGE_SIZE};
+    use pagedfile::{PagedFile, PageStore};
+    use memtable::key_hash;
 
     use self::rand::thread_rng;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use std::{env, fs};
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
     use api::test::rand::RngCore;
 
+    /// a db name in a fresh temporary directory
+    pub fn temp_db_name(test: &str) -> String {
+        let dir = env::temp_dir().join(format!("hammersbald-{}-{}", test, thread_rng().next_u64()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("test").to_string_lossy().to_string()
+    }
+
     #[test]
     fn test_two_batches () {
         let mut db = Transient::new_db("first", 1, 1).unwrap();
@@ -313,4 +1683,2210 @@ mod test {
         }
         db.shutdown();
     }
-}
\ No newline at end of file
+
+    // a page store recording the pages read from each file
+    struct Recording {
+        store: MemoryStore,
+        reads: Arc<Mutex<Vec<(String, PRef)>>>
+    }
+
+    struct RecordingFile {
+        extension: String,
+        file: Box<dyn PagedFile>,
+        reads: Arc<Mutex<Vec<(String, PRef)>>>
+    }
+
+    impl PageStore for Recording {
+        fn open (&self, name: &str, extension: &str, append_only: bool, read_only: bool) -> Result<Box<dyn PagedFile>, Error> {
+            let file = self.store.open(name, extension, append_only, read_only)?;
+            Ok(Box::new(RecordingFile { extension: extension.to_string(), file, reads: self.reads.clone() }))
+        }
+    }
+
+    impl PagedFile for RecordingFile {
+        fn read_page (&self, pref: PRef) -> Result<Option<Page>, Error> {
+            self.reads.lock().unwrap().push((self.extension.clone(), pref));
+            self.file.read_page(pref)
+        }
+        fn len (&self) -> Result<u64, Error> { self.file.len() }
+        fn truncate (&mut self, new_len: u64) -> Result<(), Error> { self.file.truncate(new_len) }
+        fn sync (&self) -> Result<(), Error> { self.file.sync() }
+        fn shutdown (&mut self) { self.file.shutdown() }
+        fn append_page (&mut self, page: Page) -> Result<(), Error> { self.file.append_page(page) }
+        fn update_page (&mut self, page: Page) -> Result<u64, Error> { self.file.update_page(page) }
+        fn flush (&mut self) -> Result<(), Error> { self.file.flush() }
+    }
+
+    #[test]
+    fn test_get_many () {
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let store = Arc::new(Recording { store: MemoryStore::new(), reads: reads.clone() });
+        let config = Config::default().page_store(store);
+        let mut db = persistent_with_config("db", &config).unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 5000u32 {
+            let key = i.to_be_bytes();
+            check.insert(key, db.put_keyed(&key, &[i as u8; 300]).unwrap());
+        }
+        db.batch().unwrap();
+        // links of the buckets of later keys are written after those of the others
+        for i in 5000 .. 5100u32 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 300]).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        // buckets are read from the link file as needed
+        let db = persistent_with_config("db", &config.clone().memory_budget(1)).unwrap();
+        let mut keys = (0 .. 5000u32).rev().step_by(7).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        keys.insert(100, b"missing".to_vec());
+        let keys = keys.iter().map(|k| k.as_slice()).collect::<Vec<_>>();
+        reads.lock().unwrap().clear();
+        let many = db.get_many(keys.as_slice()).unwrap();
+
+        // results are in the order of the keys
+        assert_eq!(many.len(), keys.len());
+        assert_eq!(many[100], None);
+        for (key, found) in keys.iter().zip(many.iter()).filter(|(key, _)| **key != b"missing") {
+            let i = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+            assert_eq!(found, &Some((check[*key], vec!(i as u8; 300))));
+        }
+        // pages of the link and the data file are read in the order of their position
+        let reads = reads.lock().unwrap();
+        for extension in ["bl", "bc"].iter() {
+            let positions = reads.iter().filter(|(e, _)| e == extension).map(|(_, pref)| *pref).collect::<Vec<_>>();
+            assert!(!positions.is_empty());
+            assert!(positions.windows(2).all(|w| w[0] <= w[1]), "{} read out of order", extension);
+        }
+    }
+
+    #[test]
+    fn test_overwrite () {
+        let mut db = Transient::new_db("overwrite", 1, 1).unwrap();
+        for i in 0 .. 1000u32 {
+            db.put_keyed(&i.to_be_bytes(), b"first").unwrap();
+        }
+        db.batch().unwrap();
+        let buckets = db.table_stats().unwrap().buckets;
+        let mut last = PRef::invalid();
+        for round in 0 .. 20u32 {
+            for i in 0 .. 1000u32 {
+                last = db.put_keyed(&i.to_be_bytes(), &round.to_be_bytes()).unwrap();
+            }
+        }
+        db.batch().unwrap();
+        // overwrites replace the link entry of the key, they neither add entries nor split buckets
+        let stats = db.table_stats().unwrap();
+        assert_eq!(stats.buckets, buckets);
+        assert_eq!(stats.keys, 1000);
+        assert_eq!(db.count(&999u32.to_be_bytes()).unwrap(), 1);
+        assert_eq!(db.get_keyed(&999u32.to_be_bytes()).unwrap(), Some((last, 19u32.to_be_bytes().to_vec())));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_migrate () {
+        let name = temp_db_name("migrate");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        // strip the header pages to simulate files of the legacy format
+        // legacy dbs have no bloom file
+        let dir = Path::new(&name).parent().unwrap().to_path_buf();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == "bf" {
+                fs::remove_file(&path).unwrap();
+                continue;
+            }
+            // neither lock nor write ahead log are versioned
+            if path.extension().unwrap() == "lock" || path.extension().unwrap() == "wl" {
+                continue;
+            }
+            let content = fs::read(&path).unwrap();
+            fs::write(&path, &content[4096..]).unwrap();
+        }
+        match persistent(&name, 10, 1) {
+            Err(Error::UnsupportedVersion(1)) => {},
+            _ => panic!("legacy files should be rejected")
+        }
+
+        migrate(&name).unwrap();
+        let mut db = persistent(&name, 10, 1).unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot () {
+        let name = temp_db_name("snapshot");
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        for _ in 0 .. 1000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            let pref = db.put_keyed(&key, &data).unwrap();
+            check.insert(key, (pref, data));
+        }
+        db.snapshot(&name).unwrap();
+        assert!(db.snapshot(&name).is_err());
+        // writes after the snapshot are not in the copy
+        db.put_keyed(b"later", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut copy = persistent(&name, 10, 1).unwrap();
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(copy.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+        assert!(copy.get_keyed(b"later").unwrap().is_none());
+        copy.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_linked () {
+        let name = temp_db_name("snapshot-linked");
+        let snapshot = format!("{}-snapshot", name);
+        let config = Config::default().max_file_size(1 << 16);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 2000u32 {
+            if i == 1000 {
+                db.batch_named(1).unwrap();
+            }
+            let key = format!("key{}", i);
+            let pref = db.put_keyed(key.as_bytes(), &[i as u8; 200]).unwrap();
+            check.insert(key, pref);
+        }
+        db.snapshot(&snapshot).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(fs::metadata(format!("{}.1.bc", snapshot)).unwrap().nlink(), 2);
+        }
+        // the rollback truncates chunks shared with the snapshot, the appends after it reuse their space
+        db.rollback_to(1).unwrap();
+        for i in 1000 .. 2000u32 {
+            db.put_keyed(format!("other{}", i).as_bytes(), &[0xff; 200]).unwrap();
+        }
+        db.batch().unwrap();
+        assert!(db.get_keyed(b"key1999").unwrap().is_none());
+        db.shutdown();
+
+        let mut copy = persistent_with_config(&snapshot, &config).unwrap();
+        for (key, pref) in check.iter() {
+            let i = key[3..].parse::<u32>().unwrap();
+            assert_eq!(copy.get_keyed(key.as_bytes()).unwrap(), Some((*pref, vec!(i as u8; 200))));
+        }
+        assert!(copy.get_keyed(b"other1999").unwrap().is_none());
+        copy.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_backup_incremental () {
+        let name = temp_db_name("backup");
+        let base = format!("{}-base", name);
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let mut check = HashMap::new();
+        let mut rng = thread_rng();
+        let mut put = |db: &mut Box<dyn HammersbaldAPI>, check: &mut HashMap<u32, Option<(PRef, Vec<u8>)>>, key: u32| {
+            let mut data = vec!(0u8; 100);
+            rng.fill_bytes(&mut data);
+            check.insert(key, Some((db.put_keyed(&key.to_be_bytes(), &data).unwrap(), data)));
+        };
+        for key in 0 .. 2000 {
+            put(&mut db, &mut check, key);
+        }
+        db.batch_named(1).unwrap();
+        db.snapshot(&base).unwrap();
+
+        // overwrites, forgets and new keys splitting buckets, in two deltas
+        let mut deltas = Vec::new();
+        for (checkpoint, keys) in [(1, 1000 .. 6000u32), (2, 5000 .. 9000u32)].iter() {
+            for key in keys.clone() {
+                put(&mut db, &mut check, key);
+            }
+            for key in keys.clone().step_by(7) {
+                db.forget(&key.to_be_bytes()).unwrap();
+                check.insert(key, None);
+            }
+            db.batch_named(checkpoint + 1).unwrap();
+            let mut delta = Vec::new();
+            let pages = db.backup_incremental(*checkpoint, &mut delta).unwrap();
+            // pages of the data, link and table files of the db
+            let all = fs::read_dir(Path::new(&name).parent().unwrap()).unwrap().map(|entry| entry.unwrap().path())
+                .filter(|path| !path.to_string_lossy().contains("-base") && ["bc", "bl", "tb"].iter().any(|e| path.extension().unwrap() == *e))
+                .map(|path| fs::metadata(path).unwrap().len()).sum::<u64>() / PAGE_SIZE as u64;
+            assert!(pages > 0 && pages < all, "{} pages of {}", pages, all);
+            deltas.push(delta);
+        }
+        match db.backup_incremental(7, &mut Vec::new()) {
+            Err(Error::UnknownCheckpoint(7)) => {},
+            other => panic!("expected unknown checkpoint, got {:?}", other)
+        }
+        db.shutdown();
+        drop(db);
+
+        // deltas apply in order onto the snapshot of the first checkpoint
+        assert!(super::restore(&base, &mut deltas[1].as_slice()).is_err());
+        assert!(super::restore(&base, &mut &b"not a delta"[..]).is_err());
+        for delta in &deltas {
+            assert!(super::restore(&base, &mut delta.as_slice()).unwrap() > 0);
+        }
+        assert!(super::restore(&base, &mut deltas[1].as_slice()).is_err());
+        let mut restored = persistent(&base, 10, 1).unwrap();
+        for (key, expected) in &check {
+            assert_eq!(restored.get_keyed(&key.to_be_bytes()).unwrap(), *expected);
+        }
+        assert!(restored.verify(VerifyLevel::Deep).unwrap().is_ok());
+        restored.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_read_only () {
+        let name = temp_db_name("read_only");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        db.put_keyed(b"uncommitted", b"data").unwrap();
+
+        let reader = read_only(&name, 10).unwrap();
+        assert_eq!(reader.get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+        assert_eq!(reader.get(pref).unwrap(), (b"key".to_vec(), b"data".to_vec()));
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_read_only_sync () {
+        let name = temp_db_name("read_only_sync");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        let files = || ["bc", "bl", "lg", "tb"].iter().map(|e| fs::read(format!("{}.0.{}", name, e)).unwrap()).collect::<Vec<_>>();
+        let before = files();
+
+        // files opened without write access are synced without a change
+        for extension in ["bc", "bl", "lg", "tb"].iter() {
+            let file = ::rolledfile::RolledFile::new_read_only(&name, extension, &Config::default()).unwrap();
+            file.sync().unwrap();
+            file.sync().unwrap();
+        }
+        let reader = Persistent::open_read_only(&name, &Config::default()).unwrap();
+        assert_eq!(reader.get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+        drop(reader);
+        assert!(files() == before);
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_fixed_key_size () {
+        let name = temp_db_name("fixed_key_size");
+        let config = Config::default().fixed_key_size(32);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut rng = thread_rng();
+        let mut stored = Vec::new();
+        for _ in 0 .. 2000 {
+            let mut key = [0u8; 32];
+            rng.fill_bytes(&mut key);
+            stored.push((key, db.put_keyed(&key, &key[.. 8]).unwrap()));
+        }
+        assert!(db.put_keyed(b"short", b"data").is_err());
+        db.batch().unwrap();
+        assert_eq!(db.table_stats().unwrap().fixed_key_size, 32);
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        // keys are stored without their length, envelopes within a page are 3 + 1 + 3 + 8 + 32 bytes apart
+        let apart = stored.windows(2).map(|w| w[1].1.as_u64() - w[0].1.as_u64()).collect::<Vec<_>>();
+        assert!(apart.iter().filter(|d| **d == 47).count() > 1900);
+        assert!(apart.iter().all(|d| *d != 48));
+        assert_eq!(db.iter().filter(|(_, key, _)| key.len() == 32).count(), 2000);
+        // keys are read following the data
+        let (key, pref) = stored[0];
+        assert_eq!(db.count(&key).unwrap(), 1);
+        assert_eq!(db.get_nth(&key, 0).unwrap(), Some((pref, key[.. 8].to_vec())));
+        assert_eq!(db.get_keys_only(&key).unwrap(), vec!((pref, key.to_vec())));
+        assert_eq!(db.get_all(&key).unwrap().collect::<Result<Vec<_>, _>>().unwrap(), vec!((pref, key[.. 8].to_vec())));
+        assert_eq!(db.probe(&key).unwrap().found, Some(pref));
+        let info = db.entry_info(pref).unwrap();
+        assert_eq!((info.key_len, info.links), (32, 0));
+        let mut keys = db.iter_keys();
+        assert_eq!((&mut keys).filter(|(_, key)| key.len() == 32).count(), 2000);
+        assert!(keys.error().is_ok());
+        drop(keys);
+        let (hashed, hash) = db.put_hashed(b"hashed").unwrap();
+        assert_eq!(db.put_hashed(b"hashed").unwrap(), (hashed, hash));
+        assert_eq!(db.count(&hash).unwrap(), 1);
+        db.batch().unwrap();
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+        drop(db);
+
+        // a db with data keeps its size, an other size configured fails
+        assert!(persistent_with_config(&name, &Config::default().fixed_key_size(20)).is_err());
+        let other = temp_db_name("fixed_key_size_other");
+        let mut db = persistent_with_config(&other, &Config::default()).unwrap();
+        db.put_keyed(&[1u8; 32], b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+        assert!(persistent_with_config(&other, &config).is_err());
+
+        // the size of the db applies whatever is configured
+        let mut db = persistent_with_config(&name, &Config::default()).unwrap();
+        assert_eq!(db.table_stats().unwrap().fixed_key_size, 32);
+        for (key, pref) in &stored {
+            assert_eq!(db.get_keyed(key).unwrap(), Some((*pref, key[.. 8].to_vec())));
+        }
+        assert_eq!(db.get_keyed(b"short").unwrap(), None);
+        let reader = read_only(&name, 10).unwrap();
+        assert_eq!(reader.get_keyed(&stored[0].0).unwrap().unwrap().0, stored[0].1);
+        db.shutdown();
+        drop(db);
+        // a rebuilt table keeps fixed size keys
+        let mut db = repair(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(&stored[0].0).unwrap(), Some((stored[0].1, stored[0].0[.. 8].to_vec())));
+        assert!(db.put_keyed(b"short", b"data").is_err());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+        fs::remove_dir_all(Path::new(&other).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_overflow () {
+        let name = temp_db_name("overflow");
+        // long buckets
+        let config = Config::default().bucket_fill_target(64).overflow(Arc::new(RobinHood));
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut stored = Vec::new();
+        for i in 0 .. 20000u32 {
+            stored.push((i, db.put_keyed(&i.to_be_bytes(), &i.to_le_bytes()).unwrap()));
+        }
+        for i in (0 .. 20000u32).step_by(3) {
+            db.forget(&i.to_be_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        let examined = |db: &dyn HammersbaldRead| (0 .. 20000u32).map(|i| db.probe(&i.to_be_bytes()).unwrap().slots).sum::<usize>();
+        let robin_hood = examined(db.as_ref());
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+        drop(db);
+
+        // the table is read by any strategy
+        for (overflow, chained) in [(Arc::new(Chained) as Arc<dyn Overflow>, true), (Arc::new(RobinHood), false)].iter() {
+            let mut db = persistent_with_config(&name, &config.clone().overflow(overflow.clone())).unwrap();
+            for (i, pref) in &stored {
+                let expected = if i % 3 == 0 { None } else { Some((*pref, i.to_le_bytes().to_vec())) };
+                assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap(), expected);
+            }
+            if *chained {
+                assert!(robin_hood * 4 < examined(db.as_ref()), "robin hood examined {} slots, chained {}", robin_hood, examined(db.as_ref()));
+            }
+            db.put_keyed(b"key", b"data").unwrap();
+            db.batch().unwrap();
+            db.shutdown();
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+
+        let store = Arc::new(MemoryStore::new());
+        let config = Config::default().bucket_fill_target(64).overflow(Arc::new(RobinHood)).page_store(store);
+        conformance(1, 1000, true, || persistent_with_config("db", &config)).unwrap();
+    }
+
+    #[test]
+    fn test_large_compressible () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let data = b"compressible ".iter().cycle().take(100000).cloned().collect::<Vec<u8>>();
+        let keyed = db.put_keyed(b"key", data.as_slice()).unwrap();
+        let referred = db.put(data.as_slice()).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((keyed, data.clone())));
+        assert_eq!(db.get(referred).unwrap(), (vec!(), data.clone()));
+        assert_eq!(db.iter().filter(|(_, _, d)| *d == data).count(), 2);
+        // the keyed data is stored between the two references
+        let stored = (referred.as_u64() - keyed.as_u64()) as usize;
+        #[cfg(any(feature="lz4", feature="snappy"))]
+        assert!(stored < data.len() / 10, "stored {} bytes", stored);
+        #[cfg(not(any(feature="lz4", feature="snappy")))]
+        assert!(stored > data.len(), "stored {} bytes", stored);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_error () {
+        let name = temp_db_name("iter-error");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let first = db.put_keyed(b"first", b"one").unwrap();
+        db.put_keyed(b"second", b"two").unwrap();
+        db.put_keyed(b"third", b"three").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        // flag the data of second as encrypted, so it can not be decoded without a key
+        for entry in fs::read_dir(Path::new(&name).parent().unwrap()).unwrap() {
+            let path = entry.unwrap().path();
+            let mut content = fs::read(&path).unwrap();
+            if let Some(at) = content.windows(7).position(|w| w == b"\x06second") {
+                content[at - 1] |= ::encryption::ENCRYPTED;
+                fs::write(&path, content).unwrap();
+            }
+        }
+
+        let db = read_only(&name, 10).unwrap();
+        {
+            let mut iter = db.iter();
+            assert_eq!(iter.by_ref().map(|(pref, _, _)| pref).collect::<Vec<_>>(), vec!(first));
+            assert!(matches!(iter.error(), Err(Error::WrongKey)));
+            assert!(iter.error().is_ok());
+            let mut cursor = db.cursor_at(PRef::from(0)).unwrap();
+            assert_eq!(cursor.prefs().count(), 1);
+            assert!(cursor.error().is_err());
+        }
+        assert!(db.export(&mut Vec::new()).is_err());
+        assert!(!db.verify(VerifyLevel::Structure).unwrap().is_ok());
+        let mut other = transient(1).unwrap();
+        assert!(other.ingest(db.as_ref(), Conflict::KeepNewest).is_err());
+        drop(db);
+        assert!(repair(&name, &Config::default()).is_err());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_iter_live () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        db.put_keyed(b"overwritten", b"old").unwrap();
+        db.put_keyed(b"forgotten", b"data").unwrap();
+        db.put(b"referred").unwrap();
+        db.batch().unwrap();
+        let kept = db.put_keyed(b"overwritten", b"new").unwrap();
+        db.forget(b"forgotten").unwrap();
+        db.batch().unwrap();
+
+        assert_eq!(db.iter().filter(|(_, key, _)| !key.is_empty()).count(), 3);
+        assert_eq!(db.iter_live().collect::<Vec<_>>(), vec!((kept, b"overwritten".to_vec(), b"new".to_vec())));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_prefetch () {
+        let name = temp_db_name("prefetch");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        for i in 0 .. 300u32 {
+            db.put_keyed(&i.to_be_bytes(), &vec!(i as u8; 3000)).unwrap();
+        }
+        db.put(&vec!(7u8; 100000)).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        // pages are read from the file, not the cache
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let prefetched = db.iter_prefetch(8).collect::<Vec<_>>();
+        assert_eq!(prefetched.iter().filter(|(_, key, _)| !key.is_empty()).count(), 300);
+        assert_eq!(prefetched, db.iter().collect::<Vec<_>>());
+        assert_eq!(db.iter_prefetch(1).count(), prefetched.len());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_cursor () {
+        let name = temp_db_name("cursor");
+        let mut db = persistent(&name, 100, 1).unwrap();
+        for i in 0 .. 100u32 {
+            if i % 2 == 0 {
+                db.put_keyed(&i.to_be_bytes(), &vec!(i as u8; 1000)).unwrap();
+            } else {
+                db.put(&vec!(i as u8; 5000)).unwrap();
+            }
+        }
+        db.batch().unwrap();
+        let all = db.iter().collect::<Vec<_>>();
+        assert_eq!(db.iter().prefs().collect::<Vec<_>>(), all.iter().map(|(pref, _, _)| *pref).collect::<Vec<_>>());
+        let mut cursor = db.cursor_at(PRef::from(0)).unwrap();
+        let first = cursor.by_ref().take(40).collect::<Vec<_>>();
+        let pos = cursor.position();
+        db.shutdown();
+
+        // resume after a restart
+        let db = persistent(&name, 100, 1).unwrap();
+        let mut resumed = first;
+        let mut rest = db.cursor_at(pos).unwrap();
+        resumed.extend(rest.by_ref());
+        assert_eq!(resumed, all);
+        let end = rest.position();
+        assert_eq!(db.cursor_at(pos).unwrap().prefs().count(), all.len() - 40);
+        assert_eq!(db.cursor_at(end).unwrap().count(), 0);
+        assert_eq!(db.cursor_at(PRef::from(pos.as_u64() + 3)).err().unwrap().to_string(), Error::InvalidOffset.to_string());
+        assert!(db.cursor_at(PRef::from(end.as_u64() + 4096)).is_err());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load () {
+        let name = temp_db_name("bulk_load");
+        let config = Config::default().bucket_fill_target(1);
+        let mut db = Persistent::open(&name, &config).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        for _ in 0 .. 1000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            check.insert(key, (db.put_keyed(&key, &data).unwrap(), data));
+        }
+        db.batch().unwrap();
+        db.bulk_load(20000).unwrap();
+        for _ in 0 .. 20000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            check.insert(key, (db.put_keyed(&key, &data).unwrap(), data));
+        }
+        db.batch().unwrap();
+
+        // all buckets allocated are addressed, the upper half is used as the lower
+        let (_, _, n_buckets, ..) = db.params();
+        assert_eq!(n_buckets, 1 << 15);
+        let used = |range: ::std::ops::Range<usize>| range.filter(|b| !db.bucket_slots(*b).unwrap().is_empty()).count();
+        let (lower, upper) = (used(0 .. n_buckets / 2), used(n_buckets / 2 .. n_buckets));
+        assert!(lower > 5000 && upper > 5000 && lower.abs_diff(upper) < 1000, "{} {}", lower, upper);
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+
+        // the geometry survives a reopen
+        let mut db = Persistent::open(&name, &config).unwrap();
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+        db.shutdown();
+
+        // buckets evicted at a batch are not read by puts of the bulk load, their slots are deferred to the next batch
+        let mut db = Persistent::open(&name, &config.clone().memory_budget(1)).unwrap();
+        db.bulk_load(20000).unwrap();
+        assert_eq!(db.params().2, 2 * n_buckets);
+        for _ in 0 .. 1000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            check.insert(key, (db.put_keyed(&key, &data).unwrap(), data));
+        }
+        db.batch().unwrap();
+        let overwritten = *check.keys().next().unwrap();
+        let mut deferred = Vec::new();
+        for _ in 0 .. 1000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            check.insert(key, (db.put_keyed(&key, &data).unwrap(), data));
+            deferred.push(key);
+        }
+        check.insert(overwritten, (db.put_keyed(&overwritten, b"again").unwrap(), [0u8; 40]));
+        let also = db.put_keyed_also(&deferred[0], b"also").unwrap();
+        db.forget(&deferred[1]).unwrap();
+        check.remove(&deferred[1]);
+        // deferred slots are seen before the batch
+        assert_eq!(db.get_keyed(&deferred[2]).unwrap(), Some((check[&deferred[2]].0, check[&deferred[2]].1.to_vec())));
+        assert_eq!(db.get_keyed(&overwritten).unwrap().unwrap().1, b"again".to_vec());
+        db.batch().unwrap();
+        db.shutdown();
+
+        let db = Persistent::open(&name, &config).unwrap();
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        assert_eq!(db.get_keyed(&overwritten).unwrap().unwrap().1, b"again".to_vec());
+        assert_eq!(db.get_keyed(&deferred[1]).unwrap(), None);
+        assert_eq!(db.count(&deferred[0]).unwrap(), 2);
+        assert!(db.get_keys_only(&deferred[0]).unwrap().iter().any(|(pref, _)| *pref == also));
+        for (k, (o, v)) in check.iter().filter(|(k, _)| **k != overwritten) {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_table_allocated () {
+        let name = temp_db_name("allocated");
+        let config = Config::default().bucket_fill_target(1);
+        let mut db = Persistent::open(&name, &config).unwrap();
+        db.bulk_load(1 << 16).unwrap();
+        // few keys write few pages of the table, the pages skipped are allocated
+        let mut keys = Vec::new();
+        for i in 0 .. 20u32 {
+            keys.push((i.to_be_bytes(), db.put_keyed(&i.to_be_bytes(), b"data").unwrap()));
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        let table = fs::metadata(format!("{}.0.tb", name)).unwrap();
+        assert!(table.len() > 512 << 10);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(table.blocks() * 512 < table.len() / 4, "{} of {} bytes allocated", table.blocks() * 512, table.len());
+        }
+
+        // pages allocated read as empty buckets, also after a reopen
+        let mut db = Persistent::open(&name, &config).unwrap();
+        let (_, _, n_buckets, ..) = db.params();
+        assert_eq!(n_buckets, 1 << 17);
+        assert_eq!(db.slots().map(|slots| slots.unwrap().len()).sum::<usize>(), 20);
+        for (key, pref) in &keys {
+            assert_eq!(db.get_keyed(key).unwrap(), Some((*pref, b"data".to_vec())));
+        }
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        for i in 20 .. 2000u32 {
+            db.put_keyed(&i.to_be_bytes(), b"more").unwrap();
+        }
+        db.batch().unwrap();
+        assert_eq!(db.get_keyed(&1999u32.to_be_bytes()).unwrap().unwrap().1, b"more".to_vec());
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_table_sparse () {
+        use std::os::unix::fs::MetadataExt;
+        let name = temp_db_name("sparse");
+        let config = Config::default().bucket_fill_target(1);
+        let mut db = Persistent::open(&name, &config).unwrap();
+        db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        let table = || fs::metadata(format!("{}.0.tb", name)).unwrap();
+        let (len, allocated) = (table().len(), table().blocks() * 512);
+
+        // the table grows by the pages skipped, only pages of buckets written are allocated on disk
+        db.bulk_load(1 << 16).unwrap();
+        for i in 0 .. 20u32 {
+            db.put_keyed(&i.to_be_bytes(), b"data").unwrap();
+        }
+        db.batch().unwrap();
+        assert!(table().len() >= len + (256 << 10));
+        assert!(table().blocks() * 512 <= allocated + 24 * PAGE_SIZE as u64, "{} bytes allocated, {} before", table().blocks() * 512, allocated);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_resize () {
+        let name = temp_db_name("resize");
+        let mut db = persistent(&name, 100, 64).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        for i in 0 .. 20000u32 {
+            thread_rng().fill_bytes(&mut key);
+            check.insert(key, (db.put_keyed(&key, &i.to_be_bytes()).unwrap(), i));
+        }
+        db.batch().unwrap();
+        assert!(!db.needs_resize().unwrap());
+        db.shutdown();
+
+        // a table grown for 64 keys per bucket is undersized for 1
+        let mut db = persistent(&name, 100, 1).unwrap();
+        let stats = db.table_stats().unwrap();
+        assert_eq!(stats.keys, 20000);
+        assert!(stats.chain_percentiles.0 <= stats.chain_percentiles.3);
+        assert!(db.needs_resize().unwrap());
+        db.resize(stats.keys).unwrap();
+        db.batch().unwrap();
+        // split incrementally
+        let grown = db.table_stats().unwrap().buckets;
+        assert!(grown > stats.buckets && grown < stats.keys);
+        for _ in 0 .. 5 {
+            db.batch().unwrap();
+        }
+        assert!(db.table_stats().unwrap().buckets >= stats.keys);
+        assert!(!db.needs_resize().unwrap());
+        assert!(db.verify(VerifyLevel::Structure).unwrap().is_ok());
+        db.shutdown();
+
+        let db = persistent(&name, 100, 1).unwrap();
+        for (k, (o, i)) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, i.to_be_bytes().to_vec())));
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_split_table () {
+        // all buckets of the next level are split but the level is not yet advanced
+        let name = temp_db_name("split");
+        let mut db = persistent(&name, 100, 128).unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 1000u32 {
+            check.insert(i, db.put_keyed(&i.to_be_bytes(), &[i as u8; 10]).unwrap());
+        }
+        db.resize(1024).unwrap();
+        while db.table_stats().unwrap().buckets < 1024 {
+            db.batch().unwrap();
+        }
+        assert_eq!(db.table_stats().unwrap().buckets, 1024);
+        db.shutdown();
+
+        let db = persistent(&name, 100, 128).unwrap();
+        for (i, pref) in &check {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap(), Some((*pref, vec!(*i as u8; 10))));
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_sample_keys () {
+        let mut db = transient(1).unwrap();
+        assert!(db.sample_keys(10).unwrap().is_empty());
+        for i in 0 .. 1000u32 {
+            db.put_keyed(&i.to_be_bytes(), &vec!(0u8; i as usize % 50)).unwrap();
+        }
+        db.forget(&7u32.to_be_bytes()).unwrap();
+        db.batch().unwrap();
+        let sample = db.sample_keys(100).unwrap();
+        assert_eq!(sample.len(), 100);
+        let mut keys = HashSet::new();
+        for (key, len) in sample {
+            let i = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+            assert!(i != 7 && i < 1000);
+            assert_eq!(len, i as usize % 50);
+            keys.insert(key);
+        }
+        assert_eq!(keys.len(), 100);
+        // no more than the live keys
+        assert_eq!(db.sample_keys(5000).unwrap().len(), 999);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_config () {
+        let name = temp_db_name("config");
+        let dir = Path::new(&name).parent().unwrap().to_path_buf();
+        let config = Config::default()
+            .directory(dir.join("index"))
+            .data_directory(dir.join("data"))
+            .sync(SyncMode::All)
+            .max_file_size(16 * 4096);
+        fs::create_dir_all(dir.join("index")).unwrap();
+        fs::create_dir_all(dir.join("data")).unwrap();
+
+        let mut db = persistent_with_config("test", &config).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        for _ in 0 .. 10000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            check.insert(key, (db.put_keyed(&key, &data).unwrap(), data));
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        assert!(dir.join("data").join("test.1.bc").exists());
+        assert!(dir.join("index").join("test.0.tb").exists());
+        assert!(!dir.join("index").join("test.0.bc").exists());
+        assert!(persistent_with_config("test", &config.clone().max_file_size(32 * 4096)).is_err());
+
+        let db = persistent_with_config("test", &config).unwrap();
+        for (k, (o, v)) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, v.to_vec())));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_index () {
+        let name = temp_db_name("index");
+        let by_data = || -> IndexKeys { Box::new(|_, data: &[u8]| vec!(data.to_vec())) };
+
+        let mut db = persistent(&name, 100, 2).unwrap();
+        let first = db.put_keyed(b"first", b"one").unwrap();
+        db.batch().unwrap();
+        // index data stored before it was defined
+        db.create_index("bydata", by_data()).unwrap();
+        let second = db.put_keyed(b"second", b"two").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap(), Some((first, b"first".to_vec(), b"one".to_vec())));
+        assert_eq!(db.get_by_index("bydata", b"two").unwrap(), Some((second, b"second".to_vec(), b"two".to_vec())));
+        assert_eq!(db.get_by_index("bydata", b"three").unwrap(), None);
+        assert!(db.get_by_index("other", b"one").is_err());
+        db.shutdown();
+
+        // index catches up with data stored while it was not defined
+        let mut db = persistent(&name, 100, 2).unwrap();
+        let third = db.put_keyed(b"third", b"three").unwrap();
+        db.batch().unwrap();
+        db.create_index("bydata", by_data()).unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap(), Some((first, b"first".to_vec(), b"one".to_vec())));
+        assert_eq!(db.get_by_index("bydata", b"three").unwrap(), Some((third, b"third".to_vec(), b"three".to_vec())));
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        db.put_keyed(b"first", b"one").unwrap();
+        db.create_index("bydata", by_data()).unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap().map(|(_, k, _)| k), Some(b"first".to_vec()));
+
+        // data of forgotten or overwritten keys is not found
+        db.put_keyed(b"second", b"two").unwrap();
+        db.put_keyed(b"third", b"three").unwrap();
+        db.batch().unwrap();
+        db.forget(b"first").unwrap();
+        let overwritten = db.put_keyed(b"second", b"four").unwrap();
+        db.put_keyed_also(b"third", b"five").unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap(), None);
+        assert_eq!(db.get_by_index("bydata", b"two").unwrap(), None);
+        assert_eq!(db.get_by_index("bydata", b"four").unwrap(), Some((overwritten, b"second".to_vec(), b"four".to_vec())));
+        db.batch().unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap(), None);
+        assert_eq!(db.get_by_index("bydata", b"two").unwrap(), None);
+        // data kept with put_keyed_also is live
+        assert_eq!(db.get_by_index("bydata", b"three").unwrap().map(|(_, k, _)| k), Some(b"third".to_vec()));
+        assert_eq!(db.get_by_index("bydata", b"five").unwrap().map(|(_, k, _)| k), Some(b"third".to_vec()));
+    }
+
+    #[test]
+    fn test_index_rollback () {
+        let name = temp_db_name("index_rollback");
+        let by_data = || -> IndexKeys { Box::new(|_, data: &[u8]| vec!(data.to_vec())) };
+
+        let mut db = persistent(&name, 100, 2).unwrap();
+        db.create_index("bydata", by_data()).unwrap();
+        let first = db.put_keyed(b"first", b"one").unwrap();
+        db.batch_named(1).unwrap();
+        db.put_keyed(b"second", b"two").unwrap();
+        // an index key stored again after the checkpoint refers to the data of the checkpoint after rollback
+        db.put_keyed(b"again", b"one").unwrap();
+        db.batch_named(2).unwrap();
+        // an index created after the checkpoint
+        db.create_index("bykey", Box::new(|key: &[u8], _| vec!(key.to_vec()))).unwrap();
+        db.put_keyed(b"third", b"three").unwrap();
+        db.batch().unwrap();
+        assert!(db.get_by_index("bydata", b"two").unwrap().is_some());
+        assert!(db.get_by_index("bykey", b"second").unwrap().is_some());
+
+        db.rollback_to(1).unwrap();
+        assert_eq!(db.get_by_index("bydata", b"one").unwrap(), Some((first, b"first".to_vec(), b"one".to_vec())));
+        assert_eq!(db.get_by_index("bydata", b"two").unwrap(), None);
+        assert_eq!(db.get_by_index("bydata", b"three").unwrap(), None);
+        assert_eq!(db.get_by_index("bykey", b"first").unwrap(), Some((first, b"first".to_vec(), b"one".to_vec())));
+        assert_eq!(db.get_by_index("bykey", b"second").unwrap(), None);
+        assert_eq!(db.get_by_index("bykey", b"third").unwrap(), None);
+
+        // data stored after the rollback is indexed
+        let fourth = db.put_keyed(b"fourth", b"four").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_by_index("bydata", b"four").unwrap(), Some((fourth, b"fourth".to_vec(), b"four".to_vec())));
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature="encryption")]
+    #[test]
+    fn test_encryption () {
+        let name = temp_db_name("encryption");
+        let config = Config::default().encryption_key([7u8; 32]);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let keyed = db.put_keyed(b"key", b"wallet secret").unwrap();
+        let long = db.put_stream(&mut Cursor::new(vec!(3u8; 5 << 20)), 5 << 20).unwrap();
+        let linked = db.put_with_links(b"linked secret", &[keyed]).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        for entry in fs::read_dir(Path::new(&name).parent().unwrap()).unwrap() {
+            let content = fs::read(entry.unwrap().path()).unwrap();
+            assert!(!content.windows(13).any(|w| w == b"wallet secret" || w == b"linked secret"));
+        }
+        match persistent_with_config(&name, &Config::default().encryption_key([8u8; 32])) {
+            Err(Error::WrongKey) => {},
+            _ => panic!("an other key should not open")
+        }
+        assert!(persistent_with_config(&name, &Config::default()).is_err());
+        assert!(read_only(&name, 1).is_err());
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((keyed, b"wallet secret".to_vec())));
+        assert_eq!(db.get(long).unwrap().1, vec!(3u8; 5 << 20));
+        assert_eq!(db.get(linked).unwrap().1, b"linked secret".to_vec());
+        assert_eq!(db.get_links(linked).unwrap(), vec!(keyed));
+        assert_eq!(db.get_keys_only(b"key").unwrap(), vec!((keyed, b"key".to_vec())));
+        assert_eq!(db.iter().filter(|(_, key, _)| !key.is_empty()).count(), 1);
+        // a snapshot opens with the key of the db
+        let snapshot = format!("{}-snapshot", name);
+        db.snapshot(&snapshot).unwrap();
+        db.shutdown();
+        assert!(persistent_with_config(&snapshot, &Config::default()).is_err());
+        let mut db = persistent_with_config(&snapshot, &config).unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((keyed, b"wallet secret".to_vec())));
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_throttle () {
+        let name = temp_db_name("throttle");
+        let mut db = persistent_with_config(&name, &Config::default().throttle(10000, Throttle::Fail)).unwrap();
+        assert_eq!(db.pending_bytes(), 0);
+        // incompressible
+        let mut data = [0u8; 4000];
+        thread_rng().fill_bytes(&mut data);
+        db.put_keyed(b"one", &data).unwrap();
+        db.put(&data).unwrap();
+        assert!(db.pending_bytes() >= 8000);
+        match db.put_keyed(b"three", &data) {
+            Err(Error::WouldBlock(pending)) => assert_eq!(pending, db.pending_bytes()),
+            _ => panic!("put should exceed the throttle")
+        }
+        assert_eq!(db.get_keyed(b"three").unwrap(), None);
+        db.batch().unwrap();
+        assert_eq!(db.pending_bytes(), 0);
+        // a single put larger than the limit is stored
+        db.put_keyed(b"large", &[4u8; 20000]).unwrap();
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &Config::default().throttle(10000, Throttle::Block)).unwrap();
+        for i in 0 .. 100u32 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 1000]).unwrap();
+            assert!(db.pending_bytes() <= 10000 + PAGE_SIZE as u64);
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        let db = persistent(&name, 1, 1).unwrap();
+        assert_eq!(db.get_keyed(&90u32.to_be_bytes()).unwrap().unwrap().1, vec!(90u8; 1000));
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[derive(Default)]
+    struct AutoCommits {
+        n: AtomicUsize
+    }
+
+    impl CommitObserver for AutoCommits {
+        fn committed (&self, _: &[Change]) {}
+
+        fn auto_committed (&self) {
+            self.n.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_quota () {
+        let name = temp_db_name("quota");
+        let config = Config::default().quota(256 * 1024);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut rng = thread_rng();
+        let mut stored = 0u32;
+        let failed = loop {
+            let mut data = [0u8; 1000];
+            rng.fill_bytes(&mut data);
+            let pending = db.pending_bytes();
+            match db.put_keyed(&stored.to_be_bytes(), &data) {
+                Ok(_) => stored += 1,
+                Err(e) => {
+                    // nothing is written by a put exceeding the quota
+                    assert_eq!(db.pending_bytes(), pending);
+                    break e;
+                }
+            }
+            if stored.is_multiple_of(50) {
+                if let Err(e) = db.batch() {
+                    break e;
+                }
+            }
+        };
+        assert!(matches!(failed, Error::OutOfSpace { source: None, .. }));
+        assert_eq!(failed.class(), ErrorClass::Transient);
+        assert!(stored > 150 && stored < 256);
+        db.shutdown();
+        drop(db);
+
+        // a larger quota lets the db grow again
+        let mut db = persistent_with_config(&name, &config.quota(1024 * 1024)).unwrap();
+        db.put_keyed(b"more", b"data").unwrap();
+        db.batch().unwrap();
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_reserve_space () {
+        let name = temp_db_name("reserve");
+        let mut db = persistent_with_config(&name, &Config::default()).unwrap();
+        db.put_keyed(b"stored", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        // no disk has the reserve free, puts fail before they write
+        let free = fs2::available_space(Path::new(&name).parent().unwrap()).unwrap();
+        let mut db = persistent_with_config(&name, &Config::default().reserve_space(free + (1 << 40))).unwrap();
+        let pending = db.pending_bytes();
+        let failed = db.put_keyed(b"more", b"data").unwrap_err();
+        assert!(matches!(failed, Error::OutOfSpace { source: None, .. }));
+        assert_eq!(db.pending_bytes(), pending);
+        assert_eq!(db.get_keyed(b"stored").unwrap().unwrap().1, b"data".to_vec());
+        db.shutdown();
+        drop(db);
+
+        // without the reserve the db grows again
+        let mut db = persistent_with_config(&name, &Config::default()).unwrap();
+        db.put_keyed(b"more", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_auto_batch () {
+        let name = temp_db_name("auto");
+        let config = Config::default().auto_batch(AutoBatch::default().bytes(10000));
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let observer = Arc::new(AutoCommits::default());
+        db.observe(observer.clone());
+        for i in 0 .. 100u32 {
+            // random, so data does not compress
+            let data = (0 .. 1000).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+            db.put_keyed(&i.to_be_bytes(), data.as_slice()).unwrap();
+            assert!(db.pending_bytes() < 10000);
+        }
+        assert!(observer.n.load(Ordering::Relaxed) >= 9);
+        // puts up to the last auto batch are stored without a batch call
+        db.shutdown();
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert!(db.get_keyed(&80u32.to_be_bytes()).unwrap().is_some());
+
+        let config = Config::default().auto_batch(AutoBatch::default().interval(Duration::from_millis(10)));
+        db.shutdown();
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let observer = Arc::new(AutoCommits::default());
+        db.observe(observer.clone());
+        db.put_keyed(b"first", b"data").unwrap();
+        assert_eq!(observer.n.load(Ordering::Relaxed), 0);
+        std::thread::sleep(Duration::from_millis(20));
+        db.forget(b"first").unwrap();
+        assert_eq!(observer.n.load(Ordering::Relaxed), 1);
+        assert_eq!(db.pending_bytes(), 0);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_write_ahead_log () {
+        let name = temp_db_name("wal");
+        let config = Config::default().write_ahead_log(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let batched = db.put_keyed(b"batched", b"in batch").unwrap();
+        db.batch().unwrap();
+        let keyed = db.put_keyed(b"keyed", &[1u8; 5000]).unwrap();
+        let referred = db.put(b"referred").unwrap();
+        db.forget(b"batched").unwrap();
+        let also = db.put_keyed_also(b"keyed", b"also").unwrap();
+        // crash before batch
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"keyed").unwrap(), Some((keyed, vec!(1u8; 5000))));
+        assert_eq!(db.get_nth(b"keyed", 0).unwrap(), Some((also, b"also".to_vec())));
+        assert_eq!(db.count(b"keyed").unwrap(), 2);
+        assert_eq!(db.get(referred).unwrap(), (vec!(), b"referred".to_vec()));
+        assert_eq!(db.get_keyed(b"batched").unwrap(), None);
+        assert_eq!(db.get(batched).unwrap(), (b"batched".to_vec(), b"in batch".to_vec()));
+        db.batch().unwrap();
+        db.shutdown();
+
+        // the log is not replayed on top of a later batch
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.iter().filter(|(_, k, _)| k.as_slice() == b"keyed").count(), 2);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_put_group () {
+        let name = temp_db_name("group");
+        let config = Config::default().write_ahead_log(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        db.put_keyed(b"block", b"stale").unwrap();
+        db.batch().unwrap();
+        let block = vec!(3u8; 10000);
+        let entries = vec!((&b"block"[..], block.as_slice()), (&b"tx1"[..], &b"block"[..]), (&b"tx2"[..], &b"block"[..]));
+        let prefs = db.put_group(entries.as_slice()).unwrap();
+        assert_eq!(db.get_keyed(b"tx1").unwrap(), Some((prefs[1], b"block".to_vec())));
+        assert!(db.put_group(&[(b"ok", b"data"), (&[0u8; 256], b"data")]).is_err());
+        // crash before batch
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"block").unwrap(), Some((prefs[0], block)));
+        assert_eq!(db.get_keyed(b"tx2").unwrap(), Some((prefs[2], b"block".to_vec())));
+        assert_eq!(db.get_keyed(b"ok").unwrap(), None);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_put_durable () {
+        let name = temp_db_name("durable");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        db.put_keyed(b"batched", b"in batch").unwrap();
+        db.batch().unwrap();
+        // a batch without durable puts leaves the log alone
+        db.batch().unwrap();
+        db.put_keyed(b"fast", b"lost").unwrap();
+        let durable = db.put_durable(b"durable", &[2u8; 5000]).unwrap();
+        db.put(&[3u8; 20000]).unwrap();
+        let later = db.put_durable(b"later", b"durable too").unwrap();
+        // crash before batch
+        db.shutdown();
+
+        // durable puts are replayed to the references returned, lost data is padded
+        let mut db = persistent(&name, 10, 1).unwrap();
+        assert_eq!(db.get_keyed(b"fast").unwrap(), None);
+        assert_eq!(db.get_keyed(b"durable").unwrap(), Some((durable, vec!(2u8; 5000))));
+        assert_eq!(db.get_keyed(b"later").unwrap(), Some((later, b"durable too".to_vec())));
+        assert!(db.iter().all(|(_, key, data)| !key.is_empty() || data.iter().all(|b| *b == 0)));
+        assert!(db.get_keyed(b"batched").unwrap().is_some());
+        let durable = db.put_durable(b"again", b"durable").unwrap();
+        db.shutdown();
+
+        // without further puts the reference is kept
+        let mut db = persistent(&name, 10, 1).unwrap();
+        assert_eq!(db.get_keyed(b"again").unwrap(), Some((durable, b"durable".to_vec())));
+        db.shutdown();
+
+        // a put of a stream is not logged, if every put is logged it ends the batch
+        let name = format!("{}-stream", name);
+        let config = Config::default().write_ahead_log(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let before = db.put_keyed(b"before", b"logged").unwrap();
+        let stream = db.put_stream(&mut Cursor::new(vec!(4u8; 100000)), 100000).unwrap();
+        let after = db.put_keyed(b"after", b"logged").unwrap();
+        db.shutdown();
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"before").unwrap(), Some((before, b"logged".to_vec())));
+        assert_eq!(db.get(stream).unwrap(), (vec!(), vec!(4u8; 100000)));
+        assert_eq!(db.get_keyed(b"after").unwrap(), Some((after, b"logged".to_vec())));
+        db.shutdown();
+
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let pref = db.put_durable(b"transient", b"data").unwrap();
+        assert_eq!(db.get_keyed(b"transient").unwrap(), Some((pref, b"data".to_vec())));
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_ingest () {
+        let shard = || {
+            let mut db = Transient::new_db("", 1, 1).unwrap();
+            let tx = db.put_keyed(b"tx", b"shard").unwrap();
+            let header = db.put(b"header").unwrap();
+            db.put_with_links(b"block", &[header, tx]).unwrap();
+            db.put_keyed(b"overwritten", b"old").unwrap();
+            db.put_keyed(b"overwritten", b"shard").unwrap();
+            db.batch().unwrap();
+            db
+        };
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        db.put_keyed(b"overwritten", b"mine").unwrap();
+        db.put_keyed(b"only", b"mine").unwrap();
+        db.batch().unwrap();
+
+        match db.ingest(shard().as_ref(), Conflict::Error) {
+            Err(Error::Conflict(key)) => assert_eq!(key, b"overwritten".to_vec()),
+            _ => panic!("conflict expected")
+        }
+        assert_eq!(db.iter().filter(|(_, key, _)| key.as_slice() == b"tx").count(), 0);
+
+        assert_eq!(db.ingest(shard().as_ref(), Conflict::KeepNewest).unwrap(), Ingested { keys: 2, referred: 2, conflicts: 1 });
+        assert_eq!(db.get_keyed(b"overwritten").unwrap().unwrap().1, b"shard".to_vec());
+        assert_eq!(db.get_keyed(b"only").unwrap().unwrap().1, b"mine".to_vec());
+        let (tx, _) = db.get_keyed(b"tx").unwrap().unwrap();
+        let (block, _, _) = db.iter().find(|(_, _, data)| data.as_slice() == b"block").unwrap();
+        let links = db.get_links(block).unwrap();
+        assert_eq!(db.get(links[0]).unwrap(), (vec!(), b"header".to_vec()));
+        assert_eq!(links[1], tx);
+        assert!(db.verify_links().unwrap().is_empty());
+
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        db.put_keyed(b"overwritten", b"mine").unwrap();
+        db.ingest(shard().as_ref(), Conflict::KeepBoth).unwrap();
+        assert_eq!(db.get_keyed(b"overwritten").unwrap().unwrap().1, b"mine".to_vec());
+        assert_eq!(db.get_filtered(b"overwritten", &|key, _| key == b"overwritten").unwrap().len(), 2);
+
+        // the later generation is kept
+        let name = temp_db_name("ingest");
+        let config = Config::default().generations(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        db.set_generation(10).unwrap();
+        let kept = db.put_keyed(b"overwritten", b"mine").unwrap();
+        db.batch().unwrap();
+        let mut other = persistent_with_config(&format!("{}-other", name), &config).unwrap();
+        other.set_generation(5).unwrap();
+        other.put_keyed(b"overwritten", b"older").unwrap();
+        other.batch().unwrap();
+        assert_eq!(db.ingest(other.as_ref(), Conflict::KeepNewest).unwrap(), Ingested { keys: 1, referred: 0, conflicts: 1 });
+        assert_eq!(db.get_keyed(b"overwritten").unwrap(), Some((kept, b"mine".to_vec())));
+        db.shutdown();
+        other.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_zeros_to_page_end () {
+        // data without key of zeros up to the end of the page is data, padding is marked as such
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        db.put_keyed(b"key", b"data").unwrap();
+        let zeros = vec!(0u8; PAGE_SIZE - 15 - 7);
+        let pref = db.put(&zeros).unwrap();
+        assert_eq!(pref.as_u64(), 15);
+        db.batch().unwrap();
+        db.put(b"after").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(pref).unwrap(), (vec!(), zeros.clone()));
+        assert_eq!(db.iter().map(|(_, _, data)| data).collect::<Vec<_>>(), vec!(b"data".to_vec(), zeros.clone(), b"after".to_vec()));
+
+        let mut other = Transient::new_db("", 1, 1).unwrap();
+        assert_eq!(other.ingest(db.as_ref(), Conflict::Error).unwrap(), Ingested { keys: 1, referred: 2, conflicts: 0 });
+        assert!(other.iter().any(|(_, key, data)| key.is_empty() && data == zeros));
+        let mut dump = Vec::new();
+        assert_eq!(db.export(&mut dump).unwrap(), 3);
+        let mut imported = Transient::new_db("", 1, 1).unwrap();
+        assert_eq!(imported.import(&mut dump.as_slice()).unwrap(), 3);
+        assert!(imported.iter().any(|(_, key, data)| key.is_empty() && data == zeros));
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_count_get_nth () {
+        let name = temp_db_name("count");
+        let mut db = persistent_with_config(&name, &Config::default().bucket_fill_target(2)).unwrap();
+        let first = db.put_keyed(b"kept", b"first").unwrap();
+        // replaced, not kept both ways
+        db.put_keyed(b"replaced", b"old").unwrap();
+        db.put_keyed(b"replaced", b"new").unwrap();
+        for i in 0 .. 1000u32 {
+            db.put_keyed(&i.to_be_bytes(), b"other").unwrap();
+        }
+        db.batch().unwrap();
+        for data in &[b"second", b"third!"] {
+            let mut other = Transient::new_db("", 1, 1).unwrap();
+            other.put_keyed(b"kept", *data).unwrap();
+            other.batch().unwrap();
+            db.ingest(other.as_ref(), Conflict::KeepBoth).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        let db = persistent_with_config(&name, &Config::default().bucket_fill_target(2)).unwrap();
+        assert_eq!(db.count(b"kept").unwrap(), 3);
+        assert_eq!(db.count(b"replaced").unwrap(), 1);
+        assert_eq!(db.count(b"absent").unwrap(), 0);
+        // keys sharing a bucket are not counted
+        assert!((0 .. 1000u32).all(|i| db.count(&i.to_be_bytes()).unwrap() == 1));
+
+        // the most recent first
+        assert_eq!(db.get_nth(b"kept", 0).unwrap().unwrap().1, b"third!".to_vec());
+        assert_eq!(db.get_nth(b"kept", 1).unwrap().unwrap().1, b"second".to_vec());
+        assert_eq!(db.get_nth(b"kept", 2).unwrap(), Some((first, b"first".to_vec())));
+        assert_eq!(db.get_nth(b"kept", 3).unwrap(), None);
+        assert_eq!(db.get_nth(b"replaced", 0).unwrap().unwrap().1, b"new".to_vec());
+        assert_eq!(db.get_nth(b"replaced", 1).unwrap(), None);
+        assert_eq!(db.get_nth(b"absent", 0).unwrap(), None);
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_get_all () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let first = db.put_keyed(b"kept", b"first").unwrap();
+        db.put_keyed(b"single", b"data").unwrap();
+        db.batch().unwrap();
+        for data in &[b"second", b"third!"] {
+            let mut other = Transient::new_db("", 1, 1).unwrap();
+            other.put_keyed(b"kept", *data).unwrap();
+            other.batch().unwrap();
+            db.ingest(other.as_ref(), Conflict::KeepBoth).unwrap();
+        }
+
+        // the size is exact from the start to the end
+        let mut all = db.get_all(b"kept").unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all.size_hint(), (3, Some(3)));
+        let (newest, data) = all.next().unwrap().unwrap();
+        assert_eq!(data, b"third!".to_vec());
+        assert_eq!(all.len(), 2);
+        assert_eq!(all.next_back().unwrap().unwrap(), (first, b"first".to_vec()));
+        assert_eq!(all.len(), 1);
+        assert_eq!(all.next().unwrap().unwrap().1, b"second".to_vec());
+        assert_eq!(all.size_hint(), (0, Some(0)));
+        assert!(all.next().is_none());
+
+        // oldest first, references only
+        let oldest = db.get_all(b"kept").unwrap().rev().map(|r| r.unwrap().1).collect::<Vec<_>>();
+        assert_eq!(oldest, vec!(b"first".to_vec(), b"second".to_vec(), b"third!".to_vec()));
+        let prefs = db.get_all(b"kept").unwrap().prefs();
+        assert_eq!(prefs.len(), 3);
+        assert_eq!(prefs.as_slice()[0], newest);
+        assert_eq!(prefs.as_slice()[2], first);
+
+        assert_eq!(db.get_all(b"single").unwrap().len(), 1);
+        assert_eq!(db.get_all(b"absent").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_put_hashed () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let (pref, hash) = db.put_hashed(b"abc").unwrap();
+        assert_eq!(hex::encode(hash), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        // stored once
+        assert_eq!(db.put_hashed(b"abc").unwrap(), (pref, hash));
+        db.batch().unwrap();
+        assert_eq!(db.iter().filter(|(_, key, _)| !key.is_empty()).count(), 1);
+        assert_eq!(db.get_hashed(&hash).unwrap(), Some((pref, b"abc".to_vec())));
+        assert_eq!(db.get_hashed(&[0u8; 32]).unwrap(), None);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_export_import () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let dead = db.put_keyed(b"overwritten", b"old").unwrap();
+        db.put_keyed(b"overwritten", b"new").unwrap();
+        let header = db.put(b"header").unwrap();
+        db.batch().unwrap();
+        let tx = db.put_keyed(b"tx", &[1u8; 5000]).unwrap();
+        db.put_with_links(b"block", &[header, tx, dead]).unwrap();
+        db.forget(b"tx").unwrap();
+        db.batch().unwrap();
+
+        let mut dump = Vec::new();
+        assert_eq!(db.export(&mut dump).unwrap(), 5);
+
+        let name = temp_db_name("import");
+        let mut imported = persistent(&name, 10, 1).unwrap();
+        assert_eq!(imported.import(&mut Cursor::new(dump.as_slice())).unwrap(), 5);
+        assert_eq!(imported.get_keyed(b"overwritten").unwrap().unwrap().1, b"new".to_vec());
+        assert_eq!(imported.get_keyed(b"tx").unwrap(), None);
+        let (block, _, _) = imported.iter().find(|(_, _, data)| data.as_slice() == b"block").unwrap();
+        let links = imported.get_links(block).unwrap().iter().map(|link| imported.get(*link).unwrap().1).collect::<Vec<_>>();
+        assert_eq!(links, vec!(b"header".to_vec(), vec!(1u8; 5000), b"old".to_vec()));
+        // a dump is independent of layout, the same content exports the same
+        let mut again = Vec::new();
+        imported.export(&mut again).unwrap();
+        assert_eq!(again, dump);
+        imported.shutdown();
+
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        assert!(db.import(&mut Cursor::new(&dump[.. dump.len() - 4])).is_err());
+        assert!(db.import(&mut Cursor::new(b"not a dump at all")).is_err());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_links () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let tx1 = db.put(b"tx1").unwrap();
+        let tx2 = db.put_keyed(b"tx2", b"tx2").unwrap();
+        let block = db.put_with_links(b"block", &[tx1, tx2]).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get(block).unwrap(), (vec!(), b"block".to_vec()));
+        assert_eq!(db.get_links(block).unwrap(), vec!(tx1, tx2));
+        assert_eq!(db.get_links(tx1).unwrap(), vec!());
+        assert_eq!(db.verify_links().unwrap(), vec!());
+
+        let dangling = PRef::from(tx1.as_u64() + 1);
+        let orphan = db.put_with_links(&[0u8; 1000], &[block, dangling]).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.verify_links().unwrap(), vec!((orphan, dangling)));
+    }
+
+    #[test]
+    fn test_bloom () {
+        let name = temp_db_name("bloom");
+        let mut db = persistent(&name, 10, 2).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 10000 {
+            thread_rng().fill_bytes(&mut key);
+            check.insert(key, db.put_keyed(&key, &key).unwrap());
+        }
+        db.batch().unwrap();
+        let forgotten = *check.keys().next().unwrap();
+        db.forget(&forgotten).unwrap();
+        check.remove(&forgotten);
+        db.batch().unwrap();
+        db.shutdown();
+
+        let dir = Path::new(&name).parent().unwrap().to_path_buf();
+        for reopen in 0 .. 2 {
+            if reopen == 1 {
+                // an invalid bloom file is ignored
+                let bloom = dir.join("test.0.bf");
+                let mut content = fs::read(&bloom).unwrap();
+                content[4096] ^= 0xff;
+                fs::write(&bloom, content).unwrap();
+            }
+            let mut db = persistent(&name, 10, 2).unwrap();
+            for (k, o) in check.iter() {
+                assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, k.to_vec())));
+            }
+            assert_eq!(db.get_keyed(&forgotten).unwrap(), None);
+            for _ in 0 .. 1000 {
+                thread_rng().fill_bytes(&mut key);
+                assert!(!check.contains_key(&key) && db.get_keyed(&key).unwrap().is_none());
+            }
+            db.shutdown();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoints () {
+        let name = temp_db_name("checkpoints");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 5000 {
+            thread_rng().fill_bytes(&mut key);
+            check.insert(key, db.put_keyed(&key, &key).unwrap());
+        }
+        db.batch_named(1).unwrap();
+        let forgotten = *check.keys().next().unwrap();
+        db.forget(&forgotten).unwrap();
+        let mut later = Vec::new();
+        for _ in 0 .. 5000 {
+            thread_rng().fill_bytes(&mut key);
+            db.put_keyed(&key, &key).unwrap();
+            later.push(key);
+        }
+        db.batch_named(2).unwrap();
+        db.put_keyed(b"uncommitted", b"data").unwrap();
+        assert!(db.get_keyed(b"uncommitted").unwrap().is_some());
+
+        db.rollback_to(1).unwrap();
+        assert!(db.get_keyed(b"uncommitted").unwrap().is_none());
+        assert!(db.rollback_to(2).is_err());
+        for (k, o) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, k.to_vec())));
+        }
+        for k in later.iter() {
+            assert!(db.get_keyed(&k[..]).unwrap().is_none());
+        }
+        assert_eq!(db.iter().filter(|(_, k, _)| !k.is_empty()).count(), check.len());
+
+        // the db continues from the checkpoint and can be rolled back to it again
+        let after = db.put_keyed(b"after", b"rollback").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        let mut db = persistent(&name, 10, 1).unwrap();
+        assert_eq!(db.get_keyed(b"after").unwrap(), Some((after, b"rollback".to_vec())));
+        assert_eq!(db.get_keyed(&forgotten).unwrap(), Some((check[&forgotten], forgotten.to_vec())));
+        db.rollback_to(1).unwrap();
+        assert!(db.get_keyed(b"after").unwrap().is_none());
+        assert_eq!(db.get_keyed(&forgotten).unwrap(), Some((check[&forgotten], forgotten.to_vec())));
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_flush () {
+        let name = temp_db_name("parallel_flush");
+        let config = Config::default().bucket_fill_target(1).flush_threads(4);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        for _ in 0 .. 30000 {
+            thread_rng().fill_bytes(&mut key);
+            check.insert(key, db.put_keyed(&key, &key).unwrap());
+        }
+        db.batch().unwrap();
+        db.shutdown();
+
+        let db = persistent_with_config(&name, &config.flush_threads(1)).unwrap();
+        for (k, o) in check.iter() {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*o, k.to_vec())));
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_entry_info () {
+        let name = temp_db_name("entry-info");
+        let mut db = persistent_with_config(&name, &Config::default().generations(true)).unwrap();
+        db.set_generation(7).unwrap();
+        let keyed = db.put_keyed(b"key", b"data").unwrap();
+        // random, so data does not compress
+        let mut data = vec!(0u8; 5 << 20);
+        thread_rng().fill_bytes(&mut data);
+        let linked = db.put_with_links(&data[.. 10000], &[keyed]).unwrap();
+        db.batch().unwrap();
+        let extended = db.put(data.as_slice()).unwrap();
+        db.batch().unwrap();
+
+        let info = db.entry_info(keyed).unwrap();
+        assert_eq!((info.key_len, info.links, info.spans_pages, info.generation), (3, 0, 1, Some(7)));
+        assert_eq!(info.stored_len, 1 + 1 + 3 + 3 + 4);
+        let info = db.entry_info(linked).unwrap();
+        assert_eq!((info.key_len, info.links, info.generation), (0, 1, Some(7)));
+        assert!(info.spans_pages >= 3);
+        let info = db.entry_info(extended).unwrap();
+        assert!(info.extended);
+        assert_eq!(info.generation, Some(8));
+        assert!(db.entry_info(PRef::from(db.pending_bytes() + (1 << 30))).is_err());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_get_filtered () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let small = db.put_keyed(b"small", b"data").unwrap();
+        let large = db.put_keyed(b"large", &[7u8; 20000]).unwrap();
+        db.batch().unwrap();
+
+        assert_eq!(db.get_filtered(b"small", &|k, _| k == b"small").unwrap(), vec!((small, b"small".to_vec(), b"data".to_vec())));
+        assert_eq!(db.get_filtered(b"small", &|_, d| d.len() > 4).unwrap(), vec!());
+        assert_eq!(db.get_filtered(b"missing", &|_, _| true).unwrap(), vec!());
+        assert_eq!(db.get_keys_only(b"large").unwrap(), vec!((large, b"large".to_vec())));
+        assert_eq!(db.get_keys_only(b"missing").unwrap(), vec!());
+    }
+
+    #[test]
+    fn test_repair () {
+        let name = temp_db_name("repair");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let mut check = HashMap::new();
+        let mut key = [0x0u8;32];
+        let mut data = [0x0u8;40];
+        for _ in 0 .. 2000 {
+            thread_rng().fill_bytes(&mut key);
+            thread_rng().fill_bytes(&mut data);
+            let pref = db.put_keyed(&key, &data).unwrap();
+            check.insert(key, (pref, data));
+        }
+        let overwritten = db.put_keyed(&key, b"replaced").unwrap();
+        check.remove(&key);
+        db.batch().unwrap();
+        db.shutdown();
+
+        // lose the hash table
+        let dir = Path::new(&name).parent().unwrap().to_path_buf();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().unwrap() == "tb" {
+                fs::write(&path, b"garbage").unwrap();
+            }
+        }
+        assert!(persistent(&name, 10, 1).is_err());
+
+        let mut db = repair(&name, &Config::default()).unwrap();
+        for (k, (pref, data)) in &check {
+            assert_eq!(db.get_keyed(&k[..]).unwrap(), Some((*pref, data.to_vec())));
+        }
+        assert_eq!(db.get_keyed(&key[..]).unwrap(), Some((overwritten, b"replaced".to_vec())));
+        db.shutdown();
+
+        let mut db = persistent(&name, 10, 1).unwrap();
+        assert_eq!(db.iter_live().count(), check.len() + 1);
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dedup () {
+        let name = temp_db_name("dedup");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let before = db.put(b"script").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        // data stored before deduplication was on is found too
+        let config = Config::default().dedup(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.put(b"script").unwrap(), before);
+        let other = db.put(b"other").unwrap();
+        assert_ne!(other, before);
+        assert_eq!(db.put(b"other").unwrap(), other);
+        let keyed = db.put_keyed(b"key", b"other").unwrap();
+        assert_ne!(keyed, other);
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.put(b"other").unwrap(), other);
+        assert_eq!(db.get(other).unwrap(), (vec!(), b"other".to_vec()));
+        assert_eq!(db.iter().filter(|(_, _, data)| data.as_slice() == b"other").count(), 2);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        puts: AtomicUsize,
+        gets: AtomicUsize,
+        batches: AtomicUsize,
+        pages_written: AtomicUsize,
+        syncs: AtomicUsize
+    }
+
+    impl Metrics for CountingMetrics {
+        fn put (&self) {
+            self.puts.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn get (&self) {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn batch (&self, _: Duration, pages_written: u64) {
+            self.batches.fetch_add(1, Ordering::Relaxed);
+            self.pages_written.fetch_add(pages_written as usize, Ordering::Relaxed);
+        }
+
+        fn sync (&self, _: Duration) {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        batches: Mutex<Vec<Vec<Change>>>
+    }
+
+    impl CommitObserver for RecordingObserver {
+        fn committed (&self, changes: &[Change]) {
+            self.batches.lock().unwrap().push(changes.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_observe () {
+        let mut db = transient(1).unwrap();
+        db.put_keyed(b"before", b"observed").unwrap();
+        let observer = Arc::new(RecordingObserver::default());
+        db.observe(observer.clone());
+        let first = db.put_keyed(b"first", b"one").unwrap();
+        let referred = db.put(b"referred").unwrap();
+        db.forget(b"before").unwrap();
+        let linked = db.put_with_links(b"linked", &[referred]).unwrap();
+        db.batch().unwrap();
+        let batched = db.put_batch(&[(b"a", b"1"), (b"b", b"2")]).unwrap();
+        db.batch().unwrap();
+        // empty batches are reported too
+        db.batch().unwrap();
+
+        let batches = observer.batches.lock().unwrap();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[0], vec!(Change::Stored(b"first".to_vec(), first), Change::Stored(vec!(), referred),
+            Change::Forgotten(b"before".to_vec()), Change::Stored(vec!(), linked)));
+        assert_eq!(batches[1], vec!(Change::Stored(b"a".to_vec(), batched[0]), Change::Stored(b"b".to_vec(), batched[1])));
+        assert!(batches[2].is_empty());
+    }
+
+    #[test]
+    fn test_metrics () {
+        let name = temp_db_name("metrics");
+        let metrics = Arc::new(CountingMetrics::default());
+        let mut db = persistent_with_config(&name, &Config::default().metrics(metrics.clone())).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.put(b"referred").unwrap();
+        db.get_keyed(b"key").unwrap();
+        db.get(pref).unwrap();
+        db.batch().unwrap();
+        assert_eq!(metrics.puts.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.gets.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.batches.load(Ordering::Relaxed), 1);
+        assert!(metrics.pages_written.load(Ordering::Relaxed) > 0);
+        assert!(metrics.syncs.load(Ordering::Relaxed) >= 4);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_get_ref () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let keyed = db.put_keyed(b"key", b"data").unwrap();
+        let referred = db.put(&[1u8; 5000]).unwrap();
+        let linked = db.put_with_links(b"linked", &[keyed]).unwrap();
+        db.batch().unwrap();
+
+        let content = db.get_ref(keyed).unwrap();
+        assert_eq!((content.key(), content.data()), (&b"key"[..], &b"data"[..]));
+        assert_eq!(content.to_owned(), db.get(keyed).unwrap());
+        let content = db.get_ref(referred).unwrap();
+        assert_eq!((content.key(), content.data()), (&b""[..], &[1u8; 5000][..]));
+        assert_eq!(db.get_ref(linked).unwrap().data(), b"linked");
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_large_data () {
+        let name = temp_db_name("large");
+        let config = Config::default().write_ahead_log(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut data = vec!(0u8; 17 << 20);
+        thread_rng().fill_bytes(&mut data);
+        let small = db.put_keyed(b"small", b"data").unwrap();
+        let keyed = db.put_keyed(b"key", data.as_slice()).unwrap();
+        let referred = db.put(data.as_slice()).unwrap();
+        let linked = db.put_with_links(data.as_slice(), &[keyed]).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((keyed, data.clone())));
+        assert_eq!(db.get(referred).unwrap(), (vec!(), data.clone()));
+        assert_eq!(db.get_ref(linked).unwrap().data(), data.as_slice());
+        assert_eq!(db.get_links(linked).unwrap(), vec!(keyed));
+        assert_eq!(db.iter().filter(|(_, _, d)| *d == data).count(), 3);
+        assert_eq!(db.get_keyed(b"small").unwrap(), Some((small, b"data".to_vec())));
+
+        // replayed from the write ahead log
+        let unbatched = db.put_keyed(b"unbatched", data.as_slice()).unwrap();
+        db.shutdown();
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"unbatched").unwrap(), Some((unbatched, data.clone())));
+        assert_eq!(db.iter_live().count(), 3);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_limits () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        match db.put_keyed(&[0u8; 256], b"data") {
+            Err(Error::DoesNotFit { what, max, got }) => assert_eq!((what, max, got), ("key length", 255, 256)),
+            _ => panic!("key should not fit")
+        }
+        assert!(db.put_keyed(&[0u8; 255], b"data").is_ok());
+        let links = vec!(PRef::from(0); super::MAX_LINKS + 1);
+        match db.put_with_links(b"data", links.as_slice()) {
+            Err(Error::DoesNotFit { what, .. }) => assert_eq!(what, "number of links"),
+            _ => panic!("links should not fit")
+        }
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_stream () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let mut data = vec!(0u8; 9 << 20);
+        thread_rng().fill_bytes(&mut data);
+        let streamed = db.put_stream(&mut Cursor::new(data.as_slice()), data.len() as u64).unwrap();
+        let small = db.put_stream(&mut Cursor::new(b"small".to_vec()), 5).unwrap();
+        let keyed = db.put_keyed(b"key", data.as_slice()).unwrap();
+        assert!(db.put_stream(&mut Cursor::new(b"short".to_vec()), 6).is_err());
+        db.batch().unwrap();
+
+        let mut read = Vec::new();
+        db.get_stream(streamed).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == data);
+        assert!(db.get(streamed).unwrap().1 == data);
+        let mut read = Vec::new();
+        db.get_stream(keyed).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == data);
+        let mut read = Vec::new();
+        db.get_stream(small).unwrap().read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"small".to_vec());
+        assert_eq!(db.iter().filter(|(_, _, d)| *d == data).count(), 2);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_generations () {
+        let name = temp_db_name("generations");
+        let config = Config::default().generations(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let a = db.put_keyed(b"a", b"first").unwrap();
+        let b = db.put_keyed(b"b", b"referred later").unwrap();
+        db.batch().unwrap();
+        let c = db.put_keyed(b"c", b"second").unwrap();
+        db.put_with_links(b"links", &[b]).unwrap();
+        db.batch().unwrap();
+        // a batch storing nothing does not start a generation
+        db.batch().unwrap();
+        assert_eq!(db.generation(), Some(2));
+        assert_eq!(db.generation_of(a).unwrap(), Some(0));
+        assert_eq!(db.generation_of(c).unwrap(), Some(1));
+
+        assert!(db.set_generation(1).is_err());
+        db.set_generation(10).unwrap();
+        let d = db.put_keyed(b"d", b"tenth").unwrap();
+        assert_eq!(db.generation_of(d).unwrap(), Some(10));
+        db.batch().unwrap();
+
+        // b is referred to by data of generation 1
+        assert_eq!(db.prune_before(1).unwrap(), 1);
+        assert_eq!(db.get_keyed(b"a").unwrap(), None);
+        assert_eq!(db.get_keyed(b"b").unwrap(), Some((b, b"referred later".to_vec())));
+        db.batch().unwrap();
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.generation(), Some(11));
+        assert_eq!(db.generation_of(c).unwrap(), Some(1));
+        assert_eq!(db.generation_of(d).unwrap(), Some(10));
+        assert_eq!(db.prune_before(11).unwrap(), 3);
+        assert_eq!(db.iter_live().count(), 0);
+        db.shutdown();
+
+        let mut transient = Transient::new_db("", 1, 1).unwrap();
+        assert_eq!(transient.generation(), None);
+        assert!(transient.prune_before(0).is_err());
+        transient.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_pref_range () {
+        assert_eq!(PRef::try_from_u64(0xffff_ffff_fffe).unwrap(), PRef::from(0xffff_ffff_fffe));
+        assert!(PRef::try_from_u64(0xffff_ffff_ffff).is_err());
+        assert!(PRef::try_from_u64(u64::MAX).is_err());
+        assert_eq!(PRef::from(1).checked_add(1), Some(PRef::from(2)));
+        assert_eq!(PRef::from(0xffff_ffff_fffe).checked_add(1), None);
+        assert_eq!(PRef::from(1).checked_add(u64::MAX), None);
+
+        // appending beyond the addressable range fails instead of wrapping
+        let mut appender = PagedFileAppender::new(Box::new(Transient::new(true)), PRef::from(0xffff_ffff_e000));
+        assert!(appender.append(&[0u8; 100]).is_ok());
+        match appender.append(&[0u8; 4096]) {
+            Err(Error::DoesNotFit { what, .. }) => assert_eq!(what, "file length"),
+            _ => panic!("append should not fit")
+        }
+    }
+
+    #[test]
+    fn test_split_moving_all () {
+        let name = temp_db_name("split-moving-all");
+        let config = Config::default().bucket_fill_target(7);
+        let mut db = Persistent::open(&name, &config).unwrap();
+        let (step, log_mod, _, _, _, _, sip0, sip1) = db.params();
+        let low = |hash: u32| (hash & (!0u32 >> (32 - log_mod))) as usize;
+        // keys of the bucket split next that all move to its new bucket, without splitting themselves,
+        // a put splits a bucket if the hash of its key is a multiple of the fill target
+        let keys = (0 .. u32::MAX).map(|i| i.to_be_bytes()).filter(|key| {
+            let hash = key_hash(key, sip0, sip1);
+            low(hash) == step && hash & (1 << log_mod) != 0 && !hash.is_multiple_of(7)
+        }).take(10).collect::<Vec<_>>();
+        let mut stored = Vec::new();
+        for key in &keys {
+            stored.push(db.put_keyed(key, key).unwrap());
+        }
+        db.batch().unwrap();
+        assert_eq!(db.bucket_slots(step).unwrap().len(), 10);
+
+        // a key of an other bucket splits it
+        let splitting = (0 .. u32::MAX).map(|i| (i | 1 << 31).to_be_bytes()).find(|key| {
+            let hash = key_hash(key, sip0, sip1);
+            low(hash) != step && hash.is_multiple_of(7)
+        }).unwrap();
+        db.put_keyed(&splitting, b"split").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.params().0, step + 1);
+        assert!(db.bucket_slots(step).unwrap().is_empty());
+        db.shutdown();
+        drop(db);
+
+        // the emptied bucket does not find the moved slots again after reopen
+        let mut db = Persistent::open(&name, &config).unwrap();
+        assert!(db.bucket_slots(step).unwrap().is_empty());
+        assert_eq!(db.bucket_slots(step + (1 << log_mod)).unwrap().len(), 10);
+        for (key, pref) in keys.iter().zip(stored.iter()) {
+            assert_eq!(db.get_keyed(key).unwrap(), Some((*pref, key.to_vec())));
+        }
+        assert_eq!(db.iter_keys().count(), 11);
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_page_size () {
+        let name = temp_db_name("page-size");
+        let config = Config::default().data_page_size(60000).table_page_size(16384).max_file_size(1 << 20);
+        assert_eq!(config.data_page_size, 1 << 16);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut rng = thread_rng();
+        let mut check = HashMap::new();
+        for i in 0 .. 3000u32 {
+            let mut data = vec!(0u8; (rng.next_u32() % 1000) as usize);
+            rng.fill_bytes(&mut data);
+            let key = i.to_be_bytes();
+            let pref = db.put_keyed(&key, data.as_slice()).unwrap();
+            check.insert(key, (pref, data));
+            if i % 1000 == 999 {
+                db.batch_named(i as u64).unwrap();
+            }
+        }
+        db.put_keyed(b"rolled back", b"data").unwrap();
+        db.batch().unwrap();
+        db.rollback_to(2999).unwrap();
+        db.shutdown();
+
+        // the page size of existing files is that of their header
+        let mut db = persistent_with_config(&name, &Config::default().max_file_size(1 << 20)).unwrap();
+        let mut header = [0u8; 24];
+        fs::File::open(format!("{}.0.bc", name)).unwrap().read_exact(&mut header).unwrap();
+        assert_eq!(header[20 .. 24], (1u32 << 16).to_be_bytes());
+        assert_eq!(db.get_keyed(b"rolled back").unwrap(), None);
+        for (key, (pref, data)) in &check {
+            assert_eq!(db.get_keyed(key).unwrap(), Some((*pref, data.clone())));
+        }
+        assert_eq!(db.iter_live().count(), 3000);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_put_batch () {
+        let name = temp_db_name("put-batch");
+        let config = Config::default().write_ahead_log(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let first = db.put_keyed(b"first", b"before").unwrap();
+        let large = vec!(7u8; 10000);
+        let entries: Vec<(&[u8], &[u8])> = vec!((b"one", b"1"), (b"two", large.as_slice()), (b"first", b"replaced"), (b"three", b"3"));
+        let prefs = db.put_batch(entries.as_slice()).unwrap();
+        assert_eq!(prefs.len(), 4);
+        assert!(first < prefs[0] && prefs[0] < prefs[1] && prefs[1] < prefs[2] && prefs[2] < prefs[3]);
+        for ((key, data), pref) in entries.iter().zip(prefs.iter()) {
+            assert_eq!(db.get(*pref).unwrap(), (key.to_vec(), data.to_vec()));
+        }
+        assert_eq!(db.get_keyed(b"first").unwrap(), Some((prefs[2], b"replaced".to_vec())));
+        // crash before batch, the log is replayed
+        db.shutdown();
+
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"two").unwrap(), Some((prefs[1], large.clone())));
+        assert_eq!(db.get_keyed(b"first").unwrap(), Some((prefs[2], b"replaced".to_vec())));
+        assert_eq!(db.iter_live().count(), 4);
+        let too_long = [0u8; ::format::MAX_KEY_LEN + 1];
+        assert!(db.put_batch(&[(b"ok", b"data"), (&too_long, b"data")]).is_err());
+        assert_eq!(db.get_keyed(b"ok").unwrap(), None);
+        db.batch().unwrap();
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[cfg(feature="encryption")]
+    #[test]
+    fn test_put_batch_extended () {
+        let name = temp_db_name("put-batch-extended");
+        let config = Config::default().encryption_key([7u8; 32]);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        // does not compress and is extended only once encrypted
+        let mut near = vec!(0u8; (1 << 22) - 10);
+        thread_rng().fill_bytes(&mut near);
+        let entries: Vec<(&[u8], &[u8])> = vec!((b"before", b"1"), (b"near", near.as_slice()), (b"after", b"2"));
+        let prefs = db.put_batch(entries.as_slice()).unwrap();
+        for ((key, data), pref) in entries.iter().zip(prefs.iter()) {
+            assert_eq!(db.get(*pref).unwrap(), (key.to_vec(), data.to_vec()));
+            assert_eq!(db.get_keyed(key).unwrap(), Some((*pref, data.to_vec())));
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        let db = persistent_with_config(&name, &config).unwrap();
+        assert_eq!(db.get_keyed(b"near").unwrap(), Some((prefs[1], near)));
+        assert_eq!(db.get_keyed(b"after").unwrap(), Some((prefs[2], b"2".to_vec())));
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_verify () {
+        let name = temp_db_name("verify");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let mut keys = Vec::new();
+        for i in 0 .. 1000u32 {
+            keys.push(db.put_keyed(&i.to_be_bytes(), &[i as u8; 100]).unwrap());
+        }
+        db.put_with_links(b"block", keys.as_slice()).unwrap();
+        db.batch().unwrap();
+        let report = db.verify(VerifyLevel::Deep).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.keys, 1000);
+        assert!(report.links > 0 && report.links <= report.buckets);
+        assert!(report.envelopes > 1001);
+        db.shutdown();
+
+        // damage data in the second page
+        let mut file = fs::OpenOptions::new().write(true).open(format!("{}.0.bc", name)).unwrap();
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 + 100)).unwrap();
+        file.write_all(&[0xffu8; 100]).unwrap();
+        drop(file);
+        let db = read_only(&name, 10).unwrap();
+        assert!(!db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_iter_keys () {
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        let mut expected = HashMap::new();
+        for i in 0 .. 500u32 {
+            expected.insert(i.to_be_bytes().to_vec(), db.put_keyed(&i.to_be_bytes(), &[i as u8; 10]).unwrap());
+        }
+        db.batch().unwrap();
+        expected.insert(b"long".to_vec(), db.put_keyed(b"long", &vec!(1u8; 5 << 20)).unwrap());
+        expected.insert(0u32.to_be_bytes().to_vec(), db.put_keyed(&0u32.to_be_bytes(), b"overwritten").unwrap());
+        db.forget(&1u32.to_be_bytes()).unwrap();
+        expected.remove(1u32.to_be_bytes().as_slice());
+        db.put(b"not keyed").unwrap();
+
+        let keys = db.iter_keys().map(|(pref, key)| (key, pref)).collect::<HashMap<_, _>>();
+        assert_eq!(db.iter_keys().count(), expected.len());
+        assert_eq!(keys, expected);
+        assert_eq!(db.iter_live().map(|(pref, key, _)| (key, pref)).collect::<HashMap<_, _>>(), keys);
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_iter_keys_error () {
+        let name = temp_db_name("iter_keys_error");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        // the slot of the key points to data without key, the file starts with a header
+        let mut file = fs::OpenOptions::new().write(true).open(format!("{}.0.bc", name)).unwrap();
+        file.seek(SeekFrom::Start(::singlefile::HEADER_SIZE + pref.as_u64() + 3)).unwrap();
+        file.write_all(&[1u8]).unwrap();
+        drop(file);
+        let damaged = read_only(&name, 10).unwrap();
+        let mut keys = damaged.iter_keys();
+        assert_eq!((&mut keys).count(), 0);
+        assert!(keys.error().is_err());
+        drop(keys);
+        let mut db = Transient::new_db("", 1, 1).unwrap();
+        assert!(db.ingest(damaged.as_ref(), Conflict::Error).is_err());
+        assert_eq!(db.iter().count(), 0);
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_bucket_error () {
+        let name = temp_db_name("bucket_error");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        for i in 0 .. 100u32 {
+            db.put_keyed(&i.to_be_bytes(), &[i as u8; 10]).unwrap();
+        }
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        // buckets are read from the damaged link file as they are iterated
+        let mut file = fs::OpenOptions::new().write(true).open(format!("{}.0.bl", name)).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.seek(SeekFrom::Start(::singlefile::HEADER_SIZE)).unwrap();
+        file.write_all(vec!(0xffu8; (len - ::singlefile::HEADER_SIZE) as usize).as_slice()).unwrap();
+        drop(file);
+        let db = Persistent::read_only(&name, &Config::default().memory_budget(1)).unwrap();
+        let mut live = db.iter_live();
+        assert_eq!((&mut live).count(), 0);
+        assert!(live.error().is_err());
+        drop(live);
+        let mut keys = db.iter_keys();
+        assert_eq!((&mut keys).count(), 0);
+        assert!(keys.error().is_err());
+        drop(keys);
+        assert!(db.table_stats().is_err());
+        assert!(db.export(&mut Vec::new()).is_err());
+        assert!(!db.verify(VerifyLevel::Structure).unwrap().is_ok());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_close () {
+        let name = temp_db_name("close");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        match persistent(&name, 10, 1) {
+            Err(Error::Locked(_)) => {},
+            _ => panic!("a second writer should not open the db")
+        }
+        // readers are not locked out
+        assert_eq!(read_only(&name, 10).unwrap().get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+        db.close();
+
+        for _ in 0 .. 3 {
+            let db = persistent(&name, 10, 1).unwrap();
+            assert_eq!(db.get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+            db.close();
+        }
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_container () {
+        let name = temp_db_name("container");
+        let config = Config::default().container(true).write_ahead_log(true).generations(true);
+        let mut db = persistent_with_config(&name, &config).unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 2000u32 {
+            let data = vec!(i as u8; 1000 + i as usize % 1000);
+            check.insert(i, (db.put_keyed(&i.to_be_bytes(), data.as_slice()).unwrap(), data));
+        }
+        db.batch_named(1).unwrap();
+        for i in 2000 .. 4000u32 {
+            db.put_keyed(&i.to_be_bytes(), &[0u8; 1000]).unwrap();
+        }
+        db.batch().unwrap();
+        // rolled back extents are reused
+        db.rollback_to(1).unwrap();
+        let referred = db.put(b"after rollback").unwrap();
+        db.batch().unwrap();
+        let unbatched = db.put_keyed(b"wal", b"replayed").unwrap();
+        db.close();
+
+        // all files are in the container
+        let dir = Path::new(&name).parent().unwrap();
+        let mut files = fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!("test.hb".to_string(), "test.lock".to_string()));
+
+        // an existing container is opened without asking for it
+        let db = persistent_with_config(&name, &Config::default().write_ahead_log(true).generations(true)).unwrap();
+        assert_eq!(db.get_keyed(b"wal").unwrap(), Some((unbatched, b"replayed".to_vec())));
+        assert_eq!(db.get(referred).unwrap(), (vec!(), b"after rollback".to_vec()));
+        assert_eq!(db.get_keyed(&2000u32.to_be_bytes()).unwrap(), None);
+        for (key, (pref, data)) in &check {
+            assert_eq!(db.get_keyed(&key.to_be_bytes()).unwrap(), Some((*pref, data.clone())));
+        }
+        let report = db.verify(VerifyLevel::Deep).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        db.close();
+        let db = read_only(&name, 10).unwrap();
+        assert_eq!(db.iter_keys().count(), 2001);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_page_store () {
+        let dir = env::temp_dir().join(format!("hammersbald-page-store-{}", thread_rng().next_u64()));
+        let store = Arc::new(MemoryStore::new());
+        let config = Config::default().directory(&dir).write_ahead_log(true).page_store(store.clone());
+        let mut db = persistent_with_config("test", &config).unwrap();
+        let mut check = HashMap::new();
+        for i in 0 .. 1000u32 {
+            check.insert(i, db.put_keyed(&i.to_be_bytes(), &[i as u8; 100]).unwrap());
+        }
+        db.batch().unwrap();
+        db.create_index("first", Box::new(|_, data: &[u8]| vec!(data[..1].to_vec()))).unwrap();
+        db.shutdown();
+        drop(db);
+
+        // nothing is stored on the local file system
+        assert!(!dir.exists());
+        assert!(store.contains("test.first", "bc"));
+        let db = persistent_with_config("test", &config).unwrap();
+        for (i, pref) in &check {
+            assert_eq!(db.get_keyed(&i.to_be_bytes()).unwrap(), Some((*pref, vec!(*i as u8; 100))));
+        }
+        assert!(db.verify(VerifyLevel::Deep).unwrap().is_ok());
+        let db = read_only_with_config("test", &config).unwrap();
+        assert_eq!(db.iter_keys().count(), 1000);
+    }
+}
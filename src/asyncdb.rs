@@ -0,0 +1,323 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Async API
+//! Calls of a db as futures, executed by a pool of threads owned by the wrapper, so the file IO
+//! does not block the threads of an async runtime. Iterators are streams fed by a pool thread, that
+//! locks the db only while it reads a few items, then resumes after them, so writes go on meanwhile.
+//!
+
+use api::HammersbaldAPI;
+use pref::PRef;
+use error::Error;
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use std::cmp::max;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{mpsc as channel, Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+
+// items read ahead of the consumer of a stream
+const STREAM_BUFFER: usize = 64;
+// data a stream reads while it holds the lock of the db
+const STREAM_CHUNK: usize = 256;
+
+type Db = RwLock<Box<dyn HammersbaldAPI>>;
+type Job = Box<dyn FnOnce(&Db) + Send>;
+
+/// a db with an async API
+pub struct AsyncHammersbald {
+    db: Arc<Db>,
+    jobs: Option<channel::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>
+}
+
+impl AsyncHammersbald {
+    /// execute calls of the db with a pool of threads, at least one
+    /// an open stream occupies a thread of the pool until it is dropped
+    pub fn new (db: Box<dyn HammersbaldAPI>, threads: usize) -> AsyncHammersbald {
+        let db = Arc::new(RwLock::new(db));
+        let (sender, receiver) = channel::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0 .. max(threads, 1)).map(|_| {
+            let db = db.clone();
+            let receiver = receiver.clone();
+            thread::Builder::new().name("hammersbald-async".to_string()).spawn(move || {
+                loop {
+                    let job = receiver.lock().expect("job queue lock poisoned").recv();
+                    match job {
+                        Ok(job) => job(&db),
+                        Err(_) => break
+                    }
+                }
+            }).expect("hammersbald can not start thread for async calls")
+        }).collect();
+        AsyncHammersbald { db, jobs: Some(sender), workers }
+    }
+
+    /// store data accessible with key
+    pub fn put_keyed (&self, key: Vec<u8>, data: Vec<u8>) -> Pending<PRef> {
+        self.call(move |db| db.write()?.put_keyed(key.as_slice(), data.as_slice()))
+    }
+
+    /// store data
+    pub fn put (&self, data: Vec<u8>) -> Pending<PRef> {
+        self.call(move |db| db.write()?.put(data.as_slice()))
+    }
+
+    /// forget a key
+    pub fn forget (&self, key: Vec<u8>) -> Pending<()> {
+        self.call(move |db| db.write()?.forget(key.as_slice()))
+    }
+
+    /// end current batch and start a new batch
+    pub fn batch (&self) -> Pending<()> {
+        self.call(move |db| db.write()?.batch())
+    }
+
+    /// retrieve data by key
+    pub fn get_keyed (&self, key: Vec<u8>) -> Pending<Option<(PRef, Vec<u8>)>> {
+        self.call(move |db| db.read()?.get_keyed(key.as_slice()))
+    }
+
+    /// retrieve data by persistent reference
+    pub fn get (&self, pref: PRef) -> Pending<(Vec<u8>, Vec<u8>)> {
+        self.call(move |db| db.read()?.get(pref))
+    }
+
+    /// stream of data
+    /// data stored while the stream is open is included if stored after the data it reached
+    pub fn iter (&self) -> AsyncIterator<'_, (PRef, Vec<u8>, Vec<u8>)> {
+        self.stream(|_, pref, key, data| Ok(Some((pref, key, data))))
+    }
+
+    /// stream of data still accessible with its key, in the order of the data file
+    pub fn iter_live (&self) -> AsyncIterator<'_, (PRef, Vec<u8>, Vec<u8>)> {
+        self.stream(|db, pref, key, data|
+            Ok(if Self::is_live(db, pref, &key)? { Some((pref, key, data)) } else { None }))
+    }
+
+    /// stream of keys still accessible, in the order of the data file
+    pub fn iter_keys (&self) -> AsyncIterator<'_, (PRef, Vec<u8>)> {
+        self.stream(|db, pref, key, _|
+            Ok(if Self::is_live(db, pref, &key)? { Some((pref, key)) } else { None }))
+    }
+
+    /// wait for calls already made and stop the background writer of the db
+    /// this blocks the calling thread
+    pub fn shutdown (&mut self) {
+        self.stop();
+        if let Ok(mut db) = self.db.write() {
+            db.shutdown();
+        }
+    }
+
+    fn call<T, F> (&self, f: F) -> Pending<T>
+        where T: Send + 'static, F: FnOnce(&Db) -> Result<T, Error> + Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        if let Some(ref jobs) = self.jobs {
+            // the future fails if the pool is gone
+            jobs.send(Box::new(move |db| { sender.send(f(db)).ok(); })).ok();
+        }
+        Pending { receiver }
+    }
+
+    // stream the items f makes of the data, the stream ends at the end of the data or at an error
+    fn stream<T, F> (&self, f: F) -> AsyncIterator<'_, T>
+        where T: Send + 'static,
+              F: Fn(&dyn HammersbaldAPI, PRef, Vec<u8>, Vec<u8>) -> Result<Option<T>, Error> + Send + 'static {
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        if let Some(ref jobs) = self.jobs {
+            jobs.send(Box::new(move |db| {
+                let mut pos = Some(PRef::from(0));
+                while let Some(at) = pos {
+                    let mut items = Vec::new();
+                    // the lock is released before the items are sent, writes wait for a chunk at most
+                    pos = match db.read() {
+                        Ok(db) => Self::chunk(db.as_ref(), at, &f, &mut items).unwrap_or(None),
+                        Err(_) => None
+                    };
+                    for item in items {
+                        // sending fails once the stream is dropped
+                        if sender.blocking_send(item).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })).ok();
+        }
+        AsyncIterator { receiver, db: PhantomData }
+    }
+
+    // items of the data from pos on, returns the position after them or None at the end of the data
+    fn chunk<T, F> (db: &dyn HammersbaldAPI, pos: PRef, f: &F, items: &mut Vec<T>) -> Result<Option<PRef>, Error>
+        where F: Fn(&dyn HammersbaldAPI, PRef, Vec<u8>, Vec<u8>) -> Result<Option<T>, Error> {
+        let mut cursor = db.cursor_at(pos)?;
+        let mut read = 0;
+        for (pref, key, data) in cursor.by_ref().take(STREAM_CHUNK) {
+            read += 1;
+            if let Some(item) = f(db, pref, key, data)? {
+                items.push(item);
+            }
+        }
+        cursor.error()?;
+        Ok(if read < STREAM_CHUNK { None } else { Some(cursor.position()) })
+    }
+
+    // the data at pref is found with its key
+    fn is_live (db: &dyn HammersbaldAPI, pref: PRef, key: &[u8]) -> Result<bool, Error> {
+        Ok(!key.is_empty() && db.get_keys_only(key)?.iter().any(|(at, _)| *at == pref))
+    }
+
+    // finish calls and join the pool
+    fn stop (&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            worker.join().expect("async call panicked");
+        }
+    }
+}
+
+impl Drop for AsyncHammersbald {
+    fn drop (&mut self) {
+        self.stop()
+    }
+}
+
+/// the result of a call of the db
+pub struct Pending<T> {
+    receiver: oneshot::Receiver<Result<T, Error>>
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T, Error>;
+
+    fn poll (mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.receiver).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Error::Queue("async calls are stopped".to_string()))),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+/// a stream of items of an iterator of the db
+pub struct AsyncIterator<'a, T> {
+    receiver: mpsc::Receiver<T>,
+    db: PhantomData<&'a AsyncHammersbald>
+}
+
+impl<'a, T> Stream for AsyncIterator<'a, T> {
+    type Item = T;
+
+    fn poll_next (mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient;
+    use super::*;
+
+    use tokio::runtime::{Builder, Runtime};
+
+    // the next item of a stream
+    struct Next<'s, S: 's> (&'s mut S);
+
+    impl<'s, S: Stream + Unpin> Future for Next<'s, S> {
+        type Output = Option<S::Item>;
+
+        fn poll (mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            Pin::new(&mut *self.0).poll_next(cx)
+        }
+    }
+
+    fn collect<S: Stream + Unpin> (rt: &Runtime, mut stream: S) -> Vec<S::Item> {
+        let mut items = Vec::new();
+        while let Some(item) = rt.block_on(Next(&mut stream)) {
+            items.push(item);
+        }
+        items
+    }
+
+    #[test]
+    fn test_async () {
+        let rt = Builder::new_current_thread().build().unwrap();
+        let mut db = AsyncHammersbald::new(transient(1).unwrap(), 2);
+        let puts = (0 .. 200u32).map(|i| db.put_keyed(i.to_be_bytes().to_vec(), vec!(i as u8; 100))).collect::<Vec<_>>();
+        let prefs = puts.into_iter().map(|put| rt.block_on(put).unwrap()).collect::<Vec<_>>();
+        let referred = rt.block_on(db.put(b"referred".to_vec())).unwrap();
+        rt.block_on(db.batch()).unwrap();
+
+        assert_eq!(rt.block_on(db.get_keyed(7u32.to_be_bytes().to_vec())).unwrap(), Some((prefs[7], vec!(7u8; 100))));
+        assert_eq!(rt.block_on(db.get(referred)).unwrap(), (vec!(), b"referred".to_vec()));
+        rt.block_on(db.forget(7u32.to_be_bytes().to_vec())).unwrap();
+        assert_eq!(rt.block_on(db.get_keyed(7u32.to_be_bytes().to_vec())).unwrap(), None);
+
+        assert_eq!(collect(&rt, db.iter()).into_iter().filter(|(_, key, _)| !key.is_empty()).count(), 200);
+        assert_eq!(collect(&rt, db.iter_live()).len(), 199);
+        let keys = collect(&rt, db.iter_keys());
+        assert!(keys.contains(&(prefs[8], 8u32.to_be_bytes().to_vec())));
+        {
+            // a dropped stream releases its thread
+            let mut stream = db.iter();
+            assert!(rt.block_on(Next(&mut stream)).is_some());
+        }
+        rt.block_on(db.put_keyed(b"after".to_vec(), b"stream".to_vec())).unwrap();
+        assert!(collect(&rt, db.iter_keys()).contains(&(rt.block_on(db.get_keyed(b"after".to_vec())).unwrap().unwrap().0, b"after".to_vec())));
+        db.shutdown();
+        match rt.block_on(db.get(referred)) {
+            Err(Error::Queue(_)) => {},
+            _ => panic!("calls after shutdown should fail")
+        }
+    }
+
+    #[test]
+    fn test_put_while_streaming () {
+        let rt = Builder::new_current_thread().build().unwrap();
+        // the stream occupies one thread, the other writes
+        let mut db = AsyncHammersbald::new(transient(1).unwrap(), 2);
+        for i in 0 .. 2000u32 {
+            rt.block_on(db.put_keyed(i.to_be_bytes().to_vec(), vec!(i as u8; 10))).unwrap();
+        }
+        rt.block_on(db.batch()).unwrap();
+
+        let mut stream = db.iter_live();
+        let mut streamed = vec!(rt.block_on(Next(&mut stream)).unwrap());
+        // the stream waits for its consumer without holding the lock of the db
+        for i in 0 .. 10u32 {
+            rt.block_on(db.put_keyed(i.to_be_bytes().to_vec(), vec!(0xff; 10))).unwrap();
+        }
+        rt.block_on(db.put_keyed(b"new".to_vec(), b"data".to_vec())).unwrap();
+        rt.block_on(db.batch()).unwrap();
+        streamed.extend(collect(&rt, stream));
+
+        // data not overwritten is streamed once, new data at the end of the file is streamed too
+        let keys = streamed.iter().map(|(_, key, _)| key.clone()).collect::<Vec<_>>();
+        for i in 10 .. 2000u32 {
+            assert_eq!(keys.iter().filter(|key| key.as_slice() == i.to_be_bytes()).count(), 1);
+        }
+        assert!(keys.contains(&b"new".to_vec()));
+        assert!(streamed.windows(2).all(|w| w[0].0 < w[1].0));
+        db.shutdown();
+    }
+}
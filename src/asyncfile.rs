@@ -29,7 +29,8 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 
 pub struct AsyncFile {
-    inner: Arc<AsyncFileInner>
+    inner: Arc<AsyncFileInner>,
+    writer: Option<thread::JoinHandle<()>>
 }
 
 struct AsyncFileInner {
@@ -52,16 +53,20 @@ impl AsyncFile {
     pub fn new (file: Box<dyn PagedFile + Send + Sync>) -> Result<AsyncFile, Error> {
         let inner = Arc::new(AsyncFileInner::new(file)?);
         let inner2 = inner.clone();
-        thread::Builder::new().name("hammersbald".to_string()).spawn(move || { AsyncFile::background(inner2) }).expect("hammersbald can not start thread for async file IO");
-        Ok(AsyncFile { inner })
+        let writer = thread::Builder::new().name("hammersbald".to_string()).spawn(move || { AsyncFile::background(inner2) }).expect("hammersbald can not start thread for async file IO");
+        Ok(AsyncFile { inner, writer: Some(writer) })
     }
 
     fn background (inner: Arc<AsyncFileInner>) {
         let mut queue = inner.queue.lock().expect("page queue lock poisoned");
-        while inner.run.load(Ordering::Acquire) {
-            while queue.is_empty() {
+        loop {
+            while queue.is_empty() && inner.run.load(Ordering::Acquire) {
                 queue = inner.work.wait(queue).expect("page queue lock poisoned");
             }
+            // the queue is written before stopping
+            if queue.is_empty() {
+                break;
+            }
             let mut file = inner.file.lock().expect("file lock poisoned");
             for page in queue.iter() {
                 file.append_page(page.clone()).expect("can not write in background");
@@ -71,15 +76,27 @@ impl AsyncFile {
         }
     }
 
+    // stop and join the background writer
+    fn stop (&mut self) {
+        if let Some(writer) = self.writer.take() {
+            {
+                let _queue = self.inner.queue.lock().unwrap();
+                self.inner.run.store(false, Ordering::Release);
+                self.inner.work.notify_one();
+            }
+            writer.join().expect("background writer panicked");
+        }
+    }
+
     fn read_in_queue (&self, pref: PRef) -> Result<Option<Page>, Error> {
         let queue = self.inner.queue.lock().expect("page queue lock poisoned");
-        if queue.len () > 0 {
+        if !queue.is_empty() {
             let file = self.inner.file.lock().expect("file lock poisoned");
             let len = PRef::from(file.len()?);
             if pref >= len {
                 let index = len.pages_until(pref);
-                if index < queue.len() {
-                    let page = queue[index].clone();
+                if index < queue.len() as u64 {
+                    let page = queue[index as usize].clone();
                     return Ok(Some(page));
                 }
             }
@@ -117,7 +134,9 @@ impl PagedFile for AsyncFile {
         }
         let mut file = self.inner.file.lock().unwrap();
         file.flush().unwrap();
-        self.inner.run.store(false, Ordering::Release)
+        drop(file);
+        drop(queue);
+        self.stop()
     }
 
     fn append_page (&mut self, page: Page) -> Result<(), Error> {
@@ -140,4 +159,18 @@ impl PagedFile for AsyncFile {
         let mut file = self.inner.file.lock().unwrap();
         file.flush()
     }
+
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        self.inner.file.lock().unwrap().prefetch(pref, pages)
+    }
+
+    fn link_chunks(&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        self.inner.file.lock().unwrap().link_chunks(name, len, chunk_size)
+    }
+}
+
+impl Drop for AsyncFile {
+    fn drop (&mut self) {
+        self.stop()
+    }
 }
@@ -0,0 +1,169 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Incremental backup
+//! A delta of the pages changed since a checkpoint. Data and link files are only appended to, so
+//! their pages since the lengths at the checkpoint are copied. Hash table pages changed after the
+//! checkpoint have a pre-image in the checkpoint file, those and the pages added are copied.
+//! The delta is restored onto a snapshot taken at the checkpoint, or a snapshot a delta up to the
+//! checkpoint was restored onto, as a batch would write it, so an interrupted restore recovers to
+//! the snapshot.
+//!
+
+use memtable::MemTable;
+use logfile::LogFile;
+use persistent::Persistent;
+use page::{Page, PAGE_SIZE};
+use pagedfile::PagedFile;
+use pref::PRef;
+use error::{Error, FileKind};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"HAMMERSI";
+const DELTA_VERSION: u32 = 1;
+
+const DATA: u8 = 0;
+const LINK: u8 = 1;
+const TABLE: u8 = 2;
+// followed by the number of pages, so a truncated delta is detected
+const END: u8 = 0xFF;
+
+// table pages whose pre-images are logged before they are overwritten
+const RESTORE_CHUNK: usize = 1024;
+
+/// end current batch and write the pages changed since the last checkpoint with the id
+/// returns the number of pages written
+pub fn backup_incremental(mem: &mut MemTable, since: u64, writer: &mut dyn Write) -> Result<u64, Error> {
+    let changes = mem.changed_since(since)?;
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<BigEndian>(DELTA_VERSION)?;
+    for (data, table, link) in [changes.since, changes.now].iter() {
+        writer.write_u64::<BigEndian>(*data)?;
+        writer.write_u64::<BigEndian>(*table)?;
+        writer.write_u64::<BigEndian>(*link)?;
+    }
+    let mut pages = 0;
+    for (kind, file, from, to) in [(DATA, FileKind::Data, changes.since.0, changes.now.0), (LINK, FileKind::Link, changes.since.2, changes.now.2)].iter() {
+        for page in mem.page_iter_from(*file, PRef::from(*from)).take(((to - from) / PAGE_SIZE as u64) as usize) {
+            write_page(writer, *kind, &page)?;
+            pages += 1;
+        }
+    }
+    for pref in &changes.table_pages {
+        let page = mem.page_iter_from(FileKind::Table, *pref).next()
+            .ok_or_else(|| Error::Bug(format!("table page {} should exist", pref)))?;
+        write_page(writer, TABLE, &page)?;
+        pages += 1;
+    }
+    writer.write_u8(END)?;
+    writer.write_u64::<BigEndian>(pages)?;
+    Ok(pages)
+}
+
+fn write_page(writer: &mut dyn Write, kind: u8, page: &Page) -> Result<(), Error> {
+    writer.write_u8(kind)?;
+    writer.write_all(page.as_buf())?;
+    Ok(())
+}
+
+/// apply a delta written by backup_incremental onto the snapshot with the name, that should be
+/// of the checkpoint of the delta. Bloom filters and checkpoints of the snapshot are dropped.
+/// fails with Error::Corrupted if the snapshot is of an other state
+/// returns the number of pages applied
+pub fn restore(name: &str, reader: &mut dyn Read) -> Result<u64, Error> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Corrupted("not a hammersbald delta".to_string()));
+    }
+    let version = reader.read_u32::<BigEndian>()?;
+    if version != DELTA_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut lengths = [0u64; 6];
+    for len in lengths.iter_mut() {
+        *len = reader.read_u64::<BigEndian>()?;
+    }
+    let (since, now) = ((lengths[0], lengths[1], lengths[2]), (lengths[3], lengths[4], lengths[5]));
+
+    let (mut log, mut table, mut data, mut link) = Persistent::restore_files(name)?;
+    if (data.len()?, table.len()?, link.len()?) != since {
+        return Err(Error::Corrupted(format!("{} is not of the checkpoint of the delta", name)));
+    }
+    // as a batch of the snapshot, recovered to it if interrupted
+    log.init(since.0, since.1, since.2)?;
+    log.flush()?;
+    log.sync()?;
+    log.reset(since.1);
+
+    let mut pages = 0;
+    let mut table_pages = Vec::with_capacity(RESTORE_CHUNK);
+    loop {
+        let kind = reader.read_u8()?;
+        if kind == END {
+            if reader.read_u64::<BigEndian>()? != pages {
+                return Err(Error::Corrupted("number of pages of the delta does not match".to_string()));
+            }
+            break;
+        }
+        let mut buf = vec!(0u8; PAGE_SIZE);
+        reader.read_exact(&mut buf)?;
+        let mut page = Page::new();
+        page.write(0, &buf);
+        match kind {
+            DATA => data.append_page(page)?,
+            LINK => link.append_page(page)?,
+            TABLE => {
+                table_pages.push(page);
+                if table_pages.len() == RESTORE_CHUNK {
+                    restore_table(&mut log, &mut table, &mut table_pages)?;
+                }
+            },
+            k => return Err(Error::Corrupted(format!("unknown page type {} in delta", k)))
+        }
+        pages += 1;
+    }
+    restore_table(&mut log, &mut table, &mut table_pages)?;
+    data.flush()?;
+    data.sync()?;
+    link.flush()?;
+    link.sync()?;
+    if (data.len()?, table.len()?, link.len()?) != now {
+        return Err(Error::Corrupted("lengths of the files restored do not match the delta".to_string()));
+    }
+    // the batch is complete
+    log.init(now.0, now.1, now.2)?;
+    log.flush()?;
+    log.sync()?;
+    Ok(pages)
+}
+
+// log pre-images of the table pages, then overwrite them
+fn restore_table(log: &mut LogFile, table: &mut dyn PagedFile, pages: &mut Vec<Page>) -> Result<(), Error> {
+    for page in pages.iter() {
+        log.log_page(page.pref(), table)?;
+    }
+    log.flush()?;
+    log.sync()?;
+    for page in pages.drain(..) {
+        table.update_page(page)?;
+    }
+    table.flush()?;
+    table.sync()
+}
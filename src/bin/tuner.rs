@@ -0,0 +1,31 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Hammersbald tuner
+//! Synthetic workloads on a transient and a persistent db and recommended settings
+//!
+
+extern crate hammersbald;
+
+use std::{env, io, process};
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = hammersbald::run_tuner(args.as_slice(), &mut io::stdout()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
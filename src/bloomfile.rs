@@ -0,0 +1,122 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # The bloom file
+//! A 64 bit bloom filter of key hashes for each hash table bucket, so lookups of keys not stored
+//! do not read the bucket's link. The first page marks the file valid. It is invalidated
+//! before filters of a batch are written and validated after the batch is committed,
+//! an invalid file is ignored at open and rewritten with the next batch.
+//!
+
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
+use pagedfile::PagedFile;
+use error::Error;
+use pref::PRef;
+
+/// filters stored in a page
+pub const BLOOMS_PER_PAGE: usize = PAGE_PAYLOAD_SIZE / 8;
+/// filter of a bucket whose filter is not known, it does not exclude any key
+pub const UNKNOWN: u64 = !0;
+
+const VALID: u64 = 0x48424C4F4F4D0001;
+
+/// filter bits of a key hash
+pub fn bloom_bits (hash: u32) -> u64 {
+    // low bits of the hash select the bucket, mix in the high bits
+    let mixed = hash.wrapping_mul(0x9E37_79B1);
+    (1u64 << (mixed >> 26)) | (1u64 << ((mixed >> 20) & 0x3f))
+}
+
+/// filter of a bucket's slots
+pub fn bloom<'a, I> (hashes: I) -> u64 where I: Iterator<Item=&'a u32> {
+    hashes.fold(0, |bloom, hash| bloom | bloom_bits(*hash))
+}
+
+/// the bloom file
+pub struct BloomFile {
+    file: Box<dyn PagedFile>
+}
+
+impl BloomFile {
+    pub fn new (file: Box<dyn PagedFile>) -> BloomFile {
+        BloomFile { file }
+    }
+
+    /// filters of buckets, None if the file is not valid
+    /// filters of pages not written are unknown
+    pub fn load (&self, n_buckets: usize) -> Result<Option<Vec<u64>>, Error> {
+        match self.file.read_page(PRef::from(0))? {
+            Some(ref first) if first.pref() == PRef::from(0) && first.read_u64(0) == VALID => {},
+            _ => return Ok(None)
+        }
+        let mut blooms = Vec::with_capacity(n_buckets);
+        let mut page_number = 0;
+        while blooms.len() < n_buckets {
+            let pref = Self::page_pref(page_number);
+            match self.file.read_page(pref)? {
+                Some(ref page) if page.pref() == pref => {
+                    for i in 0 .. BLOOMS_PER_PAGE {
+                        blooms.push(page.read_u64(i * 8));
+                    }
+                },
+                _ => blooms.extend(vec!(UNKNOWN; BLOOMS_PER_PAGE))
+            }
+            page_number += 1;
+        }
+        blooms.truncate(n_buckets);
+        Ok(Some(blooms))
+    }
+
+    /// mark the file invalid before filters are written
+    pub fn invalidate (&mut self) -> Result<(), Error> {
+        self.write_first(0)
+    }
+
+    /// mark the file valid after the batch of written filters is committed
+    pub fn validate (&mut self) -> Result<(), Error> {
+        self.write_first(VALID)
+    }
+
+    /// write a page of filters
+    pub fn write_page (&mut self, page_number: usize, blooms: &[u64]) -> Result<(), Error> {
+        let mut page = Page::new_table_page(Self::page_pref(page_number));
+        for (i, bloom) in blooms.iter().enumerate().take(BLOOMS_PER_PAGE) {
+            page.write_u64(i * 8, *bloom);
+        }
+        self.file.update_page(page)?;
+        Ok(())
+    }
+
+    pub fn flush (&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.sync()
+    }
+
+    pub fn shutdown (&mut self) {
+        self.file.shutdown()
+    }
+
+    fn write_first (&mut self, mark: u64) -> Result<(), Error> {
+        let mut first = Page::new_table_page(PRef::from(0));
+        first.write_u64(0, mark);
+        self.file.update_page(first)?;
+        self.flush()
+    }
+
+    fn page_pref (page_number: usize) -> PRef {
+        PRef::from((page_number as u64 + 1) * PAGE_SIZE as u64)
+    }
+}
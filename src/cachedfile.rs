@@ -86,6 +86,23 @@ impl PagedFile for CachedFile {
         self.cache.lock().unwrap().clear();
         self.file.flush()
     }
+
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        self.file.prefetch(pref, pages)
+    }
+
+    fn link_chunks(&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        self.file.link_chunks(name, len, chunk_size)
+    }
+
+    fn allocate(&mut self, len: u64) -> Result<bool, Error> {
+        let mut cache = self.cache.lock().unwrap();
+        let allocated = self.file.allocate(len)?;
+        if allocated {
+            cache.reset_len(len);
+        }
+        Ok(allocated)
+    }
 }
 
 
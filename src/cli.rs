@@ -0,0 +1,284 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Command line inspection and maintenance
+//! Commands of the hammersbald binary. Inspecting commands open the db read only.
+//!
+
+use api::{HammersbaldRead, repair};
+use persistent::Persistent;
+use config::Config;
+use stats::VerifyLevel;
+
+use std::error::Error;
+use std::io::Write;
+
+const USAGE: &str = "usage: hammersbald <db> <command>
+commands:
+  stats                   print statistics of the hash table and files
+  dump [--prefix <hex>]   print live keyed data as: hex key, reference, hex data
+  get <hexkey>            print reference and hex data stored with the key
+  verify [--deep]         check hash table, links, references between data and file lengths,
+                          deep also decodes all data
+  compact <target>        copy live keyed data into the new db target, referred data,
+                          persistent references and keys pruned by generation are not kept
+  rebuild-index           rebuild hash table and link file from the data file";
+
+// a command of the hammersbald binary with its arguments decoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Stats,
+    Dump(Vec<u8>),
+    Get(Vec<u8>),
+    Verify(VerifyLevel),
+    Compact(String),
+    RebuildIndex
+}
+
+/// run a command of the hammersbald binary, args exclude the program name
+pub fn run_cli (args: &[String], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let (name, command) = parse(args)?;
+    let config = Config::default();
+    match command {
+        Command::Stats => print_stats(name, &config, out),
+        Command::Dump(prefix) => dump(name, &config, prefix.as_slice(), out),
+        Command::Get(key) => get(name, &config, key.as_slice(), out),
+        Command::Verify(level) => verify(name, &config, level, out),
+        Command::Compact(target) => compact(name, &config, target.as_str(), out),
+        Command::RebuildIndex => rebuild_index(name, &config, out)
+    }
+}
+
+// name of the db and the command of args
+fn parse (args: &[String]) -> Result<(&str, Command), Box<dyn Error>> {
+    if args.len() < 2 {
+        return Err(USAGE.into());
+    }
+    let command = match (args[1].as_str(), &args[2..]) {
+        ("stats", []) => Command::Stats,
+        ("dump", []) => Command::Dump(vec!()),
+        ("dump", [flag, prefix]) if flag == "--prefix" => Command::Dump(from_hex(prefix)?),
+        ("get", [key]) => Command::Get(from_hex(key)?),
+        ("verify", []) => Command::Verify(VerifyLevel::Structure),
+        ("verify", [flag]) if flag == "--deep" => Command::Verify(VerifyLevel::Deep),
+        ("compact", [target]) => Command::Compact(target.clone()),
+        ("rebuild-index", []) => Command::RebuildIndex,
+        _ => return Err(USAGE.into())
+    };
+    Ok((args[0].as_str(), command))
+}
+
+fn print_stats (name: &str, config: &Config, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let db = Persistent::open_read_only(name, config)?;
+    let (step, log_mod, _, table_len, data_len, link_len, _, _) = db.params();
+    writeln!(out, "file sizes: table {}, data {}, links {}", table_len, data_len, link_len)?;
+    let table = db.table_stats()?;
+    writeln!(out, "hash table: buckets {}, log_mod {}, step {}, keys {}, load factor {:.1}",
+             table.buckets, log_mod, step, table.keys, table.load_factor)?;
+    let (p50, p90, p99, longest) = table.chain_percentiles;
+    writeln!(out, "keys per bucket: p50 {}, p90 {}, p99 {}, max {}{}", p50, p90, p99, longest,
+             if table.needs_resize() { ", the table needs a resize" } else { "" })?;
+    Ok(())
+}
+
+fn dump (name: &str, config: &Config, prefix: &[u8], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let db = Persistent::open_read_only(name, config)?;
+    let mut live = db.iter_live();
+    for (pref, key, data) in &mut live {
+        if key.starts_with(prefix) {
+            writeln!(out, "{} {} {}", to_hex(key.as_slice()), pref, to_hex(data.as_slice()))?;
+        }
+    }
+    Ok(live.error()?)
+}
+
+fn get (name: &str, config: &Config, key: &[u8], out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let db = Persistent::open_read_only(name, config)?;
+    match db.get_keyed(key)? {
+        Some((pref, data)) => Ok(writeln!(out, "{} {}", pref, to_hex(data.as_slice()))?),
+        None => Err(format!("key {} not found", to_hex(key)).into())
+    }
+}
+
+fn verify (name: &str, config: &Config, level: VerifyLevel, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let report = Persistent::open_read_only(name, config)?.verify(level)?;
+    for problem in &report.problems {
+        writeln!(out, "{}", problem)?;
+    }
+    if !report.is_ok() {
+        return Err(format!("{} problems found", report.problems.len()).into());
+    }
+    Ok(writeln!(out, "ok")?)
+}
+
+fn compact (name: &str, config: &Config, target: &str, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    let db = Persistent::open_read_only(name, config)?;
+    // generations of the data are kept
+    let mut compacted = Persistent::with_config(target, &config.clone().generations(db.generation().is_some()))?;
+    if compacted.iter().next().is_some() {
+        compacted.shutdown();
+        return Err(format!("db {} is not empty", target).into());
+    }
+    let mut n = 0;
+    let mut generation = None;
+    let mut live = db.iter_live();
+    for (pref, key, data) in &mut live {
+        if let Some(of) = db.generation_of(pref)? {
+            if generation != Some(of) {
+                if generation.is_some() {
+                    compacted.batch()?;
+                }
+                compacted.set_generation(of)?;
+                generation = Some(of);
+            }
+        }
+        compacted.put_keyed(key.as_slice(), data.as_slice())?;
+        n += 1;
+    }
+    live.error()?;
+    compacted.batch()?;
+    compacted.shutdown();
+    Ok(writeln!(out, "copied {} keys", n)?)
+}
+
+fn rebuild_index (name: &str, config: &Config, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    repair(name, config)?.shutdown();
+    Ok(writeln!(out, "ok")?)
+}
+
+fn to_hex (bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex (hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(format!("odd length hex {}", hex).into());
+    }
+    // digits only, a sign is not accepted and a multi byte character is not split
+    if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("invalid hex {}", hex).into());
+    }
+    (0 .. hex.len()).step_by(2).map(|i|
+        u8::from_str_radix(&hex[i .. i + 2], 16).map_err(|_| format!("invalid hex {}", hex).into())).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use api::test::temp_db_name;
+    use persistent;
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    fn args (args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn run (args: &[&str]) -> Result<String, Box<dyn Error>> {
+        let mut out = Vec::new();
+        run_cli(self::args(args).as_slice(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn hex_test () {
+        assert_eq!(from_hex("").unwrap(), Vec::<u8>::new());
+        assert_eq!(from_hex("ab01FF").unwrap(), vec!(0xab, 0x01, 0xff));
+        assert!(from_hex("ab0").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("+1").is_err());
+        assert!(from_hex("é1").is_err());
+        assert_eq!(to_hex(&[0xab, 0x01, 0xff]), "ab01ff");
+        assert_eq!(to_hex(from_hex("00c0fe").unwrap().as_slice()), "00c0fe");
+    }
+
+    #[test]
+    fn parse_test () {
+        let parsed = |a: &[&str]| parse(args(a).as_slice()).map(|(name, command)| (name.to_string(), command)).ok();
+        assert_eq!(parsed(&["db", "stats"]), Some(("db".to_string(), Command::Stats)));
+        assert_eq!(parsed(&["db", "dump"]), Some(("db".to_string(), Command::Dump(vec!()))));
+        assert_eq!(parsed(&["db", "dump", "--prefix", "ab"]), Some(("db".to_string(), Command::Dump(vec!(0xab)))));
+        assert_eq!(parsed(&["db", "get", "ab01"]), Some(("db".to_string(), Command::Get(vec!(0xab, 0x01)))));
+        assert_eq!(parsed(&["db", "verify"]), Some(("db".to_string(), Command::Verify(VerifyLevel::Structure))));
+        assert_eq!(parsed(&["db", "verify", "--deep"]), Some(("db".to_string(), Command::Verify(VerifyLevel::Deep))));
+        assert_eq!(parsed(&["db", "compact", "new"]), Some(("db".to_string(), Command::Compact("new".to_string()))));
+        assert_eq!(parsed(&["db", "rebuild-index"]), Some(("db".to_string(), Command::RebuildIndex)));
+
+        assert_eq!(parsed(&[]), None);
+        assert_eq!(parsed(&["db"]), None);
+        assert_eq!(parsed(&["db", "unknown"]), None);
+        assert_eq!(parsed(&["db", "stats", "more"]), None);
+        assert_eq!(parsed(&["db", "dump", "--prefix"]), None);
+        assert_eq!(parsed(&["db", "dump", "--other", "ab"]), None);
+        assert_eq!(parsed(&["db", "dump", "--prefix", "abc"]), None);
+        assert_eq!(parsed(&["db", "get"]), None);
+        assert_eq!(parsed(&["db", "get", "xy"]), None);
+        assert_eq!(parsed(&["db", "verify", "--shallow"]), None);
+        assert_eq!(parsed(&["db", "compact"]), None);
+        assert_eq!(parsed(&["db", "rebuild-index", "now"]), None);
+    }
+
+    #[test]
+    fn cli_test () {
+        let name = temp_db_name("cli");
+        let mut db = persistent(&name, 10, 1).unwrap();
+        let pref = db.put_keyed(&[0xab, 0x01], b"one").unwrap();
+        db.put_keyed(&[0xcd, 0x02], b"two").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        assert_eq!(run(&[&name, "get", "ab01"]).unwrap(), format!("{} 6f6e65\n", pref));
+        assert_eq!(run(&[&name, "get", "ab02"]).unwrap_err().to_string(), "key ab02 not found");
+        assert_eq!(run(&[&name, "dump", "--prefix", "ab"]).unwrap(), format!("ab01 {} 6f6e65\n", pref));
+        assert_eq!(run(&[&name, "dump"]).unwrap().lines().count(), 2);
+        let stats = run(&[&name, "stats"]).unwrap();
+        assert_eq!(stats.lines().count(), 3);
+        assert!(stats.contains("keys 2,"), "{}", stats);
+        assert_eq!(run(&[&name, "verify"]).unwrap(), "ok\n");
+        assert_eq!(run(&[&name, "verify", "--deep"]).unwrap(), "ok\n");
+        assert_eq!(run(&[&name, "rebuild-index"]).unwrap(), "ok\n");
+
+        let target = format!("{}-compact", name);
+        assert_eq!(run(&[&name, "compact", &target]).unwrap(), "copied 2 keys\n");
+        assert_eq!(run(&[&target, "dump", "--prefix", "cd"]).unwrap().split(' ').next_back(), Some("74776f\n"));
+        assert!(run(&[&name, "compact", &target]).is_err());
+        assert!(run(&[&name, "unknown"]).is_err());
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn cli_compact_generations () {
+        let name = temp_db_name("cli-generations");
+        let config = Config::default().generations(true);
+        let mut db = Persistent::with_config(&name, &config).unwrap();
+        db.put_keyed(b"old", b"pruned").unwrap();
+        db.batch().unwrap();
+        db.set_generation(5).unwrap();
+        db.put_keyed(b"new", b"kept").unwrap();
+        db.batch().unwrap();
+        db.prune_before(5).unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+
+        let target = format!("{}-compact", name);
+        assert_eq!(run(&[&name, "compact", &target]).unwrap(), "copied 1 keys\n");
+        let compacted = Persistent::open_read_only(&target, &config).unwrap();
+        let (pref, _) = compacted.get_keyed(b"new").unwrap().unwrap();
+        assert_eq!(compacted.generation_of(pref).unwrap(), Some(5));
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Column families
+//! Named namespaces of keys within one db. Keys of a column are stored with a prefix of the
+//! marker byte 0xFF, the length of the name and the name, so they do not collide with keys of
+//! other columns. Keys stored without a column should not start with the marker.
+//! Columns share the hash table, the data file and the batches of the db.
+//!
+
+use api::HammersbaldAPI;
+use format::MAX_KEY_LEN;
+use pref::PRef;
+use error::Error;
+
+/// first byte of keys of a column
+pub const COLUMN_MARK: u8 = 0xFF;
+/// longest name of a column
+pub const MAX_COLUMN_NAME: usize = 64;
+
+/// keys and data of a column
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ColumnStats {
+    /// live keys of the column
+    pub keys: usize,
+    /// length of their data
+    pub bytes: u64
+}
+
+/// a namespace of keys of a db, see HammersbaldAPI::column
+pub struct Column<'d> {
+    db: &'d mut dyn HammersbaldAPI,
+    prefix: Vec<u8>
+}
+
+impl<'d> Column<'d> {
+    /// the column with the name of the db
+    pub fn new (db: &'d mut dyn HammersbaldAPI, name: &str) -> Result<Column<'d>, Error> {
+        if name.len() > MAX_COLUMN_NAME {
+            return Err(Error::DoesNotFit { what: "column name", max: MAX_COLUMN_NAME as u64, got: name.len() as u64 });
+        }
+        let mut prefix = vec!(COLUMN_MARK, name.len() as u8);
+        prefix.extend_from_slice(name.as_bytes());
+        Ok(Column { db, prefix })
+    }
+
+    /// name of the column
+    pub fn name (&self) -> &str {
+        ::std::str::from_utf8(&self.prefix[2 ..]).expect("name is a str")
+    }
+
+    /// longest key of the column
+    pub fn max_key_len (&self) -> usize {
+        MAX_KEY_LEN - self.prefix.len()
+    }
+
+    fn key (&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        if key.len() > self.max_key_len() {
+            return Err(Error::DoesNotFit { what: "key of the column", max: self.max_key_len() as u64, got: key.len() as u64 });
+        }
+        let mut prefixed = self.prefix.clone();
+        prefixed.extend_from_slice(key);
+        Ok(prefixed)
+    }
+
+    /// store data with a key of the column
+    pub fn put_keyed (&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        let key = self.key(key)?;
+        self.db.put_keyed(key.as_slice(), data)
+    }
+
+    /// retrieve data with a key of the column
+    /// returns Some(persistent reference, data) or None
+    pub fn get_keyed (&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.db.get_keyed(self.key(key)?.as_slice())
+    }
+
+    /// forget a key of the column
+    pub fn forget (&mut self, key: &[u8]) -> Result<(), Error> {
+        let key = self.key(key)?;
+        self.db.forget(key.as_slice())
+    }
+
+    /// a quick (in-memory) check if the column may have the key
+    pub fn may_have_key (&self, key: &[u8]) -> Result<bool, Error> {
+        self.db.may_have_key(self.key(key)?.as_slice())
+    }
+
+    /// end the current batch of the db, with changes of all columns
+    pub fn batch (&mut self) -> Result<(), Error> {
+        self.db.batch()
+    }
+
+    /// iterator of live (persistent reference, key, data) of the column, keys without the prefix
+    /// all keys of the db are read, data of the column only
+    pub fn iter (&self) -> impl Iterator<Item=Result<(PRef, Vec<u8>, Vec<u8>), Error>> + '_ {
+        let prefix = self.prefix.as_slice();
+        let db = &*self.db;
+        db.iter_keys().filter(move |(_, key)| key.starts_with(prefix))
+            .map(move |(pref, key)| Ok((pref, key[prefix.len() ..].to_vec(), db.get(pref)?.1)))
+    }
+
+    /// live keys of the column and the length of their data
+    pub fn stats (&self) -> Result<ColumnStats, Error> {
+        let mut stats = ColumnStats::default();
+        for entry in self.iter() {
+            let (_, _, data) = entry?;
+            stats.keys += 1;
+            stats.bytes += data.len() as u64;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use transient;
+
+    #[test]
+    fn test_column () {
+        let mut db = transient(1).unwrap();
+        db.put_keyed(b"key", b"default").unwrap();
+        {
+            let mut tx = db.column("tx").unwrap();
+            assert_eq!(tx.name(), "tx");
+            tx.put_keyed(b"key", b"tx").unwrap();
+            tx.put_keyed(b"other", b"tx other").unwrap();
+            assert!(tx.put_keyed(&[0u8; 252], b"long").is_err());
+        }
+        let headers_pref = db.column("headers").unwrap().put_keyed(b"key", b"header").unwrap();
+        db.batch().unwrap();
+
+        assert_eq!(db.get_keyed(b"key").unwrap().unwrap().1, b"default".to_vec());
+        let mut tx = db.column("tx").unwrap();
+        assert_eq!(tx.get_keyed(b"key").unwrap().unwrap().1, b"tx".to_vec());
+        tx.forget(b"other").unwrap();
+        assert_eq!(tx.get_keyed(b"other").unwrap(), None);
+        let all = tx.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all.iter().map(|(_, key, data)| (key.clone(), data.clone())).collect::<Vec<_>>(), vec!((b"key".to_vec(), b"tx".to_vec())));
+        assert_eq!(tx.stats().unwrap(), ColumnStats { keys: 1, bytes: 2 });
+        let headers = db.column("headers").unwrap();
+        assert_eq!(headers.get_keyed(b"key").unwrap(), Some((headers_pref, b"header".to_vec())));
+        assert!(db.column(&"x".repeat(MAX_COLUMN_NAME + 1)).is_err());
+    }
+}
@@ -0,0 +1,68 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Compression of data
+//! Data larger than a threshold is compressed with the codec compiled in.
+//! The codec is recorded in flag bits of the payload type.
+//!
+
+use error::Error;
+
+/// payload type flag of lz4 compressed data
+pub const LZ4: u8 = 0x80;
+/// payload type flag of snappy compressed data
+pub const SNAPPY: u8 = 0x40;
+/// mask of compression flags in the payload type
+pub const CODEC_MASK: u8 = LZ4 | SNAPPY;
+/// data not longer than this is stored uncompressed
+pub const COMPRESSION_THRESHOLD: usize = 256;
+
+/// compress data if it is worth it, returns the codec flag and compressed data
+pub fn compress(data: &[u8]) -> Option<(u8, Vec<u8>)> {
+    if data.len() <= COMPRESSION_THRESHOLD {
+        return None;
+    }
+    codec_compress(data).filter(|(_, compressed)| compressed.len() < data.len())
+}
+
+#[cfg(feature="lz4")]
+fn codec_compress(data: &[u8]) -> Option<(u8, Vec<u8>)> {
+    Some((LZ4, lz4_flex::compress_prepend_size(data)))
+}
+
+#[cfg(all(feature="snappy", not(feature="lz4")))]
+fn codec_compress(data: &[u8]) -> Option<(u8, Vec<u8>)> {
+    snap::raw::Encoder::new().compress_vec(data).ok().map(|compressed| (SNAPPY, compressed))
+}
+
+#[cfg(not(any(feature="lz4", feature="snappy")))]
+fn codec_compress(_: &[u8]) -> Option<(u8, Vec<u8>)> {
+    None
+}
+
+/// decompress data compressed with the codec of the flag
+#[cfg_attr(not(any(feature="lz4", feature="snappy")), allow(unused_variables))]
+pub fn decompress(codec: u8, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match codec {
+        #[cfg(feature="lz4")]
+        LZ4 => lz4_flex::decompress_size_prepended(data)
+            .map_err(|e| Error::Corrupted(format!("lz4 decompression failed: {}", e))),
+        #[cfg(feature="snappy")]
+        SNAPPY => snap::raw::Decoder::new().decompress_vec(data)
+            .map_err(|e| Error::Corrupted(format!("snappy decompression failed: {}", e))),
+        _ => Err(Error::Corrupted(format!("data compressed with codec {:x} not compiled in", codec)))
+    }
+}
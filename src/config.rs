@@ -0,0 +1,393 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Configuration of a persistent db
+//!
+
+use page::PAGE_SIZE;
+use singlefile::MAX_PAGE_SIZE;
+use metrics::Metrics;
+use pagedfile::PageStore;
+use overflow::{Overflow, Chained};
+#[cfg(feature="testing")]
+use testing::Faults;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// default chunk size of files, larger files are rolled over into a next chunk
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// How files are synced to disk at batch boundaries
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// sync content only (fdatasync)
+    Data,
+    /// sync content and metadata (fsync)
+    All
+}
+
+/// What a put does if the bytes stored since the last batch would exceed the throttle limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// fail with Error::WouldBlock, nothing is stored
+    Fail,
+    /// end the batch first, the put blocks until the batch is written
+    Block
+}
+
+/// When a batch is ended automatically, checked after each put or forget
+/// A batch is due if its time or its bytes stored reached a limit set, whichever comes first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AutoBatch {
+    /// longest time since the last batch
+    pub interval: Option<Duration>,
+    /// most bytes stored since the last batch
+    pub bytes: Option<u64>
+}
+
+impl AutoBatch {
+    /// end a batch at latest the interval after the last
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// end a batch once it stored the bytes
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// a batch running for elapsed that stored pending bytes is due
+    pub fn is_due(&self, elapsed: Duration, pending: u64) -> bool {
+        self.interval.is_some_and(|interval| elapsed >= interval) || self.bytes.is_some_and(|bytes| pending >= bytes)
+    }
+}
+
+/// Configuration of a persistent db
+#[derive(Clone)]
+pub struct Config {
+    /// read cache size in pages
+    pub cached_data_pages: usize,
+    /// average re-use of a hash table entry
+    pub bucket_fill_target: usize,
+    /// directory of the files, the name of the db is relative to this if set
+    pub directory: Option<PathBuf>,
+    /// directory of the data file, overrides directory for the data file
+    pub data_directory: Option<PathBuf>,
+    /// sync mode at batch boundaries
+    pub sync: SyncMode,
+    /// bypass the OS page cache (O_DIRECT), only effective on Linux
+    pub direct_io: bool,
+    /// maximum size of a single file, content beyond is stored in name.1.ext, name.2.ext ...
+    pub max_file_size: u64,
+    /// keep all files of a new db in the single container file name.hb
+    pub container: bool,
+    /// unit of reads and writes of new data and link files, recorded in their header
+    pub data_page_size: usize,
+    /// unit of reads and writes of new hash table, log and other files, recorded in their header
+    pub table_page_size: usize,
+    /// sync a redo record of each put to a write ahead log, so puts are durable before the next batch
+    pub write_ahead_log: bool,
+    /// threads flushing the hash table at batch, 0 for the number of CPUs
+    pub flush_threads: usize,
+    /// bytes of hash table buckets kept in memory, least recently used beyond are read again, 0 for no limit
+    pub memory_budget: usize,
+    /// size of all keys of a new db, their first bytes are used as hash, 0 for keys of any size
+    pub fixed_key_size: usize,
+    /// arrangement of the slots of hash table buckets in memory
+    pub overflow: Arc<dyn Overflow>,
+    /// store identical data of put once, the content hash table is kept in the db name.dedup
+    pub dedup: bool,
+    /// record the generation of data in the file name.gn, so it can be pruned by generation
+    pub generations: bool,
+    /// most bytes of the data, table, link and log files of the db, 0 for no limit
+    pub quota: u64,
+    /// bytes left free on the disks of the files, puts and batches that would use them fail
+    pub reserve_space: u64,
+    /// most bytes stored between batches, 0 for no limit
+    pub max_pending_bytes: u64,
+    /// what a put exceeding max_pending_bytes does
+    pub throttle: Throttle,
+    /// end batches automatically
+    pub auto_batch: Option<AutoBatch>,
+    /// key encrypting data of the data file, checked against the file name.ek at open
+    #[cfg(feature="encryption")]
+    pub encryption_key: Option<[u8; 32]>,
+    /// faults injected into page writes of the files, to test recovery
+    #[cfg(feature="testing")]
+    pub faults: Option<Faults>,
+    /// receiver of puts, gets, batches and syncs
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// opens the files instead of the local file system, directories, container, page sizes and locking do not apply
+    pub page_store: Option<Arc<dyn PageStore>>
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cached_data_pages: 100,
+            bucket_fill_target: 2,
+            directory: None,
+            data_directory: None,
+            sync: SyncMode::Data,
+            direct_io: false,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            container: false,
+            data_page_size: PAGE_SIZE,
+            table_page_size: PAGE_SIZE,
+            write_ahead_log: false,
+            flush_threads: 0,
+            memory_budget: 0,
+            fixed_key_size: 0,
+            overflow: Arc::new(Chained),
+            quota: 0,
+            reserve_space: 0,
+            dedup: false,
+            generations: false,
+            max_pending_bytes: 0,
+            throttle: Throttle::Fail,
+            auto_batch: None,
+            #[cfg(feature="encryption")]
+            encryption_key: None,
+            #[cfg(feature="testing")]
+            faults: None,
+            metrics: None,
+            page_store: None
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut debug = f.debug_struct("Config");
+        debug
+            .field("cached_data_pages", &self.cached_data_pages)
+            .field("bucket_fill_target", &self.bucket_fill_target)
+            .field("directory", &self.directory)
+            .field("data_directory", &self.data_directory)
+            .field("sync", &self.sync)
+            .field("direct_io", &self.direct_io)
+            .field("max_file_size", &self.max_file_size)
+            .field("container", &self.container)
+            .field("data_page_size", &self.data_page_size)
+            .field("table_page_size", &self.table_page_size)
+            .field("write_ahead_log", &self.write_ahead_log)
+            .field("flush_threads", &self.flush_threads)
+            .field("memory_budget", &self.memory_budget)
+            .field("fixed_key_size", &self.fixed_key_size)
+            .field("overflow", &self.overflow)
+            .field("quota", &self.quota)
+            .field("reserve_space", &self.reserve_space)
+            .field("dedup", &self.dedup)
+            .field("generations", &self.generations)
+            .field("max_pending_bytes", &self.max_pending_bytes)
+            .field("throttle", &self.throttle)
+            .field("auto_batch", &self.auto_batch);
+        // the key is not revealed
+        #[cfg(feature="encryption")]
+        debug.field("encryption_key", &self.encryption_key.is_some());
+        #[cfg(feature="testing")]
+        debug.field("faults", &self.faults.is_some());
+        debug.field("metrics", &self.metrics.is_some())
+            .field("page_store", &self.page_store.is_some()).finish()
+    }
+}
+
+impl Config {
+    /// read cache size in pages
+    pub fn cached_data_pages(mut self, pages: usize) -> Self {
+        self.cached_data_pages = pages;
+        self
+    }
+
+    /// average re-use of a hash table entry
+    pub fn bucket_fill_target(mut self, target: usize) -> Self {
+        self.bucket_fill_target = target;
+        self
+    }
+
+    /// directory of the files
+    pub fn directory<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.directory = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// directory of the data file, e.g. on a separate device than the hash table
+    pub fn data_directory<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.data_directory = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// sync mode at batch boundaries
+    pub fn sync(mut self, sync: SyncMode) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// bypass the OS page cache
+    pub fn direct_io(mut self, direct: bool) -> Self {
+        self.direct_io = direct;
+        self
+    }
+
+    /// maximum size of a single file, rounded down to a page boundary
+    pub fn max_file_size(mut self, size: u64) -> Self {
+        self.max_file_size = std::cmp::max(size - size % PAGE_SIZE as u64, PAGE_SIZE as u64);
+        self
+    }
+
+    /// keep all files of a new db in a single file, e.g. to ship it as one artifact
+    /// directories, file and page sizes do not apply to a container
+    pub fn container(mut self, container: bool) -> Self {
+        self.container = container;
+        self
+    }
+
+    /// unit of reads and writes of data and link files, a power of two from 4k to 64k,
+    /// larger pages suit sequential workloads. Existing files keep the size they were created with.
+    pub fn data_page_size(mut self, size: usize) -> Self {
+        self.data_page_size = Self::valid_page_size(size);
+        self
+    }
+
+    /// unit of reads and writes of hash table, log and other files, a power of two from 4k to 64k
+    pub fn table_page_size(mut self, size: usize) -> Self {
+        self.table_page_size = Self::valid_page_size(size);
+        self
+    }
+
+    fn valid_page_size(size: usize) -> usize {
+        size.next_power_of_two().clamp(PAGE_SIZE, MAX_PAGE_SIZE)
+    }
+
+    /// make puts durable before the next batch at the cost of a sync with each put
+    pub fn write_ahead_log(mut self, wal: bool) -> Self {
+        self.write_ahead_log = wal;
+        self
+    }
+
+    /// threads flushing the hash table at batch, 0 for the number of CPUs
+    pub fn flush_threads(mut self, threads: usize) -> Self {
+        self.flush_threads = threads;
+        self
+    }
+
+    /// limit the memory of the hash table, cold buckets are dropped and read again from the
+    /// table when needed. Buckets modified in a batch are kept until it is written.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = bytes;
+        self
+    }
+
+    /// keys of a new db are all of the size, e.g. 32 for hashes. They are stored without their length and
+    /// their first 8 bytes are used as hash of the table instead of siphash, so keys should be random.
+    /// The size is recorded at creation and applies to later opens, an open configuring an other size
+    /// fails. Puts of other key sizes fail.
+    pub fn fixed_key_size(mut self, size: usize) -> Self {
+        self.fixed_key_size = size;
+        self
+    }
+
+    /// arrange the slots of buckets with the strategy, e.g. RobinHood so lookups in long buckets
+    /// examine few slots. The table file is the same with any strategy, so it can change at each open.
+    pub fn overflow(mut self, overflow: Arc<dyn Overflow>) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// limit the bytes of the files of the db, puts and batches that would exceed it fail with Error::OutOfSpace
+    /// before they write. Secondary indexes and the content hash table of dedup are not counted.
+    pub fn quota(mut self, bytes: u64) -> Self {
+        self.quota = bytes;
+        self
+    }
+
+    /// leave the bytes free on the disks of the files, puts and batches that would use them fail with
+    /// Error::OutOfSpace before they write. The free space of the disks is checked with any reserve.
+    pub fn reserve_space(mut self, bytes: u64) -> Self {
+        self.reserve_space = bytes;
+        self
+    }
+
+    /// store identical data of put once
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// record the generation of data
+    pub fn generations(mut self, generations: bool) -> Self {
+        self.generations = generations;
+        self
+    }
+
+    /// limit the bytes stored between batches, so puts do not stall unpredictably on a saturated page cache
+    /// a put exceeding the limit fails or ends the batch first, a single put larger than the limit is stored
+    pub fn throttle(mut self, max_pending_bytes: u64, throttle: Throttle) -> Self {
+        self.max_pending_bytes = max_pending_bytes;
+        self.throttle = throttle;
+        self
+    }
+
+    /// end batches automatically after a time or an amount of bytes stored, so a forgotten batch does
+    /// not risk a large loss. Observers registered with observe are told of batches ended so.
+    pub fn auto_batch(mut self, policy: AutoBatch) -> Self {
+        self.auto_batch = Some(policy);
+        self
+    }
+
+    /// encrypt data with a 256 bit key, an encrypted db opens with its key only.
+    /// Keys, the hash table and links are not encrypted.
+    #[cfg(feature="encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// inject faults into page writes of the files
+    #[cfg(feature="testing")]
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// report puts, gets, batches and syncs to metrics
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// store the files with the page store
+    pub fn page_store(mut self, store: Arc<dyn PageStore>) -> Self {
+        self.page_store = Some(store);
+        self
+    }
+
+    /// path name of a file of the db
+    pub fn file_name(&self, name: &str, data: bool) -> String {
+        let dir = if data { self.data_directory.as_ref().or(self.directory.as_ref()) } else { self.directory.as_ref() };
+        if let Some(dir) = dir {
+            dir.join(name).to_string_lossy().to_string()
+        }
+        else {
+            name.to_string()
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Container file
+//! The files of a db multiplexed into a single file. The container is allocated in extents,
+//! the first page of an extent names the file and the position of the extent within it, so the
+//! allocation map is rebuilt by reading extent headers at open. The header page of the container
+//! records the length of every file as of its last sync, content beyond is lost at the next open
+//! as if the file was not synced.
+//!
+
+use error::Error;
+use pagedfile::PagedFile;
+use page::{PAGE_SIZE, Page};
+use pref::PRef;
+use singlefile::FORMAT_VERSION;
+use config::SyncMode;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+
+/// magic bytes at the start of a container
+pub const CONTAINER_MAGIC: [u8; 8] = *b"HAMMERSC";
+
+// pages of an extent including its header page
+const EXTENT_PAGES: u64 = 256;
+const EXTENT_SIZE: u64 = EXTENT_PAGES * PAGE_SIZE as u64;
+// content of a file in an extent
+const EXTENT_CONTENT: u64 = EXTENT_SIZE - PAGE_SIZE as u64;
+
+const VERSION_POS: usize = 8;
+const EXTENT_PAGES_POS: usize = 12;
+// file name and length of each file
+const LENGTHS_POS: usize = 32;
+const LENGTH_ENTRY: usize = 12;
+const MAX_FILES: usize = (PAGE_SIZE - LENGTHS_POS) / LENGTH_ENTRY;
+
+type Name = [u8; 4];
+
+/// a single file holding the files of a db
+pub struct Container {
+    inner: Mutex<Inner>,
+    sync: SyncMode
+}
+
+struct Inner {
+    file: File,
+    // length of each file as of its last sync
+    lens: HashMap<Name, u64>,
+    // extent of a position in a file
+    extents: HashMap<(Name, u64), u64>,
+    free: Vec<u64>,
+    n_extents: u64,
+    // sequence of allocations, the later wins if an extent was allocated twice
+    allocation: u64
+}
+
+impl Container {
+    /// open or create a container
+    pub fn open (path: &str, read_only: bool, sync: SyncMode) -> Result<Arc<Container>, Error> {
+        let mut file = if read_only {
+            OpenOptions::new().read(true).open(path)?
+        } else {
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?
+        };
+        let flen = file.seek(SeekFrom::End(0))?;
+        let mut inner = Inner { file, lens: HashMap::new(), extents: HashMap::new(), free: Vec::new(), n_extents: 0, allocation: 0 };
+        if flen == 0 && !read_only {
+            inner.write_header()?;
+        }
+        else {
+            let header = inner.read_at(0)?;
+            let buf = header.as_buf();
+            if buf[0 .. CONTAINER_MAGIC.len()] != CONTAINER_MAGIC {
+                return Err(Error::Corrupted(format!("{} is not a container", path)));
+            }
+            let version = BigEndian::read_u32(&buf[VERSION_POS .. VERSION_POS + 4]);
+            if version != FORMAT_VERSION {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            if BigEndian::read_u32(&buf[EXTENT_PAGES_POS .. EXTENT_PAGES_POS + 4]) as u64 != EXTENT_PAGES {
+                return Err(Error::Corrupted("container of a different extent size".to_string()));
+            }
+            for i in 0 .. MAX_FILES {
+                let pos = LENGTHS_POS + i * LENGTH_ENTRY;
+                let mut name = [0u8; 4];
+                name.copy_from_slice(&buf[pos .. pos + 4]);
+                if name != [0u8; 4] {
+                    inner.lens.insert(name, BigEndian::read_u64(&buf[pos + 4 .. pos + 12]));
+                }
+            }
+            inner.scan(flen)?;
+        }
+        Ok(Arc::new(Container { inner: Mutex::new(inner), sync }))
+    }
+
+    /// a file in the container, named with up to four bytes
+    pub fn file (container: &Arc<Container>, name: &str) -> Result<ContainerFile, Error> {
+        if name.is_empty() || name.len() > 4 {
+            return Err(Error::Corrupted(format!("invalid file name {} in container", name)));
+        }
+        let mut n = [0u8; 4];
+        n[.. name.len()].copy_from_slice(name.as_bytes());
+        let mut inner = container.inner.lock()?;
+        if !inner.lens.contains_key(&n) && inner.lens.len() >= MAX_FILES {
+            return Err(Error::DoesNotFit { what: "number of files in container", max: MAX_FILES as u64, got: inner.lens.len() as u64 + 1 });
+        }
+        let len = *inner.lens.entry(n).or_insert(0);
+        Ok(ContainerFile { container: container.clone(), name: n, len })
+    }
+}
+
+impl Inner {
+    // rebuild the allocation map from extent headers, extents beyond the recorded length are free
+    fn scan (&mut self, flen: u64) -> Result<(), Error> {
+        self.n_extents = flen.saturating_sub(PAGE_SIZE as u64).div_ceil(EXTENT_SIZE);
+        let mut allocated: HashMap<(Name, u64), (u64, u64)> = HashMap::new();
+        for extent in 0 .. self.n_extents {
+            let header = self.read_at(Self::extent_pos(extent))?;
+            let mut name = [0u8; 4];
+            name.copy_from_slice(&header.as_buf()[0 .. 4]);
+            let seq = header.read_u64(4);
+            let allocation = header.read_u64(12);
+            self.allocation = std::cmp::max(self.allocation, allocation + 1);
+            let used = match self.lens.get(&name) {
+                Some(len) => name != [0u8; 4] && seq * EXTENT_CONTENT < *len,
+                None => false
+            };
+            if !used {
+                self.free.push(extent);
+                continue;
+            }
+            match allocated.get(&(name, seq)) {
+                Some((_, earlier)) if *earlier > allocation => self.free.push(extent),
+                Some((other, _)) => {
+                    self.free.push(*other);
+                    allocated.insert((name, seq), (extent, allocation));
+                },
+                None => { allocated.insert((name, seq), (extent, allocation)); }
+            }
+        }
+        self.extents = allocated.into_iter().map(|(k, (extent, _))| (k, extent)).collect();
+        Ok(())
+    }
+
+    fn extent_pos (extent: u64) -> u64 {
+        PAGE_SIZE as u64 + extent * EXTENT_SIZE
+    }
+
+    // position of content in the container, None if never written
+    fn position (&self, name: Name, pos: u64) -> Option<u64> {
+        self.extents.get(&(name, pos / EXTENT_CONTENT)).map(|extent|
+            Self::extent_pos(*extent) + PAGE_SIZE as u64 + pos % EXTENT_CONTENT)
+    }
+
+    // position of content in the container, allocating an extent if needed
+    fn allocate (&mut self, name: Name, pos: u64) -> Result<u64, Error> {
+        let seq = pos / EXTENT_CONTENT;
+        if !self.extents.contains_key(&(name, seq)) {
+            let extent = match self.free.pop() {
+                Some(extent) => {
+                    // content not written again reads as zeros, as in a file of its own
+                    self.file.seek(SeekFrom::Start(Self::extent_pos(extent) + PAGE_SIZE as u64))?;
+                    self.file.write_all(vec!(0u8; EXTENT_CONTENT as usize).as_slice())?;
+                    extent
+                },
+                None => { self.n_extents += 1; self.n_extents - 1 }
+            };
+            let mut header = Page::new();
+            header.write(0, &name);
+            header.write_u64(4, seq);
+            header.write_u64(12, self.allocation);
+            self.allocation += 1;
+            self.write_at(Self::extent_pos(extent), &header)?;
+            self.extents.insert((name, seq), extent);
+        }
+        self.position(name, pos).ok_or_else(|| Error::Corrupted(format!("no extent for {} in container", pos)))
+    }
+
+    // release extents of a file beyond len
+    fn release (&mut self, name: Name, len: u64) -> Result<(), Error> {
+        let released = self.extents.keys().filter(|(n, seq)| *n == name && seq * EXTENT_CONTENT >= len).cloned().collect::<Vec<_>>();
+        for key in released {
+            if let Some(extent) = self.extents.remove(&key) {
+                self.write_at(Self::extent_pos(extent), &Page::new())?;
+                self.free.push(extent);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_header (&mut self) -> Result<(), Error> {
+        let mut header = Page::new();
+        header.write(0, &CONTAINER_MAGIC);
+        let mut n = [0u8; 4];
+        BigEndian::write_u32(&mut n, FORMAT_VERSION);
+        header.write(VERSION_POS, &n);
+        BigEndian::write_u32(&mut n, EXTENT_PAGES as u32);
+        header.write(EXTENT_PAGES_POS, &n);
+        let mut names = self.lens.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        for (i, name) in names.iter().enumerate() {
+            let pos = LENGTHS_POS + i * LENGTH_ENTRY;
+            header.write(pos, name);
+            header.write_u64(pos + 4, self.lens[name]);
+        }
+        self.write_at(0, &header)
+    }
+
+    // read a page, zeros beyond the end of the container
+    fn read_at (&mut self, pos: u64) -> Result<Page, Error> {
+        let mut page = Page::new();
+        self.file.seek(SeekFrom::Start(pos))?;
+        let buf = page.as_mut_buf();
+        let mut read = 0;
+        while read < buf.len() {
+            let n = self.file.read(&mut buf[read ..])?;
+            if n == 0 {
+                break;
+            }
+            read += n;
+        }
+        Ok(page)
+    }
+
+    fn write_at (&mut self, pos: u64, page: &Page) -> Result<(), Error> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        Ok(self.file.write_all(page.as_buf())?)
+    }
+
+    fn sync (&mut self, sync: SyncMode) -> Result<(), Error> {
+        match sync {
+            SyncMode::Data => self.file.sync_data()?,
+            SyncMode::All => self.file.sync_all()?
+        }
+        Ok(())
+    }
+}
+
+/// a file of a db in a container
+pub struct ContainerFile {
+    container: Arc<Container>,
+    name: Name,
+    len: u64
+}
+
+impl ContainerFile {
+    fn write_page (&mut self, pos: u64, page: &Page) -> Result<(), Error> {
+        let mut inner = self.container.inner.lock()?;
+        let at = inner.allocate(self.name, pos)?;
+        inner.write_at(at, page)
+    }
+}
+
+impl PagedFile for ContainerFile {
+    fn read_page (&self, pref: PRef) -> Result<Option<Page>, Error> {
+        let pos = pref.as_u64();
+        if pos < self.len {
+            let mut inner = self.container.inner.lock()?;
+            return match inner.position(self.name, pos) {
+                Some(at) => Ok(Some(inner.read_at(at)?)),
+                None => Ok(Some(Page::new()))
+            };
+        }
+        Ok(None)
+    }
+
+    fn len (&self) -> Result<u64, Error> {
+        Ok(self.len)
+    }
+
+    fn truncate (&mut self, new_len: u64) -> Result<(), Error> {
+        if !new_len.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::Bug(format!("truncate not to page boundary {}", new_len)));
+        }
+        let mut inner = self.container.inner.lock()?;
+        self.len = new_len;
+        let synced = inner.lens.get(&self.name).cloned().unwrap_or(0);
+        inner.lens.insert(self.name, std::cmp::min(synced, new_len));
+        inner.write_header()?;
+        inner.release(self.name, new_len)
+    }
+
+    fn sync (&self) -> Result<(), Error> {
+        let mut inner = self.container.inner.lock()?;
+        inner.lens.insert(self.name, self.len);
+        // content is durable before the lengths referring to it
+        inner.sync(self.container.sync)?;
+        inner.write_header()?;
+        inner.sync(self.container.sync)
+    }
+
+    fn shutdown (&mut self) {}
+
+    fn append_page (&mut self, page: Page) -> Result<(), Error> {
+        let pos = self.len;
+        self.write_page(pos, &page)?;
+        self.len += PAGE_SIZE as u64;
+        Ok(())
+    }
+
+    fn update_page (&mut self, page: Page) -> Result<u64, Error> {
+        let pos = page.pref().as_u64();
+        self.write_page(pos, &page)?;
+        self.len = std::cmp::max(self.len, pos + PAGE_SIZE as u64);
+        Ok(self.len)
+    }
+
+    fn flush (&mut self) -> Result<(), Error> {
+        Ok(self.container.inner.lock()?.file.flush()?)
+    }
+}
@@ -0,0 +1,95 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Stable content ids
+//! A content id is a random 128 bit number data is stored with as key, after the marker byte 0xFE.
+//! The hash table maps it to the current persistent reference of the data, so it stays valid when
+//! data is moved by compact, ingest or import, that copy keyed data with its key.
+//!
+
+use api::HammersbaldRead;
+use error::Error;
+
+use rand::{thread_rng, RngCore};
+
+/// first byte of keys of content ids
+pub const CONTENT_MARK: u8 = 0xFE;
+
+/// id of data stored with put_content_stable, valid as long as the data is not forgotten
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ContentId([u8; 16]);
+
+impl ContentId {
+    /// a random id not yet used in the db
+    pub fn unused (db: &dyn HammersbaldRead) -> Result<ContentId, Error> {
+        let mut rng = thread_rng();
+        loop {
+            let mut id = [0u8; 16];
+            rng.fill_bytes(&mut id);
+            let id = ContentId(id);
+            let key = id.key();
+            // only keys are read to find an id in use
+            if !db.may_have_key(&key)? || !db.get_keys_only(&key)?.iter().any(|(_, k)| *k == key) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// key the data of the id is stored with
+    pub fn key (&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(17);
+        key.push(CONTENT_MARK);
+        key.extend_from_slice(&self.0);
+        key
+    }
+
+    /// serialized id, e.g. to store it outside of the db
+    pub fn to_bytes (&self) -> [u8; 16] {
+        self.0
+    }
+
+    /// the id serialized with to_bytes
+    pub fn from_bytes (bytes: [u8; 16]) -> ContentId {
+        ContentId(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::Conflict;
+    use transient;
+
+    #[test]
+    fn test_content_id () {
+        let mut db = transient(1).unwrap();
+        let (pref, id) = db.put_content_stable(b"content").unwrap();
+        db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.resolve(id).unwrap(), Some((pref, b"content".to_vec())));
+        assert_eq!(db.resolve(ContentId::from_bytes([0u8; 16])).unwrap(), None);
+
+        // the id resolves to the data moved into another db
+        let mut other = transient(1).unwrap();
+        other.put(b"moves the data of ingest").unwrap();
+        other.ingest(db.as_ref(), Conflict::Error).unwrap();
+        let (moved, data) = other.resolve(ContentId::from_bytes(id.to_bytes())).unwrap().unwrap();
+        assert_ne!(moved, pref);
+        assert_eq!(data, b"content".to_vec());
+        db.forget(&id.key()).unwrap();
+        assert_eq!(db.resolve(id).unwrap(), None);
+    }
+}
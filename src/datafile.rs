@@ -19,16 +19,34 @@
 //!
 
 use page::PAGE_SIZE;
-use pagedfile::{PagedFile, PagedFileAppender};
-use format::{Envelope, Payload, Data, IndexedData, Link};
-use error::Error;
+use pagedfile::{PagedFile, PagedFileAppender, PagedFileIterator};
+use format::{Envelope, Payload, Data, IndexedData, LinkedData, Extension, EXTENDED};
+use error::{Error, FileKind};
+use compression;
+use encryption::{Cipher, ENCRYPTED};
 use pref::PRef;
 
 use byteorder::{ByteOrder, BigEndian};
 
+use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::io;
+use std::io::Read;
+
+// longest data stored in a single envelope, longer data is continued in extensions
+const MAX_CHUNK: usize = 1 << 22;
+/// pages read ahead by full scans such as export and copies of a db, 1 MiB
+pub const SCAN_PREFETCH: usize = 256;
+
+/// number of envelopes of a file decoded and the position and error of the first that failed
+pub type EnvelopeCheck = (usize, Option<(PRef, Error)>);
+
 /// file storing indexed and referred data
 pub struct DataFile {
-    appender: PagedFileAppender
+    appender: PagedFileAppender,
+    cipher: Option<Cipher>,
+    // keys are appended without their length
+    fixed_keys: bool
 }
 
 impl DataFile {
@@ -39,17 +57,48 @@ impl DataFile {
             return Err(Error::Corrupted("data file does not end at page boundary".to_string()));
         }
         if len >= PAGE_SIZE as u64 {
-            return Ok(DataFile{appender: PagedFileAppender::new(file, PRef::from(len))});
+            Ok(DataFile{appender: PagedFileAppender::new(file, PRef::from(len)), cipher: None, fixed_keys: false})
         }
         else {
             let appender = PagedFileAppender::new(file, PRef::from(0));
-            return Ok(DataFile{appender})
+            Ok(DataFile{appender, cipher: None, fixed_keys: false})
         }
     }
 
+    /// encrypt data appended thereafter and decrypt encrypted data read
+    pub fn with_cipher(mut self, cipher: Cipher) -> DataFile {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// append keys without their length, for a db of fixed size keys
+    pub fn set_fixed_keys(&mut self, fixed: bool) {
+        self.fixed_keys = fixed;
+    }
+
+    // indexed data, with the key following the data if keys are of fixed size
+    fn indexed<'e> (fixed: bool, key: &'e [u8], data: Data<'e>) -> IndexedData<'e> {
+        if fixed { IndexedData::new_fixed(key, data) } else { IndexedData::new(key, data) }
+    }
+
+    /// the cipher of encrypted data
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.cipher.as_ref()
+    }
+
     /// return an iterator of all payloads
     pub fn envelopes<'a>(&'a self) -> EnvelopeIterator<'a> {
-        EnvelopeIterator::new(&self.appender)
+        EnvelopeIterator {file: &self.appender, cipher: self.cipher.as_ref(), pos: PRef::from(0), ahead: 0, prefetched: PRef::from(0)}
+    }
+
+    /// return an iterator of payloads starting with the one at pos
+    pub fn envelopes_from(&self, pos: PRef) -> EnvelopeIterator<'_> {
+        EnvelopeIterator {file: &self.appender, cipher: self.cipher.as_ref(), pos, ahead: 0, prefetched: pos}
+    }
+
+    /// return an iterator of pages starting with the one at pos
+    pub fn page_iter_from(&self, pos: PRef) -> PagedFileIterator<'_> {
+        PagedFileIterator::new(&self.appender, pos)
     }
 
     /// shutdown
@@ -58,53 +107,275 @@ impl DataFile {
     }
 
     /// get a stored content at pref
-    pub fn get_envelope(&self, mut pref: PRef) -> Result<Envelope, Error> {
-        let mut len = [0u8;3];
-        pref = self.appender.read(pref, &mut len, 3)?;
-        let blen = BigEndian::read_u24(&len) as usize;
-        if blen >= PAGE_SIZE {
-            let mut buf = vec!(0u8; blen);
-            self.appender.read(pref, &mut buf, blen)?;
-            Ok(Envelope::deseralize(buf))
+    pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, Error> {
+        expand(&self.appender, self.cipher.as_ref(), read_envelope(&self.appender, pref)?.0)
+    }
+
+    /// decode every envelope of the file with its extensions and compressed data
+    /// returns the number of envelopes decoded and where and why decoding stopped before the end
+    pub fn check_envelopes (&self) -> EnvelopeCheck {
+        let end = self.appender.position();
+        let mut pos = PRef::from(0);
+        let mut n = 0;
+        while pos < end {
+            match self.check_envelope(pos) {
+                Ok(next) => pos = next,
+                Err(e) => return (n, Some((pos, e)))
+            }
+            n += 1;
         }
-        else {
-            let mut buf = [0u8;PAGE_SIZE];
-            self.appender.read(pref, &mut buf, blen)?;
-            Ok(Envelope::deseralize(buf[0..blen].to_vec()))
+        (n, None)
+    }
+
+    fn check_envelope (&self, pos: PRef) -> Result<PRef, Error> {
+        let (envelope, next) = read_envelope(&self.appender, pos)?;
+        if envelope.payload().is_empty() {
+            return Err(Error::Corrupted("empty envelope".to_string()));
+        }
+        // the payload type of compressed or extended data is flagged
+        if let Ok(Payload::Extension(_)) = Payload::deserialize(envelope.payload()) {
+            return Ok(next);
+        }
+        // the key is read without the data by lookups of the key
+        if let Ok(Payload::Indexed(indexed)) = Payload::deserialize(envelope.payload()) {
+            if indexed.key.is_empty() || self.get_key(pos)? != indexed.key || self.get_head(pos)?.2 != indexed.key.len() {
+                return Err(Error::Corrupted("key of indexed data can not be read".to_string()));
+            }
         }
+        let envelope = expand(&self.appender, self.cipher.as_ref(), envelope)?;
+        Payload::deserialize(envelope.payload())?;
+        Ok(next)
     }
 
-    /// append link
-    pub fn append_link (&mut self, link: Link) -> Result<PRef, Error> {
-        let mut payload = vec!();
-        Payload::Link(link).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
-        let mut store = vec!();
-        envelope.serialize(&mut store);
+    /// check that an envelope starts at pos or pos is the end of the file, so iteration can resume there
+    pub fn check_boundary (&self, pos: PRef) -> Result<(), Error> {
+        let end = self.appender.position();
+        if pos == end {
+            return Ok(());
+        }
+        if !pos.is_valid() || pos > end {
+            return Err(Error::InvalidOffset);
+        }
+        // a position within data might decode by chance, but hardly also the envelope thereafter
+        let next = self.well_formed(pos, end)?;
+        if next < end {
+            self.well_formed(next, end)?;
+        }
+        Ok(())
+    }
+
+    // end of a well formed envelope at pos
+    fn well_formed (&self, pos: PRef, end: PRef) -> Result<PRef, Error> {
+        match read_envelope(&self.appender, pos) {
+            Ok((envelope, next)) if next <= end && Payload::is_well_formed(envelope.payload()) => Ok(next),
+            _ => Err(Error::InvalidOffset)
+        }
+    }
+
+    /// length of the envelope at pref, its payload type with flags and the key length of indexed or
+    /// the number of links of linked data, without reading the data
+    pub fn get_head(&self, pref: PRef) -> Result<(usize, u8, usize), Error> {
+        if !pref.is_valid() || pref >= self.appender.position() {
+            return Err(Error::InvalidOffset);
+        }
+        // length, payload type and key length or number of links
+        let mut head = [0u8; 7];
+        self.appender.read(pref, &mut head, 7)?;
+        let len = BigEndian::read_u24(&head[0 .. 3]) as usize;
+        let count = match head[3] & !(compression::CODEC_MASK | ENCRYPTED | EXTENDED) {
+            0 => head[4] as usize,
+            3 => BigEndian::read_u24(&head[4 .. 7]) as usize,
+            5 => Self::fixed_key_at(pref, &head)?.1,
+            _ => 0
+        };
+        Ok((len, head[3], count))
+    }
+
+    // offset and length of the key of indexed data of a fixed size key, given the head of its envelope
+    // the key follows the data, the length of the data stored in one envelope fits three bytes
+    fn fixed_key_at(pref: PRef, head: &[u8; 7]) -> Result<(usize, usize), Error> {
+        let len = BigEndian::read_u24(&head[0 .. 3]) as usize;
+        let data_len = BigEndian::read_u24(&head[4 .. 7]) as usize;
+        if len < 4 + data_len {
+            return Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "data exceeds its envelope".to_string() });
+        }
+        Ok((7 + data_len, len - 4 - data_len))
+    }
+
+    /// get the key of indexed data at pref without reading the data
+    pub fn get_key(&self, pref: PRef) -> Result<Vec<u8>, Error> {
+        // length, payload type and key length or data length
+        let mut head = [0u8; 7];
+        self.appender.read(pref, &mut head, 7)?;
+        let (pos, key_len) = match head[3] & !(compression::CODEC_MASK | ENCRYPTED | EXTENDED) {
+            0 => (pref + 5, head[4] as usize),
+            5 => {
+                let (offset, key_len) = Self::fixed_key_at(pref, &head)?;
+                (pref + offset as u64, key_len)
+            },
+            _ => return Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "pref should point to indexed data".to_string() })
+        };
+        let mut key = vec!(0u8; key_len);
+        self.appender.read(pos, &mut key, key_len)?;
+        Ok(key)
+    }
+
+    /// append serialized envelopes, returns the position of the first
+    pub fn append_serialized (&mut self, store: &[u8]) -> Result<PRef, Error> {
         let me = self.appender.position();
-        self.appender.append(store.as_slice())?;
+        self.appender.append(store)?;
         Ok(me)
     }
 
     /// append indexed data
     pub fn append_data (&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
-        let indexed = IndexedData::new(key, Data::new(data));
-        let mut payload = vec!();
-        Payload::Indexed(indexed).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
+        let fixed = self.fixed_keys;
+        self.append_payload(data, |data, payload| Payload::Indexed(Self::indexed(fixed, key, data)).serialize(payload))
+    }
+
+    /// append indexed data of several keys with one write, returns their positions
+    pub fn append_data_batch (&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        let mut start = self.appender.position();
         let mut store = vec!();
-        envelope.serialize(&mut store);
-        let me = self.appender.position();
+        let mut prefs = Vec::with_capacity(entries.len());
+        for (key, data) in entries {
+            let (flags, stored) = self.stored_form(data)?;
+            let fixed = self.fixed_keys;
+            let serialize = |data: Data, payload: &mut Vec<u8>| Payload::Indexed(Self::indexed(fixed, key, data)).serialize(payload);
+            if stored.len() > MAX_CHUNK {
+                // extensions are written right away, so envelopes collected so far go first
+                self.appender.append(store.as_slice())?;
+                store.clear();
+                let payload = self.serialize_stored(flags, &stored, serialize)?;
+                prefs.push(self.append_envelope(payload.as_slice())?);
+                start = self.appender.position();
+            }
+            else {
+                let pos = start + store.len() as u64;
+                let payload = self.serialize_stored(flags, &stored, serialize)?;
+                Envelope::new(payload.as_slice()).serialize(&mut store);
+                prefs.push(pos);
+            }
+        }
         self.appender.append(store.as_slice())?;
-        Ok(me)
+        Ok(prefs)
     }
 
     /// append referred data
     pub fn append_referred (&mut self, data: &[u8]) -> Result<PRef, Error> {
-        let data = Data::new(data);
+        self.append_payload(data, |data, payload| Payload::Referred(data).serialize(payload))
+    }
+
+    /// append data with references to other data
+    pub fn append_linked (&mut self, data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        let links = LinkedData::from_prefs(links);
+        self.append_payload(data, |data, payload| Payload::Linked(LinkedData::new(links.as_slice(), data)).serialize(payload))
+    }
+
+    /// append referred data of len bytes read from reader, long data is written chunk by chunk
+    /// and not compressed, unless encrypted
+    pub fn append_stream (&mut self, reader: &mut dyn Read, len: u64) -> Result<PRef, Error> {
+        if len <= MAX_CHUNK as u64 || self.cipher.is_some() {
+            let mut data = vec!(0u8; len as usize);
+            reader.read_exact(&mut data)?;
+            return self.append_referred(data.as_slice());
+        }
+        // extensions are appended first to last, each refers to the envelope following it
+        let first = self.appender.position();
+        let mut chunk = vec!(0u8; MAX_CHUNK);
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = min(remaining, MAX_CHUNK as u64) as usize;
+            reader.read_exact(&mut chunk[..n])?;
+            remaining -= n as u64;
+            let mut extension = vec!();
+            Payload::Extension(Extension::new(PRef::invalid(), Data::new(&chunk[..n]))).serialize(&mut extension);
+            if remaining > 0 {
+                // three bytes of envelope length precede the extension
+                let next = self.appender.position() + (3 + extension.len()) as u64;
+                BigEndian::write_u48(&mut extension[1 .. 7], next.as_u64());
+            }
+            self.append_envelope(extension.as_slice())?;
+        }
+        let mut head = [0u8; 6];
+        BigEndian::write_u48(&mut head, first.as_u64());
+        let mut payload = vec!();
+        Payload::Referred(Data::new(&head)).serialize(&mut payload);
+        payload[0] |= EXTENDED;
+        self.append_envelope(payload.as_slice())
+    }
+
+    /// read data at pref, extended data is read chunk by chunk unless compressed or encrypted
+    pub fn get_stream (&self, pref: PRef) -> Result<DataStream<'_>, Error> {
+        let (envelope, _) = read_envelope(&self.appender, pref)?;
+        let flags = envelope.payload()[0];
+        if flags & EXTENDED == 0 || flags & (compression::CODEC_MASK | ENCRYPTED) != 0 {
+            let envelope = expand(&self.appender, self.cipher.as_ref(), envelope)?;
+            let buffer = payload_data(&envelope)?.to_vec();
+            return Ok(DataStream { file: &self.appender, buffer, pos: 0, next: PRef::invalid() });
+        }
+        let mut plain = envelope.payload().to_vec();
+        plain[0] &= !EXTENDED;
+        let head = payload_data(&Envelope::new(plain.as_slice()))?.to_vec();
+        let next = PRef::from(BigEndian::read_u48(&head[0 .. 6]));
+        Ok(DataStream { file: &self.appender, buffer: head[6 ..].to_vec(), pos: 0, next })
+    }
+
+    // append a payload of data serialized with the given function, compressed if worth it,
+    // encrypted if a cipher is set and continued in extensions if too long
+    fn append_payload<F> (&mut self, data: &[u8], serialize: F) -> Result<PRef, Error>
+        where F: Fn(Data, &mut Vec<u8>) {
+        let payload = self.serialize_payload(data, serialize)?;
+        self.append_envelope(payload.as_slice())
+    }
+
+    // serialize a payload of data, extensions of too long data are appended
+    fn serialize_payload<F> (&mut self, data: &[u8], serialize: F) -> Result<Vec<u8>, Error>
+        where F: Fn(Data, &mut Vec<u8>) {
+        let (flags, stored) = self.stored_form(data)?;
+        self.serialize_stored(flags, &stored, serialize)
+    }
+
+    // data as stored, compressed if worth it and encrypted if a cipher is set, with the flags of its payload
+    fn stored_form<'d> (&self, data: &'d [u8]) -> Result<(u8, Cow<'d, [u8]>), Error> {
+        let (mut flags, mut stored) = match compression::compress(data) {
+            Some((codec, compressed)) => (codec, Cow::Owned(compressed)),
+            None => (0, Cow::Borrowed(data))
+        };
+        if let Some(ref cipher) = self.cipher {
+            stored = Cow::Owned(cipher.encrypt(&stored)?);
+            flags |= ENCRYPTED;
+        }
+        Ok((flags, stored))
+    }
+
+    // serialize a payload of data as stored, extensions of data longer than a chunk are appended
+    fn serialize_stored<F> (&mut self, flags: u8, stored: &[u8], serialize: F) -> Result<Vec<u8>, Error>
+        where F: Fn(Data, &mut Vec<u8>) {
         let mut payload = vec!();
-        Payload::Referred(data).serialize(&mut payload);
-        let envelope = Envelope::new(payload.as_slice());
+        if stored.len() > MAX_CHUNK {
+            // extensions are appended last first, so each can refer to the next
+            let mut next = PRef::invalid();
+            for chunk in stored[MAX_CHUNK..].chunks(MAX_CHUNK).rev() {
+                let mut extension = vec!();
+                Payload::Extension(Extension::new(next, Data::new(chunk))).serialize(&mut extension);
+                next = self.append_envelope(extension.as_slice())?;
+            }
+            let mut head = vec!(0u8; 6);
+            BigEndian::write_u48(&mut head, next.as_u64());
+            head.extend_from_slice(&stored[..MAX_CHUNK]);
+            serialize(Data::new(head.as_slice()), &mut payload);
+            payload[0] |= EXTENDED;
+        }
+        else {
+            serialize(Data::new(stored), &mut payload);
+        }
+        payload[0] |= flags;
+        Ok(payload)
+    }
+
+    fn append_envelope (&mut self, payload: &[u8]) -> Result<PRef, Error> {
+        let envelope = Envelope::new(payload);
         let mut store = vec!();
         envelope.serialize(&mut store);
         let me = self.appender.position();
@@ -112,6 +383,25 @@ impl DataFile {
         Ok(me)
     }
 
+    /// append padding up to pos, in place of data lost in a crash before a batch, so data is appended at pos
+    pub fn pad_to (&mut self, pos: PRef) -> Result<(), Error> {
+        while self.appender.position() < pos {
+            let gap = (pos.as_u64() - self.appender.position().as_u64()) as usize;
+            // envelopes of padding are at least 7 bytes
+            if gap < 7 {
+                return Err(Error::CorruptedAt { file: FileKind::Data, pos: pos.as_u64(), reason: "data can not be padded to".to_string() });
+            }
+            let mut len = min(gap, MAX_CHUNK);
+            if gap - len > 0 && gap - len < 7 {
+                len -= 7;
+            }
+            let mut payload = vec!();
+            Payload::Padding(Data::new(vec!(0u8; len - 7).as_slice())).serialize(&mut payload);
+            self.append_envelope(payload.as_slice())?;
+        }
+        Ok(())
+    }
+
     /// truncate file
     pub fn truncate(&mut self, pref: u64) -> Result<(), Error> {
         self.appender.truncate (pref)
@@ -121,13 +411,15 @@ impl DataFile {
     pub fn flush (&mut self) -> Result<(), Error> {
         let pos = self.appender.position();
         if pos.in_page_pos() > 0 {
-            if PAGE_SIZE - pos.in_page_pos() >= 7 {
-                let padding = vec!(0u8; PAGE_SIZE - pos.in_page_pos() - 7);
-                self.append_referred(padding.as_slice())?;
+            // padding is not compressed, so it fills the page
+            let padding = if PAGE_SIZE - pos.in_page_pos() >= 7 {
+                vec!(0u8; PAGE_SIZE - pos.in_page_pos() - 7)
             } else {
-                let padding = vec!(0u8; 2 * PAGE_SIZE - pos.in_page_pos() - 7);
-                self.append_referred(padding.as_slice())?;
-            }
+                vec!(0u8; 2 * PAGE_SIZE - pos.in_page_pos() - 7)
+            };
+            let mut payload = vec!();
+            Payload::Padding(Data::new(padding.as_slice())).serialize(&mut payload);
+            self.append_envelope(payload.as_slice())?;
         }
         self.appender.flush()
     }
@@ -141,36 +433,197 @@ impl DataFile {
     pub fn len (&self) -> Result<u64, Error> {
         self.appender.len()
     }
+
+    /// hard link whole chunks before len into the rolled file with the name, returns the length linked
+    pub fn link_chunks (&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        self.appender.link_chunks(name, len, chunk_size)
+    }
+
+    /// position of the next append
+    pub fn position (&self) -> PRef {
+        self.appender.position()
+    }
 }
 
-/// Iterate data file content
+// read the envelope at pref, returns it with the position following
+fn read_envelope (file: &PagedFileAppender, pref: PRef) -> Result<(Envelope, PRef), Error> {
+    let mut len = [0u8;3];
+    let pos = file.read(pref, &mut len, 3)?;
+    let blen = BigEndian::read_u24(&len) as usize;
+    if blen >= PAGE_SIZE {
+        let mut buf = vec!(0u8; blen);
+        let next = file.read(pos, &mut buf, blen)?;
+        Ok((Envelope::deseralize(buf), next))
+    }
+    else {
+        let mut buf = [0u8;PAGE_SIZE];
+        let next = file.read(pos, &mut buf, blen)?;
+        Ok((Envelope::deseralize(buf[0..blen].to_vec()), next))
+    }
+}
+
+/// replace extended data in an envelope with its reassembled form, encrypted data with its
+/// decrypted and compressed data with its decompressed form
+fn expand (file: &PagedFileAppender, cipher: Option<&Cipher>, envelope: Envelope) -> Result<Envelope, Error> {
+    let codec = envelope.payload()[0] & compression::CODEC_MASK;
+    let extended = envelope.payload()[0] & EXTENDED != 0;
+    let encrypted = envelope.payload()[0] & ENCRYPTED != 0;
+    if codec == 0 && !extended && !encrypted {
+        return Ok(envelope);
+    }
+    let mut plain = envelope.payload().to_vec();
+    plain[0] &= !(compression::CODEC_MASK | ENCRYPTED | EXTENDED);
+    let restore = |data: &[u8]| -> Result<Vec<u8>, Error> {
+        let mut data = data;
+        let mut assembled;
+        if extended {
+            let mut next = PRef::from(BigEndian::read_u48(&data[0 .. 6]));
+            assembled = data[6 ..].to_vec();
+            while next.is_valid() {
+                let (extension, _) = read_envelope(file, next)?;
+                match Payload::deserialize(extension.payload())? {
+                    Payload::Extension(extension) => {
+                        assembled.extend_from_slice(extension.data.data);
+                        next = extension.next;
+                    },
+                    _ => return Err(Error::Corrupted("extension should follow extended data".to_string()))
+                }
+            }
+            data = assembled.as_slice();
+        }
+        let decrypted;
+        if encrypted {
+            decrypted = cipher.ok_or(Error::WrongKey)?.decrypt(data)?;
+            data = decrypted.as_slice();
+        }
+        if codec != 0 {
+            return compression::decompress(codec, data);
+        }
+        Ok(data.to_vec())
+    };
+    let mut payload = vec!();
+    match Payload::deserialize(plain.as_slice())? {
+        Payload::Indexed(indexed) => {
+            let data = restore(indexed.data.data)?;
+            Payload::Indexed(IndexedData { data: Data::new(data.as_slice()), ..indexed }).serialize(&mut payload);
+        },
+        Payload::Referred(referred) => {
+            let data = restore(referred.data)?;
+            Payload::Referred(Data::new(data.as_slice())).serialize(&mut payload);
+        },
+        Payload::Linked(linked) => {
+            let data = restore(linked.data.data)?;
+            Payload::Linked(LinkedData::new(linked.serialized_links(), Data::new(data.as_slice()))).serialize(&mut payload);
+        },
+        Payload::Link(_) | Payload::Extension(_) | Payload::Padding(_) => return Err(Error::Corrupted("links, extensions and padding are not compressed, encrypted or extended".to_string()))
+    }
+    Ok(Envelope::new(payload.as_slice()))
+}
+
+// data of indexed, referred or linked payload
+fn payload_data (envelope: &Envelope) -> Result<&[u8], Error> {
+    match Payload::deserialize(envelope.payload())? {
+        Payload::Indexed(indexed) => Ok(indexed.data.data),
+        Payload::Referred(referred) => Ok(referred.data),
+        Payload::Linked(linked) => Ok(linked.data.data),
+        _ => Err(Error::Corrupted("referred should point to data".to_string()))
+    }
+}
+
+/// Read data chunk by chunk
+pub struct DataStream<'f> {
+    file: &'f PagedFileAppender,
+    buffer: Vec<u8>,
+    pos: usize,
+    next: PRef
+}
+
+impl<'f> Read for DataStream<'f> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() && self.next.is_valid() {
+            let (envelope, _) = read_envelope(self.file, self.next)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            match Payload::deserialize(envelope.payload()) {
+                Ok(Payload::Extension(extension)) => {
+                    self.buffer = extension.data.data.to_vec();
+                    self.next = extension.next;
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "extension should follow extended data"))
+            }
+            self.pos = 0;
+        }
+        let n = min(buf.len(), self.buffer.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buffer[self.pos .. self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Iterate data file content, ends with an error if an envelope can not be read or decoded
 pub struct EnvelopeIterator<'f> {
     file: &'f PagedFileAppender,
-    pos: PRef
+    cipher: Option<&'f Cipher>,
+    pos: PRef,
+    // pages to read ahead of the position and the end of those read ahead so far
+    ahead: usize,
+    prefetched: PRef
 }
 
 impl<'f> EnvelopeIterator<'f> {
-    /// create a new iterator
-    pub fn new (file: &'f PagedFileAppender) -> EnvelopeIterator<'f> {
-        EnvelopeIterator {file, pos: PRef::from(0)}
+    /// position of the envelope read next
+    pub fn position (&self) -> PRef {
+        self.pos
+    }
+
+    /// read the pages ahead of the position in the background
+    pub fn prefetch (mut self, pages: usize) -> EnvelopeIterator<'f> {
+        self.ahead = pages;
+        self
+    }
+
+    // iteration ends with the error
+    fn failed (&mut self, e: Error) -> Error {
+        self.pos = PRef::invalid();
+        e
+    }
+
+    // the next pages are read ahead once half of those read ahead before are passed
+    fn read_ahead (&mut self) {
+        let window = (self.ahead * PAGE_SIZE) as u64;
+        if self.pos.as_u64() + window / 2 >= self.prefetched.as_u64() {
+            let from = max(self.prefetched, self.pos.this_page());
+            self.file.prefetch(from, self.ahead);
+            self.prefetched = from + window;
+        }
     }
 }
 
 impl<'f> Iterator for EnvelopeIterator<'f> {
-    type Item = (PRef, Envelope);
+    type Item = Result<(PRef, Envelope), Error>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         if self.pos.is_valid() {
-            let start = self.pos;
-            let mut len = [0u8;3];
-            if let Ok(pos) = self.file.read(start, &mut len, 3) {
-                let length = BigEndian::read_u24(&len) as usize;
-                if length > 0 {
-                    let mut buf = vec!(0u8; length);
-                    self.pos = self.file.read(pos, &mut buf, length).unwrap();
-                    let envelope = Envelope::deseralize(buf);
-                    return Some((start, envelope))
+            if self.ahead > 0 {
+                self.read_ahead();
+            }
+            loop {
+                let start = self.pos;
+                let (envelope, next) = match read_envelope(self.file, start) {
+                    Ok(read) => read,
+                    // the end of the file
+                    Err(_) if start >= self.file.position() => break,
+                    Err(e) => return Some(Err(self.failed(e)))
+                };
+                if envelope.payload().is_empty() {
+                    break;
+                }
+                self.pos = next;
+                // extensions are returned with the data they extend
+                if let Ok(Payload::Extension(_)) = Payload::deserialize(envelope.payload()) {
+                    continue;
                 }
+                // e.g. a wrong key, a codec not compiled in or corrupted data
+                return Some(expand(self.file, self.cipher, envelope).map(|envelope| (start, envelope)).map_err(|e| self.failed(e)));
             }
         }
         None
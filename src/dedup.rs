@@ -0,0 +1,93 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Deduplication of referred data
+//! A db of its own maps the hash of referred data to its persistent reference, so identical data
+//! is stored once. Like a secondary index it remembers the position of the data file it is built
+//! until and catches up with data stored while deduplication was off.
+//!
+
+use api::HammersbaldAPI;
+use format::{Envelope, Payload};
+use pref::PRef;
+use error::Error;
+
+use bitcoin_hashes::{sha256, Hash};
+use byteorder::{ByteOrder, BigEndian};
+
+// the empty key is reserved for the position the table is built until
+const BUILT_UNTIL: &[u8] = b"";
+
+/// content hash table of referred data
+pub struct Dedup {
+    db: Box<dyn HammersbaldAPI>
+}
+
+impl Dedup {
+    /// wrap a content hash db
+    pub fn new (db: Box<dyn HammersbaldAPI>) -> Dedup {
+        Dedup { db }
+    }
+
+    /// position of the data file the table is built until
+    pub fn built_until (&self) -> Result<PRef, Error> {
+        if let Some((_, pos)) = self.db.get_keyed(BUILT_UNTIL)? {
+            return Ok(PRef::from(BigEndian::read_u48(pos.as_slice())));
+        }
+        Ok(PRef::from(0))
+    }
+
+    /// add referred data in envelopes of the data file
+    pub fn catch_up<I> (&mut self, envelopes: I) -> Result<(), Error>
+        where I: Iterator<Item=Result<(PRef, Envelope), Error>> {
+        for envelope in envelopes {
+            let (pref, envelope) = envelope?;
+            if let Payload::Referred(referred) = Payload::deserialize(envelope.payload())? {
+                self.add(pref, referred.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// remember the reference of referred data
+    pub fn add (&mut self, pref: PRef, data: &[u8]) -> Result<(), Error> {
+        let mut target = [0u8; 6];
+        BigEndian::write_u48(&mut target, pref.as_u64());
+        self.db.put_keyed(&sha256::Hash::hash(data)[..], &target)?;
+        Ok(())
+    }
+
+    /// reference of data with the same hash, the caller has to check the content
+    pub fn get (&self, data: &[u8]) -> Result<Option<PRef>, Error> {
+        if let Some((_, target)) = self.db.get_keyed(&sha256::Hash::hash(data)[..])? {
+            return Ok(Some(PRef::from(BigEndian::read_u48(target.as_slice()))));
+        }
+        Ok(None)
+    }
+
+    /// end batch of the table, data until the given position is added
+    pub fn batch (&mut self, until: PRef) -> Result<(), Error> {
+        let mut pos = [0u8; 6];
+        BigEndian::write_u48(&mut pos, until.as_u64());
+        self.db.put_keyed(BUILT_UNTIL, &pos)?;
+        self.db.batch()
+    }
+
+    /// stop background writer of the content hash db
+    pub fn shutdown (&mut self) {
+        self.db.shutdown()
+    }
+}
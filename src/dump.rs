@@ -0,0 +1,161 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Portable dump
+//! A stream of length prefixed records of live keyed data and data without key, independent of
+//! the page layout and the file format version. References stored with data are recorded as the
+//! number of the record they point to, so they are rewritten at import.
+//!
+
+use api::{HammersbaldAPI, HammersbaldRead};
+use datafile;
+use pref::PRef;
+use error::Error;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"HAMMERSD";
+const DUMP_VERSION: u32 = 1;
+
+const KEYED: u8 = 0;
+const REFERRED: u8 = 1;
+const LINKED: u8 = 2;
+// followed by the number of records, so a truncated dump is detected
+const END: u8 = 0xFF;
+
+/// write live keyed data and data without key of the db in the order it was stored
+/// returns the number of records written
+pub fn export(db: &dyn HammersbaldRead, writer: &mut dyn Write) -> Result<u64, Error> {
+    writer.write_all(MAGIC)?;
+    writer.write_u32::<BigEndian>(DUMP_VERSION)?;
+    let mut records = HashMap::new();
+    let mut scan = db.iter_prefetch(datafile::SCAN_PREFETCH);
+    for (pref, key, data) in &mut scan {
+        if key.is_empty() {
+            let links = db.get_links(pref)?;
+            let mut numbers = Vec::with_capacity(links.len());
+            for link in links {
+                numbers.push(match records.get(&link) {
+                    Some(n) => *n,
+                    None => {
+                        // data no longer live is exported as data without key
+                        let (_, data) = db.get(link)?;
+                        write_referred(writer, data.as_slice())?;
+                        let n = records.len() as u64;
+                        records.insert(link, n);
+                        n
+                    }
+                });
+            }
+            if numbers.is_empty() {
+                write_referred(writer, data.as_slice())?;
+            }
+            else {
+                writer.write_u8(LINKED)?;
+                writer.write_u32::<BigEndian>(numbers.len() as u32)?;
+                for n in numbers {
+                    writer.write_u64::<BigEndian>(n)?;
+                }
+                write_data(writer, data.as_slice())?;
+            }
+        }
+        else if db.get_keyed(key.as_slice())?.map(|(live, _)| live) == Some(pref) {
+            writer.write_u8(KEYED)?;
+            writer.write_u8(key.len() as u8)?;
+            writer.write_all(key.as_slice())?;
+            write_data(writer, data.as_slice())?;
+        }
+        else {
+            continue;
+        }
+        let n = records.len() as u64;
+        records.insert(pref, n);
+    }
+    scan.error()?;
+    writer.write_u8(END)?;
+    writer.write_u64::<BigEndian>(records.len() as u64)?;
+    writer.flush()?;
+    Ok(records.len() as u64)
+}
+
+/// store the records of a dump in the db, ending the batch thereafter
+/// returns the number of records read
+pub fn import(db: &mut dyn HammersbaldAPI, reader: &mut dyn Read) -> Result<u64, Error> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Corrupted("not a hammersbald dump".to_string()));
+    }
+    let version = reader.read_u32::<BigEndian>()?;
+    if version != DUMP_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let mut prefs: Vec<PRef> = Vec::new();
+    loop {
+        let pref = match reader.read_u8()? {
+            KEYED => {
+                let mut key = vec!(0u8; reader.read_u8()? as usize);
+                reader.read_exact(&mut key)?;
+                db.put_keyed(key.as_slice(), read_data(reader)?.as_slice())?
+            },
+            REFERRED => db.put(read_data(reader)?.as_slice())?,
+            LINKED => {
+                let n_links = reader.read_u32::<BigEndian>()? as usize;
+                let mut links = Vec::with_capacity(n_links);
+                for _ in 0 .. n_links {
+                    let n = reader.read_u64::<BigEndian>()?;
+                    links.push(*prefs.get(n as usize).ok_or_else(|| Error::Corrupted(format!("link to record {} not read before", n)))?);
+                }
+                db.put_with_links(read_data(reader)?.as_slice(), links.as_slice())?
+            },
+            END => {
+                if reader.read_u64::<BigEndian>()? != prefs.len() as u64 {
+                    return Err(Error::Corrupted("number of records of the dump does not match".to_string()));
+                }
+                break;
+            },
+            t => return Err(Error::Corrupted(format!("unknown record type {} in dump", t)))
+        };
+        prefs.push(pref);
+    }
+    db.batch()?;
+    Ok(prefs.len() as u64)
+}
+
+fn write_referred(writer: &mut dyn Write, data: &[u8]) -> Result<(), Error> {
+    writer.write_u8(REFERRED)?;
+    write_data(writer, data)
+}
+
+fn write_data(writer: &mut dyn Write, data: &[u8]) -> Result<(), Error> {
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+fn read_data(reader: &mut dyn Read) -> Result<Vec<u8>, Error> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    // a corrupted length does not allocate beyond the end of the dump
+    let mut data = Vec::new();
+    (&mut *reader).take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(Error::Corrupted("dump is truncated".to_string()));
+    }
+    Ok(data)
+}
@@ -0,0 +1,163 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Encryption of data
+//! Data of the data file is encrypted with ChaCha20-Poly1305 after compression, each with a
+//! random nonce stored with it. Encryption is recorded in a flag bit of the payload type.
+//! The file name.ek holds a check value, so a db does not open with a wrong key.
+//!
+
+use page::Page;
+use pagedfile::PagedFile;
+use config::Config;
+use pref::PRef;
+use error::Error;
+
+#[cfg(feature="encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, KeyInit, aead::Aead};
+#[cfg(feature="encryption")]
+use rand::{thread_rng, RngCore};
+
+/// payload type flag of encrypted data
+pub const ENCRYPTED: u8 = 0x10;
+
+// nonce preceding and authentication tag following encrypted data
+#[cfg(feature="encryption")]
+const NONCE_LEN: usize = 12;
+#[cfg(feature="encryption")]
+const TAG_LEN: usize = 16;
+
+// encrypted into the key check value
+const KEY_CHECK: &[u8; 32] = b"hammersbald encryption key check";
+
+/// encrypts and decrypts data with the key of a db
+pub struct Cipher {
+    #[cfg(feature="encryption")]
+    aead: ChaCha20Poly1305
+}
+
+impl Cipher {
+    /// a cipher with a 256 bit key
+    #[cfg(feature="encryption")]
+    pub fn new (key: &[u8; 32]) -> Cipher {
+        Cipher { aead: ChaCha20Poly1305::new(Key::from_slice(key)) }
+    }
+
+    /// encrypt data, returns nonce, encrypted data and tag
+    #[cfg(feature="encryption")]
+    pub fn encrypt (&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let encrypted = self.aead.encrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| Error::Corrupted("data can not be encrypted".to_string()))?;
+        let mut result = Vec::with_capacity(NONCE_LEN + encrypted.len());
+        result.extend_from_slice(&nonce);
+        result.extend_from_slice(encrypted.as_slice());
+        Ok(result)
+    }
+
+    /// decrypt data encrypted with the key
+    #[cfg(feature="encryption")]
+    pub fn decrypt (&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(Error::Corrupted("encrypted data is truncated".to_string()));
+        }
+        self.aead.decrypt(Nonce::from_slice(&data[.. NONCE_LEN]), &data[NONCE_LEN ..])
+            .map_err(|_| Error::Corrupted("encrypted data fails authentication".to_string()))
+    }
+
+    #[cfg(not(feature="encryption"))]
+    pub fn encrypt (&self, _: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::WrongKey)
+    }
+
+    #[cfg(not(feature="encryption"))]
+    pub fn decrypt (&self, _: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::WrongKey)
+    }
+}
+
+/// the cipher of the key configured
+#[cfg(feature="encryption")]
+pub fn cipher (config: &Config) -> Option<Cipher> {
+    config.encryption_key.as_ref().map(Cipher::new)
+}
+
+/// the cipher of the key configured
+#[cfg(not(feature="encryption"))]
+pub fn cipher (_: &Config) -> Option<Cipher> {
+    None
+}
+
+/// check the key against the check value of the file, a new check value is stored in an empty file
+/// unless read only. Fails if the db is encrypted and no or an other key is given
+pub fn check_key (file: &mut dyn PagedFile, cipher: Option<&Cipher>, read_only: bool) -> Result<(), Error> {
+    let stored = file.read_page(PRef::from(0))?;
+    match (stored, cipher) {
+        (None, None) => Ok(()),
+        (None, Some(_)) if read_only => Ok(()),
+        (None, Some(cipher)) => {
+            let check = cipher.encrypt(KEY_CHECK)?;
+            let mut page = Page::new_table_page(PRef::from(0));
+            page.write(0, &[check.len() as u8]);
+            page.write(1, check.as_slice());
+            file.update_page(page)?;
+            file.flush()?;
+            file.sync()
+        },
+        (Some(_), None) => Err(Error::WrongKey),
+        (Some(page), Some(cipher)) => {
+            let mut len = [0u8; 1];
+            page.read(0, &mut len);
+            let mut check = vec!(0u8; len[0] as usize);
+            page.read(1, &mut check);
+            match cipher.decrypt(check.as_slice()) {
+                Ok(ref plain) if plain.as_slice() == &KEY_CHECK[..] => Ok(()),
+                _ => Err(Error::WrongKey)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature="encryption"))]
+mod test {
+    use transient::Transient;
+    use super::*;
+
+    #[test]
+    fn test_cipher () {
+        let cipher = Cipher::new(&[1u8; 32]);
+        let encrypted = cipher.encrypt(b"secret").unwrap();
+        assert_eq!(encrypted.len(), NONCE_LEN + 6 + TAG_LEN);
+        assert_eq!(cipher.decrypt(encrypted.as_slice()).unwrap(), b"secret".to_vec());
+        // random nonces, so equal data does not encrypt the same
+        assert_ne!(cipher.encrypt(b"secret").unwrap(), encrypted);
+        let mut tampered = encrypted.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(cipher.decrypt(tampered.as_slice()).is_err());
+
+        let mut file = Transient::new(false);
+        check_key(&mut file, Some(&cipher), true).unwrap();
+        assert_eq!(file.len().unwrap(), 0);
+        check_key(&mut file, Some(&cipher), false).unwrap();
+        check_key(&mut file, Some(&cipher), false).unwrap();
+        match check_key(&mut file, Some(&Cipher::new(&[2u8; 32])), false) {
+            Err(Error::WrongKey) => {},
+            _ => panic!("an other key should not open")
+        }
+        assert!(check_key(&mut file, None, true).is_err());
+    }
+}
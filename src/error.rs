@@ -25,14 +25,162 @@ use std::fmt;
 use std::io;
 use std::sync;
 
+/// a file of a db
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    /// data, name.bc
+    Data,
+    /// links of hash table buckets, name.bl
+    Link,
+    /// hash table, name.tb
+    Table,
+    /// log of table pages modified by the current batch, name.lg
+    Log,
+    /// filters of hash table buckets, name.bf
+    Bloom,
+    /// write ahead log, name.wl
+    WriteAheadLog,
+    /// generations of data, name.gn
+    Generations,
+    /// check value of the encryption key, name.ek
+    Key,
+    /// other files, e.g. of a page store
+    Other
+}
+
+impl FileKind {
+    /// the kind of a file with the extension
+    pub fn from_extension (extension: &str) -> FileKind {
+        match extension {
+            "bc" => FileKind::Data,
+            "bl" => FileKind::Link,
+            "tb" => FileKind::Table,
+            "lg" => FileKind::Log,
+            "bf" => FileKind::Bloom,
+            "wl" => FileKind::WriteAheadLog,
+            "gn" => FileKind::Generations,
+            "ek" => FileKind::Key,
+            _ => FileKind::Other
+        }
+    }
+}
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            FileKind::Data => "data",
+            FileKind::Link => "link",
+            FileKind::Table => "hash table",
+            FileKind::Log => "log",
+            FileKind::Bloom => "bloom filter",
+            FileKind::WriteAheadLog => "write ahead log",
+            FileKind::Generations => "generations",
+            FileKind::Key => "key check",
+            FileKind::Other => "other"
+        })
+    }
+}
+
+/// what failed on a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// read a page
+    Read,
+    /// append or update a page
+    Write,
+    /// flush buffered writes
+    Flush,
+    /// sync to disk
+    Sync,
+    /// truncate
+    Truncate
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            Operation::Read => "read",
+            Operation::Write => "write",
+            Operation::Flush => "flush",
+            Operation::Sync => "sync",
+            Operation::Truncate => "truncate"
+        })
+    }
+}
+
+/// what an application might do about an error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// the operation might succeed if retried later, e.g. after space is freed or the other writer closed the db
+    Transient,
+    /// the files are damaged, repair or restore the db
+    Corrupted,
+    /// the call was invalid, e.g. a key too long or an unknown index, retrying it does not help
+    Usage,
+    /// an invariant of the library is broken, abort
+    Bug
+}
+
 /// Errors returned by this library
 pub enum Error {
     /// pref is invalid (> 2^48)
     InvalidOffset,
     /// corrupted data
     Corrupted(String),
-    /// key too long
-    KeyTooLong,
+    /// corrupted data of a file at a position
+    CorruptedAt {
+        /// the file
+        file: FileKind,
+        /// position of the corrupted data
+        pos: u64,
+        /// what is wrong
+        reason: String
+    },
+    /// an IO operation on a file failed
+    File {
+        /// the file
+        file: FileKind,
+        /// what failed
+        operation: Operation,
+        /// position of the page read or written, the length truncated to
+        pos: u64,
+        /// the IO error
+        source: io::Error
+    },
+    /// an invariant of the library is broken
+    Bug(String),
+    /// a key, data or number of links exceeds a limit of the file format
+    DoesNotFit {
+        /// what does not fit
+        what: &'static str,
+        /// the limit
+        max: u64,
+        /// the size given
+        got: u64
+    },
+    /// the db is open for writing elsewhere
+    Locked(String),
+    /// secondary index is not defined
+    UnknownIndex(String),
+    /// no checkpoint with the id
+    UnknownCheckpoint(u64),
+    /// the key is stored in both dbs of an ingest
+    Conflict(Vec<u8>),
+    /// generations are not kept or the generation would go back
+    InvalidGeneration(u64),
+    /// the db is encrypted with an other key or no key is given
+    WrongKey,
+    /// a put would exceed the bytes allowed between batches, given the bytes pending
+    WouldBlock(u64),
+    /// the files of the db would exceed its quota or the disk is full
+    OutOfSpace {
+        /// bytes to write, 0 if not known
+        bytes: u64,
+        /// the error of the disk reported full, none if found before writing
+        source: Option<io::Error>
+    },
+    /// file format version is not supported, legacy files can be upgraded with migrate
+    UnsupportedVersion(u32),
     /// wrapped IO error
     IO(io::Error),
     /// Wrapped bitcoin util error
@@ -52,8 +200,20 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             Error::InvalidOffset => None,
-            Error::KeyTooLong => None,
+            Error::DoesNotFit { .. } => None,
+            Error::UnsupportedVersion(_) => None,
+            Error::Locked(_) => None,
+            Error::UnknownIndex(_) => None,
+            Error::UnknownCheckpoint(_) => None,
+            Error::Conflict(_) => None,
+            Error::InvalidGeneration(_) => None,
+            Error::WrongKey => None,
+            Error::WouldBlock(_) => None,
+            Error::OutOfSpace { ref source, .. } => source.as_ref().map(|e| e as &(dyn std::error::Error + 'static)),
             Error::Corrupted (_) => None,
+            Error::CorruptedAt { .. } => None,
+            Error::File { ref source, .. } => Some(source),
+            Error::Bug(_) => None,
             Error::IO(ref e) => Some(e),
             #[cfg(feature="bitcoin_support")]
             Error::BitcoinSerialize(ref e) => Some(e),
@@ -67,8 +227,21 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
             Error::InvalidOffset => write!(f, "invalid pref"),
-            Error::KeyTooLong => write!(f, "key too long"),
+            Error::DoesNotFit { what, max, got } => write!(f, "{} of {} does not fit the limit of {}", what, got, max),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported file format version {}", v),
+            Error::Locked(ref s) => write!(f, "db {} is open for writing elsewhere", s),
+            Error::UnknownIndex(ref s) => write!(f, "unknown index {}", s),
+            Error::UnknownCheckpoint(id) => write!(f, "unknown checkpoint {}", id),
+            Error::Conflict(ref key) => write!(f, "key {} is stored in both dbs", key.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+            Error::InvalidGeneration(g) => write!(f, "invalid generation {}", g),
+            Error::WrongKey => write!(f, "the db is encrypted with an other key or no key is given"),
+            Error::WouldBlock(n) => write!(f, "{} bytes are pending, a batch should end before the next put", n),
+            Error::OutOfSpace { bytes, source: None } => write!(f, "out of space for {} bytes, the quota of the db or the disk is exhausted", bytes),
+            Error::OutOfSpace { source: Some(ref e), .. } => write!(f, "out of space, the disk is full: {}", e),
             Error::Corrupted(ref s) => write!(f, "corrupted data: {}", s),
+            Error::CorruptedAt { file, pos, ref reason } => write!(f, "corrupted data of the {} file at {}: {}", file, pos, reason),
+            Error::File { file, operation, pos, ref source } => write!(f, "{} of the {} file at {} failed: {}", operation, file, pos, source),
+            Error::Bug(ref s) => write!(f, "bug: {}", s),
             Error::IO(e) => e.fmt(f),
             #[cfg(feature = "bitcoin_support")]
             Error::BitcoinSerialize(e) => write!(f, "bitcoin serialize error: {}", e),
@@ -78,6 +251,33 @@ impl fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// what an application might do about the error: retry, repair or abort
+    pub fn class(&self) -> ErrorClass {
+        match *self {
+            Error::Corrupted(_) | Error::CorruptedAt { .. } => ErrorClass::Corrupted,
+            Error::File { ref source, .. } | Error::IO(ref source) => match source.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData => ErrorClass::Corrupted,
+                _ => ErrorClass::Transient
+            },
+            Error::Locked(_) | Error::WouldBlock(_) | Error::OutOfSpace { .. } => ErrorClass::Transient,
+            Error::InvalidOffset | Error::DoesNotFit { .. } | Error::UnknownIndex(_) | Error::UnknownCheckpoint(_) |
+            Error::Conflict(_) | Error::InvalidGeneration(_) | Error::WrongKey | Error::UnsupportedVersion(_) => ErrorClass::Usage,
+            #[cfg(feature="bitcoin_support")]
+            Error::BitcoinSerialize(_) => ErrorClass::Corrupted,
+            Error::Bug(_) | Error::Poisoned(_) | Error::Queue(_) => ErrorClass::Bug
+        }
+    }
+
+    /// add the file, operation and position to an IO error, other errors are returned as they are
+    pub fn within(self, file: FileKind, operation: Operation, pos: u64) -> Error {
+        match self {
+            Error::IO(source) => Error::File { file, operation, pos, source },
+            e => e
+        }
+    }
+}
+
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         (self as &dyn fmt::Display).fmt(f)
@@ -86,13 +286,27 @@ impl fmt::Debug for Error {
 
 impl convert::From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
+        if err.kind() == io::ErrorKind::StorageFull {
+            return Error::OutOfSpace { bytes: 0, source: Some(err) };
+        }
         Error::IO(err)
     }
 }
 
+// the message of the error is kept, so are the kind of IO errors
 impl convert::From<Error> for io::Error {
-    fn from(_: Error) -> io::Error {
-        io::Error::from(io::ErrorKind::UnexpectedEof)
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::IO(e) => e,
+            Error::File { ref source, .. } => io::Error::new(source.kind(), err.to_string()),
+            Error::OutOfSpace { source: Some(e), .. } => e,
+            Error::OutOfSpace { .. } => io::Error::new(io::ErrorKind::StorageFull, err.to_string()),
+            e => match e.class() {
+                ErrorClass::Corrupted => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+                ErrorClass::Usage => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+                _ => io::Error::other(e.to_string())
+            }
+        }
     }
 }
 
@@ -107,3 +321,35 @@ impl<T> convert::From<sync::mpsc::SendError<T>> for Error {
         Error::Queue(err.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_error_class () {
+        let e = Error::IO(io::Error::from(io::ErrorKind::PermissionDenied)).within(FileKind::Table, Operation::Write, 4096);
+        assert_eq!(e.to_string(), "write of the hash table file at 4096 failed: permission denied");
+        assert_eq!(e.class(), ErrorClass::Transient);
+        assert_eq!(e.source().unwrap().to_string(), "permission denied");
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::PermissionDenied);
+
+        let e = Error::CorruptedAt { file: FileKind::Data, pos: 7, reason: "empty envelope".to_string() };
+        assert_eq!(e.class(), ErrorClass::Corrupted);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Error::InvalidOffset.within(FileKind::Data, Operation::Read, 0).class(), ErrorClass::Usage);
+        assert_eq!(Error::Bug("missing chunk".to_string()).class(), ErrorClass::Bug);
+        assert_eq!(FileKind::from_extension("bl"), FileKind::Link);
+
+        // a full disk keeps its IO error as source
+        let e = Error::from(io::Error::new(io::ErrorKind::StorageFull, "no space left"));
+        assert!(matches!(e, Error::OutOfSpace { bytes: 0, source: Some(_) }));
+        assert_eq!(e.class(), ErrorClass::Transient);
+        assert_eq!(e.source().unwrap().to_string(), "no space left");
+        assert_eq!(io::Error::from(e).to_string(), "no space left");
+        let e = Error::OutOfSpace { bytes: 10, source: None };
+        assert!(e.source().is_none());
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::StorageFull);
+    }
+}
@@ -0,0 +1,191 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Follower
+//! A read only view of a db written by an other process, moving to the latest batch of the writer
+//! on refresh. The log of the writer is a single page with the lengths of the data, table and link
+//! files between its batches and longer while a batch is written, so the follower opens the files
+//! only between batches and if the lengths did not change while it opened them.
+//!
+
+use api::{Hammersbald, HammersbaldRead};
+use persistent::Persistent;
+use config::Config;
+use page::PAGE_SIZE;
+use pref::PRef;
+use error::Error;
+
+use std::thread;
+use std::time::Duration;
+
+// lengths of the data, table and link files
+type Lengths = (u64, u64, u64);
+
+// attempts to open the db between batches of the writer and the wait in between
+const OPEN_ATTEMPTS: usize = 100;
+const OPEN_WAIT: Duration = Duration::from_millis(10);
+
+/// a read only db following the batches of a writer in an other process
+pub struct Follower {
+    name: String,
+    config: Config,
+    db: Hammersbald,
+    // lengths of the data, table and link files at the batch seen
+    lengths: Lengths
+}
+
+impl Follower {
+    /// open the db at the last batch of its writer
+    /// fails with Error::Locked if the writer is in a batch for long
+    pub fn open (name: &str, config: &Config) -> Result<Follower, Error> {
+        for _ in 0 .. OPEN_ATTEMPTS {
+            if let Some((db, lengths)) = Self::view(name, config)? {
+                return Ok(Follower { name: name.to_string(), config: config.clone(), db, lengths });
+            }
+            thread::sleep(OPEN_WAIT);
+        }
+        Err(Error::Locked(format!("{} is in a batch", name)))
+    }
+
+    /// move to the latest batch of the writer, the view is kept while the writer is in a batch
+    /// returns true if the follower moved to a new batch
+    /// The files are reopened at a new batch, which reads the whole hash table and bloom filters but
+    /// no data, so its cost is in proportion to the buckets of the table, not to the batch. Poll at
+    /// an interval that allows for it with large tables.
+    pub fn refresh (&mut self) -> Result<bool, Error> {
+        match Self::batch_lengths(&self.name, &self.config)? {
+            Some(lengths) if lengths != self.lengths => {},
+            _ => return Ok(false)
+        }
+        if let Some((db, lengths)) = Self::view(&self.name, &self.config)? {
+            self.db = db;
+            self.lengths = lengths;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// the db at the batch seen
+    pub fn db (&self) -> &dyn HammersbaldRead {
+        &self.db
+    }
+
+    /// end of the data of the batch seen
+    pub fn position (&self) -> PRef {
+        PRef::from(self.lengths.0)
+    }
+
+    // open the db if between batches of the writer and none was completed while opening
+    fn view (name: &str, config: &Config) -> Result<Option<(Hammersbald, Lengths)>, Error> {
+        let before = match Self::batch_lengths(name, config)? {
+            Some(lengths) => lengths,
+            None => return Ok(None)
+        };
+        let db = Persistent::open_read_only(name, config)?;
+        if Self::batch_lengths(name, config)? == Some(before) {
+            return Ok(Some((db, before)));
+        }
+        Ok(None)
+    }
+
+    // lengths of the files recorded in the log, None while the writer is in a batch
+    fn batch_lengths (name: &str, config: &Config) -> Result<Option<Lengths>, Error> {
+        let log = Persistent::raw_file(name, config, "lg")?;
+        if log.len()? != PAGE_SIZE as u64 {
+            return Ok(None);
+        }
+        Ok(log.read_page(PRef::from(0))?.map(|page|
+            (page.read_pref(0).as_u64(), page.read_pref(6).as_u64(), page.read_pref(12).as_u64())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::persistent_with_config;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_follower () {
+        let dir = env::temp_dir().join(format!("hammersbald-follower-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config::default().directory(&dir);
+        let mut db = persistent_with_config("db", &config).unwrap();
+        let first = db.put_keyed(b"first", b"1").unwrap();
+        db.batch().unwrap();
+
+        let mut follower = Follower::open("db", &config).unwrap();
+        assert_eq!(follower.db().get_keyed(b"first").unwrap(), Some((first, b"1".to_vec())));
+        assert!(!follower.refresh().unwrap());
+
+        // data of the writer is seen with its batch
+        let second = db.put_keyed(b"second", b"2").unwrap();
+        db.forget(b"first").unwrap();
+        assert!(!follower.refresh().unwrap());
+        assert_eq!(follower.db().get_keyed(b"second").unwrap(), None);
+        db.batch().unwrap();
+        assert!(follower.refresh().unwrap());
+        assert_eq!(follower.db().get_keyed(b"second").unwrap(), Some((second, b"2".to_vec())));
+        assert_eq!(follower.db().get_keyed(b"first").unwrap(), None);
+        assert!(follower.position() > second);
+
+        // a batch splitting buckets of the table
+        for i in 0 .. 10000u32 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+        assert!(follower.refresh().unwrap());
+        assert_eq!(follower.db().get_keyed(&9999u32.to_be_bytes()).unwrap().unwrap().1, 9999u32.to_le_bytes().to_vec());
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_follower_large_table () {
+        let dir = env::temp_dir().join(format!("hammersbald-follower-large-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config::default().directory(&dir);
+        let mut db = persistent_with_config("db", &config).unwrap();
+        for i in 0 .. 100000u32 {
+            db.put_keyed(&i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        db.batch().unwrap();
+
+        let mut follower = Follower::open("db", &config).unwrap();
+        let buckets = follower.db().table_stats().unwrap().buckets;
+        assert!(buckets > 10000);
+        // small batches each reload the whole table
+        for round in 0 .. 5u32 {
+            let key = (1000000 + round).to_be_bytes();
+            let pref = db.put_keyed(&key, b"round").unwrap();
+            db.forget(&round.to_be_bytes()).unwrap();
+            db.batch().unwrap();
+            assert!(follower.refresh().unwrap());
+            assert_eq!(follower.db().get_keyed(&key).unwrap(), Some((pref, b"round".to_vec())));
+            assert_eq!(follower.db().get_keyed(&round.to_be_bytes()).unwrap(), None);
+        }
+        assert!(follower.db().table_stats().unwrap().buckets >= buckets);
+        for i in (5 .. 100000u32).step_by(997) {
+            assert_eq!(follower.db().get_keyed(&i.to_be_bytes()).unwrap().unwrap().1, i.to_le_bytes().to_vec());
+        }
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -18,11 +18,33 @@
 //!
 use error::Error;
 use pref::PRef;
+use compression::CODEC_MASK;
+use encryption::ENCRYPTED;
 
 use byteorder::{WriteBytesExt, ByteOrder, BigEndian};
 
 use std::io::Write;
 
+/// payload type flag of data continued in extensions, the data starts with the reference of the first extension
+pub const EXTENDED: u8 = 0x20;
+
+/// longest key
+pub const MAX_KEY_LEN: usize = 255;
+/// longest data, longer than an envelope as it is continued in extensions
+pub const MAX_DATA_LEN: usize = u32::MAX as usize;
+/// most links of data, they have to fit an envelope with the first chunk of data
+pub const MAX_LINKS: usize = 1 << 20;
+
+/// data read with its persistent reference
+pub type DataAt = (PRef, Vec<u8>);
+/// key and data read with their persistent reference
+pub type KeyedDataAt = (PRef, Vec<u8>, Vec<u8>);
+/// key read with the persistent reference of its data
+pub type KeyAt = (PRef, Vec<u8>);
+
+// length of data that does not fit into three bytes, followed by four bytes of length
+const LONG_DATA: u32 = 0xFF_FFFF;
+
 /// Content envelope wrapping in data file
 pub struct Envelope {
     buffer: Vec<u8>
@@ -42,7 +64,7 @@ impl Envelope {
     /// serialize for storage
     pub fn serialize (&self, result: &mut dyn Write) {
         result.write_u24::<BigEndian>(self.buffer.len() as u32).unwrap();
-        result.write(self.buffer.as_slice()).unwrap();
+        result.write_all(self.buffer.as_slice()).unwrap();
     }
 
     /// deserialize for storage
@@ -58,7 +80,13 @@ pub enum Payload<'e> {
     /// data
     Referred(Data<'e>),
     /// hash table extension,
-    Link(Link<'e>)
+    Link(Link<'e>),
+    /// data with references to other data
+    Linked(LinkedData<'e>),
+    /// continuation of extended data
+    Extension(Extension<'e>),
+    /// filler up to the end of a page, not data
+    Padding(Data<'e>)
 }
 
 impl<'e> Payload<'e> {
@@ -66,7 +94,7 @@ impl<'e> Payload<'e> {
     pub fn serialize (&self, result: &mut dyn Write) {
         match self {
             Payload::Indexed(indexed) => {
-                result.write_u8(0).unwrap();
+                result.write_u8(if indexed.fixed { 5 } else { 0 }).unwrap();
                 indexed.serialize(result);
             },
             Payload::Referred(referred) => {
@@ -76,20 +104,55 @@ impl<'e> Payload<'e> {
             Payload::Link(link) => {
                 result.write_u8(2).unwrap();
                 link.serialize(result);
+            },
+            Payload::Linked(linked) => {
+                result.write_u8(3).unwrap();
+                linked.serialize(result);
+            },
+            Payload::Extension(extension) => {
+                result.write_u8(4).unwrap();
+                extension.serialize(result);
+            },
+            Payload::Padding(padding) => {
+                result.write_u8(6).unwrap();
+                padding.serialize(result);
             }
         }
     }
 
     /// deserialize from storage
-    pub fn deserialize(slice: &'e [u8]) -> Result<Payload, Error> {
+    pub fn deserialize(slice: &'e [u8]) -> Result<Payload<'e>, Error> {
         match slice [0] {
             0 => Ok(Payload::Indexed(IndexedData::deserialize(&slice[1..]))),
             1 => Ok(Payload::Referred(Data::deserialize(&slice[1..]))),
             2 => Ok(Payload::Link(Link::deserialize(&slice[1..]))),
+            3 => Ok(Payload::Linked(LinkedData::deserialize(&slice[1..]))),
+            4 => Ok(Payload::Extension(Extension::deserialize(&slice[1..]))),
+            5 => Ok(Payload::Indexed(IndexedData::deserialize_fixed(&slice[1..]))),
+            6 => Ok(Payload::Padding(Data::deserialize(&slice[1..]))),
             // Link and Table are not serialized with a type
             _ => Err(Error::Corrupted("unknown payload type".to_string()))
         }
     }
+
+    /// check that the lengths within a serialized payload add up to its length, so it deserializes
+    /// flags of compressed, encrypted or extended data are ignored
+    pub fn is_well_formed(slice: &[u8]) -> bool {
+        if slice.is_empty() {
+            return false;
+        }
+        let body = &slice[1..];
+        match slice[0] & !(CODEC_MASK | ENCRYPTED | EXTENDED) {
+            0 => body.get(1 + *body.first().unwrap_or(&0) as usize ..).is_some_and(Data::is_well_formed),
+            1 => Data::is_well_formed(body),
+            2 => body.len().is_multiple_of(10),
+            3 => body.len() >= 3 && body.get(3 + 6 * BigEndian::read_u24(&body[0 .. 3]) as usize ..).is_some_and(Data::is_well_formed),
+            4 => body.len() >= 6 && Data::is_well_formed(&body[6 ..]),
+            5 => Data::serialized_len(body).is_some_and(|len| len < body.len()),
+            6 => Data::is_well_formed(body),
+            _ => false
+        }
+    }
 }
 
 
@@ -106,15 +169,50 @@ impl<'e> Data<'e> {
     }
 
     /// serialize for storage
+    /// stored data is shorter than three bytes of length, reassembled extended data might be longer
     pub fn serialize (&self, result: &mut dyn Write) {
-        result.write_u24::<BigEndian>(self.data.len() as u32).unwrap();
-        result.write(self.data).unwrap();
+        if self.data.len() >= LONG_DATA as usize {
+            result.write_u24::<BigEndian>(LONG_DATA).unwrap();
+            result.write_u32::<BigEndian>(self.data.len() as u32).unwrap();
+        }
+        else {
+            result.write_u24::<BigEndian>(self.data.len() as u32).unwrap();
+        }
+        result.write_all(self.data).unwrap();
+    }
+
+    // length of the data serialized at the start of the slice
+    fn serialized_len(slice: &[u8]) -> Option<usize> {
+        if slice.len() < 3 {
+            return None;
+        }
+        let data_len = BigEndian::read_u24(&slice[0 .. 3]);
+        if data_len == LONG_DATA {
+            return slice.get(3 .. 7).map(|len| 7 + BigEndian::read_u32(len) as usize);
+        }
+        Some(3 + data_len as usize)
+    }
+
+    // the length of data matches the slice
+    fn is_well_formed(slice: &[u8]) -> bool {
+        if slice.len() < 3 {
+            return false;
+        }
+        let data_len = BigEndian::read_u24(&slice[0 .. 3]);
+        if data_len == LONG_DATA {
+            return slice.len() >= 7 && slice.len() - 7 == BigEndian::read_u32(&slice[3 .. 7]) as usize;
+        }
+        slice.len() - 3 == data_len as usize
     }
 
     /// deserialize from storage
-    pub fn deserialize(slice: &'e [u8]) -> Data {
-        let data_len = BigEndian::read_u24(&slice[0 .. 3]) as usize;
-        let data = &slice[3 .. 3+data_len];
+    pub fn deserialize(slice: &'e [u8]) -> Data<'e> {
+        let data_len = BigEndian::read_u24(&slice[0 .. 3]);
+        if data_len == LONG_DATA {
+            let data_len = BigEndian::read_u32(&slice[3 .. 7]) as usize;
+            return Data { data: &slice[7 .. 7+data_len] };
+        }
+        let data = &slice[3 .. 3+data_len as usize];
         Data {data}
     }
 }
@@ -124,20 +222,33 @@ pub struct IndexedData<'e> {
     /// key
     pub key: &'e [u8],
     /// data
-    pub data: Data<'e>
+    pub data: Data<'e>,
+    /// the key of a db of fixed size keys is stored without its length, following the data
+    pub fixed: bool
 }
 
 impl<'e> IndexedData<'e> {
     /// new indexed data
     pub fn new (key: &'e [u8], data: Data<'e>) -> IndexedData<'e> {
-        IndexedData {key, data}
+        IndexedData {key, data, fixed: false}
+    }
+
+    /// new indexed data of a db of fixed size keys
+    pub fn new_fixed (key: &'e [u8], data: Data<'e>) -> IndexedData<'e> {
+        IndexedData {key, data, fixed: true}
     }
 
     /// serialize for storage
     pub fn serialize (&self, result: &mut dyn Write) {
-        result.write_u8(self.key.len() as u8).unwrap();
-        result.write(self.key).unwrap();
-        self.data.serialize(result);
+        if self.fixed {
+            self.data.serialize(result);
+            result.write_all(self.key).unwrap();
+        }
+        else {
+            result.write_u8(self.key.len() as u8).unwrap();
+            result.write_all(self.key).unwrap();
+            self.data.serialize(result);
+        }
     }
 
     /// deserialize from storage
@@ -145,7 +256,91 @@ impl<'e> IndexedData<'e> {
         let key_len = slice[0] as usize;
         let key = &slice[1 .. key_len+1];
         let data = Data::deserialize(&slice[key_len+1 ..]);
-        IndexedData{key, data }
+        IndexedData{key, data, fixed: false}
+    }
+
+    /// deserialize from storage the data followed by a key without length
+    pub fn deserialize_fixed(slice: &'e [u8]) -> IndexedData<'e> {
+        let data_len = Data::serialized_len(slice).unwrap_or(slice.len());
+        let data = Data::deserialize(&slice[.. data_len]);
+        IndexedData{key: &slice[data_len ..], data, fixed: true}
+    }
+}
+
+/// data with references to other data
+pub struct LinkedData<'e> {
+    /// serialized references
+    links: &'e [u8],
+    /// data
+    pub data: Data<'e>
+}
+
+impl<'e> LinkedData<'e> {
+    /// new linked data with serialized references
+    pub fn new (links: &'e [u8], data: Data<'e>) -> LinkedData<'e> {
+        LinkedData {links, data}
+    }
+
+    /// serialize references
+    pub fn from_prefs(prefs: &[PRef]) -> Vec<u8> {
+        let mut links = vec!(0u8; 6*prefs.len());
+        for (i, pref) in prefs.iter().enumerate() {
+            BigEndian::write_u48(&mut links[i*6 .. i*6+6], pref.as_u64());
+        }
+        links
+    }
+
+    /// get references
+    pub fn links(&self) -> Vec<PRef> {
+        self.links.chunks(6).map(|c| PRef::from(BigEndian::read_u48(c))).collect()
+    }
+
+    /// serialized references
+    pub fn serialized_links(&self) -> &'e [u8] {
+        self.links
+    }
+
+    /// serialize for storage
+    pub fn serialize (&self, result: &mut dyn Write) {
+        result.write_u24::<BigEndian>((self.links.len()/6) as u32).unwrap();
+        result.write_all(self.links).unwrap();
+        self.data.serialize(result);
+    }
+
+    /// deserialize from storage
+    pub fn deserialize(slice: &'e [u8]) -> LinkedData<'e> {
+        let n_links = BigEndian::read_u24(&slice[0 .. 3]) as usize;
+        let links = &slice[3 .. 3 + 6*n_links];
+        let data = Data::deserialize(&slice[3 + 6*n_links ..]);
+        LinkedData{links, data}
+    }
+}
+
+/// a part of extended data
+pub struct Extension<'e> {
+    /// the next part, invalid for the last
+    pub next: PRef,
+    /// data
+    pub data: Data<'e>
+}
+
+impl<'e> Extension<'e> {
+    /// new part of extended data
+    pub fn new (next: PRef, data: Data<'e>) -> Extension<'e> {
+        Extension {next, data}
+    }
+
+    /// serialize for storage
+    pub fn serialize (&self, result: &mut dyn Write) {
+        result.write_u48::<BigEndian>(self.next.as_u64()).unwrap();
+        self.data.serialize(result);
+    }
+
+    /// deserialize from storage
+    pub fn deserialize(slice: &'e [u8]) -> Extension<'e> {
+        let next = PRef::from(BigEndian::read_u48(&slice[0 .. 6]));
+        let data = Data::deserialize(&slice[6 ..]);
+        Extension{next, data}
     }
 }
 
@@ -179,7 +374,7 @@ impl<'e> Link<'e> {
 
     /// serialize for storage
     pub fn serialize (&self, write: &mut dyn Write) {
-        write.write(&self.links).unwrap();
+        write.write_all(self.links).unwrap();
     }
 
     /// deserialize from storage
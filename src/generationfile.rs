@@ -0,0 +1,172 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # The generation file
+//! Records the range of the data file each batch wrote, the generation of data is the
+//! sequence number of the batch that stored it. Batches that store nothing do not start
+//! a new generation. The first page holds the number of records, following pages
+//! hold the records.
+//!
+
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
+use pagedfile::PagedFile;
+use error::Error;
+use pref::PRef;
+
+use std::cmp::min;
+
+// generation, start and end of its range in the data file
+const RECORD_SIZE: usize = 20;
+const RECORDS_PER_PAGE: usize = PAGE_PAYLOAD_SIZE / RECORD_SIZE;
+
+#[derive(Clone, Copy)]
+struct Generation {
+    generation: u64,
+    start: PRef,
+    end: PRef
+}
+
+/// the generation file
+pub struct GenerationFile {
+    file: Box<dyn PagedFile>,
+    generations: Vec<Generation>,
+    // generation of the open batch and where it starts
+    current: u64,
+    start: PRef
+}
+
+impl GenerationFile {
+    /// load recorded generations, data stored until data_len is not in any generation if nothing is recorded
+    pub fn new (file: Box<dyn PagedFile>, data_len: u64) -> Result<GenerationFile, Error> {
+        let mut generations = Vec::new();
+        if let Some(first) = file.read_page(PRef::from(0))? {
+            let n = first.read_u64(0) as usize;
+            let mut page_number = 0;
+            while generations.len() < n {
+                let pref = Self::page_pref(page_number);
+                match file.read_page(pref)? {
+                    Some(ref page) if page.pref() == pref => {
+                        for i in 0 .. min(RECORDS_PER_PAGE, n - generations.len()) {
+                            generations.push(Generation {
+                                generation: page.read_u64(i * RECORD_SIZE),
+                                start: page.read_pref(i * RECORD_SIZE + 8),
+                                end: page.read_pref(i * RECORD_SIZE + 14)
+                            });
+                        }
+                    },
+                    _ => return Err(Error::Corrupted("missing page of the generation file".to_string()))
+                }
+                page_number += 1;
+            }
+        }
+        // data of a batch not recorded before a crash is added to the open batch
+        let (current, start) = match generations.last() {
+            Some(last) => (last.generation + 1, last.end),
+            None => (0, PRef::from(data_len))
+        };
+        Ok(GenerationFile { file, generations, current, start })
+    }
+
+    /// generation of the open batch
+    pub fn current (&self) -> u64 {
+        self.current
+    }
+
+    /// set generation of the open batch, it can not go back
+    pub fn set_current (&mut self, generation: u64) -> Result<(), Error> {
+        if generation < self.current {
+            return Err(Error::InvalidGeneration(generation));
+        }
+        self.current = generation;
+        Ok(())
+    }
+
+    /// generation of data at pref, None if stored before generations were kept
+    pub fn generation_of (&self, pref: PRef) -> Option<u64> {
+        if pref >= self.start {
+            return Some(self.current);
+        }
+        let n = self.generations.partition_point(|g| g.start <= pref);
+        if n > 0 && pref < self.generations[n - 1].end {
+            return Some(self.generations[n - 1].generation);
+        }
+        None
+    }
+
+    /// position in the data file where data of the generation or later starts
+    /// None if all data is older
+    pub fn start_of (&self, generation: u64) -> Option<PRef> {
+        if generation > self.current {
+            return None;
+        }
+        let n = self.generations.partition_point(|g| g.generation < generation);
+        Some(self.generations.get(n).map(|g| g.start).unwrap_or(self.start))
+    }
+
+    /// record the batch that ends at end of the data file
+    pub fn batch (&mut self, end: PRef) -> Result<(), Error> {
+        if end > self.start {
+            self.generations.push(Generation { generation: self.current, start: self.start, end });
+            self.current += 1;
+            self.start = end;
+            self.write()?;
+        }
+        Ok(())
+    }
+
+    /// forget generations beyond the data file truncated to data_len
+    pub fn truncate (&mut self, data_len: u64) -> Result<(), Error> {
+        let end = PRef::from(data_len);
+        if self.start <= end {
+            return Ok(());
+        }
+        self.generations.retain(|g| g.start < end);
+        if let Some(last) = self.generations.last_mut() {
+            last.end = min(last.end, end);
+        }
+        self.start = self.generations.last().map(|g| g.end).unwrap_or(end);
+        self.write()
+    }
+
+    pub fn shutdown (&mut self) {
+        self.file.shutdown()
+    }
+
+    // write the page of the last record, then the number of records
+    fn write (&mut self) -> Result<(), Error> {
+        if !self.generations.is_empty() {
+            let page_number = (self.generations.len() - 1) / RECORDS_PER_PAGE;
+            let mut page = Page::new_table_page(Self::page_pref(page_number));
+            for (i, g) in self.generations[page_number * RECORDS_PER_PAGE ..].iter().enumerate() {
+                page.write_u64(i * RECORD_SIZE, g.generation);
+                page.write_pref(i * RECORD_SIZE + 8, g.start);
+                page.write_pref(i * RECORD_SIZE + 14, g.end);
+            }
+            self.file.update_page(page)?;
+            self.file.flush()?;
+            self.file.sync()?;
+        }
+        let mut first = Page::new_table_page(PRef::from(0));
+        first.write_u64(0, self.generations.len() as u64);
+        self.file.update_page(first)?;
+        self.file.flush()?;
+        self.file.sync()
+    }
+
+    fn page_pref (page_number: usize) -> PRef {
+        PRef::from((page_number as u64 + 1) * PAGE_SIZE as u64)
+    }
+}
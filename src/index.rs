@@ -0,0 +1,130 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Secondary indexes
+//! A secondary index is a db of its own, that maps index keys extracted from keyed data
+//! to the persistent reference of the data. The index remembers the position of the data file
+//! it is built until, so it catches up with data stored while it was not defined.
+//! Checkpoints of the db are recorded in the index db too, so a rollback rolls the index back with it.
+//!
+
+use api::HammersbaldAPI;
+use format::{Envelope, Payload};
+use pref::PRef;
+use error::Error;
+
+use byteorder::{ByteOrder, BigEndian};
+
+/// extract index keys from (key, data) of keyed data
+pub type IndexKeys = Box<dyn Fn(&[u8], &[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+/// create or open the db of an index with the given name
+pub type IndexFactory = Box<dyn Fn(&str) -> Result<Box<dyn HammersbaldAPI>, Error> + Send + Sync>;
+
+// the empty key is reserved for the position the index is built until
+const BUILT_UNTIL: &[u8] = b"";
+
+/// a secondary index
+pub struct Index {
+    db: Box<dyn HammersbaldAPI>,
+    keys: IndexKeys
+}
+
+impl Index {
+    /// wrap an index db
+    pub fn new (db: Box<dyn HammersbaldAPI>, keys: IndexKeys) -> Index {
+        Index { db, keys }
+    }
+
+    /// position of the data file the index is built until
+    pub fn built_until (&self) -> Result<PRef, Error> {
+        if let Some((_, pos)) = self.db.get_keyed(BUILT_UNTIL)? {
+            return Ok(PRef::from(BigEndian::read_u48(pos.as_slice())));
+        }
+        Ok(PRef::from(0))
+    }
+
+    /// index keyed data in envelopes of the data file
+    pub fn catch_up<I> (&mut self, envelopes: I) -> Result<(), Error>
+        where I: Iterator<Item=Result<(PRef, Envelope), Error>> {
+        for envelope in envelopes {
+            let (pref, envelope) = envelope?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                self.add(pref, indexed.key, indexed.data.data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// add keyed data to the index
+    pub fn add (&mut self, pref: PRef, key: &[u8], data: &[u8]) -> Result<(), Error> {
+        let mut target = [0u8; 6];
+        BigEndian::write_u48(&mut target, pref.as_u64());
+        for index_key in (self.keys)(key, data) {
+            if index_key.as_slice() != BUILT_UNTIL {
+                self.db.put_keyed(index_key.as_slice(), &target)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// find the reference of data with an index key
+    pub fn get (&self, index_key: &[u8]) -> Result<Option<PRef>, Error> {
+        if index_key == BUILT_UNTIL {
+            return Ok(None);
+        }
+        if let Some((_, target)) = self.db.get_keyed(index_key)? {
+            return Ok(Some(PRef::from(BigEndian::read_u48(target.as_slice()))));
+        }
+        Ok(None)
+    }
+
+    /// end batch of the index, data until the given position is indexed
+    pub fn batch (&mut self, until: PRef) -> Result<(), Error> {
+        let mut pos = [0u8; 6];
+        BigEndian::write_u48(&mut pos, until.as_u64());
+        self.db.put_keyed(BUILT_UNTIL, &pos)?;
+        self.db.batch()
+    }
+
+    /// record a checkpoint of the index committed with batch
+    pub fn checkpoint (&mut self, id: u64) -> Result<(), Error> {
+        self.db.batch_named(id)
+    }
+
+    /// roll the index back to the checkpoint with the id, an index created after it is cleared,
+    /// it then catches up with the data of the checkpoint
+    pub fn rollback_to (&mut self, id: u64) -> Result<(), Error> {
+        match self.db.rollback_to(id) {
+            Err(Error::UnknownCheckpoint(_)) => {
+                let mut keys = self.db.iter_keys();
+                let forgotten = (&mut keys).map(|(_, key)| key).collect::<Vec<_>>();
+                keys.error()?;
+                drop(keys);
+                for key in forgotten {
+                    self.db.forget(key.as_slice())?;
+                }
+                self.db.batch()
+            },
+            rolled => rolled
+        }
+    }
+
+    /// stop background writer of the index db
+    pub fn shutdown (&mut self) {
+        self.db.shutdown()
+    }
+}
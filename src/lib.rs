@@ -25,47 +25,132 @@
 #![deny(unused_mut)]
 #![deny(missing_docs)]
 #![deny(unused_must_use)]
-#![forbid(unsafe_code)]
+// unsafe code is denied, but for calls of the Win32 API without a safe wrapper
+#![deny(unsafe_code)]
 
 #[cfg(feature="bitcoin_support")]extern crate bitcoin;
-#[cfg(feature="bitcoin_support")]extern crate serde;
-#[cfg(feature="bitcoin_support")]extern crate serde_cbor;
+#[cfg(feature="serde")]extern crate serde;
+#[cfg(feature="serde")]extern crate serde_cbor;
+#[cfg(feature="lz4")]extern crate lz4_flex;
+#[cfg(feature="snappy")]extern crate snap;
+#[cfg(feature="prometheus")]extern crate prometheus;
+#[cfg(feature="async")]extern crate tokio;
+#[cfg(feature="async")]extern crate futures_core;
+#[cfg(feature="encryption")]extern crate chacha20poly1305;
 extern crate bitcoin_hashes;
 extern crate rand;
 extern crate byteorder;
 extern crate lru_cache;
+extern crate fs2;
+#[cfg(target_os = "linux")]extern crate libc;
+#[cfg(windows)]extern crate winapi;
 
 mod page;
 mod pagedfile;
 mod logfile;
+mod walfile;
+mod bloomfile;
+mod generationfile;
 mod tablefile;
 mod cachedfile;
 mod singlefile;
 mod rolledfile;
+mod containerfile;
 mod asyncfile;
 mod memtable;
+mod overflow;
 mod format;
+mod compression;
+mod encryption;
 mod datafile;
 mod pref;
 mod transient;
 mod persistent;
+mod config;
+mod index;
+mod dedup;
+mod memcache;
+mod metrics;
+mod observer;
+mod replication;
+mod column;
+mod content;
+mod sharded;
+mod follower;
 mod error;
 mod stats;
+mod dump;
+mod backup;
 mod api;
+/// raw access to the pages of a db, not a stable API
+pub mod raw;
 
 pub use pref::PRef;
-pub use error::Error;
+pub use page::{Page, PAGE_SIZE};
+pub use pagedfile::{PagedFile, PageStore};
+pub use error::{Error, ErrorClass, FileKind, Operation};
+pub use config::{Config, SyncMode, Throttle, AutoBatch};
+pub use index::IndexKeys;
+pub use format::{MAX_KEY_LEN, MAX_DATA_LEN, MAX_LINKS, DataAt, KeyedDataAt, KeyAt};
+pub use metrics::{Metrics, NoMetrics};
+pub use overflow::{Overflow, Chained, RobinHood};
+pub use observer::{CommitObserver, Change};
+pub use replication::{ReplicationSource, ReplicationSink};
+pub use column::{Column, ColumnStats, MAX_COLUMN_NAME};
+pub use sharded::{ShardedHammersbald, ShardedIterator, MAX_SHARDS};
+pub use follower::Follower;
+pub use content::ContentId;
+pub use stats::{VerifyLevel, VerifyReport, TableStats, Probe, EntryInfo};
+#[cfg(feature="prometheus")]
+pub use metrics::PrometheusMetrics;
 pub use api::{
     HammersbaldAPI,
+    HammersbaldRead,
     HammersbaldDataWriter,
     HammersbaldDataReader,
     HammersbaldIterator,
+    HammersbaldKeyIterator,
+    GetIterator,
+    DataCursor,
+    ContentRef,
+    Conflict,
+    Ingested,
     persistent,
-    transient
+    persistent_with_config,
+    transient,
+    read_only,
+    read_only_with_config,
+    open_follower,
+    migrate, repair, restore,
+    cached
 };
 
 #[cfg(feature="bitcoin_support")]
 mod bitcoin_adaptor;
+#[cfg(feature="serde")]
+mod typed;
+#[cfg(feature="cli")]
+mod cli;
+#[cfg(feature="async")]
+mod asyncdb;
+#[cfg(feature="testing")]
+mod testing;
+#[cfg(any(test, feature="testing"))]
+mod model;
+#[cfg(feature="bench")]
+mod tuner;
 
 #[cfg(feature="bitcoin_support")]
 pub use bitcoin_adaptor::BitcoinAdaptor;
+#[cfg(feature="serde")]
+pub use typed::TypedHammersbald;
+#[cfg(feature="cli")]
+pub use cli::run_cli;
+#[cfg(feature="async")]
+pub use asyncdb::{AsyncHammersbald, Pending, AsyncIterator};
+#[cfg(feature="testing")]
+pub use testing::{Faults, Fault, reopen_and_verify, crash_test};
+#[cfg(feature="testing")]
+pub use model::{Model, Op, MemoryStore, conformance};
+#[cfg(feature="bench")]
+pub use tuner::{Workloads, Measurement, Report, tune, write_report, run_tuner};
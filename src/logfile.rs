@@ -16,27 +16,62 @@
 //!
 //! # The log file
 //! The writer of the log file.
+//! Pre-images of table pages modified in a batch are logged, so an interrupted batch can be undone.
+//! If checkpoints are kept, pre-images of batches following the first checkpoint are also
+//! appended to the checkpoint file, behind the marker page of the checkpoint they undo to.
 //!
 
-use page::Page;
+use page::{Page, PAGE_PAYLOAD_SIZE};
 use pagedfile::{PagedFile, PagedFileIterator};
 use error::Error;
 use pref::PRef;
 
-use std::collections::HashSet;
+use std::collections::{HashSet, HashMap};
+
+const CHECKPOINT: u64 = 0x4842434845434B50;
 
 pub struct LogFile {
     file: Box<dyn PagedFile>,
     logged: HashSet<PRef>,
-    source_len: u64
+    source_len: u64,
+    checkpoints: Option<Box<dyn PagedFile>>
+}
+
+/// lengths of files and pre-images of table pages to undo to a checkpoint
+pub struct Undo {
+    pub data_len: u64,
+    pub table_len: u64,
+    pub link_len: u64,
+    pub pages: Vec<Page>,
+    // end of the checkpoint marker in the checkpoint file
+    marker_end: u64
 }
 
 impl LogFile {
     pub fn new(rw: Box<dyn PagedFile>) -> LogFile {
-        LogFile { file: rw, logged: HashSet::new(), source_len:0 }
+        LogFile { file: rw, logged: HashSet::new(), source_len:0, checkpoints: None }
+    }
+
+    /// keep checkpoints in the given file
+    pub fn with_checkpoints(mut self, checkpoints: Box<dyn PagedFile>) -> LogFile {
+        self.checkpoints = Some(checkpoints);
+        self
     }
 
     pub fn init (&mut self, data_len: u64, table_len: u64, link_len: u64) -> Result<(), Error> {
+        if let Some(ref mut checkpoints) = self.checkpoints {
+            if checkpoints.len()? > 0 {
+                for page in PagedFileIterator::new(self.file.as_ref(), PRef::from(0)).skip(1) {
+                    checkpoints.append_page(page)?;
+                }
+                checkpoints.flush()?;
+                checkpoints.sync()?;
+            }
+        }
+        self.write_first(data_len, table_len, link_len)
+    }
+
+    fn write_first (&mut self, data_len: u64, table_len: u64, link_len: u64) -> Result<(), Error> {
         self.truncate(0)?;
         let mut first = Page::new();
         first.write_pref(0, PRef::from(data_len));
@@ -48,7 +83,7 @@ impl LogFile {
         Ok(())
     }
 
-    pub fn page_iter (&self) -> PagedFileIterator {
+    pub fn page_iter (&self) -> PagedFileIterator<'_> {
         PagedFileIterator::new(self, PRef::from(0))
     }
 
@@ -65,6 +100,83 @@ impl LogFile {
         self.source_len = len;
         self.logged.clear();
     }
+
+    /// record a checkpoint at the batch boundary with given file lengths
+    pub fn checkpoint(&mut self, id: u64, data_len: u64, table_len: u64, link_len: u64) -> Result<(), Error> {
+        if let Some(ref mut checkpoints) = self.checkpoints {
+            let mut marker = Page::new();
+            marker.write_u64(0, CHECKPOINT);
+            marker.write_u64(8, id);
+            marker.write_pref(16, PRef::from(data_len));
+            marker.write_pref(22, PRef::from(table_len));
+            marker.write_pref(28, PRef::from(link_len));
+            marker.write_pref(PAGE_PAYLOAD_SIZE, PRef::invalid());
+            checkpoints.append_page(marker)?;
+            checkpoints.flush()?;
+            return checkpoints.sync();
+        }
+        Err(Error::UnknownCheckpoint(id))
+    }
+
+    /// what to undo to get to the state of the last checkpoint with the id
+    pub fn undo_to(&self, id: u64) -> Result<Option<Undo>, Error> {
+        let mut undo: Option<Undo> = None;
+        if let Some(ref checkpoints) = self.checkpoints {
+            for (n, page) in PagedFileIterator::new(checkpoints.as_ref(), PRef::from(0)).enumerate() {
+                if !page.pref().is_valid() {
+                    if page.read_u64(0) == CHECKPOINT && page.read_u64(8) == id {
+                        undo = Some(Undo {
+                            data_len: page.read_pref(16).as_u64(),
+                            table_len: page.read_pref(22).as_u64(),
+                            link_len: page.read_pref(28).as_u64(),
+                            pages: Vec::new(),
+                            marker_end: PRef::from(0).add_pages(n as u64 + 1).as_u64()
+                        });
+                    }
+                }
+                else if let Some(ref mut undo) = undo {
+                    undo.pages.push(page);
+                }
+            }
+        }
+        if let Some(ref mut undo) = undo {
+            // the first pre-image of a page after the checkpoint is its state at the checkpoint
+            let mut first = HashMap::new();
+            for page in undo.pages.drain(..) {
+                first.entry(page.pref()).or_insert(page);
+            }
+            undo.pages = first.into_values().filter(|p| p.pref().as_u64() < undo.table_len).collect();
+        }
+        Ok(undo)
+    }
+
+    /// log the undo as if it was the log of an interrupted batch, so recovery applies it
+    pub fn log_undo(&mut self, undo: &Undo) -> Result<(), Error> {
+        self.write_first(undo.data_len, undo.table_len, undo.link_len)?;
+        for page in &undo.pages {
+            self.append_page(page.clone())?;
+        }
+        self.flush()?;
+        self.sync()
+    }
+
+    /// forget all checkpoints
+    pub fn forget_checkpoints(&mut self) -> Result<(), Error> {
+        if let Some(ref mut checkpoints) = self.checkpoints {
+            checkpoints.truncate(0)?;
+            checkpoints.sync()?;
+        }
+        Ok(())
+    }
+
+    /// forget checkpoints following the one undone to
+    pub fn undone(&mut self, undo: &Undo) -> Result<(), Error> {
+        if let Some(ref mut checkpoints) = self.checkpoints {
+            checkpoints.truncate(undo.marker_end)?;
+            checkpoints.sync()?;
+        }
+        Ok(())
+    }
 }
 
 impl PagedFile for LogFile {
@@ -95,6 +207,6 @@ impl PagedFile for LogFile {
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        Ok(self.file.flush()?)
+        self.file.flush()
     }
 }
@@ -0,0 +1,431 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Memory cache tier
+//! Keeps keyed data in memory in front of a db. Puts are written through to the db,
+//! gets check memory first. The least recently used data is evicted once the cached
+//! keys and data exceed the byte budget.
+//!
+
+use api::{HammersbaldAPI, HammersbaldRead, HammersbaldIterator, HammersbaldKeyIterator, GetIterator, DataCursor, ContentRef, Conflict, Ingested};
+use index::IndexKeys;
+use column::Column;
+use content::ContentId;
+use stats::{VerifyLevel, VerifyReport, TableStats, Probe, EntryInfo};
+use observer::CommitObserver;
+use format::{DataAt, KeyedDataAt};
+use pref::PRef;
+use error::Error;
+
+use lru_cache::LruCache;
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// a db with keyed data cached in memory
+pub struct MemCache {
+    db: Box<dyn HammersbaldAPI>,
+    cache: Mutex<KeyCache>
+}
+
+impl MemCache {
+    /// cache keyed data of the db up to budget bytes
+    pub fn new (db: Box<dyn HammersbaldAPI>, budget: usize) -> MemCache {
+        MemCache { db, cache: Mutex::new(KeyCache::new(budget)) }
+    }
+}
+
+impl HammersbaldAPI for MemCache {
+    fn batch (&mut self) -> Result<(), Error> {
+        self.db.batch()
+    }
+
+    fn observe (&mut self, observer: Arc<dyn CommitObserver>) {
+        self.db.observe(observer)
+    }
+
+    fn pending_bytes (&self) -> u64 {
+        self.db.pending_bytes()
+    }
+
+    fn batch_named (&mut self, id: u64) -> Result<(), Error> {
+        self.db.batch_named(id)
+    }
+
+    fn rollback_to (&mut self, id: u64) -> Result<(), Error> {
+        self.cache.lock().unwrap().clear();
+        self.db.rollback_to(id)
+    }
+
+    fn snapshot (&mut self, name: &str) -> Result<(), Error> {
+        self.db.snapshot(name)
+    }
+
+    fn backup_incremental (&mut self, since: u64, target: &mut dyn Write) -> Result<u64, Error> {
+        self.db.backup_incremental(since, target)
+    }
+
+    fn bulk_load (&mut self, expected_keys: usize) -> Result<(), Error> {
+        self.db.bulk_load(expected_keys)
+    }
+
+    fn resize (&mut self, buckets: usize) -> Result<(), Error> {
+        self.db.resize(buckets)
+    }
+
+    fn rebuild_index (&mut self) -> Result<(), Error> {
+        // forgotten keys might be restored
+        self.cache.lock().unwrap().clear();
+        self.db.rebuild_index()
+    }
+
+    fn shutdown (&mut self) {
+        self.db.shutdown()
+    }
+
+    fn close (self: Box<Self>) {
+        self.db.close()
+    }
+
+    fn put_keyed (&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        let pref = self.db.put_keyed(key, data)?;
+        self.cache.lock().unwrap().insert(key, pref, data);
+        Ok(pref)
+    }
+
+    fn put_keyed_also (&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        // get_keyed still returns the data stored first
+        self.db.put_keyed_also(key, data)
+    }
+
+    fn put_durable (&mut self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        let pref = self.db.put_durable(key, data)?;
+        self.cache.lock().unwrap().insert(key, pref, data);
+        Ok(pref)
+    }
+
+    fn put_hashed (&mut self, data: &[u8]) -> Result<(PRef, [u8; 32]), Error> {
+        let (pref, hash) = self.db.put_hashed(data)?;
+        self.cache.lock().unwrap().insert(&hash, pref, data);
+        Ok((pref, hash))
+    }
+
+    fn put_content_stable (&mut self, data: &[u8]) -> Result<(PRef, ContentId), Error> {
+        let (pref, id) = self.db.put_content_stable(data)?;
+        self.cache.lock().unwrap().insert(&id.key(), pref, data);
+        Ok((pref, id))
+    }
+
+    fn put_batch (&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        let prefs = self.db.put_batch(entries)?;
+        let mut cache = self.cache.lock().unwrap();
+        for ((key, data), pref) in entries.iter().zip(prefs.iter()) {
+            cache.insert(key, *pref, data);
+        }
+        Ok(prefs)
+    }
+
+    fn put_group (&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        let prefs = self.db.put_group(entries)?;
+        let mut cache = self.cache.lock().unwrap();
+        for ((key, data), pref) in entries.iter().zip(prefs.iter()) {
+            cache.insert(key, *pref, data);
+        }
+        Ok(prefs)
+    }
+
+    fn put (&mut self, data: &[u8]) -> Result<PRef, Error> {
+        self.db.put(data)
+    }
+
+    fn put_stream (&mut self, reader: &mut dyn Read, len: u64) -> Result<PRef, Error> {
+        self.db.put_stream(reader, len)
+    }
+
+    fn put_with_links (&mut self, data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        self.db.put_with_links(data, links)
+    }
+
+    fn ingest (&mut self, other: &dyn HammersbaldRead, conflict: Conflict) -> Result<Ingested, Error> {
+        // ingested keys replace cached data
+        self.cache.lock().unwrap().clear();
+        self.db.ingest(other, conflict)
+    }
+
+    fn import (&mut self, reader: &mut dyn Read) -> Result<u64, Error> {
+        // imported keys replace cached data
+        self.cache.lock().unwrap().clear();
+        self.db.import(reader)
+    }
+
+    fn forget (&mut self, key: &[u8]) -> Result<(), Error> {
+        self.cache.lock().unwrap().remove(key);
+        self.db.forget(key)
+    }
+
+    fn create_index (&mut self, name: &str, keys: IndexKeys) -> Result<(), Error> {
+        self.db.create_index(name, keys)
+    }
+
+    fn get_by_index (&self, name: &str, index_key: &[u8]) -> Result<Option<KeyedDataAt>, Error> {
+        self.db.get_by_index(name, index_key)
+    }
+
+    fn set_generation (&mut self, generation: u64) -> Result<(), Error> {
+        self.db.set_generation(generation)
+    }
+
+    fn prune_before (&mut self, generation: u64) -> Result<usize, Error> {
+        self.cache.lock().unwrap().clear();
+        self.db.prune_before(generation)
+    }
+
+    fn column (&mut self, name: &str) -> Result<Column<'_>, Error> {
+        Column::new(self, name)
+    }
+}
+
+impl HammersbaldRead for MemCache {
+    fn get_keyed (&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        if let Some(cached) = self.cache.lock().unwrap().get(key) {
+            return Ok(Some(cached));
+        }
+        let stored = self.db.get_keyed(key)?;
+        if let Some((pref, ref data)) = stored {
+            self.cache.lock().unwrap().insert(key, pref, data.as_slice());
+        }
+        Ok(stored)
+    }
+
+    fn get_hashed (&self, hash: &[u8; 32]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.get_keyed(hash)
+    }
+
+    fn resolve (&self, id: ContentId) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.get_keyed(&id.key())
+    }
+
+    fn get_many (&self, keys: &[&[u8]]) -> Result<Vec<Option<DataAt>>, Error> {
+        let mut result = Vec::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                let cached = cache.get(key);
+                if cached.is_none() {
+                    missing.push(i);
+                }
+                result.push(cached);
+            }
+        }
+        if !missing.is_empty() {
+            let stored = self.db.get_many(missing.iter().map(|i| keys[*i]).collect::<Vec<_>>().as_slice())?;
+            let mut cache = self.cache.lock().unwrap();
+            for (i, found) in missing.into_iter().zip(stored) {
+                if let Some((pref, ref data)) = found {
+                    cache.insert(keys[i], pref, data.as_slice());
+                }
+                result[i] = found;
+            }
+        }
+        Ok(result)
+    }
+
+    fn get_filtered (&self, key: &[u8], predicate: &dyn Fn(&[u8], &[u8]) -> bool) -> Result<Vec<KeyedDataAt>, Error> {
+        self.db.get_filtered(key, predicate)
+    }
+
+    fn get_keys_only (&self, key: &[u8]) -> Result<Vec<(PRef, Vec<u8>)>, Error> {
+        self.db.get_keys_only(key)
+    }
+
+    fn count (&self, key: &[u8]) -> Result<usize, Error> {
+        self.db.count(key)
+    }
+
+    fn get_nth (&self, key: &[u8], n: usize) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        self.db.get_nth(key, n)
+    }
+
+    fn get_all (&self, key: &[u8]) -> Result<GetIterator<'_>, Error> {
+        self.db.get_all(key)
+    }
+
+    fn get (&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        self.db.get(pref)
+    }
+
+    fn get_stream (&self, pref: PRef) -> Result<Box<dyn Read + '_>, Error> {
+        self.db.get_stream(pref)
+    }
+
+    fn get_ref (&self, pref: PRef) -> Result<ContentRef, Error> {
+        self.db.get_ref(pref)
+    }
+
+    fn get_links (&self, pref: PRef) -> Result<Vec<PRef>, Error> {
+        self.db.get_links(pref)
+    }
+
+    fn entry_info (&self, pref: PRef) -> Result<EntryInfo, Error> {
+        self.db.entry_info(pref)
+    }
+
+    fn verify_links (&self) -> Result<Vec<(PRef, PRef)>, Error> {
+        self.db.verify_links()
+    }
+
+    fn table_stats (&self) -> Result<TableStats, Error> {
+        self.db.table_stats()
+    }
+
+    fn needs_resize (&self) -> Result<bool, Error> {
+        self.db.needs_resize()
+    }
+
+    fn sample_keys (&self, n: usize) -> Result<Vec<(Vec<u8>, usize)>, Error> {
+        self.db.sample_keys(n)
+    }
+
+    fn verify (&self, level: VerifyLevel) -> Result<VerifyReport, Error> {
+        self.db.verify(level)
+    }
+
+    fn probe (&self, key: &[u8]) -> Result<Probe, Error> {
+        self.db.probe(key)
+    }
+
+    fn may_have_key (&self, key: &[u8]) -> Result<bool, Error> {
+        if self.cache.lock().unwrap().contains(key) {
+            return Ok(true);
+        }
+        self.db.may_have_key(key)
+    }
+
+    fn iter (&self) -> HammersbaldIterator<'_> {
+        self.db.iter()
+    }
+
+    fn iter_prefetch (&self, pages: usize) -> HammersbaldIterator<'_> {
+        self.db.iter_prefetch(pages)
+    }
+
+    fn export (&self, writer: &mut dyn Write) -> Result<u64, Error> {
+        self.db.export(writer)
+    }
+
+    fn cursor_at (&self, pos: PRef) -> Result<DataCursor<'_>, Error> {
+        self.db.cursor_at(pos)
+    }
+
+    fn iter_live (&self) -> HammersbaldIterator<'_> {
+        self.db.iter_live()
+    }
+
+    fn iter_keys (&self) -> HammersbaldKeyIterator<'_> {
+        self.db.iter_keys()
+    }
+
+    fn generation (&self) -> Option<u64> {
+        self.db.generation()
+    }
+
+    fn generation_of (&self, pref: PRef) -> Result<Option<u64>, Error> {
+        self.db.generation_of(pref)
+    }
+
+    fn data_len (&self) -> u64 {
+        self.db.data_len()
+    }
+}
+
+// least recently used keyed data within a byte budget
+struct KeyCache {
+    entries: LruCache<Vec<u8>, (PRef, Vec<u8>)>,
+    bytes: usize,
+    budget: usize
+}
+
+impl KeyCache {
+    fn new (budget: usize) -> KeyCache {
+        KeyCache { entries: LruCache::new(usize::MAX), bytes: 0, budget }
+    }
+
+    fn get (&mut self, key: &[u8]) -> Option<(PRef, Vec<u8>)> {
+        self.entries.get_mut(key).map(|(pref, data)| (*pref, data.clone()))
+    }
+
+    fn contains (&mut self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn insert (&mut self, key: &[u8], pref: PRef, data: &[u8]) {
+        self.remove(key);
+        if key.len() + data.len() > self.budget {
+            return;
+        }
+        self.bytes += key.len() + data.len();
+        self.entries.insert(key.to_vec(), (pref, data.to_vec()));
+        while self.bytes > self.budget {
+            match self.entries.remove_lru() {
+                Some((key, (_, data))) => self.bytes -= key.len() + data.len(),
+                None => break
+            }
+        }
+    }
+
+    fn remove (&mut self, key: &[u8]) {
+        if let Some((_, data)) = self.entries.remove(key) {
+            self.bytes -= key.len() + data.len();
+        }
+    }
+
+    fn clear (&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use transient;
+    use super::*;
+
+    #[test]
+    fn test_memcache () {
+        let mut db = MemCache::new(transient(1).unwrap(), 100);
+        let one = db.put_keyed(b"one", &[1u8; 40]).unwrap();
+        let two = db.put_keyed(b"two", &[2u8; 40]).unwrap();
+        assert_eq!(db.cache.lock().unwrap().bytes, 86);
+        // touch one, so two is evicted
+        assert_eq!(db.get_keyed(b"one").unwrap(), Some((one, vec!(1u8; 40))));
+        let three = db.put_keyed(b"three", &[3u8; 40]).unwrap();
+        assert!(!db.cache.lock().unwrap().contains(b"two"));
+        assert!(db.cache.lock().unwrap().bytes <= 100);
+
+        // evicted data is read from the db and cached again
+        assert_eq!(db.get_many(&[b"two", b"three", b"four"]).unwrap(),
+                   vec!(Some((two, vec!(2u8; 40))), Some((three, vec!(3u8; 40))), None));
+        assert!(db.cache.lock().unwrap().contains(b"two"));
+
+        db.forget(b"two").unwrap();
+        assert_eq!(db.get_keyed(b"two").unwrap(), None);
+        // larger than the budget is not cached
+        db.put_keyed(b"large", &[0u8; 200]).unwrap();
+        assert!(!db.cache.lock().unwrap().contains(b"large"));
+        db.shutdown();
+    }
+}
@@ -18,30 +18,73 @@
 //! Specific implementation details to in-memory index of the db
 //!
 //!
-use error::Error;
+use error::{Error, FileKind};
 use pref::PRef;
-use datafile::{DataFile, EnvelopeIterator};
+use datafile::{DataFile, DataStream, EnvelopeIterator, EnvelopeCheck};
 use tablefile::{TableFile, FIRST_PAGE_HEAD, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE, BUCKET_SIZE};
 use logfile::LogFile;
+use bloomfile::{self, BloomFile, BLOOMS_PER_PAGE, UNKNOWN};
 use page::PAGE_SIZE;
-use pagedfile::PagedFile;
-use format::{Link, Payload, Envelope};
+use pagedfile::{PagedFile, PagedFileIterator};
+use format::{Link, Payload, Envelope, DataAt, KeyedDataAt};
 use page::Page;
+use metrics::{Metrics, NoMetrics};
+use stats::Probe;
+use encryption::Cipher;
+use overflow::{Overflow, Chained};
 
 use bitcoin_hashes::siphash24;
 use rand::{thread_rng, RngCore};
 
-use std::collections::HashMap;
+use std::cmp::{max, min, Reverse};
+use std::collections::{HashMap, BTreeSet, BTreeMap};
 use std::fmt;
-use std::cmp::{min, max};
-use std::sync::RwLock;
+use std::io::Read;
+use std::thread;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 const INIT_BUCKETS: usize = 512;
 const INIT_LOGMOD :usize = 8;
+// minimum number of buckets or table pages a flush thread works on
+const MIN_FLUSH_PARTITION: usize = 4096;
+// maximum number of buckets split by a batch growing the table to a requested size
+const RESIZE_STEP: usize = 4096;
+// first siphash key of a table of fixed size keys, the second is the key size
+const FIXED_KEYS: u64 = 0x4649_5845_444b_4559;
+
+/// hash of a key in the table with the siphash keys, the first 8 bytes of fixed size keys are used as they are
+pub fn key_hash (key: &[u8], sip0: u64, sip1: u64) -> u32 {
+    if sip0 == FIXED_KEYS {
+        key.iter().take(8).fold(0u64, |h, b| h << 8 | *b as u64) as u32
+    }
+    else {
+        siphash24::Hash::hash_to_u64_with_keys(sip0, sip1, key) as u32
+    }
+}
+
+/// step and log_mod of the linear hash from the number of buckets and the step field of the first table page.
+/// The field holds log_mod + 1 above the step, as the number of buckets does not tell it after a bulk_load,
+/// tables written before hold the step only.
+pub fn table_geometry (n_buckets: u64, field: u64) -> (usize, u32) {
+    let step = field & 0xFFFF_FFFF;
+    if field >> 32 > 0 {
+        return (step as usize, (field >> 32) as u32 - 1);
+    }
+    // buckets before the step split are at least 2^(log_mod + 1), less than 2^(log_mod + 2)
+    (step as usize, (32 - ((n_buckets - step) as u32).leading_zeros()) - 2)
+}
+
+// slots stored during a bulk load in buckets not read yet, with hash, data and whether to keep both, by bucket
+type Deferred = HashMap<usize, Vec<(u32, PRef, bool)>>;
+// bucket, offset of its link in the serialized links, none if it has no slots, and its filter
+type Written = (usize, Option<usize>, u64);
 
 pub struct MemTable {
     step: usize,
     forget: usize,
+    presized: usize,
     log_mod: u32,
     sip0: u64,
     sip1: u64,
@@ -51,19 +94,100 @@ pub struct MemTable {
     data_file: DataFile,
     table_file: TableFile,
     link_file: DataFile,
-    bucket_fill_target: usize
+    bloom_file: BloomFile,
+    rewrite_blooms: bool,
+    flush_threads: usize,
+    metrics: Arc<dyn Metrics>,
+    bucket_fill_target: usize,
+    resize_to: usize,
+    memory_budget: usize,
+    lru: Mutex<Lru>,
+    overflow: Arc<dyn Overflow>,
+    // slots put during a bulk load into buckets not read yet, with whether earlier data of the key is kept,
+    // merged into the bucket when it is read or in bucket order at the batch
+    deferred: Mutex<Deferred>
 }
 
 impl MemTable {
-    pub fn new (log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bucket_fill_target: usize) -> MemTable {
+    pub fn new (log_file: LogFile, table_file: TableFile, data_file: DataFile, link_file: DataFile, bloom_file: BloomFile, bucket_fill_target: usize) -> MemTable {
         let mut rng = thread_rng();
 
-        MemTable {log_mod: INIT_LOGMOD as u32, step: 0, forget: 0,
+        MemTable {log_mod: INIT_LOGMOD as u32, step: 0, forget: 0, presized: 0,
             sip0: rng.next_u64(),
             sip1: rng.next_u64(),
             buckets: RwLock::new(vec!(Bucket::default(); INIT_BUCKETS)),
             dirty: Dirty::new(INIT_BUCKETS), log_file, table_file, data_file, link_file,
-            bucket_fill_target: max(min(bucket_fill_target, 128), 1)}
+            bloom_file, rewrite_blooms: false,
+            flush_threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            metrics: Arc::new(NoMetrics),
+            bucket_fill_target: bucket_fill_target.clamp(1, 128), resize_to: 0,
+            memory_budget: 0, lru: Mutex::new(Lru::default()), overflow: Arc::new(Chained),
+            deferred: Mutex::new(HashMap::new())}
+    }
+
+    /// number of threads flushing the hash table, 0 for the number of CPUs
+    pub fn set_flush_threads (&mut self, threads: usize) {
+        if threads > 0 {
+            self.flush_threads = threads;
+        }
+    }
+
+    /// limit the bytes of bucket slots in memory, least recently used buckets beyond are read again
+    /// from the link file when needed, 0 for no limit. Buckets modified in a batch stay until written.
+    pub fn set_memory_budget (&mut self, bytes: usize) {
+        self.memory_budget = bytes;
+    }
+
+    /// arrange the slots of buckets with the overflow strategy, buckets in memory are arranged again
+    pub fn set_overflow (&mut self, overflow: Arc<dyn Overflow>) {
+        for bucket in self.buckets.get_mut().unwrap().iter_mut() {
+            if let Some(slots) = bucket.slots.take() {
+                bucket.slots = Some(overflow.arrange(self.overflow.stored(&slots)));
+            }
+        }
+        self.overflow = overflow;
+    }
+
+    /// bytes of bucket slots in memory, only counted if memory is limited
+    #[cfg(test)]
+    pub fn resident_bytes (&self) -> usize {
+        self.lru.lock().unwrap().bytes
+    }
+
+    /// store keys of the size only, without their length, and use their first bytes as hash, e.g. for
+    /// keys that are hashes. The size is recorded in the table, a db with keys fails unless it has the size
+    /// or it has no keys yet, e.g. as its table is rebuilt
+    pub fn set_fixed_key_size (&mut self, size: usize) -> Result<(), Error> {
+        let keyed = self.buckets.get_mut().unwrap().iter()
+            .any(|bucket| bucket.stored.is_valid() || bucket.slots.as_ref().is_some_and(|slots| !slots.is_empty()));
+        if !keyed {
+            self.sip0 = FIXED_KEYS;
+            self.sip1 = size as u64;
+            self.data_file.set_fixed_keys(true);
+        }
+        else if self.fixed_key_size() != size {
+            return Err(Error::Corrupted(format!("db was created with fixed key size {} not {}", self.fixed_key_size(), size)));
+        }
+        Ok(())
+    }
+
+    /// size of all keys, 0 if keys are of any size
+    pub fn fixed_key_size (&self) -> usize {
+        if self.sip0 == FIXED_KEYS { self.sip1 as usize } else { 0 }
+    }
+
+    /// check the size of a key to store
+    pub fn check_key (&self, key: &[u8]) -> Result<(), Error> {
+        let size = self.fixed_key_size();
+        if size > 0 && key.len() != size {
+            return Err(Error::DoesNotFit { what: "key length of fixed size keys", max: size as u64, got: key.len() as u64 });
+        }
+        Ok(())
+    }
+
+    /// report batches, syncs and lookups to metrics
+    pub fn set_metrics (&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = metrics;
     }
 
     pub fn params(&self) -> (usize, u32, usize, u64, u64, u64, u64, u64) {
@@ -71,38 +195,235 @@ impl MemTable {
         self.sip0, self.sip1)
     }
 
+    /// average re-use of a hash table entry the table grows for
+    pub fn bucket_fill_target (&self) -> usize {
+        self.bucket_fill_target
+    }
+
+    /// grow the hash table to at least the number of buckets
+    /// buckets are split incrementally by the next batches
+    pub fn resize (&mut self, buckets: usize) {
+        self.resize_to = max(self.resize_to, min(buckets, 1 << 31));
+    }
+
     /// end current batch and start a new batch
     pub fn batch (&mut self)  -> Result<(), Error> {
+        let start = Instant::now();
+        self.grow()?;
+        let data_before = self.data_file.len()?;
+        let link_before = self.link_file.len()?;
+
+        // the first page is rewritten with the table size at every flush
+        self.log_file.log_page(PRef::from(0), &self.table_file)?;
         self.log_file.flush()?;
-        self.log_file.sync()?;
+        Self::sync_timed(self.metrics.as_ref(), || self.log_file.sync())?;
+        let log_pages = self.log_file.len()? / PAGE_SIZE as u64;
 
-        self.flush()?;
+        let bloom_pages = self.bloom_pages();
+        let table_pages = self.flush()?;
         self.dirty.clear();
 
-        self.table_file.sync()?;
+        Self::sync_timed(self.metrics.as_ref(), || self.table_file.sync())?;
         let table_len = self.table_file.len()?;
 
-        self.link_file.sync()?;
+        Self::sync_timed(self.metrics.as_ref(), || self.link_file.sync())?;
         let link_len = self.link_file.len()?;
 
         self.data_file.flush()?;
-        self.data_file.sync()?;
+        Self::sync_timed(self.metrics.as_ref(), || self.data_file.sync())?;
         let data_len = self.data_file.len()?;
 
+        if !bloom_pages.is_empty() {
+            self.bloom_file.invalidate()?;
+            self.write_blooms(&bloom_pages)?;
+        }
+
         self.log_file.reset(table_len);
         self.log_file.init(data_len, table_len, link_len)?;
         self.log_file.flush()?;
         self.log_file.sync()?;
 
+        if !bloom_pages.is_empty() {
+            self.bloom_file.validate()?;
+            self.rewrite_blooms = false;
+        }
+
+        let pages_written = log_pages + table_pages + (data_len - data_before + link_len - link_before) / PAGE_SIZE as u64;
+        self.metrics.batch(start.elapsed(), pages_written);
+        Ok(())
+    }
+
+    // sync a file and report the time it took
+    fn sync_timed<F> (metrics: &dyn Metrics, sync: F) -> Result<(), Error> where F: FnOnce() -> Result<(), Error> {
+        let start = Instant::now();
+        sync()?;
+        metrics.sync(start.elapsed());
+        Ok(())
+    }
+
+    // pages of the bloom file with filters of modified buckets
+    fn bloom_pages (&self) -> BTreeSet<usize> {
+        let n_buckets = self.buckets.read().unwrap().len();
+        if self.rewrite_blooms {
+            return (0 .. n_buckets.div_ceil(BLOOMS_PER_PAGE)).collect();
+        }
+        DirtyIterator::new(&self.dirty).enumerate().filter(|a| a.1 && a.0 < n_buckets)
+            .map(|(bucket_number, _)| bucket_number / BLOOMS_PER_PAGE).collect()
+    }
+
+    fn write_blooms (&mut self, pages: &BTreeSet<usize>) -> Result<(), Error> {
+        let blooms = self.buckets.read().unwrap().iter().map(|b| b.bloom).collect::<Vec<_>>();
+        for page_number in pages {
+            let start = page_number * BLOOMS_PER_PAGE;
+            let end = std::cmp::min(start + BLOOMS_PER_PAGE, blooms.len());
+            self.bloom_file.write_page(*page_number, &blooms[start .. end])?;
+        }
+        self.bloom_file.flush()
+    }
+
+    /// record the last batch boundary as checkpoint with the id
+    pub fn checkpoint (&mut self, id: u64) -> Result<(), Error> {
+        let data_len = self.data_file.len()?;
+        let table_len = self.table_file.len()?;
+        let link_len = self.link_file.len()?;
+        self.log_file.checkpoint(id, data_len, table_len, link_len)
+    }
+
+    /// drop everything stored after the last checkpoint with the id
+    pub fn rollback_to (&mut self, id: u64) -> Result<(), Error> {
+        let undo = self.log_file.undo_to(id)?.ok_or(Error::UnknownCheckpoint(id))?;
+        // drain background writes before files are truncated
+        self.data_file.flush()?;
+        self.link_file.flush()?;
+        // filters of the checkpoint state are not known
+        self.bloom_file.invalidate()?;
+        // undo is logged first so it is completed by recovery if interrupted
+        self.log_file.log_undo(&undo)?;
+        self.recover()?;
+        self.log_file.undone(&undo)?;
+        // pages logged in the dropped batch have to be logged again
+        self.log_file.reset(undo.table_len);
+
+        self.clear();
+        self.load()
+    }
+
+    /// rebuild hash table and link file from keyed data of the data file
+    /// the last data stored with a key wins, forgotten keys are restored as forget is not
+    /// recorded in the data file. Checkpoints are dropped.
+    pub fn rebuild (&mut self) -> Result<(), Error> {
+        self.data_file.flush()?;
+        self.link_file.flush()?;
+        let data_len = self.data_file.len()?;
+        self.bloom_file.invalidate()?;
+        self.log_file.forget_checkpoints()?;
+        // an interrupted rebuild recovers to an empty table
+        self.log_file.init(data_len, 0, 0)?;
+        self.log_file.flush()?;
+        self.log_file.sync()?;
+        self.table_file.truncate(0)?;
+        self.link_file.truncate(0)?;
+        self.log_file.reset(0);
+        self.clear();
+
+        let mut keyed = Vec::new();
+        for envelope in self.data_file.envelopes() {
+            let (pref, envelope) = envelope?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                keyed.push((indexed.key.to_vec(), pref));
+            }
+        }
+        self.bulk_load(keyed.len())?;
+        for (key, pref) in keyed {
+            self.put(key.as_slice(), pref)?;
+        }
+        self.rewrite_blooms = true;
+        self.batch()
+    }
+
+    // empty hash table in memory
+    fn clear (&mut self) {
+        self.step = 0;
+        self.forget = 0;
+        self.presized = 0;
+        self.log_mod = INIT_LOGMOD as u32;
+        self.buckets = RwLock::new(vec!(Bucket::default(); INIT_BUCKETS));
+        self.dirty = Dirty::new(INIT_BUCKETS);
+        *self.lru.get_mut().unwrap() = Lru::default();
+        self.deferred.get_mut().unwrap().clear();
+    }
+
+    /// end current batch and hard link whole chunks of the data and link files as of the batch
+    /// boundary into the files of the snapshot with the name, rolled at the chunk size
+    pub fn snapshot_link (&mut self, name: &str, chunk_size: u64) -> Result<Snapshot, Error> {
+        self.batch()?;
+        let data_len = self.data_file.len()?;
+        let link_len = self.link_file.len()?;
+        Ok(Snapshot {
+            data_len, table_len: self.table_file.len()?, link_len,
+            data_linked: self.data_file.link_chunks(name, data_len, chunk_size)?,
+            link_linked: self.link_file.link_chunks(name, link_len, chunk_size)?
+        })
+    }
+
+    /// copy the table and what was not linked of the data and link files into the files of the snapshot
+    pub fn snapshot_copy (&self, snapshot: &Snapshot, log: &mut LogFile, table: &mut dyn PagedFile, data: &mut dyn PagedFile, link: &mut dyn PagedFile) -> Result<(), Error> {
+        for page in PagedFileIterator::new(&self.table_file, PRef::from(0)).take((snapshot.table_len / PAGE_SIZE as u64) as usize) {
+            table.update_page(page)?;
+        }
+        for page in self.data_file.page_iter_from(PRef::from(snapshot.data_linked))
+            .take(((snapshot.data_len - snapshot.data_linked) / PAGE_SIZE as u64) as usize) {
+            data.append_page(page)?;
+        }
+        for page in self.link_file.page_iter_from(PRef::from(snapshot.link_linked))
+            .take(((snapshot.link_len - snapshot.link_linked) / PAGE_SIZE as u64) as usize) {
+            link.append_page(page)?;
+        }
+        table.flush()?;
+        table.sync()?;
+        data.flush()?;
+        data.sync()?;
+        link.flush()?;
+        link.sync()?;
+
+        log.init(snapshot.data_len, snapshot.table_len, snapshot.link_len)?;
+        log.sync()?;
         Ok(())
     }
 
+    /// end current batch and find pages changed since the last checkpoint with the id
+    /// data and link files are appended to, table pages changed have a pre-image in the checkpoint file
+    pub fn changed_since (&mut self, id: u64) -> Result<Changes, Error> {
+        self.batch()?;
+        let undo = self.log_file.undo_to(id)?.ok_or(Error::UnknownCheckpoint(id))?;
+        let now = (self.data_file.len()?, self.table_file.len()?, self.link_file.len()?);
+        // the first page has the size of the table, pages beyond the table of the checkpoint are new
+        let mut table_pages = undo.pages.iter().map(|page| page.pref()).collect::<BTreeSet<_>>();
+        table_pages.insert(PRef::from(0));
+        let mut pos = PRef::from(undo.table_len);
+        while pos.as_u64() < now.1 {
+            table_pages.insert(pos);
+            pos = pos.add_pages(1);
+        }
+        Ok(Changes { since: (undo.data_len, undo.table_len, undo.link_len), now, table_pages: table_pages.into_iter().collect() })
+    }
+
+    /// pages of the data, link or table file starting with the one at pos
+    pub fn page_iter_from (&self, file: FileKind, pos: PRef) -> PagedFileIterator<'_> {
+        match file {
+            FileKind::Data => self.data_file.page_iter_from(pos),
+            FileKind::Link => self.link_file.page_iter_from(pos),
+            _ => PagedFileIterator::new(&self.table_file, pos)
+        }
+    }
+
     /// stop background writer
     pub fn shutdown (&mut self) {
         self.data_file.shutdown();
         self.link_file.shutdown();
         self.table_file.shutdown();
         self.log_file.shutdown();
+        self.bloom_file.shutdown();
     }
 
     pub fn recover(&mut self) -> Result<(), Error> {
@@ -139,10 +460,13 @@ impl MemTable {
             let n_buckets = first.read_pref(0).as_u64() as u32;
             self.buckets = RwLock::new(vec![Bucket::default(); n_buckets as usize]);
             self.dirty = Dirty::new(n_buckets as usize);
-            self.step = first.read_pref(6).as_u64() as usize;
-            self.log_mod = (32 - n_buckets.leading_zeros()) as u32 - 2;
+            *self.lru.get_mut().unwrap() = Lru::default();
+            let (step, log_mod) = table_geometry(n_buckets as u64, first.read_pref(6).as_u64());
+            self.step = step;
+            self.log_mod = log_mod;
             self.sip0 = first.read_u64(12);
             self.sip1 = first.read_u64(20);
+            self.data_file.set_fixed_keys(self.sip0 == FIXED_KEYS);
         }
 
         let mut buckets = self.buckets.write().unwrap();
@@ -156,57 +480,225 @@ impl MemTable {
             }
         }
 
+        let blooms = self.bloom_file.load(buckets.len())?;
+        self.rewrite_blooms = blooms.is_none();
+        for (i, bucket) in buckets.iter_mut().enumerate() {
+            // an empty bucket has an empty filter
+            bucket.bloom = if !bucket.stored.is_valid() { 0 } else if let Some(ref blooms) = blooms { blooms[i] } else { UNKNOWN };
+        }
+
         Ok(())
     }
 
     fn resolve_bucket(&self, bucket_number: usize) -> Result<(), Error> {
-        if let Some(bucket) = self.buckets.write().unwrap().get_mut(bucket_number) {
-            if bucket.slots.is_none () {
-                if bucket.stored.is_valid() {
-                    if let Ok(Payload::Link(link)) = Payload::deserialize(self.link_file.get_envelope(bucket.stored)?.payload()) {
-                        bucket.slots = Some(link.slots());
+        let mut buckets = self.buckets.write().unwrap();
+        self.resolve_in(&mut buckets, bucket_number)
+    }
+
+    // read a bucket with resolved slots under one lock, so it is not evicted in between
+    fn read_bucket<T, F> (&self, bucket_number: usize, read: F) -> Result<T, Error> where F: FnOnce(&Bucket) -> T {
+        let mut buckets = self.buckets.write().unwrap();
+        self.resolve_in(&mut buckets, bucket_number)?;
+        match buckets.get(bucket_number) {
+            Some(bucket) => Ok(read(bucket)),
+            None => Err(Error::Bug(format!("bucket {} should exist", bucket_number)))
+        }
+    }
+
+    fn resolve_in(&self, buckets: &mut [Bucket], bucket_number: usize) -> Result<(), Error> {
+        if let Some(bucket) = buckets.get_mut(bucket_number) {
+            if bucket.slots.is_none () && bucket.stored.is_valid() {
+                if let Ok(Payload::Link(link)) = Payload::deserialize(self.link_file.get_envelope(bucket.stored)?.payload()) {
+                    let mut slots = link.slots();
+                    if let Some(deferred) = self.deferred.lock().unwrap().remove(&bucket_number) {
+                        self.merge_deferred(&mut slots, deferred)?;
                     }
+                    bucket.bloom = bloomfile::bloom(slots.iter().map(|(hash, _)| hash));
+                    bucket.slots = Some(self.overflow.arrange(slots));
                 }
             }
+            if self.memory_budget > 0 {
+                let bytes = bucket.slots.as_ref().map_or(0, |slots| slots.capacity() * mem::size_of::<(u32, PRef)>());
+                let mut lru = self.lru.lock().unwrap();
+                lru.touch(bucket_number, bytes);
+                self.evict(buckets, &mut lru, Some(bucket_number));
+            }
+        }
+        Ok(())
+    }
+
+    // drop slots of least recently used buckets not modified until within the memory budget
+    // add deferred slots to slots read, a slot not keeping earlier data replaces the slot of its key
+    fn merge_deferred(&self, slots: &mut Vec<(u32, PRef)>, deferred: Vec<(u32, PRef, bool)>) -> Result<(), Error> {
+        for (hash, pref, also) in deferred {
+            if !also && slots.iter().any(|(h, _)| *h == hash) {
+                let key = self.key_at(pref)?;
+                let mut replaced = false;
+                for slot in slots.iter_mut().filter(|(h, _)| *h == hash) {
+                    if self.key_at(slot.1)? == key {
+                        slot.1 = pref;
+                        replaced = true;
+                        break;
+                    }
+                }
+                if replaced {
+                    continue;
+                }
+            }
+            slots.push((hash, pref));
         }
         Ok(())
     }
 
-    pub fn flush (&mut self) -> Result<(), Error> {
+    // key of keyed data
+    fn key_at(&self, pref: PRef) -> Result<Option<Vec<u8>>, Error> {
+        if let Payload::Indexed(indexed) = Payload::deserialize(self.data_file.get_envelope(pref)?.payload())? {
+            return Ok(Some(indexed.key.to_vec()));
+        }
+        Ok(None)
+    }
+
+    fn evict(&self, buckets: &mut [Bucket], lru: &mut Lru, keep: Option<usize>) {
+        if lru.bytes <= self.memory_budget {
+            return;
+        }
+        let mut excess = lru.bytes - self.memory_budget;
+        let mut victims = Vec::new();
+        for (_, bucket_number) in lru.by_use.iter() {
+            if excess == 0 {
+                break;
+            }
+            if Some(*bucket_number) != keep && *bucket_number < buckets.len() && !self.dirty.get(*bucket_number) {
+                excess = excess.saturating_sub(lru.used[bucket_number].1);
+                victims.push(*bucket_number);
+            }
+        }
+        for bucket_number in victims {
+            lru.remove(bucket_number);
+            buckets[bucket_number].slots = None;
+        }
+    }
+
+    /// write modified buckets, returns the number of table pages written
+    pub fn flush (&mut self) -> Result<u64, Error> {
+        let mut table_pages = 1;
         {
             // first page
             let fp = PRef::from(0);
             let mut page = self.table_file.read_page(fp)?.unwrap_or(Self::invalid_offsets_page(fp));
             page.write_pref(0, PRef::from(self.buckets.read().unwrap().len() as u64));
-            page.write_pref(6, PRef::from(self.step as u64));
+            page.write_pref(6, PRef::from(self.step as u64 | (self.log_mod as u64 + 1) << 32));
             page.write_u64(12, self.sip0);
             page.write_u64(20, self.sip1);
             self.table_file.update_page(page)?;
         }
         if self.dirty.is_dirty() {
-            let dirty_iterator = DirtyIterator::new(&self.dirty);
-            for (bucket_number, _) in dirty_iterator.enumerate().filter(|a| a.1) {
-                let bucket_pref= TableFile::table_offset(bucket_number);
-                if let Some(mut bucket) = self.buckets.write().unwrap().get_mut(bucket_number) {
-                    let mut page = self.table_file.read_page(bucket_pref.this_page())?.unwrap_or(Self::invalid_offsets_page(bucket_pref.this_page()));
-                    if let Some (ref slots) = bucket.slots {
-                        let link = if slots.len() > 0 {
-                            let slots = Link::from_slots(slots.as_slice());
-                            self.link_file.append_link(Link::deserialize(slots.as_slice()))?
-                        } else {
-                            PRef::invalid()
-                        };
-                        bucket.stored = link;
-                        page.write_pref(bucket_pref.in_page_pos(), link);
-                        self.table_file.update_page(page)?;
+            // slots deferred by a bulk load are merged in bucket order, so links are read once
+            let mut deferred = self.deferred.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+            deferred.sort_unstable();
+            for bucket_number in deferred {
+                self.resolve_bucket(bucket_number)?;
+            }
+            let modified = DirtyIterator::new(&self.dirty).enumerate().filter(|a| a.1).map(|a| a.0).collect::<Vec<_>>();
+            let partitions = self.partitions(modified.len());
+
+            // serialize links of bucket ranges in parallel
+            let serialized = {
+                let buckets = self.buckets.read().unwrap();
+                let buckets = &buckets;
+                let overflow = self.overflow.as_ref();
+                thread::scope(|scope| {
+                    let workers = modified.chunks(partitions).map(|range|
+                        scope.spawn(move || Self::serialize_links(buckets, range, overflow))).collect::<Vec<_>>();
+                    workers.into_iter().map(|w| w.join().expect("link serializer panicked")).collect::<Vec<_>>()
+                })
+            };
+
+            // merge in bucket order, links are appended contiguously
+            let mut patches = Vec::with_capacity(modified.len());
+            {
+                let mut buckets = self.buckets.write().unwrap();
+                for (links, written) in serialized {
+                    let base = self.link_file.append_serialized(links.as_slice())?;
+                    for (bucket_number, offset, bloom) in written {
+                        let link = offset.map_or(PRef::invalid(), |o| base + o as u64);
+                        buckets[bucket_number].stored = link;
+                        buckets[bucket_number].bloom = bloom;
+                        patches.push((bucket_number, link));
                     }
                 }
             }
+
+            // patch table pages in parallel, then write them in order
+            let mut by_page = BTreeMap::new();
+            for (bucket_number, link) in patches {
+                let bucket_pref = TableFile::table_offset(bucket_number);
+                by_page.entry(bucket_pref.this_page()).or_insert_with(Vec::new).push((bucket_pref.in_page_pos(), link));
+            }
+            let by_page = by_page.into_iter().collect::<Vec<_>>();
+            let page_partitions = self.partitions(by_page.len());
+            let table_file = &self.table_file;
+            let pages = thread::scope(|scope| {
+                let workers = by_page.chunks(page_partitions).map(|range|
+                    scope.spawn(move || -> Result<Vec<Page>, Error> {
+                        let mut pages = Vec::with_capacity(range.len());
+                        for (pref, patch) in range {
+                            let mut page = table_file.read_page(*pref)?.unwrap_or(Self::invalid_offsets_page(*pref));
+                            for (pos, link) in patch {
+                                page.write_pref(*pos, *link);
+                            }
+                            pages.push(page);
+                        }
+                        Ok(pages)
+                    })).collect::<Vec<_>>();
+                workers.into_iter().map(|w| w.join().expect("table patcher panicked")).collect::<Vec<_>>()
+            });
+            for range in pages {
+                for page in range? {
+                    self.table_file.update_page(page)?;
+                    table_pages += 1;
+                }
+            }
         }
         self.dirty.clear();
+        if self.memory_budget > 0 {
+            // buckets written can be read again
+            let mut buckets = self.buckets.write().unwrap();
+            self.evict(&mut buckets, &mut self.lru.lock().unwrap(), None);
+        }
         self.link_file.flush()?;
         self.table_file.flush()?;
-        Ok(())
+        Ok(table_pages)
+    }
+
+    // size of work partitions for flush threads
+    fn partitions (&self, work: usize) -> usize {
+        let threads = std::cmp::max(std::cmp::min(self.flush_threads, work / MIN_FLUSH_PARTITION), 1);
+        std::cmp::max(work.div_ceil(threads), 1)
+    }
+
+    // serialized links of buckets with slots, with (bucket, link offset, filter) of each
+    fn serialize_links (buckets: &[Bucket], range: &[usize], overflow: &dyn Overflow) -> (Vec<u8>, Vec<Written>) {
+        let mut links = Vec::new();
+        let mut written = Vec::with_capacity(range.len());
+        for bucket_number in range {
+            if let Some(ref slots) = buckets[*bucket_number].slots {
+                let slots = overflow.stored(slots);
+                let bloom = bloomfile::bloom(slots.iter().map(|(hash, _)| hash));
+                if !slots.is_empty() {
+                    let offset = links.len();
+                    let slots = Link::from_slots(slots.as_slice());
+                    let mut payload = vec!();
+                    Payload::Link(Link::deserialize(slots.as_slice())).serialize(&mut payload);
+                    Envelope::new(payload.as_slice()).serialize(&mut links);
+                    written.push((*bucket_number, Some(offset), bloom));
+                }
+                else {
+                    written.push((*bucket_number, None, bloom));
+                }
+            }
+        }
+        (links, written)
     }
 
     pub fn invalid_offsets_page(pos: PRef) -> Page {
@@ -224,10 +716,19 @@ impl MemTable {
         page
     }
 
-    pub fn slots<'a>(&'a self) -> impl Iterator<Item=Vec<(u32, PRef)>> +'a {
+    pub fn slots<'a>(&'a self) -> impl Iterator<Item=Result<Vec<(u32, PRef)>, Error>> +'a {
         BucketIterator{file: self, n:0}
     }
 
+    /// slots of a bucket, read from the link file if not yet in memory
+    pub fn bucket_slots(&self, bucket_number: usize) -> Result<Vec<(u32, PRef)>, Error> {
+        self.read_bucket(bucket_number, |bucket| bucket.slots.as_ref().map_or(vec!(), |slots| self.overflow.stored(slots)))
+    }
+
+    pub fn n_buckets(&self) -> usize {
+        self.buckets.read().unwrap().len()
+    }
+
     pub fn buckets<'a>(&'a self) -> impl Iterator<Item=PRef> +'a {
         self.table_file.iter()
     }
@@ -236,7 +737,78 @@ impl MemTable {
         self.data_file.envelopes()
     }
 
-    pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=(PRef, Envelope)> +'a {
+    pub fn data_envelopes_from(&self, pos: PRef) -> EnvelopeIterator<'_> {
+        self.data_file.envelopes_from(pos)
+    }
+
+    pub fn check_data_boundary(&self, pos: PRef) -> Result<(), Error> {
+        self.data_file.check_boundary(pos)
+    }
+
+    pub fn data_position(&self) -> PRef {
+        self.data_file.position()
+    }
+
+    pub fn data_len(&self) -> Result<u64, Error> {
+        self.data_file.len()
+    }
+
+    /// bytes of the data, including data not yet batched, and of the table, link and log files
+    pub fn file_bytes(&self) -> Result<u64, Error> {
+        Ok(self.data_position().as_u64() + self.table_file.len()? + self.link_file.len()? + self.log_file.len()?)
+    }
+
+    /// most bytes the next batch adds to the files: links of modified buckets, their table pages
+    /// logged, table pages of new buckets and the partial pages completed
+    pub fn batch_bytes(&self) -> Result<u64, Error> {
+        let buckets = self.buckets.read().unwrap();
+        let mut links = 0;
+        let mut pages = BTreeSet::new();
+        for (n, _) in DirtyIterator::new(&self.dirty).enumerate().filter(|a| a.1) {
+            // an envelope of 10 bytes a slot
+            links += buckets.get(n).and_then(|b| b.slots.as_ref()).map_or(0, |slots| 10 * slots.len() as u64 + 8);
+            pages.insert(TableFile::table_offset(n).this_page());
+        }
+        let table_end = TableFile::table_offset(max(buckets.len(), self.resize_to)).this_page().as_u64() + PAGE_SIZE as u64;
+        let grown = table_end.saturating_sub(self.table_file.len()?);
+        Ok(links + (pages.len() as u64 + 1) * PAGE_SIZE as u64 + grown + 2 * PAGE_SIZE as u64)
+    }
+
+    // references of data reachable through a key, in bucket order, ends with the error of a bucket that can not be read
+    fn live_prefs<'a>(&'a self) -> impl Iterator<Item=Result<PRef, Error>> +'a {
+        self.slots().flat_map(|slots| {
+            let (slots, error) = match slots {
+                Ok(slots) => (slots, None),
+                Err(e) => (vec!(), Some(e))
+            };
+            slots.into_iter().map(|(_, pref)| Ok(pref)).chain(error.map(Err))
+        })
+    }
+
+    /// envelopes of data reachable through a key, in bucket order
+    pub fn live_envelopes<'a>(&'a self) -> impl Iterator<Item=Result<(PRef, Envelope), Error>> +'a {
+        self.live_prefs().map(move |pref|
+            pref.and_then(|pref| self.data_file.get_envelope(pref).map(|envelope| (pref, envelope))))
+    }
+
+    /// keys of data reachable through a key, in bucket order, data is not read
+    pub fn live_keys<'a>(&'a self) -> impl Iterator<Item=Result<(PRef, Vec<u8>), Error>> +'a {
+        self.live_prefs().map(move |pref|
+            pref.and_then(|pref| self.data_file.get_key(pref).map(|key| (pref, key))))
+    }
+
+    /// lengths of data, table and link files recorded in the log at the start of the batch
+    pub fn logged_lengths(&self) -> Result<Option<(u64, u64, u64)>, Error> {
+        Ok(self.log_file.read_page(PRef::from(0))?.map(|page|
+            (page.read_pref(0).as_u64(), page.read_pref(6).as_u64(), page.read_pref(12).as_u64())))
+    }
+
+    /// decode all envelopes of the data and the link file
+    pub fn check_envelopes(&self) -> (EnvelopeCheck, EnvelopeCheck) {
+        (self.data_file.check_envelopes(), self.link_file.check_envelopes())
+    }
+
+    pub fn link_envelopes<'a>(&'a self) -> impl Iterator<Item=Result<(PRef, Envelope), Error>> +'a {
         self.link_file.envelopes()
     }
 
@@ -244,62 +816,192 @@ impl MemTable {
         self.data_file.append_data(key, data)
     }
 
+    pub fn append_data_batch (&mut self, entries: &[(&[u8], &[u8])]) -> Result<Vec<PRef>, Error> {
+        self.data_file.append_data_batch(entries)
+    }
+
+    pub fn pad_data_to (&mut self, pos: PRef) -> Result<(), Error> {
+        self.data_file.pad_to(pos)
+    }
+
     pub fn append_referred (&mut self, data: &[u8]) -> Result<PRef, Error> {
         self.data_file.append_referred(data)
     }
 
+    pub fn append_stream (&mut self, reader: &mut dyn Read, len: u64) -> Result<PRef, Error> {
+        self.data_file.append_stream(reader, len)
+    }
+
+    pub fn get_stream (&self, pref: PRef) -> Result<DataStream<'_>, Error> {
+        self.data_file.get_stream(pref)
+    }
+
+    pub fn append_linked (&mut self, data: &[u8], links: &[PRef]) -> Result<PRef, Error> {
+        self.data_file.append_linked(data, links)
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.data_file.cipher()
+    }
+
     pub fn get_envelope(&self, pref: PRef) -> Result<Envelope, Error> {
         self.data_file.get_envelope(pref)
     }
 
+    pub fn get_head(&self, pref: PRef) -> Result<(usize, u8, usize), Error> {
+        self.data_file.get_head(pref)
+    }
+
     pub fn put (&mut self, key: &[u8], data_offset: PRef) -> Result<(), Error>{
+        self.check_key(key)?;
         let hash = self.hash(key);
         let bucket = self.bucket_for_hash(hash);
+        if self.defer(hash, bucket, data_offset, false)? {
+            return Ok(());
+        }
 
-        self.remove_duplicate(key, hash, bucket)?;
+        // an overwrite replaces the slot of the key, so it does not grow the table
+        if self.replace_duplicate(key, hash, bucket, Some(data_offset))? {
+            return Ok(());
+        }
+        self.insert(hash, bucket, data_offset)
+    }
 
-        self.store_to_bucket(bucket, hash, data_offset)?;
+    /// add a key without removing data stored earlier with it
+    pub fn put_also (&mut self, key: &[u8], data_offset: PRef) -> Result<(), Error>{
+        self.check_key(key)?;
+        let hash = self.hash(key);
+        let bucket = self.bucket_for_hash(hash);
+        if self.defer(hash, bucket, data_offset, true)? {
+            return Ok(());
+        }
+        self.insert(hash, bucket, data_offset)
+    }
 
-        if self.forget == 0 {
-            if hash % self.bucket_fill_target as u32 == 0 && self.step < (1 << 31) {
-                if self.step < (1 << self.log_mod) {
-                    let step = self.step;
-                    self.rehash_bucket(step)?;
-                }
+    // during a bulk load a slot of a bucket not read yet is deferred, so the put does not read its link
+    fn defer (&mut self, hash: u32, bucket_number: usize, data_offset: PRef, also: bool) -> Result<bool, Error> {
+        if self.presized == 0 {
+            return Ok(false);
+        }
+        match self.buckets.write().unwrap().get_mut(bucket_number) {
+            Some(bucket) if bucket.slots.is_none() && bucket.stored.is_valid() => bucket.bloom |= bloomfile::bloom_bits(hash),
+            _ => return Ok(false)
+        }
+        self.deferred.lock().unwrap().entry(bucket_number).or_default().push((hash, data_offset, also));
+        self.presized -= 1;
+        self.modify_bucket(bucket_number)?;
+        Ok(true)
+    }
 
-                self.step += 1;
-                if self.step > (1 << (self.log_mod + 1)) {
-                    self.log_mod += 1;
-                    self.step = 0;
-                }
+    fn insert (&mut self, hash: u32, bucket: usize, data_offset: PRef) -> Result<(), Error>{
+        self.store_to_bucket(bucket, hash, data_offset)?;
 
-                self.buckets.write().unwrap().push(Bucket::default());
-                self.dirty.append();
+        if self.forget == 0 && self.presized == 0 {
+            if hash.is_multiple_of(self.bucket_fill_target as u32) && self.step < (1 << 31) {
+                self.split()?;
             }
         }
-        else {
+        else if self.forget > 0 {
             self.forget -= 1;
         }
+        else {
+            self.presized -= 1;
+        }
+        Ok(())
+    }
+
+    // add a bucket, splitting the next bucket of the linear hash
+    fn split(&mut self) -> Result<(), Error> {
+        if self.step < (1 << self.log_mod) {
+            let step = self.step;
+            self.rehash_bucket(step)?;
+        }
+
+        self.step += 1;
+        if self.step > (1 << (self.log_mod + 1)) {
+            self.log_mod += 1;
+            self.step = 0;
+        }
+
+        self.buckets.write().unwrap().push(Bucket::default());
+        self.dirty.append();
+        Ok(())
+    }
+
+    // split up to RESIZE_STEP buckets towards a requested table size
+    fn grow(&mut self) -> Result<(), Error> {
+        let mut split = 0;
+        while self.buckets.read().unwrap().len() < self.resize_to && split < RESIZE_STEP && self.step < (1 << 31) {
+            self.split()?;
+            split += 1;
+        }
+        if self.buckets.read().unwrap().len() >= self.resize_to {
+            self.resize_to = 0;
+        }
+        Ok(())
+    }
+
+    /// size the hash table for the expected number of additional keys
+    /// existing keys are rehashed and the table does not grow with the next expected_keys puts.
+    /// The table is split to 2^(log_mod + 1) buckets, all of them addressed.
+    pub fn bulk_load(&mut self, expected_keys: usize) -> Result<(), Error> {
+        let have = self.buckets.read().unwrap().len();
+        let need = have + expected_keys / self.bucket_fill_target;
+        let mut log_mod = INIT_LOGMOD as u32;
+        while (1usize << (log_mod + 1)) < need && log_mod < 30 {
+            log_mod += 1;
+        }
+        if need > have && (1usize << (log_mod + 1)) > have {
+            let mut slots = Vec::new();
+            for bucket in 0 .. have {
+                self.resolve_bucket(bucket)?;
+                if let Some(ref mut b) = self.buckets.write().unwrap().get_mut(bucket) {
+                    if let Some(ref s) = b.slots {
+                        slots.extend(self.overflow.stored(s));
+                    }
+                }
+            }
+            let n_buckets = 1usize << (log_mod + 1);
+            // every bucket below 2^log_mod is split
+            self.log_mod = log_mod;
+            self.step = 1 << log_mod;
+            // old buckets are rewritten, even if they become empty
+            let mut buckets = vec!(Bucket::default(); n_buckets);
+            for bucket in buckets.iter_mut().take(have) {
+                bucket.slots = Some(self.overflow.arrange(vec!()));
+            }
+            *self.buckets.write().unwrap() = buckets;
+            self.dirty = Dirty::new(n_buckets);
+            *self.lru.get_mut().unwrap() = Lru::default();
+            for bucket in 0 .. have {
+                self.modify_bucket(bucket)?;
+            }
+            for (hash, pref) in slots {
+                let bucket = self.bucket_for_hash(hash);
+                self.store_to_bucket(bucket, hash, pref)?;
+            }
+        }
+        self.presized += expected_keys;
         Ok(())
     }
 
     pub fn forget(&mut self, key: &[u8]) -> Result<(), Error> {
         let hash = self.hash(key);
         let bucket = self.bucket_for_hash(hash);
-        if self.remove_duplicate(key, hash, bucket)? {
+        if self.replace_duplicate(key, hash, bucket, None)? {
             self.forget += 1;
         }
         Ok(())
     }
 
-    fn remove_duplicate(&mut self, key: &[u8], hash: u32, bucket_number: usize) -> Result<bool, Error> {
+    // replace the slot of the key with the data or remove it if None, returns if the key was found
+    fn replace_duplicate(&mut self, key: &[u8], hash: u32, bucket_number: usize, with: Option<PRef>) -> Result<bool, Error> {
         let mut remove = None;
         self.resolve_bucket(bucket_number)?;
         if let Some(bucket) = self.buckets.write().unwrap().get_mut(bucket_number) {
             if let Some(ref mut slots) = bucket.slots {
-                for (n, (_, pref)) in slots.iter().enumerate()
-                    .filter(|s| (s.1).0 == hash) {
-                    let envelope = self.data_file.get_envelope(*pref)?;
+                for n in self.overflow.find(slots, hash).0 {
+                    let envelope = self.data_file.get_envelope(slots[n].1)?;
                     if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
                         if indexed.key == key {
                             remove = Some(n);
@@ -307,8 +1009,10 @@ impl MemTable {
                         }
                     }
                 }
-                if let Some(r) = remove {
-                    slots.remove(r);
+                match (remove, with) {
+                    (Some(r), Some(pref)) => slots[r].1 = pref,
+                    (Some(r), None) => self.overflow.remove(slots, r),
+                    _ => {}
                 }
             }
         }
@@ -320,15 +1024,12 @@ impl MemTable {
 
     fn store_to_bucket(&mut self, bucket: usize, hash: u32, pref: PRef) -> Result<(), Error> {
         self.resolve_bucket(bucket)?;
-        if let Some(bucket) = self.buckets.write().unwrap().get_mut(bucket as usize) {
-            if let Some(ref mut slots) = bucket.slots {
-                slots.push((hash, pref));
-            }
-            else {
-                bucket.slots = Some(vec!((hash, pref)));
-            }
+        if let Some(bucket) = self.buckets.write().unwrap().get_mut(bucket) {
+            let slots = bucket.slots.get_or_insert_with(|| self.overflow.arrange(vec!()));
+            self.overflow.insert(slots, hash, pref);
+            bucket.bloom |= bloomfile::bloom_bits(hash);
         } else {
-            return Err(Error::Corrupted(format!("memtable does not have the bucket {}", bucket).to_string()))
+            return Err(Error::Bug(format!("memtable does not have the bucket {}", bucket)))
         }
         self.modify_bucket(bucket)?;
         Ok(())
@@ -336,29 +1037,28 @@ impl MemTable {
 
     fn rehash_bucket(&mut self, bucket: usize) -> Result<(), Error> {
         let mut rewrite = false;
-        let mut new_bucket_store = Bucket::default();
+        // resolved even if all slots move, so the flush clears its stored link
+        let mut new_bucket_store = Bucket { slots: Some(self.overflow.arrange(vec!())), ..Bucket::default() };
         let mut moves = HashMap::new();
         self.resolve_bucket(bucket)?;
-        if let Some(b) = self.buckets.read().unwrap().get(bucket as usize) {
+        if let Some(b) = self.buckets.read().unwrap().get(bucket) {
             if let Some(ref slots) = b.slots {
-                for (hash, pref) in slots {
+                for (hash, pref) in &self.overflow.stored(slots) {
                     let new_bucket = (hash & (!0u32 >> (32 - self.log_mod - 1))) as usize; // hash % 2^(log_mod + 1)
                     if new_bucket != bucket {
                         moves.entry(new_bucket).or_insert(Vec::new()).push((*hash, *pref));
                         rewrite = true;
                     } else {
                         if let Some(ref mut slots) = new_bucket_store.slots {
-                            slots.push((*hash, *pref));
-                        }
-                        else {
-                            new_bucket_store.slots = Some(vec!((*hash, *pref)));
+                            self.overflow.insert(slots, *hash, *pref);
                         }
+                        new_bucket_store.bloom |= bloomfile::bloom_bits(*hash);
                     }
                 }
             }
         }
         else {
-            return Err(Error::Corrupted(format!("does not have bucket {} for rehash", bucket)));
+            return Err(Error::Bug(format!("does not have bucket {} for rehash", bucket)));
         }
         if rewrite {
             for (bucket, added) in moves {
@@ -385,46 +1085,184 @@ impl MemTable {
     pub fn may_have_key(&self, key: &[u8]) -> Result<bool, Error> {
         let hash = self.hash(key);
         let bucket_number = self.bucket_for_hash(hash);
-        self.resolve_bucket(bucket_number)?;
-        if let Some(bucket) = self.buckets.read().unwrap().get(bucket_number) {
-            if let Some (ref slots) = bucket.slots {
-                if slots.iter().any(|(h, _)| *h == hash) {
-                    return Ok(true);
-                }
-            }
-        } else {
-            return Err(Error::Corrupted(format!("bucket {} should exist", bucket_number)));
+        if !self.may_contain(bucket_number, hash) {
+            return Ok(false);
         }
-        Ok(false)
+        self.read_bucket(bucket_number, |bucket|
+            bucket.slots.as_ref().is_some_and(|slots| !self.overflow.find(slots, hash).0.is_empty()))
     }
 
     // get the data last associated with the key
     pub fn get(&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
         let hash = self.hash(key);
         let bucket_number = self.bucket_for_hash(hash);
-        self.resolve_bucket(bucket_number)?;
-        if let Some(ref bucket) = self.buckets.read().unwrap().get(bucket_number) {
-            if let Some(ref slots) = bucket.slots {
-                for (h, data) in slots {
-                    if *h == hash {
-                        let envelope = self.data_file.get_envelope(*data)?;
-                        if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
-                            if indexed.key == key {
-                                return Ok(Some((*data, indexed.data.data.to_vec())));
-                            }
-                        } else {
-                            return Err(Error::Corrupted("pref should point to indexed data".to_string()));
-                        }
-                    }
+        if !self.may_contain(bucket_number, hash) {
+            return Ok(None);
+        }
+        let candidates = self.read_bucket(bucket_number, |bucket| {
+            let (candidates, examined) = self.matching(bucket, hash);
+            self.metrics.link_chain(examined);
+            candidates
+        })?;
+        for data in candidates {
+            let envelope = self.data_file.get_envelope(data)?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if indexed.key == key {
+                    return Ok(Some((data, indexed.data.data.to_vec())));
                 }
+            } else {
+                return Err(Error::CorruptedAt { file: FileKind::Data, pos: data.as_u64(), reason: "pref should point to indexed data".to_string() });
             }
         }
-        else {
-            return Err(Error::Corrupted(format!("bucket {} should exist", bucket_number)));
+        Ok(None)
+    }
+
+    // look up the key as get does, counting the slots and the data examined
+    pub fn probe(&self, key: &[u8]) -> Result<Probe, Error> {
+        let hash = self.hash(key);
+        let bucket = self.bucket_for_hash(hash);
+        let mut probe = Probe { bucket, ..Probe::default() };
+        if !self.may_contain(bucket, hash) {
+            probe.filtered = true;
+            return Ok(probe);
+        }
+        let (candidates, examined) = self.read_bucket(bucket, |bucket| self.matching(bucket, hash))?;
+        probe.slots = examined;
+        for data in candidates {
+            if self.data_file.get_key(data)?.as_slice() == key {
+                probe.found = Some(data);
+                break;
+            }
+            probe.collisions += 1;
+        }
+        Ok(probe)
+    }
+
+    // references to data stored with keys of the same hash as key
+    fn candidates(&self, key: &[u8]) -> Result<Vec<PRef>, Error> {
+        let hash = self.hash(key);
+        let bucket_number = self.bucket_for_hash(hash);
+        if !self.may_contain(bucket_number, hash) {
+            return Ok(vec!());
+        }
+        self.read_bucket(bucket_number, |bucket| self.matching(bucket, hash).0)
+    }
+
+    // data stored with keys of the same hash as key, accepted by the predicate on (key, data)
+    pub fn get_filtered(&self, key: &[u8], predicate: &dyn Fn(&[u8], &[u8]) -> bool) -> Result<Vec<KeyedDataAt>, Error> {
+        let mut result = Vec::new();
+        for pref in self.candidates(key)? {
+            let envelope = self.data_file.get_envelope(pref)?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                if predicate(indexed.key, indexed.data.data) {
+                    result.push((pref, indexed.key.to_vec(), indexed.data.data.to_vec()));
+                }
+            } else {
+                return Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "pref should point to indexed data".to_string() });
+            }
+        }
+        Ok(result)
+    }
+
+    // keys of the same hash as key, without reading their data
+    pub fn get_keys_only(&self, key: &[u8]) -> Result<Vec<(PRef, Vec<u8>)>, Error> {
+        let mut result = Vec::new();
+        for pref in self.candidates(key)? {
+            result.push((pref, self.data_file.get_key(pref)?));
+        }
+        Ok(result)
+    }
+
+    /// references to data stored with the key, most recent first, reading only keys of the candidates
+    pub fn stored_with(&self, key: &[u8]) -> Result<Vec<PRef>, Error> {
+        let mut result = Vec::new();
+        for pref in self.candidates(key)? {
+            if self.data_file.get_key(pref)?.as_slice() == key {
+                result.push(pref);
+            }
+        }
+        result.sort_unstable_by_key(|pref| Reverse(pref.as_u64()));
+        Ok(result)
+    }
+
+    // number of data stored with the key
+    pub fn count(&self, key: &[u8]) -> Result<usize, Error> {
+        Ok(self.stored_with(key)?.len())
+    }
+
+    // data stored with the key n-th most recently
+    pub fn get_nth(&self, key: &[u8], n: usize) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        if let Some(pref) = self.stored_with(key)?.get(n) {
+            let envelope = self.data_file.get_envelope(*pref)?;
+            if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                return Ok(Some((*pref, indexed.data.data.to_vec())));
+            }
+            return Err(Error::CorruptedAt { file: FileKind::Data, pos: pref.as_u64(), reason: "pref should point to indexed data".to_string() });
         }
         Ok(None)
     }
 
+    // get the data last associated with each of the keys
+    // lookups are sorted by bucket and data position to group page reads
+    pub fn get_many(&self, keys: &[&[u8]]) -> Result<Vec<Option<DataAt>>, Error> {
+        // buckets not in memory are read in the order of their links, data in the order of its position
+        let mut lookups = {
+            let buckets = self.buckets.read().unwrap();
+            keys.iter().enumerate().map(|(i, key)| {
+                let hash = self.hash(key);
+                let bucket_number = self.bucket_for_hash(hash);
+                let link = buckets.get(bucket_number).map_or(PRef::invalid(), |bucket| bucket.stored);
+                (link, bucket_number, hash, i)
+            }).collect::<Vec<_>>()
+        };
+        lookups.sort_unstable();
+
+        let mut candidates = Vec::new();
+        for (_, bucket_number, hash, i) in lookups {
+            if !self.may_contain(bucket_number, hash) {
+                continue;
+            }
+            self.read_bucket(bucket_number, |bucket| {
+                let (found, examined) = self.matching(bucket, hash);
+                self.metrics.link_chain(examined);
+                candidates.extend(found.into_iter().map(|data| (data, i)));
+            })?;
+        }
+        candidates.sort_unstable();
+
+        let mut result = vec!(None; keys.len());
+        for (data, i) in candidates {
+            if result[i].is_none() {
+                let envelope = self.data_file.get_envelope(data)?;
+                if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+                    if indexed.key == keys[i] {
+                        result[i] = Some((data, indexed.data.data.to_vec()));
+                    }
+                } else {
+                    return Err(Error::CorruptedAt { file: FileKind::Data, pos: data.as_u64(), reason: "pref should point to indexed data".to_string() });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // references of the slots of the hash in a bucket and the number of slots examined
+    fn matching(&self, bucket: &Bucket, hash: u32) -> (Vec<PRef>, usize) {
+        match bucket.slots {
+            Some(ref slots) => {
+                let (found, examined) = self.overflow.find(slots, hash);
+                (found.into_iter().map(|n| slots[n].1).collect(), examined)
+            },
+            None => (vec!(), 0)
+        }
+    }
+
+    // false if the filter of the bucket excludes the hash
+    fn may_contain(&self, bucket_number: usize, hash: u32) -> bool {
+        let bits = bloomfile::bloom_bits(hash);
+        self.buckets.read().unwrap().get(bucket_number).is_none_or(|bucket| bucket.bloom & bits == bits)
+    }
+
     fn bucket_for_hash(&self, hash: u32) -> usize {
         let mut bucket = (hash & (!0u32 >> (32 - self.log_mod))) as usize; // hash % 2^(log_mod)
         if bucket < self.step {
@@ -434,7 +1272,7 @@ impl MemTable {
     }
 
     fn hash (&self, key: &[u8]) -> u32 {
-        siphash24::Hash::hash_to_u64_with_keys(self.sip0, self.sip1, key) as u32
+        key_hash(key, self.sip0, self.sip1)
     }
 }
 
@@ -493,18 +1331,14 @@ struct BucketIterator<'a> {
 }
 
 impl<'a> Iterator for BucketIterator<'a> {
-    type Item = Vec<(u32, PRef)>;
+    type Item = Result<Vec<(u32, PRef)>, Error>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-        self.file.resolve_bucket(self.n).unwrap();
-        if let Some(bucket) = self.file.buckets.read().unwrap().get(self.n) {
-            self.n += 1;
-            if let Some(ref slots) = bucket.slots {
-                return Some(slots.clone());
-            }
-            else {
-                return Some(vec!());
-            }
+        if self.n < self.file.n_buckets() {
+            let slots = self.file.bucket_slots(self.n);
+            // iteration ends with a bucket that can not be read
+            self.n = if slots.is_ok() { self.n + 1 } else { self.file.n_buckets() };
+            return Some(slots);
         }
         None
     }
@@ -530,14 +1364,61 @@ impl<'b> Iterator for DirtyIterator<'b> {
             self.pos += 1;
             return Some(self.bits.get(pos));
         }
-        return None;
+        None
+    }
+}
+
+// buckets with slots in memory by their last use, if memory is limited
+#[derive(Default)]
+struct Lru {
+    // bucket number to use and bytes of slots
+    used: HashMap<usize, (u64, usize)>,
+    by_use: BTreeMap<u64, usize>,
+    uses: u64,
+    bytes: usize
+}
+
+impl Lru {
+    fn touch (&mut self, bucket_number: usize, bytes: usize) {
+        self.remove(bucket_number);
+        self.uses += 1;
+        self.used.insert(bucket_number, (self.uses, bytes));
+        self.by_use.insert(self.uses, bucket_number);
+        self.bytes += bytes;
+    }
+
+    fn remove (&mut self, bucket_number: usize) {
+        if let Some((used, bytes)) = self.used.remove(&bucket_number) {
+            self.by_use.remove(&used);
+            self.bytes -= bytes;
+        }
     }
 }
 
+/// pages changed since a checkpoint
+pub struct Changes {
+    /// lengths of the data, table and link files at the checkpoint
+    pub since: (u64, u64, u64),
+    /// their lengths now
+    pub now: (u64, u64, u64),
+    /// table pages changed or added, in order
+    pub table_pages: Vec<PRef>
+}
+
+/// lengths of the files of a snapshot and of the data and link files hard linked into it
+pub struct Snapshot {
+    pub data_len: u64,
+    pub table_len: u64,
+    pub link_len: u64,
+    pub data_linked: u64,
+    pub link_linked: u64
+}
+
 #[derive(Clone, Default)]
 pub struct Bucket {
     stored: PRef,
-    slots: Option<Vec<(u32, PRef)>>
+    slots: Option<Vec<(u32, PRef)>>,
+    bloom: u64
 }
 
 
@@ -568,7 +1449,74 @@ mod test {
         assert!(dirty.get(65));
     }
 
-        #[test]
+    #[test]
+    fn test_memory_budget() {
+        let log = LogFile::new(Box::new(Transient::new(true)));
+        let table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        let data = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let link = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let bloom = BloomFile::new(Box::new(Transient::new(false)));
+        let mut mem = MemTable::new(log, table, data, link, bloom, 2);
+        mem.set_memory_budget(8192);
+        mem.load().unwrap();
+        mem.batch().unwrap();
+
+        let mut rng = thread_rng();
+        let mut key = [0x0u8;32];
+        let mut check = Vec::new();
+        for _ in 0 .. 10000 {
+            rng.fill_bytes(&mut key);
+            let pref = mem.append_data(&key, &key).unwrap();
+            mem.put(&key, pref).unwrap();
+            check.push((key, pref));
+        }
+        // modified buckets stay in memory until written
+        assert!(mem.resident_bytes() > 8192);
+        mem.batch().unwrap();
+        assert!(mem.resident_bytes() <= 8192);
+
+        // evicted buckets are read again
+        for (key, pref) in &check {
+            assert_eq!(mem.get(&key[..]).unwrap(), Some((*pref, key.to_vec())));
+            assert!(mem.resident_bytes() <= 8192 + 1024);
+        }
+        assert_eq!(mem.slots().map(|slots| slots.unwrap().len()).sum::<usize>(), 10000);
+        mem.forget(&check[0].0).unwrap();
+        mem.batch().unwrap();
+        assert_eq!(mem.get(&check[0].0).unwrap(), None);
+        assert!(mem.get(&check[1].0).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_probe() {
+        let log = LogFile::new(Box::new(Transient::new(true)));
+        let table = TableFile::new(Box::new(Transient::new(false))).unwrap();
+        let data = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let link = DataFile::new(Box::new(Transient::new(true))).unwrap();
+        let bloom = BloomFile::new(Box::new(Transient::new(false)));
+        let mut mem = MemTable::new(log, table, data, link, bloom, 2);
+        mem.load().unwrap();
+
+        // two keys of the same hash, by the birthday bound within some 100000 keys
+        let mut seen = HashMap::new();
+        let (first, second) = (0u64 ..).find_map(|i| {
+            let key = i.to_be_bytes();
+            seen.insert(mem.hash(&key), key).filter(|other| *other != key).map(|other| (other, key))
+        }).unwrap();
+        let pref = mem.append_data(&first, b"first").unwrap();
+        mem.put(&first, pref).unwrap();
+        mem.batch().unwrap();
+
+        let hit = mem.probe(&first).unwrap();
+        assert_eq!(hit.found, Some(pref));
+        assert_eq!(hit.collisions, 0);
+        assert!(hit.slots >= 1);
+        let collision = mem.probe(&second).unwrap();
+        assert_eq!((collision.bucket, collision.found, collision.collisions), (hit.bucket, None, 1));
+        assert_eq!(mem.get(&second).unwrap(), None);
+    }
+
+    #[test]
     fn test() {
         let mut db = Transient::new_db("first", 1, 1).unwrap();
 
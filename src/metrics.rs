@@ -0,0 +1,113 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Metrics
+//! Hooks called at puts, gets, batches and syncs. All hooks do nothing by default,
+//! the prometheus feature adds an implementation exporting them to a prometheus registry.
+//!
+
+use std::time::Duration;
+
+/// receiver of measurements, called from the db and its files
+pub trait Metrics : Send + Sync {
+    /// data was stored
+    fn put (&self) {}
+
+    /// data was retrieved
+    fn get (&self) {}
+
+    /// a batch was committed, it took duration and wrote the pages
+    fn batch (&self, _duration: Duration, _pages_written: u64) {}
+
+    /// a file was synced to disk
+    fn sync (&self, _duration: Duration) {}
+
+    /// slots of a hash table bucket searched for a key
+    fn link_chain (&self, _slots: usize) {}
+}
+
+/// metrics that are not collected
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+#[cfg(feature="prometheus")]
+pub use self::prometheus_metrics::PrometheusMetrics;
+
+#[cfg(feature="prometheus")]
+mod prometheus_metrics {
+    use super::Metrics;
+
+    use prometheus;
+    use prometheus::{Histogram, HistogramOpts, IntCounter, Registry};
+
+    use std::time::Duration;
+
+    /// metrics registered with a prometheus registry
+    pub struct PrometheusMetrics {
+        puts: IntCounter,
+        gets: IntCounter,
+        batch_seconds: Histogram,
+        pages_written: IntCounter,
+        sync_seconds: Histogram,
+        link_chain: Histogram
+    }
+
+    impl PrometheusMetrics {
+        /// create the metrics and register them with the registry
+        pub fn new (registry: &Registry) -> Result<PrometheusMetrics, prometheus::Error> {
+            let metrics = PrometheusMetrics {
+                puts: IntCounter::new("hammersbald_puts_total", "data stored")?,
+                gets: IntCounter::new("hammersbald_gets_total", "data retrieved")?,
+                batch_seconds: Histogram::with_opts(HistogramOpts::new("hammersbald_batch_seconds", "duration of batches"))?,
+                pages_written: IntCounter::new("hammersbald_pages_written_total", "pages written by batches")?,
+                sync_seconds: Histogram::with_opts(HistogramOpts::new("hammersbald_sync_seconds", "duration of file syncs"))?,
+                link_chain: Histogram::with_opts(HistogramOpts::new("hammersbald_link_chain_slots", "slots of a bucket searched for a key")
+                    .buckets(vec!(0.0, 1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0)))?
+            };
+            registry.register(Box::new(metrics.puts.clone()))?;
+            registry.register(Box::new(metrics.gets.clone()))?;
+            registry.register(Box::new(metrics.batch_seconds.clone()))?;
+            registry.register(Box::new(metrics.pages_written.clone()))?;
+            registry.register(Box::new(metrics.sync_seconds.clone()))?;
+            registry.register(Box::new(metrics.link_chain.clone()))?;
+            Ok(metrics)
+        }
+    }
+
+    impl Metrics for PrometheusMetrics {
+        fn put (&self) {
+            self.puts.inc()
+        }
+
+        fn get (&self) {
+            self.gets.inc()
+        }
+
+        fn batch (&self, duration: Duration, pages_written: u64) {
+            self.batch_seconds.observe(duration.as_secs_f64());
+            self.pages_written.inc_by(pages_written)
+        }
+
+        fn sync (&self, duration: Duration) {
+            self.sync_seconds.observe(duration.as_secs_f64())
+        }
+
+        fn link_chain (&self, slots: usize) {
+            self.link_chain.observe(slots as f64)
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Reference model
+//! A hash map of the keyed data a db should have, with the state of the last batch the db falls
+//! back to if it is reopened or crashes. Random sequences of operations are applied to a db and the
+//! model, and the db is checked against the model after each. Sequences are generated from a seed,
+//! so a failing sequence is repeated with the seed reported.
+//! The model is of a db without write ahead log, that keeps data of completed batches only.
+//!
+
+use api::{HammersbaldAPI, HammersbaldRead};
+use pagedfile::{PagedFile, PageStore};
+use page::{Page, PAGE_SIZE};
+use pref::PRef;
+use error::Error;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// keyed data a db should have
+#[derive(Clone, Default)]
+pub struct Model {
+    current: HashMap<Vec<u8>, (PRef, Vec<u8>)>,
+    batched: HashMap<Vec<u8>, (PRef, Vec<u8>)>
+}
+
+impl Model {
+    /// an empty db
+    pub fn new () -> Model {
+        Model::default()
+    }
+
+    /// data was stored with key at the persistent reference returned by the db
+    pub fn put_keyed (&mut self, key: &[u8], pref: PRef, data: &[u8]) {
+        self.current.insert(key.to_vec(), (pref, data.to_vec()));
+    }
+
+    /// the key was forgotten
+    pub fn forget (&mut self, key: &[u8]) {
+        self.current.remove(key);
+    }
+
+    /// the batch ended
+    pub fn batch (&mut self) {
+        self.batched = self.current.clone();
+    }
+
+    /// the db was reopened or crashed, changes since the last batch are lost
+    pub fn restart (&mut self) {
+        self.current = self.batched.clone();
+    }
+
+    /// data the db should have for the key
+    pub fn get_keyed (&self, key: &[u8]) -> Option<(PRef, Vec<u8>)> {
+        self.current.get(key).cloned()
+    }
+
+    /// number of keys the db should have
+    pub fn len (&self) -> usize {
+        self.current.len()
+    }
+
+    /// the model has no keys
+    pub fn is_empty (&self) -> bool {
+        self.current.is_empty()
+    }
+
+    /// check that the db has the keyed data of the model and no other keys
+    /// fails with Error::Corrupted naming the first difference
+    pub fn check (&self, db: &dyn HammersbaldRead) -> Result<(), Error> {
+        for (key, (pref, data)) in &self.current {
+            self.check_key(db, key)?;
+            let (stored_key, stored) = db.get(*pref)?;
+            if stored_key != *key || stored != *data {
+                return Err(Error::Corrupted(format!("{:?} at {} has key {:?}", key, pref, stored_key)));
+            }
+        }
+        // padding is stored without key
+        let keys = db.iter_keys().filter(|(_, key)| !key.is_empty()).count();
+        if keys != self.len() {
+            return Err(Error::Corrupted(format!("{} keys stored, {} expected", keys, self.len())));
+        }
+        Ok(())
+    }
+
+    /// check the data of the db for a key
+    pub fn check_key (&self, db: &dyn HammersbaldRead, key: &[u8]) -> Result<(), Error> {
+        let stored = db.get_keyed(key)?;
+        let expected = self.get_keyed(key);
+        if stored != expected {
+            return Err(Error::Corrupted(format!("{:?} is {:?}, expected {:?}", key,
+                stored.map(|(pref, data)| (pref, data.len())), expected.map(|(pref, data)| (pref, data.len())))));
+        }
+        Ok(())
+    }
+}
+
+/// an operation of a random sequence
+#[derive(Clone, PartialEq, Eq)]
+pub enum Op {
+    /// store data with key
+    Put(Vec<u8>, Vec<u8>),
+    /// retrieve data with key
+    Get(Vec<u8>),
+    /// forget a key
+    Forget(Vec<u8>),
+    /// end the batch
+    Batch,
+    /// shutdown and open the db again
+    Reopen,
+    /// drop the db without shutdown and open it again
+    Crash
+}
+
+// data is shown with its length
+impl fmt::Debug for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Put(key, data) => write!(f, "Put({:?}, {} bytes)", key, data.len()),
+            Op::Get(key) => write!(f, "Get({:?})", key),
+            Op::Forget(key) => write!(f, "Forget({:?})", key),
+            Op::Batch => write!(f, "Batch"),
+            Op::Reopen => write!(f, "Reopen"),
+            Op::Crash => write!(f, "Crash")
+        }
+    }
+}
+
+// number of distinct keys, so keys are overwritten and forgotten often
+const KEYS: u8 = 64;
+
+impl Op {
+    /// a random operation, with reopen and crash only if restart
+    pub fn random<R: Rng> (rng: &mut R, restart: bool) -> Op {
+        let n = rng.gen_range(0, KEYS);
+        // keys of different length, below the markers of columns and content ids
+        let key = vec!(n; 1 + (n % 7) as usize);
+        match rng.gen_range(0, if restart { 100 } else { 96 }) {
+            0 ..= 44 => {
+                // mostly small data, some spanning several pages
+                let len = if rng.gen_range(0, 20) == 0 { rng.gen_range(0, 3 * PAGE_SIZE) } else { rng.gen_range(0, 200) };
+                let mut data = vec!(0u8; len);
+                rng.fill(data.as_mut_slice());
+                Op::Put(key, data)
+            },
+            45 ..= 69 => Op::Get(key),
+            70 ..= 81 => Op::Forget(key),
+            82 ..= 95 => Op::Batch,
+            96 ..= 97 => Op::Reopen,
+            _ => Op::Crash
+        }
+    }
+
+    /// a sequence of random operations from the seed
+    pub fn sequence (seed: u64, len: usize, restart: bool) -> Vec<Op> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0 .. len).map(|_| Op::random(&mut rng, restart)).collect()
+    }
+}
+
+/// apply the sequence of random operations of the seed to a db and the model and check the db
+/// after each. open creates the db and reopens it with the data of its last batch, so restart
+/// needs a persistent db, e.g. stored in a MemoryStore.
+/// fails with Error::Corrupted naming the seed, the failing operation and the difference
+pub fn conformance<O> (seed: u64, len: usize, restart: bool, mut open: O) -> Result<(), Error>
+    where O: FnMut() -> Result<Box<dyn HammersbaldAPI>, Error> {
+    let mut model = Model::new();
+    let mut db = open()?;
+    for (i, op) in Op::sequence(seed, len, restart).iter().enumerate() {
+        let done = match op {
+            Op::Reopen | Op::Crash => {
+                if *op == Op::Reopen {
+                    db.shutdown();
+                }
+                // the files are closed before they are opened again, without shutdown on a crash
+                drop(db);
+                model.restart();
+                match open() {
+                    Ok(reopened) => { db = reopened; Ok(()) },
+                    Err(e) => return Err(failed(seed, i, op, e))
+                }
+            },
+            _ => apply(&mut model, db.as_mut(), op)
+        };
+        if let Err(e) = done.and_then(|_| after(&model, db.as_ref(), op)) {
+            db.shutdown();
+            return Err(failed(seed, i, op, e));
+        }
+    }
+    db.shutdown();
+    Ok(())
+}
+
+fn failed (seed: u64, i: usize, op: &Op, e: Error) -> Error {
+    Error::Corrupted(format!("seed {} operation {} {:?}: {}", seed, i, op, e))
+}
+
+fn apply (model: &mut Model, db: &mut dyn HammersbaldAPI, op: &Op) -> Result<(), Error> {
+    match op {
+        Op::Put(key, data) => {
+            let pref = db.put_keyed(key, data)?;
+            model.put_keyed(key, pref, data);
+        },
+        Op::Get(key) => model.check_key(db, key)?,
+        Op::Forget(key) => {
+            db.forget(key)?;
+            model.forget(key);
+        },
+        Op::Batch => {
+            db.batch()?;
+            model.batch();
+        },
+        Op::Reopen | Op::Crash => {}
+    }
+    Ok(())
+}
+
+// the whole db is checked after the operations reading all keys
+fn after (model: &Model, db: &dyn HammersbaldRead, op: &Op) -> Result<(), Error> {
+    match op {
+        Op::Put(key, _) | Op::Get(key) | Op::Forget(key) => model.check_key(db, key),
+        _ => model.check(db)
+    }
+}
+
+/// files of dbs kept in memory, they outlive a db so it is opened again or after a crash
+#[derive(Default)]
+pub struct MemoryStore {
+    files: Mutex<HashMap<String, Arc<Mutex<Vec<u8>>>>>
+}
+
+impl MemoryStore {
+    /// no files
+    pub fn new () -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    /// a file of a db with the extension is stored
+    pub fn contains (&self, name: &str, extension: &str) -> bool {
+        self.files.lock().unwrap().contains_key(&format!("{}.{}", name, extension))
+    }
+}
+
+struct MemoryFile (Arc<Mutex<Vec<u8>>>);
+
+impl PageStore for MemoryStore {
+    fn open (&self, name: &str, extension: &str, _: bool, _: bool) -> Result<Box<dyn PagedFile>, Error> {
+        let mut files = self.files.lock().unwrap();
+        let file = files.entry(format!("{}.{}", name, extension)).or_default();
+        Ok(Box::new(MemoryFile(file.clone())))
+    }
+}
+
+impl PagedFile for MemoryFile {
+    fn read_page (&self, pref: PRef) -> Result<Option<Page>, Error> {
+        let content = self.0.lock().unwrap();
+        let pos = pref.as_u64() as usize;
+        if pos + PAGE_SIZE > content.len() {
+            return Ok(None);
+        }
+        let mut page = Page::new();
+        page.write(0, &content[pos .. pos + PAGE_SIZE]);
+        Ok(Some(page))
+    }
+
+    fn len (&self) -> Result<u64, Error> {
+        Ok(self.0.lock().unwrap().len() as u64)
+    }
+
+    fn truncate (&mut self, new_len: u64) -> Result<(), Error> {
+        self.0.lock().unwrap().truncate(new_len as usize);
+        Ok(())
+    }
+
+    fn sync (&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn shutdown (&mut self) {}
+
+    fn append_page (&mut self, page: Page) -> Result<(), Error> {
+        self.0.lock().unwrap().extend_from_slice(page.as_buf());
+        Ok(())
+    }
+
+    fn update_page (&mut self, page: Page) -> Result<u64, Error> {
+        let mut content = self.0.lock().unwrap();
+        let pos = page.pref().as_u64() as usize;
+        if content.len() < pos + PAGE_SIZE {
+            content.resize(pos + PAGE_SIZE, 0);
+        }
+        content[pos .. pos + PAGE_SIZE].copy_from_slice(page.as_buf());
+        Ok(content.len() as u64)
+    }
+
+    fn flush (&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::persistent_with_config;
+    use config::Config;
+    use transient;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_model_transient () {
+        for seed in 0 .. 4 {
+            conformance(seed, 1000, false, || transient(1)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_model_restart () {
+        // each seed on a store of its own
+        for seed in 0 .. 4 {
+            let config = Config::default().page_store(Arc::new(MemoryStore::new()));
+            conformance(seed, 1000, true, || persistent_with_config("db", &config)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_model_files () {
+        let dir = env::temp_dir().join(format!("hammersbald-model-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config::default().directory(&dir).bucket_fill_target(2);
+        conformance(rand::random(), 500, true, || persistent_with_config("db", &config)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_model_detects () {
+        // a db that forgets nothing differs from the model
+        let sequence = Op::sequence(7, 200, false);
+        assert!(sequence.iter().any(|op| matches!(op, Op::Forget(_))));
+        assert_eq!(sequence, Op::sequence(7, 200, false));
+        let mut db = transient(1).unwrap();
+        let mut model = Model::new();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        model.put_keyed(b"key", pref, b"data");
+        model.check(db.as_ref()).unwrap();
+        model.forget(b"key");
+        assert!(model.is_empty());
+        assert!(model.check_key(db.as_ref(), b"key").is_err());
+        assert!(model.check(db.as_ref()).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Commit observers
+//! Receivers of the changes of a batch once it is written, e.g. to invalidate a cache or to
+//! capture changes without scanning. Changes are only collected while there are observers.
+//!
+
+use pref::PRef;
+
+use std::sync::Arc;
+
+/// a change of a batch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// data was stored with the key at the persistent reference, data without key has an empty key
+    Stored(Vec<u8>, PRef),
+    /// data was stored with the key at the persistent reference, keeping data stored earlier with it
+    StoredAlso(Vec<u8>, PRef),
+    /// the key was forgotten
+    Forgotten(Vec<u8>)
+}
+
+/// receiver of the changes of each batch
+pub trait CommitObserver : Send + Sync {
+    /// a batch was written with the changes since the previous batch, in the order of the calls
+    fn committed (&self, changes: &[Change]);
+
+    /// the batch just committed was ended by the auto batch policy
+    fn auto_committed (&self) {}
+}
+
+/// changes since the last batch
+#[derive(Default)]
+pub struct Changes {
+    observers: Vec<Arc<dyn CommitObserver>>,
+    changes: Vec<Change>
+}
+
+impl Changes {
+    /// report the changes of batches to the observer from the next batch on
+    pub fn observe (&mut self, observer: Arc<dyn CommitObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// data was stored with the key
+    pub fn stored (&mut self, key: &[u8], pref: PRef) {
+        if !self.observers.is_empty() {
+            self.changes.push(Change::Stored(key.to_vec(), pref));
+        }
+    }
+
+    /// data was stored with the key, keeping data stored earlier with it
+    pub fn stored_also (&mut self, key: &[u8], pref: PRef) {
+        if !self.observers.is_empty() {
+            self.changes.push(Change::StoredAlso(key.to_vec(), pref));
+        }
+    }
+
+    /// the key was forgotten
+    pub fn forgotten (&mut self, key: &[u8]) {
+        if !self.observers.is_empty() {
+            self.changes.push(Change::Forgotten(key.to_vec()));
+        }
+    }
+
+    /// the batch was written, tell the observers
+    pub fn committed (&mut self) {
+        for observer in &self.observers {
+            observer.committed(self.changes.as_slice());
+        }
+        self.discard();
+    }
+
+    /// the batch was ended by the auto batch policy, tell the observers
+    pub fn auto_committed (&self) {
+        for observer in &self.observers {
+            observer.auto_committed();
+        }
+    }
+
+    /// the changes were not written
+    pub fn discard (&mut self) {
+        self.changes.clear();
+    }
+}
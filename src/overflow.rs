@@ -0,0 +1,231 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Bucket overflow
+//! Keys of a bucket of the hash table are slots of their hash and the reference to their data. The
+//! hash is a fingerprint of the key, the data is read only for slots of the hash of a key looked up.
+//! An overflow strategy arranges the slots of a bucket in memory and finds the slots of a hash.
+//! The link file stores the slots in use of a bucket in the order of the strategy, that is
+//! read by any other, so the strategy of a db is chosen at each open.
+//!
+
+use pref::PRef;
+
+use std::cmp::max;
+use std::fmt;
+
+/// arrangement of the slots of a bucket, slots not in use have an invalid reference
+pub trait Overflow: Send + Sync + fmt::Debug {
+    /// arrange slots read from the link file
+    fn arrange (&self, stored: Vec<(u32, PRef)>) -> Vec<(u32, PRef)>;
+
+    /// add a slot
+    fn insert (&self, slots: &mut Vec<(u32, PRef)>, hash: u32, pref: PRef);
+
+    /// remove the slot at a position returned by find
+    fn remove (&self, slots: &mut Vec<(u32, PRef)>, at: usize);
+
+    /// positions of the slots of the hash in the order they were added and the number of slots examined
+    fn find (&self, slots: &[(u32, PRef)], hash: u32) -> (Vec<usize>, usize);
+
+    /// slots in use in the order they are stored to the link file, slots of a hash in the order they were added
+    fn stored (&self, slots: &[(u32, PRef)]) -> Vec<(u32, PRef)>;
+}
+
+/// slots in the order they were added, a lookup examines all slots of the bucket
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chained;
+
+impl Overflow for Chained {
+    fn arrange (&self, stored: Vec<(u32, PRef)>) -> Vec<(u32, PRef)> {
+        stored
+    }
+
+    fn insert (&self, slots: &mut Vec<(u32, PRef)>, hash: u32, pref: PRef) {
+        slots.push((hash, pref));
+    }
+
+    fn remove (&self, slots: &mut Vec<(u32, PRef)>, at: usize) {
+        slots.remove(at);
+    }
+
+    fn find (&self, slots: &[(u32, PRef)], hash: u32) -> (Vec<usize>, usize) {
+        (slots.iter().enumerate().filter(|(_, (h, _))| *h == hash).map(|(i, _)| i).collect(), slots.len())
+    }
+
+    fn stored (&self, slots: &[(u32, PRef)]) -> Vec<(u32, PRef)> {
+        slots.to_vec()
+    }
+}
+
+/// open addressing of the slots of a bucket with robin hood linear probing. A slot is at or after
+/// the home position of its hash, ordered by home, so a lookup stops at the first slot of a later
+/// home. Lookups examine a few slots even if buckets are long, at the cost of up to twice the
+/// memory of chained slots. Slots of a hash stay in the order they were added.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RobinHood;
+
+// used slots are at most 4/5 of a bucket, arranged to half
+const MAX_LOAD: (usize, usize) = (4, 5);
+const MIN_BUCKET: usize = 4;
+
+impl RobinHood {
+    // position of the hash in a bucket of the length, with bits of the hash other than those selecting the bucket
+    fn home (hash: u32, len: usize) -> usize {
+        ((hash.wrapping_mul(0x9E37_79B1) as u64 * len as u64) >> 32) as usize
+    }
+
+    // distance of the slot at the position from the home of its hash
+    fn distance (slots: &[(u32, PRef)], at: usize) -> usize {
+        (at + slots.len() - Self::home(slots[at].0, slots.len())) % slots.len()
+    }
+
+    // shift slots from the position to the next free one, insert the slot at the position
+    fn place (slots: &mut [(u32, PRef)], hash: u32, pref: PRef) {
+        let len = slots.len();
+        let mut at = Self::home(hash, len);
+        let mut distance = 0;
+        while slots[at].1.is_valid() && Self::distance(slots, at) >= distance {
+            at = (at + 1) % len;
+            distance += 1;
+        }
+        let mut carry = (hash, pref);
+        while carry.1.is_valid() {
+            ::std::mem::swap(&mut slots[at], &mut carry);
+            at = (at + 1) % len;
+        }
+    }
+
+    fn used (slots: &[(u32, PRef)]) -> usize {
+        slots.iter().filter(|(_, pref)| pref.is_valid()).count()
+    }
+}
+
+impl Overflow for RobinHood {
+    fn arrange (&self, stored: Vec<(u32, PRef)>) -> Vec<(u32, PRef)> {
+        let mut slots = vec!((0, PRef::invalid()); max(MIN_BUCKET, 2 * stored.len()));
+        for (hash, pref) in stored {
+            Self::place(&mut slots, hash, pref);
+        }
+        slots
+    }
+
+    fn insert (&self, slots: &mut Vec<(u32, PRef)>, hash: u32, pref: PRef) {
+        if (Self::used(slots) + 1) * MAX_LOAD.1 > slots.len() * MAX_LOAD.0 {
+            *slots = self.arrange(self.stored(slots));
+        }
+        Self::place(slots, hash, pref);
+    }
+
+    fn remove (&self, slots: &mut Vec<(u32, PRef)>, at: usize) {
+        // slots after move back until one at its home
+        let len = slots.len();
+        let mut at = at;
+        loop {
+            let next = (at + 1) % len;
+            if !slots[next].1.is_valid() || Self::distance(slots, next) == 0 {
+                slots[at] = (0, PRef::invalid());
+                return;
+            }
+            slots[at] = slots[next];
+            at = next;
+        }
+    }
+
+    fn find (&self, slots: &[(u32, PRef)], hash: u32) -> (Vec<usize>, usize) {
+        let mut found = Vec::new();
+        let mut examined = 0;
+        if !slots.is_empty() {
+            let mut at = Self::home(hash, slots.len());
+            while examined < slots.len() && slots[at].1.is_valid() && Self::distance(slots, at) >= examined {
+                if slots[at].0 == hash {
+                    found.push(at);
+                }
+                at = (at + 1) % slots.len();
+                examined += 1;
+            }
+        }
+        (found, examined)
+    }
+
+    fn stored (&self, slots: &[(u32, PRef)]) -> Vec<(u32, PRef)> {
+        // from a free slot, so slots wrapping around the end stay in order
+        match slots.iter().position(|(_, pref)| !pref.is_valid()) {
+            Some(free) => slots[free ..].iter().chain(slots[.. free].iter()).filter(|(_, pref)| pref.is_valid()).cloned().collect(),
+            None => slots.to_vec()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn test_overflow () {
+        let mut rng = thread_rng();
+        for overflow in [&Chained as &dyn Overflow, &RobinHood].iter() {
+            let mut slots = overflow.arrange(vec!());
+            let mut added = Vec::new();
+            for n in 1 ..= 1000u64 {
+                // few hashes, so hashes have several slots
+                let hash = rng.gen_range(0, 300u32);
+                overflow.insert(&mut slots, hash, PRef::from(n));
+                added.push((hash, PRef::from(n)));
+                if n % 3 == 0 {
+                    let hash = added[rng.gen_range(0, added.len())].0;
+                    let (found, _) = overflow.find(&slots, hash);
+                    let at = found[rng.gen_range(0, found.len())];
+                    let removed = slots[at];
+                    overflow.remove(&mut slots, at);
+                    added.retain(|slot| *slot != removed);
+                }
+            }
+            // a lookup finds the slots of the hash in the order they were added
+            for hash in 0 .. 300u32 {
+                let (found, _) = overflow.find(&slots, hash);
+                let found = found.iter().map(|at| slots[*at]).collect::<Vec<_>>();
+                assert_eq!(found, added.iter().filter(|(h, _)| *h == hash).cloned().collect::<Vec<_>>(), "{:?}", overflow);
+            }
+            let stored = overflow.stored(&slots);
+            assert_eq!(stored.len(), added.len());
+            let mut sorted = stored.clone();
+            sorted.sort_unstable_by_key(|(_, pref)| pref.as_u64());
+            assert_eq!(sorted, added);
+            // arranged again from the stored order, by any strategy
+            for other in [&Chained as &dyn Overflow, &RobinHood].iter() {
+                let arranged = other.arrange(stored.clone());
+                for (hash, _) in &added {
+                    let found = other.find(&arranged, *hash).0.iter().map(|at| arranged[*at]).collect::<Vec<_>>();
+                    assert_eq!(found, added.iter().filter(|(h, _)| h == hash).cloned().collect::<Vec<_>>());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_robin_hood_examines_few () {
+        let mut slots = RobinHood.arrange(vec!());
+        for n in 0 .. 1000u32 {
+            RobinHood.insert(&mut slots, n.wrapping_mul(0x0100_0193), PRef::from(n as u64 + 1));
+        }
+        let examined = (0 .. 1000u32).map(|n| RobinHood.find(&slots, n.wrapping_mul(0x0100_0193)).1).sum::<usize>();
+        assert!(examined < 5 * 1000, "examined {}", examined);
+        assert_eq!(Chained.find(&Chained.arrange(RobinHood.stored(&slots)), 0).1, 1000);
+    }
+}
@@ -23,15 +23,24 @@
 use pref::PRef;
 use byteorder::{ByteOrder, BigEndian};
 
+/// size of a page read and written by a paged file
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_PAYLOAD_SIZE: usize = 4090;
 
 /// A page of the persistent files
+/// aligned to its size as required for direct IO
 #[derive(Clone)]
+#[repr(C, align(4096))]
 pub struct Page {
     content: [u8; PAGE_SIZE],
 }
 
+impl Default for Page {
+    fn default() -> Page {
+        Page::new()
+    }
+}
+
 impl Page {
     /// create an empty page for a position in the table file
     pub fn new_table_page(pref: PRef) -> Page {
@@ -94,4 +103,14 @@ impl Page {
     pub fn into_buf (self) -> [u8; PAGE_SIZE] {
         self.content
     }
+
+    /// the page content as aligned buffer
+    pub fn as_buf (&self) -> &[u8] {
+        &self.content[..]
+    }
+
+    /// the page content as aligned buffer to read into
+    pub fn as_mut_buf (&mut self) -> &mut [u8] {
+        &mut self.content[..]
+    }
 }
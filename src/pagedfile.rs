@@ -25,6 +25,8 @@ use std::cmp::min;
 use std::io::{self, ErrorKind};
 
 /// a paged file
+// the length is fallible, so is_empty would not help
+#[allow(clippy::len_without_is_empty)]
 pub trait PagedFile : Send + Sync {
     /// read a page at pref
     fn read_page (&self, pref: PRef) -> Result<Option<Page>, Error>;
@@ -42,16 +44,21 @@ pub trait PagedFile : Send + Sync {
     fn update_page (&mut self, page: Page) -> Result<u64, Error>;
     /// flush buffered writes
     fn flush(&mut self) -> Result<(), Error>;
+    /// hint that pages from pref on are read next, e.g. by a scan, so they might be read ahead
+    fn prefetch (&self, _pref: PRef, _pages: usize) {}
+    /// hard link whole chunks of the file before len as chunks of the rolled file with the name and
+    /// chunk size, returns the length linked, a file that can not link none
+    fn link_chunks (&self, _name: &str, _len: u64, _chunk_size: u64) -> Result<u64, Error> { Ok(0) }
+    /// extend the file to len without writing it, pages not written read as zeros
+    /// returns false if the file can not, its pages are then to be written
+    fn allocate (&mut self, _len: u64) -> Result<bool, Error> { Ok(false) }
 }
 
-pub trait PagedFileRead {
-    /// read a slice from a paged file
-    fn read(&self, pos: PRef, buf: &mut [u8]) -> Result<PRef, Error>;
-}
-
-pub trait PagedFileWrite {
-    /// write a slice to a paged file
-    fn append(&mut self, buf: &[u8]) -> Result<PRef, Error>;
+/// opens the paged files of a db on a storage other than the local file system, e.g. an object store
+pub trait PageStore : Send + Sync {
+    /// open the file of the db with the extension, an empty file if it does not exist yet
+    /// pages are only appended to an append only file, a read only file is not written
+    fn open (&self, name: &str, extension: &str, append_only: bool, read_only: bool) -> Result<Box<dyn PagedFile>, Error>;
 }
 
 /// a reader for a paged file
@@ -72,6 +79,11 @@ impl PagedFileAppender {
     }
 
     pub fn append(&mut self, buf: &[u8]) -> Result<PRef, Error> {
+        // data appended must stay addressable, padding up to the next page included
+        let end = self.pos.as_u64() + buf.len() as u64 + PAGE_SIZE as u64;
+        if end > PRef::invalid().as_u64() {
+            return Err(Error::DoesNotFit { what: "file length", max: PRef::invalid().as_u64(), got: end });
+        }
         let mut wrote = 0;
         while wrote < buf.len() {
             if self.page.is_none () {
@@ -153,7 +165,15 @@ impl PagedFile for PagedFileAppender {
             }
         }
         self.page = None;
-        Ok(self.file.flush()?)
+        self.file.flush()
+    }
+
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        self.file.prefetch(pref, pages)
+    }
+
+    fn link_chunks(&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        self.file.link_chunks(name, len, chunk_size)
     }
 }
 
@@ -168,7 +188,7 @@ pub struct PagedFileIterator<'file> {
 /// page iterator
 impl<'file> PagedFileIterator<'file> {
     /// create a new iterator starting at given page
-    pub fn new (file: &'file dyn PagedFile, pref: PRef) -> PagedFileIterator {
+    pub fn new (file: &'file dyn PagedFile, pref: PRef) -> PagedFileIterator<'file> {
         PagedFileIterator {pagenumber: pref.page_number(), file}
     }
 }
@@ -177,7 +197,7 @@ impl<'file> Iterator for PagedFileIterator<'file> {
     type Item = Page;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.pagenumber <= (1 << 35) / PAGE_SIZE as u64 {
+        if self.pagenumber < PRef::invalid().page_number() {
             let pref = PRef::from((self.pagenumber)* PAGE_SIZE as u64);
             if let Ok(Some(page)) = self.file.read_page(pref) {
                 self.pagenumber += 1;
@@ -18,18 +18,31 @@
 //!
 //! Implements persistent store
 
-use api::{Hammersbald, HammersbaldAPI};
+use api::{Hammersbald, HammersbaldAPI, HammersbaldRead};
 use asyncfile::AsyncFile;
 use cachedfile::CachedFile;
 use datafile::DataFile;
 use error::Error;
 use logfile::LogFile;
+use walfile::WalFile;
+use bloomfile::BloomFile;
 use rolledfile::RolledFile;
 use tablefile::TableFile;
+use pagedfile::PagedFile;
+use config::Config;
+use containerfile::Container;
+use encryption;
+#[cfg(feature="testing")]
+use testing::FaultyFile;
 
-const TABLE_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
-const DATA_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
-const LOG_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const EXTENSIONS: [&str; 4] = ["bc", "bl", "lg", "tb"];
+// files derived from the data file
+const REBUILT_EXTENSIONS: [&str; 5] = ["bl", "lg", "tb", "bf", "ck"];
 
 /// Implements persistent storage
 pub struct Persistent {}
@@ -37,26 +50,296 @@ pub struct Persistent {}
 impl Persistent {
     /// create a new db
     pub fn new_db(name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
-        let data = DataFile::new(
+        Self::with_config(name, &Config::default().cached_data_pages(cached_data_pages).bucket_fill_target(bucket_fill_target))
+    }
+
+    /// create a new db with configuration
+    pub fn with_config(name: &str, config: &Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        Ok(Box::new(Self::open(name, config)?))
+    }
+
+    /// create or open a db with configuration
+    /// the files are locked until shutdown, so a db has a single writer
+    pub fn open(name: &str, config: &Config) -> Result<Hammersbald, Error> {
+        // a page store is locked by its own means, if at all
+        let lock = if config.page_store.is_none() { Some(Self::lock(name, config)?) } else { None };
+        let files = Files::new(name, config, false)?;
+        let mut data = DataFile::new(
             Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bc", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                files.async_file("bc")?, config.cached_data_pages)?))?;
+        if let Some(cipher) = Self::cipher(&files, config, false)? {
+            data = data.with_cipher(cipher);
+        }
 
         let link = DataFile::new(
             Box::new(CachedFile::new(
-                Box::new(AsyncFile::new(
-                    Box::new(RolledFile::new(
-                        name, "bl", true, DATA_CHUNK_SIZE)?))?), cached_data_pages)?))?;
+                files.async_file("bl")?, config.cached_data_pages)?))?;
 
-        let log = LogFile::new(
-            Box::new(AsyncFile::new(
-                Box::new(RolledFile::new(name, "lg", true, LOG_CHUNK_SIZE)?))?));
+        let log = LogFile::new(files.async_file("lg")?)
+            .with_checkpoints(files.file("ck", true)?);
 
         let table = TableFile::new(
-            Box::new(CachedFile::new(
-            Box::new(RolledFile::new(name, "tb", false, TABLE_CHUNK_SIZE)?), cached_data_pages)?))?;
+            Box::new(CachedFile::new(files.file("tb", false)?, config.cached_data_pages)?))?;
+
+        let bloom = BloomFile::new(files.file("bf", false)?);
+
+        let db_name = name.to_string();
+        // secondary indexes and the content hash table are not deduplicated, measured or tagged with generations themselves
+        let mut index_config = config.clone().dedup(false).generations(false).fixed_key_size(0).quota(0);
+        index_config.metrics = None;
+        // index puts are part of the put throttled and batched with it
+        index_config.max_pending_bytes = 0;
+        index_config.auto_batch = None;
+        let dedup_config = index_config.clone();
+        let factory = Box::new(move |index: &str| Self::with_config(&format!("{}.{}", db_name, index), &index_config));
+        let mut db = Hammersbald::new(log, table, data, link, bloom, config.bucket_fill_target)?
+            .with_index_factory(factory).with_max_file_size(config.max_file_size).with_flush_threads(config.flush_threads).with_memory_budget(config.memory_budget)
+            .with_overflow(config.overflow.clone());
+        if let Some(lock) = lock {
+            db = db.with_lock(lock);
+        }
+        if config.fixed_key_size > 0 {
+            db = db.with_fixed_key_size(config.fixed_key_size)?;
+        }
+        if let Some(ref metrics) = config.metrics {
+            db = db.with_metrics(metrics.clone());
+        }
+        if config.quota > 0 {
+            db = db.with_quota(config.quota);
+        }
+        if config.page_store.is_none() {
+            db = db.with_free_space(Self::directories(name, config), config.reserve_space);
+        }
+        if config.max_pending_bytes > 0 {
+            db = db.with_throttle(config.max_pending_bytes, config.throttle);
+        }
+        if let Some(policy) = config.auto_batch {
+            db = db.with_auto_batch(policy);
+        }
+        if config.dedup {
+            db = db.with_dedup(Self::with_config(&format!("{}.dedup", name), &dedup_config)?)?;
+        }
+        if config.generations {
+            db = db.with_generations(files.file("gn", false)?)?;
+        }
+        // durable puts are logged even if not every put is
+        let wal = WalFile::new(files.file("wl", false)?);
+        db = db.with_write_ahead_log(wal, config.write_ahead_log)?;
+        Ok(db)
+    }
+
+    // directories of the files of the db, the data file may be on an other disk
+    fn directories(name: &str, config: &Config) -> Vec<PathBuf> {
+        let mut directories = Vec::new();
+        for data in &[false, true] {
+            let file_name = config.file_name(name, *data);
+            let dir = match Path::new(&file_name).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from(".")
+            };
+            if !directories.contains(&dir) {
+                directories.push(dir);
+            }
+        }
+        directories
+    }
+
+    // lock the file name.lock of the db
+    fn lock(name: &str, config: &Config) -> Result<File, Error> {
+        let lock = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(format!("{}.lock", config.file_name(name, false)))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(lock),
+            Err(TryLockError::WouldBlock) => Err(Error::Locked(name.to_string())),
+            Err(TryLockError::Error(e)) => Err(Error::IO(e))
+        }
+    }
+
+    // the cipher of the configured key, if it is the key of the db
+    fn cipher(files: &Files, config: &Config, read_only: bool) -> Result<Option<encryption::Cipher>, Error> {
+        let cipher = encryption::cipher(config);
+        encryption::check_key(files.file("ek", false)?.as_mut(), cipher.as_ref(), read_only)?;
+        Ok(cipher)
+    }
+
+    /// open a db for read only
+    pub fn read_only(name: &str, config: &Config) -> Result<Box<dyn HammersbaldRead>, Error> {
+        Ok(Box::new(Self::open_read_only(name, config)?))
+    }
+
+    /// open a db for read only
+    pub fn open_read_only(name: &str, config: &Config) -> Result<Hammersbald, Error> {
+        let files = Files::new(name, config, true)?;
+        let mut data = DataFile::new(
+            Box::new(CachedFile::new(files.file("bc", false)?, config.cached_data_pages)?))?;
+        if let Some(cipher) = Self::cipher(&files, config, true)? {
+            data = data.with_cipher(cipher);
+        }
+
+        let link = DataFile::new(
+            Box::new(CachedFile::new(files.file("bl", false)?, config.cached_data_pages)?))?;
+
+        let log = LogFile::new(files.file("lg", false)?);
+
+        let table = TableFile::new(
+            Box::new(CachedFile::new(files.file("tb", false)?, config.cached_data_pages)?))?;
+
+        let bloom = BloomFile::new(files.file("bf", false)?);
+
+        let generations = files.file("gn", false)?;
+        let db = Hammersbald::read_only(log, table, data, link, bloom)?.with_overflow(config.overflow.clone());
+        if generations.len()? > 0 {
+            return db.with_generations(generations);
+        }
+        Ok(db)
+    }
+
+    /// the db has a hash table or a container in the configured directory
+    pub fn exists(name: &str, config: &Config) -> Result<bool, Error> {
+        Ok(Path::new(&format!("{}.hb", config.file_name(name, false))).exists() ||
+            RolledFile::exists(&config.file_name(name, false), "tb")?)
+    }
+
+    /// open a file of a db with the extension for read only, without checking or recovering the db
+    pub fn raw_file(name: &str, config: &Config, extension: &str) -> Result<Box<dyn PagedFile>, Error> {
+        Files::new(name, config, true)?.open(extension, false)
+    }
+
+    /// fail if there are files of a snapshot with the name
+    pub fn snapshot_free(name: &str) -> Result<(), Error> {
+        for extension in ["bc", "bl", "lg", "tb"].iter() {
+            if RolledFile::exists(name, extension)? {
+                return Err(Error::IO(io::Error::from(io::ErrorKind::AlreadyExists)));
+            }
+        }
+        Ok(())
+    }
+
+    /// open the files of a snapshot rolled at the chunk size, data and link files might have linked chunks
+    pub fn snapshot_files(name: &str, chunk_size: u64) -> Result<(LogFile, RolledFile, RolledFile, RolledFile), Error> {
+        let config = Config::default().max_file_size(chunk_size);
+        let data = RolledFile::new(name, "bc", true, &config)?;
+        let link = RolledFile::new(name, "bl", true, &config)?;
+        let log = RolledFile::new(name, "lg", true, &config)?;
+        let table = RolledFile::new(name, "tb", false, &config)?;
+        Ok((LogFile::new(Box::new(log)), table, data, link))
+    }
+
+    /// open the files of a snapshot to restore a delta onto, its bloom filters and checkpoints are removed
+    pub fn restore_files(name: &str) -> Result<(LogFile, RolledFile, RolledFile, RolledFile), Error> {
+        let chunk_size = RolledFile::chunk_size_of(name, "tb")?
+            .ok_or_else(|| Error::Corrupted(format!("{} is not a snapshot", name)))?;
+        let config = Config::default().max_file_size(chunk_size);
+        let table = RolledFile::new(name, "tb", false, &config)?;
+        if table.len()? == 0 {
+            return Err(Error::Corrupted(format!("{} is not a snapshot", name)));
+        }
+        for extension in ["bf", "ck"].iter() {
+            RolledFile::remove(&config.file_name(name, false), extension)?;
+        }
+        let data = RolledFile::new(name, "bc", true, &config)?;
+        let link = RolledFile::new(name, "bl", true, &config)?;
+        let log = RolledFile::new(name, "lg", true, &config)?;
+        Ok((LogFile::new(Box::new(log)), table, data, link))
+    }
+
+    /// store the key check of a snapshot of an encrypted db rolled at the chunk size
+    pub fn snapshot_key(name: &str, cipher: Option<&encryption::Cipher>, chunk_size: u64) -> Result<(), Error> {
+        if cipher.is_some() {
+            let mut file = RolledFile::new(name, "ek", false, &Config::default().max_file_size(chunk_size))?;
+            encryption::check_key(&mut file, cipher, false)?;
+        }
+        Ok(())
+    }
+
+    /// rebuild hash table and link file of a db from its data file
+    pub fn repair(name: &str, config: &Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+        // damaged files might not even open
+        let files = Files::new(name, config, false)?;
+        for extension in REBUILT_EXTENSIONS.iter() {
+            match files.container {
+                Some(ref container) => Container::file(container, extension)?.truncate(0)?,
+                None if config.page_store.is_some() => files.open(extension, false)?.truncate(0)?,
+                None => RolledFile::remove(&config.file_name(name, false), extension)?
+            }
+        }
+        drop(files);
+        let mut db = Self::with_config(name, config)?;
+        db.rebuild_index()?;
+        Ok(db)
+    }
+
+    /// upgrade files of a db to the current format version
+    pub fn migrate(name: &str) -> Result<(), Error> {
+        for extension in EXTENSIONS.iter() {
+            RolledFile::migrate(name, extension)?;
+        }
+        Ok(())
+    }
+}
+
+// opens the files of a db, each of its own or in a container
+struct Files<'a> {
+    name: &'a str,
+    config: &'a Config,
+    container: Option<Arc<Container>>,
+    read_only: bool
+}
+
+impl<'a> Files<'a> {
+    // a db is in a container if there is one or if it is new and configured so
+    fn new (name: &'a str, config: &'a Config, read_only: bool) -> Result<Files<'a>, Error> {
+        if config.page_store.is_some() {
+            return Ok(Files { name, config, container: None, read_only });
+        }
+        let path = format!("{}.hb", config.file_name(name, false));
+        let container = if Path::new(&path).exists() ||
+            (config.container && !read_only && !RolledFile::exists(&config.file_name(name, true), "bc")?) {
+            Some(Container::open(&path, read_only, config.sync)?)
+        } else {
+            None
+        };
+        Ok(Files { name, config, container, read_only })
+    }
+
+    // the file with the extension
+    fn file (&self, extension: &str, append_only: bool) -> Result<Box<dyn PagedFile>, Error> {
+        Ok(self.inject(self.open(extension, append_only)?))
+    }
+
+    // the append only file with the extension written in background
+    fn async_file (&self, extension: &str) -> Result<Box<dyn PagedFile>, Error> {
+        Ok(self.inject(Box::new(AsyncFile::new(self.open(extension, true)?)?)))
+    }
+
+    // writes fail or are lost as configured by a test
+    #[cfg(feature="testing")]
+    fn inject (&self, file: Box<dyn PagedFile>) -> Box<dyn PagedFile> {
+        match self.config.faults {
+            Some(ref faults) => Box::new(FaultyFile::new(file, faults.clone())),
+            None => file
+        }
+    }
+
+    #[cfg(not(feature="testing"))]
+    fn inject (&self, file: Box<dyn PagedFile>) -> Box<dyn PagedFile> {
+        file
+    }
 
-        Ok(Box::new(Hammersbald::new(log, table, data, link, bucket_fill_target)?))
+    // data and link files are read and written in units of the data page size
+    fn open (&self, extension: &str, append_only: bool) -> Result<Box<dyn PagedFile>, Error> {
+        if let Some(ref store) = self.config.page_store {
+            return store.open(self.name, extension, append_only, self.read_only);
+        }
+        if let Some(ref container) = self.container {
+            return Ok(Box::new(Container::file(container, extension)?));
+        }
+        let data = extension == "bc";
+        let name = self.config.file_name(self.name, data);
+        if self.read_only {
+            return Ok(Box::new(RolledFile::new_read_only(&name, extension, self.config)?));
+        }
+        let page_size = if data || extension == "bl" { self.config.data_page_size } else { self.config.table_page_size };
+        Ok(Box::new(RolledFile::new(&name, extension, append_only, self.config)?.with_page_size(page_size)))
     }
 }
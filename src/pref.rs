@@ -18,6 +18,7 @@
 //! allows reference of a data space of 2^48
 
 use page::PAGE_SIZE;
+use error::Error;
 
 use std::cmp::Ordering;
 use std::fmt;
@@ -43,7 +44,7 @@ impl Ord for PRef {
 
 impl PartialOrd for PRef {
     fn partial_cmp(&self, other: &PRef) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -105,6 +106,22 @@ impl PRef {
         PRef(INVALID)
     }
 
+    /// construct a valid pref, that is less than 2^48 - 1
+    pub fn try_from_u64 (n: u64) -> Result<PRef, Error> {
+        if n >= INVALID {
+            return Err(Error::InvalidOffset);
+        }
+        Ok(PRef(n))
+    }
+
+    /// add n, None if the result is not a valid pref
+    pub fn checked_add (&self, n: u64) -> Option<PRef> {
+        match self.0.checked_add(n) {
+            Some(sum) if sum < INVALID => Some(PRef(sum)),
+            _ => None
+        }
+    }
+
     /// is this a valid pref?
     pub fn is_valid (&self) -> bool {
         self.0 < INVALID
@@ -112,7 +129,7 @@ impl PRef {
 
     /// convert to a number
     pub fn as_u64 (&self) -> u64 {
-        return self.0;
+        self.0
     }
 
     /// pref of the page of this pref
@@ -131,8 +148,8 @@ impl PRef {
     }
 
     /// number of pages from this until an other
-    pub fn pages_until(&self, other: PRef) -> usize {
-        ((other.0 - self.0)/PAGE_SIZE as u64) as usize
+    pub fn pages_until(&self, other: PRef) -> u64 {
+        (other.0 - self.0)/PAGE_SIZE as u64
     }
 
     /// next page
@@ -146,7 +163,7 @@ impl PRef {
     }
 
     /// add n pages
-    pub fn add_pages(&self, n: usize) -> PRef {
-        PRef(self.0 + n as u64 *PAGE_SIZE as u64)
+    pub fn add_pages(&self, n: u64) -> PRef {
+        PRef(self.0 + n * PAGE_SIZE as u64)
     }
 }
@@ -0,0 +1,325 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Raw page access
+//! Read only access to the pages of the files of a db and decoders of the hash table pages and of
+//! the envelopes of the data and link files, for tools such as defragmenters or analytics.
+//! This is the on-disk format as is and NOT a stable API, it changes with the format.
+//!
+
+use persistent::Persistent;
+use pagedfile::PagedFile;
+use tablefile::{TableFile, BUCKETS_FIRST_PAGE, BUCKETS_PER_PAGE};
+use memtable::table_geometry;
+use format::{Payload, EXTENDED};
+use compression::CODEC_MASK;
+use encryption::ENCRYPTED;
+use config::Config;
+use page::{Page, PAGE_SIZE};
+use pref::PRef;
+use error::Error;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use std::cmp::min;
+
+/// a file of a db
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RawFile {
+    /// data, name.bc
+    Data,
+    /// links of hash table buckets, name.bl
+    Link,
+    /// hash table, name.tb
+    Table,
+    /// log of table pages modified by the current batch, name.lg
+    Log,
+    /// filters of hash table buckets, name.bf
+    Bloom
+}
+
+impl RawFile {
+    /// extension of the file
+    pub fn extension (&self) -> &'static str {
+        match self {
+            RawFile::Data => "bc",
+            RawFile::Link => "bl",
+            RawFile::Table => "tb",
+            RawFile::Log => "lg",
+            RawFile::Bloom => "bf"
+        }
+    }
+}
+
+/// the files of a db opened for read only
+/// a db is not recovered, so content of a batch interrupted by a crash is visible
+pub struct RawDb {
+    files: Vec<(RawFile, Box<dyn PagedFile>)>
+}
+
+impl RawDb {
+    /// open the files of a db, that may be open by a writer
+    pub fn open (name: &str, config: &Config) -> Result<RawDb, Error> {
+        let mut files = Vec::new();
+        for file in [RawFile::Data, RawFile::Link, RawFile::Table, RawFile::Log, RawFile::Bloom].iter() {
+            files.push((*file, Persistent::raw_file(name, config, file.extension())?));
+        }
+        Ok(RawDb { files })
+    }
+
+    fn file (&self, file: RawFile) -> &dyn PagedFile {
+        self.files.iter().find(|(f, _)| *f == file).expect("all files are open").1.as_ref()
+    }
+
+    /// number of pages of the file, the last might be partially written
+    pub fn page_count (&self, file: RawFile) -> Result<u64, Error> {
+        Ok(self.file(file).len()?.div_ceil(PAGE_SIZE as u64))
+    }
+
+    /// read the page with the number, None beyond the end of the file
+    pub fn read_page (&self, file: RawFile, page_number: u64) -> Result<Option<Page>, Error> {
+        self.file(file).read_page(PRef::from(page_number * PAGE_SIZE as u64))
+    }
+
+    /// the envelope of the data or link file at the position, None at the end of the content
+    pub fn envelope_at (&self, file: RawFile, pos: PRef) -> Result<Option<RawEnvelope>, Error> {
+        let mut len = [0u8; 3];
+        let after = match self.read(file, pos, &mut len)? {
+            Some(after) => after,
+            None => return Ok(None)
+        };
+        let len = BigEndian::read_u24(&len) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut payload = vec!(0u8; len);
+        match self.read(file, after, &mut payload)? {
+            Some(next) => Ok(Some(RawEnvelope { pos, next, payload })),
+            None => Err(Error::Corrupted(format!("envelope at {} is truncated", pos)))
+        }
+    }
+
+    /// envelopes of the data or link file from the position on, including extensions and padding
+    pub fn envelopes (&self, file: RawFile, from: PRef) -> RawEnvelopes<'_> {
+        RawEnvelopes { db: self, file, pos: Some(from) }
+    }
+
+    // read bytes across pages, returns the position following or None if beyond the end
+    fn read (&self, file: RawFile, mut pos: PRef, buf: &mut [u8]) -> Result<Option<PRef>, Error> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file(file).read_page(pos.this_page())? {
+                Some(page) => {
+                    let have = min(PAGE_SIZE - pos.in_page_pos(), buf.len() - read);
+                    page.read(pos.in_page_pos(), &mut buf[read .. read + have]);
+                    read += have;
+                    pos += have as u64;
+                },
+                None => return Ok(None)
+            }
+        }
+        Ok(Some(pos))
+    }
+}
+
+/// iterator of envelopes of a data or link file
+pub struct RawEnvelopes<'d> {
+    db: &'d RawDb,
+    file: RawFile,
+    pos: Option<PRef>
+}
+
+impl<'d> Iterator for RawEnvelopes<'d> {
+    type Item = Result<RawEnvelope, Error>;
+
+    fn next (&mut self) -> Option<Self::Item> {
+        let pos = self.pos.take()?;
+        match self.db.envelope_at(self.file, pos) {
+            Ok(Some(envelope)) => {
+                self.pos = Some(envelope.next);
+                Some(Ok(envelope))
+            },
+            Ok(None) => None,
+            Err(e) => Some(Err(e))
+        }
+    }
+}
+
+/// an envelope of the data or link file as stored
+pub struct RawEnvelope {
+    /// position of the envelope
+    pub pos: PRef,
+    /// position following the envelope
+    pub next: PRef,
+    /// serialized payload, possibly compressed, encrypted or extended
+    pub payload: Vec<u8>
+}
+
+impl RawEnvelope {
+    /// decode the payload, data is returned as stored
+    pub fn decode (&self) -> Result<RawPayload, Error> {
+        if !Payload::is_well_formed(self.payload.as_slice()) {
+            return Err(Error::Corrupted(format!("envelope at {} is not well formed", self.pos)));
+        }
+        let flags = self.payload[0] & (CODEC_MASK | ENCRYPTED | EXTENDED);
+        let mut plain = self.payload.clone();
+        plain[0] &= !flags;
+        Ok(match Payload::deserialize(plain.as_slice())? {
+            Payload::Indexed(indexed) => RawPayload::Indexed { flags, key: indexed.key.to_vec(), data: indexed.data.data.to_vec() },
+            Payload::Referred(referred) => RawPayload::Referred { flags, data: referred.data.to_vec() },
+            Payload::Link(link) => RawPayload::Link { slots: link.slots() },
+            Payload::Linked(linked) => RawPayload::Linked { flags, links: linked.links(), data: linked.data.data.to_vec() },
+            Payload::Extension(extension) => RawPayload::Extension { next: extension.next, data: extension.data.data.to_vec() },
+            Payload::Padding(padding) => RawPayload::Padding { len: padding.data.len() }
+        })
+    }
+}
+
+/// decoded payload of an envelope
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RawPayload {
+    /// data stored with a key
+    Indexed {
+        /// codec of compressed data, encryption and extension
+        flags: u8,
+        /// key
+        key: Vec<u8>,
+        /// data as stored, extended data starts with the position of its first extension
+        data: Vec<u8>
+    },
+    /// data without key
+    Referred {
+        /// codec of compressed data, encryption and extension
+        flags: u8,
+        /// data as stored
+        data: Vec<u8>
+    },
+    /// slots of a hash table bucket
+    Link {
+        /// hash of a key and the position of its data
+        slots: Vec<(u32, PRef)>
+    },
+    /// data with references to other data
+    Linked {
+        /// codec of compressed data, encryption and extension
+        flags: u8,
+        /// references to other data
+        links: Vec<PRef>,
+        /// data as stored
+        data: Vec<u8>
+    },
+    /// continuation of extended data
+    Extension {
+        /// position of the next extension, invalid for the last
+        next: PRef,
+        /// chunk of data
+        data: Vec<u8>
+    },
+    /// filler up to the end of a page at batch ends
+    Padding {
+        /// bytes of zeros
+        len: usize
+    }
+}
+
+/// header of the first page of the hash table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableHeader {
+    /// number of buckets
+    pub buckets: u64,
+    /// next bucket of the linear hash split
+    pub step: u64,
+    /// buckets below the step are addressed by hash % 2^(log_mod + 1), others by hash % 2^log_mod
+    pub log_mod: u32,
+    /// keys of the siphash of keys
+    pub sip_keys: (u64, u64)
+}
+
+/// a decoded page of the hash table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TablePage {
+    /// header, on the first page only
+    pub header: Option<TableHeader>,
+    /// number of the first bucket of the page
+    pub first_bucket: usize,
+    /// position of the link of each bucket in the link file, invalid for an empty bucket
+    pub links: Vec<PRef>
+}
+
+/// decode a page of the hash table with the number
+pub fn decode_table_page (page: &Page, page_number: u64) -> TablePage {
+    let (header, first_bucket, n) = if page_number == 0 {
+        let buckets = page.read_pref(0).as_u64();
+        let (step, log_mod) = table_geometry(buckets, page.read_pref(6).as_u64());
+        (Some(TableHeader {
+            buckets,
+            step: step as u64,
+            log_mod,
+            sip_keys: (page.read_u64(12), page.read_u64(20))
+        }), 0, BUCKETS_FIRST_PAGE)
+    }
+    else {
+        (None, BUCKETS_FIRST_PAGE + (page_number as usize - 1) * BUCKETS_PER_PAGE, BUCKETS_PER_PAGE)
+    };
+    let links = (first_bucket .. first_bucket + n)
+        .map(|bucket| page.read_pref(TableFile::table_offset(bucket).in_page_pos())).collect();
+    TablePage { header, first_bucket, links }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::persistent_with_config;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_raw () {
+        let dir = env::temp_dir().join(format!("hammersbald-raw-{}", rand::random::<u64>()));
+        let config = Config::default().directory(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut db = persistent_with_config("db", &config).unwrap();
+        let keyed = db.put_keyed(b"key", b"value").unwrap();
+        let referred = db.put(b"referred").unwrap();
+        let linked = db.put_with_links(b"linked", &[keyed]).unwrap();
+        db.batch().unwrap();
+
+        let raw = RawDb::open("db", &config).unwrap();
+        assert!(raw.page_count(RawFile::Data).unwrap() >= 1);
+        assert!(raw.page_count(RawFile::Table).unwrap() >= 1);
+        let envelopes = raw.envelopes(RawFile::Data, PRef::from(0)).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(envelopes[0].pos, keyed);
+        assert_eq!(envelopes[0].decode().unwrap(), RawPayload::Indexed { flags: 0, key: b"key".to_vec(), data: b"value".to_vec() });
+        assert_eq!(envelopes[1].pos, referred);
+        assert_eq!(envelopes[2].decode().unwrap(), RawPayload::Linked { flags: 0, links: vec!(keyed), data: b"linked".to_vec() });
+        assert_eq!(envelopes[2].pos, linked);
+
+        // the bucket of the key links to its slot
+        let table = decode_table_page(&raw.read_page(RawFile::Table, 0).unwrap().unwrap(), 0);
+        assert!(table.header.unwrap().buckets >= 512);
+        let mut slots = Vec::new();
+        for link in table.links.iter().filter(|link| link.is_valid()) {
+            if let RawPayload::Link { slots: s } = raw.envelope_at(RawFile::Link, *link).unwrap().unwrap().decode().unwrap() {
+                slots.extend(s.into_iter().map(|(_, pref)| pref));
+            }
+        }
+        assert_eq!(slots, vec!(keyed));
+        assert!(decode_table_page(&Page::new_table_page(PRef::from(PAGE_SIZE as u64)), 1).header.is_none());
+        db.shutdown();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
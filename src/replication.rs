@@ -0,0 +1,344 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Replication
+//! A source observes the batches of a leader db and serializes the data stored and the keys
+//! forgotten by each into a frame. A sink applies frames in order to a follower, that started as a
+//! copy of the leader and has the same configuration. The follower then stores data at the same
+//! persistent references as the leader, so references stored with data need no rewrite and a
+//! follower that diverged is detected at the first data stored elsewhere.
+//!
+
+use api::{HammersbaldAPI, HammersbaldRead};
+use observer::{CommitObserver, Change};
+use pref::PRef;
+use error::Error;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 8] = b"HAMMERSR";
+const FRAME_VERSION: u32 = 1;
+
+const KEYED: u8 = 0;
+const REFERRED: u8 = 1;
+const LINKED: u8 = 2;
+const FORGOTTEN: u8 = 3;
+// keyed data stored keeping data stored earlier with the key
+const KEPT: u8 = 4;
+// followed by the number of records, so a truncated frame is detected
+const END: u8 = 0xFF;
+
+/// observer of a leader db serializing its batches into frames
+/// register with observe, batches are captured from the next batch on
+#[derive(Default)]
+pub struct ReplicationSource {
+    // batches not yet serialized with their sequence number
+    batches: Mutex<VecDeque<(u64, Vec<Change>)>>,
+    sequence: Mutex<u64>
+}
+
+impl ReplicationSource {
+    /// a source without batches
+    pub fn new () -> ReplicationSource {
+        ReplicationSource::default()
+    }
+
+    /// number of batches not yet serialized
+    pub fn pending (&self) -> usize {
+        self.batches.lock().unwrap().len()
+    }
+
+    /// serialize the oldest batch not yet serialized with data read from the leader db
+    /// returns None if there is none. A batch stays pending if serializing fails.
+    pub fn next_frame (&self, db: &dyn HammersbaldRead) -> Result<Option<Vec<u8>>, Error> {
+        let mut batches = self.batches.lock().unwrap();
+        let frame = match batches.front() {
+            Some((sequence, changes)) => serialize(db, *sequence, changes.as_slice())?,
+            None => return Ok(None)
+        };
+        batches.pop_front();
+        Ok(Some(frame))
+    }
+}
+
+impl CommitObserver for ReplicationSource {
+    fn committed (&self, changes: &[Change]) {
+        // empty batches are not shipped
+        if !changes.is_empty() {
+            let mut sequence = self.sequence.lock().unwrap();
+            self.batches.lock().unwrap().push_back((*sequence, changes.to_vec()));
+            *sequence += 1;
+        }
+    }
+}
+
+// data is read at the reference stored, as data is not overwritten while appended after it
+fn serialize (db: &dyn HammersbaldRead, sequence: u64, changes: &[Change]) -> Result<Vec<u8>, Error> {
+    let mut frame = Vec::new();
+    frame.write_all(MAGIC)?;
+    frame.write_u32::<BigEndian>(FRAME_VERSION)?;
+    frame.write_u64::<BigEndian>(sequence)?;
+    for change in changes {
+        match *change {
+            Change::StoredAlso(ref key, pref) => {
+                let (_, data) = db.get(pref)?;
+                frame.write_u8(KEPT)?;
+                frame.write_u48::<BigEndian>(pref.as_u64())?;
+                write_key(&mut frame, key.as_slice())?;
+                frame.write_u32::<BigEndian>(data.len() as u32)?;
+                frame.write_all(data.as_slice())?;
+            },
+            Change::Stored(ref key, pref) => {
+                let (_, data) = db.get(pref)?;
+                if !key.is_empty() {
+                    frame.write_u8(KEYED)?;
+                    frame.write_u48::<BigEndian>(pref.as_u64())?;
+                    write_key(&mut frame, key.as_slice())?;
+                }
+                else {
+                    let links = db.get_links(pref)?;
+                    if links.is_empty() {
+                        frame.write_u8(REFERRED)?;
+                        frame.write_u48::<BigEndian>(pref.as_u64())?;
+                    }
+                    else {
+                        frame.write_u8(LINKED)?;
+                        frame.write_u48::<BigEndian>(pref.as_u64())?;
+                        frame.write_u32::<BigEndian>(links.len() as u32)?;
+                        for link in links {
+                            frame.write_u48::<BigEndian>(link.as_u64())?;
+                        }
+                    }
+                }
+                frame.write_u32::<BigEndian>(data.len() as u32)?;
+                frame.write_all(data.as_slice())?;
+            },
+            Change::Forgotten(ref key) => {
+                frame.write_u8(FORGOTTEN)?;
+                write_key(&mut frame, key.as_slice())?;
+            }
+        }
+    }
+    frame.write_u8(END)?;
+    frame.write_u64::<BigEndian>(changes.len() as u64)?;
+    Ok(frame)
+}
+
+fn write_key (frame: &mut Vec<u8>, key: &[u8]) -> Result<(), Error> {
+    frame.write_u8(key.len() as u8)?;
+    frame.write_all(key)?;
+    Ok(())
+}
+
+// a change of a frame
+enum Record {
+    Keyed(PRef, Vec<u8>, Vec<u8>),
+    Kept(PRef, Vec<u8>, Vec<u8>),
+    Referred(PRef, Vec<u8>),
+    Linked(PRef, Vec<PRef>, Vec<u8>),
+    Forgotten(Vec<u8>)
+}
+
+/// applies frames of a source to a follower db
+pub struct ReplicationSink {
+    db: Box<dyn HammersbaldAPI>,
+    // sequence number of the next frame, unknown until the first frame is applied
+    next: Option<u64>
+}
+
+impl ReplicationSink {
+    /// apply frames to the follower, it should be a copy of the leader as it was as the source was
+    /// registered and have the same configuration
+    pub fn new (db: Box<dyn HammersbaldAPI>) -> ReplicationSink {
+        ReplicationSink { db, next: None }
+    }
+
+    /// apply the changes of a frame to the follower and end its batch
+    /// fails with Error::Corrupted if a frame is missing or the follower diverged from the leader
+    /// returns the number of changes applied
+    pub fn apply (&mut self, frame: &[u8]) -> Result<u64, Error> {
+        let (sequence, records) = deserialize(frame)?;
+        if let Some(next) = self.next {
+            if sequence != next {
+                return Err(Error::Corrupted(format!("replication frame {} is applied, expected {}", sequence, next)));
+            }
+        }
+        for record in &records {
+            let (expected, stored) = match *record {
+                Record::Keyed(pref, ref key, ref data) => (pref, self.db.put_keyed(key.as_slice(), data.as_slice())?),
+                Record::Kept(pref, ref key, ref data) => (pref, self.db.put_keyed_also(key.as_slice(), data.as_slice())?),
+                Record::Referred(pref, ref data) => (pref, self.db.put(data.as_slice())?),
+                Record::Linked(pref, ref links, ref data) => (pref, self.db.put_with_links(data.as_slice(), links.as_slice())?),
+                Record::Forgotten(ref key) => {
+                    self.db.forget(key.as_slice())?;
+                    continue;
+                }
+            };
+            if stored != expected {
+                return Err(Error::Corrupted(format!("follower diverged, data of the leader at {} is stored at {}", expected, stored)));
+            }
+        }
+        self.db.batch()?;
+        self.next = Some(sequence + 1);
+        Ok(records.len() as u64)
+    }
+
+    /// the follower db
+    pub fn db (&self) -> &dyn HammersbaldAPI {
+        self.db.as_ref()
+    }
+
+    /// stop applying frames and return the follower db
+    pub fn into_inner (self) -> Box<dyn HammersbaldAPI> {
+        self.db
+    }
+}
+
+// the frame is read completely before any change is applied
+fn deserialize (frame: &[u8]) -> Result<(u64, Vec<Record>), Error> {
+    let truncated = |_| Error::Corrupted("replication frame is truncated".to_string());
+    let mut reader = Cursor::new(frame);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(truncated)?;
+    if &magic != MAGIC {
+        return Err(Error::Corrupted("not a hammersbald replication frame".to_string()));
+    }
+    let version = reader.read_u32::<BigEndian>().map_err(truncated)?;
+    if version != FRAME_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let sequence = reader.read_u64::<BigEndian>().map_err(truncated)?;
+    let mut records = Vec::new();
+    loop {
+        let record = match reader.read_u8().map_err(truncated)? {
+            kind @ (KEYED | KEPT) => {
+                let pref = read_pref(&mut reader).map_err(truncated)?;
+                let key = read_key(&mut reader).map_err(truncated)?;
+                let data = read_data(&mut reader)?;
+                if kind == KEYED { Record::Keyed(pref, key, data) } else { Record::Kept(pref, key, data) }
+            },
+            REFERRED => {
+                let pref = read_pref(&mut reader).map_err(truncated)?;
+                Record::Referred(pref, read_data(&mut reader)?)
+            },
+            LINKED => {
+                let pref = read_pref(&mut reader).map_err(truncated)?;
+                let n_links = reader.read_u32::<BigEndian>().map_err(truncated)?;
+                let mut links = Vec::new();
+                for _ in 0 .. n_links {
+                    links.push(read_pref(&mut reader).map_err(truncated)?);
+                }
+                Record::Linked(pref, links, read_data(&mut reader)?)
+            },
+            FORGOTTEN => Record::Forgotten(read_key(&mut reader).map_err(truncated)?),
+            END => {
+                if reader.read_u64::<BigEndian>().map_err(truncated)? != records.len() as u64 {
+                    return Err(Error::Corrupted("number of changes of the replication frame does not match".to_string()));
+                }
+                return Ok((sequence, records));
+            },
+            t => return Err(Error::Corrupted(format!("unknown record type {} in replication frame", t)))
+        };
+        records.push(record);
+    }
+}
+
+fn read_pref (reader: &mut dyn Read) -> Result<PRef, ::std::io::Error> {
+    Ok(PRef::from(reader.read_u48::<BigEndian>()?))
+}
+
+fn read_key (reader: &mut dyn Read) -> Result<Vec<u8>, ::std::io::Error> {
+    let mut key = vec!(0u8; reader.read_u8()? as usize);
+    reader.read_exact(&mut key)?;
+    Ok(key)
+}
+
+fn read_data (reader: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Error> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    let start = reader.position() as usize;
+    // a corrupted length does not allocate beyond the end of the frame
+    let data = reader.get_ref().get(start .. start + len)
+        .ok_or_else(|| Error::Corrupted("replication frame is truncated".to_string()))?.to_vec();
+    reader.set_position((start + len) as u64);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use transient;
+    use api::Conflict;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_replication () {
+        let mut leader = transient(1).unwrap();
+        let doomed = leader.put_keyed(b"doomed", b"before").unwrap();
+        leader.batch().unwrap();
+        // a follower starts as a copy of the leader
+        let mut follower = transient(1).unwrap();
+        assert_eq!(follower.put_keyed(b"doomed", b"before").unwrap(), doomed);
+        follower.batch().unwrap();
+        let mut sink = ReplicationSink::new(follower);
+
+        let source = Arc::new(ReplicationSource::new());
+        leader.observe(source.clone());
+        let keyed = leader.put_keyed(b"key", b"value").unwrap();
+        let referred = leader.put(b"referred").unwrap();
+        let linked = leader.put_with_links(b"linked", &[keyed, referred]).unwrap();
+        leader.forget(b"doomed").unwrap();
+        leader.batch().unwrap();
+        leader.batch().unwrap();
+        leader.put_keyed(b"key", &[7u8; 10000]).unwrap();
+        leader.batch().unwrap();
+        // data ingested keeping both is kept on the follower too
+        let mut other = transient(1).unwrap();
+        other.put_keyed(b"both", b"theirs").unwrap();
+        leader.put_keyed(b"both", b"mine").unwrap();
+        leader.ingest(other.as_ref(), Conflict::KeepBoth).unwrap();
+        assert_eq!(leader.count(b"both").unwrap(), 2);
+        assert_eq!(source.pending(), 4);
+
+        let first = source.next_frame(leader.as_ref()).unwrap().unwrap();
+        let second = source.next_frame(leader.as_ref()).unwrap().unwrap();
+        // frames are checked completely before they are applied
+        assert!(sink.apply(&first[.. first.len() - 1]).is_err());
+        assert_eq!(sink.apply(first.as_slice()).unwrap(), 4);
+        // a frame out of sequence is not applied
+        assert!(sink.apply(first.as_slice()).is_err());
+        assert_eq!(sink.apply(second.as_slice()).unwrap(), 1);
+        // ingest ends the batch of the put before
+        for _ in 0 .. 2 {
+            let frame = source.next_frame(leader.as_ref()).unwrap().unwrap();
+            assert_eq!(sink.apply(frame.as_slice()).unwrap(), 1);
+        }
+
+        let follower = sink.db();
+        assert_eq!(follower.get_keyed(b"key").unwrap(), leader.get_keyed(b"key").unwrap());
+        assert_eq!(follower.get(referred).unwrap(), (vec!(), b"referred".to_vec()));
+        assert_eq!(follower.get_links(linked).unwrap(), vec!(keyed, referred));
+        assert_eq!(follower.get_keyed(b"doomed").unwrap(), None);
+        assert_eq!(follower.count(b"both").unwrap(), 2);
+        assert_eq!(follower.get_keyed(b"both").unwrap(), leader.get_keyed(b"both").unwrap());
+        assert_eq!(follower.get_filtered(b"both", &|_, _| true).unwrap(), leader.get_filtered(b"both", &|_, _| true).unwrap());
+        assert!(source.next_frame(leader.as_ref()).unwrap().is_none());
+    }
+}
@@ -18,16 +18,18 @@
 //!
 //! A file that is split into chunks
 //!
-use error::Error;
+use error::{Error, FileKind, Operation};
 use pref::PRef;
 use page::{Page, PAGE_SIZE};
 use pagedfile::PagedFile;
-use singlefile::SingleFile;
+use singlefile::{SingleFile, FORMAT_VERSION, LEGACY_VERSION};
+use config::{Config, SyncMode, DEFAULT_MAX_FILE_SIZE};
 
 use std::collections::HashMap;
 use std::fs::{self, File, OpenOptions};
-use std::path::Path;
-use std::cmp::max;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::cmp::{max, min};
 
 pub struct RolledFile {
     name: String,
@@ -35,23 +37,107 @@ pub struct RolledFile {
     files: HashMap<u16,SingleFile>,
     len: u64,
     append_only: bool,
-    chunk_size: u64
+    read_only: bool,
+    direct_io: bool,
+    sync: SyncMode,
+    chunk_size: u64,
+    page_size: usize
 }
 
 impl RolledFile {
-    pub fn new (name: &str, extension: &str, append_only: bool, chunk_size: u64) -> Result<RolledFile, Error> {
-        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, chunk_size};
+    pub fn new (name: &str, extension: &str, append_only: bool, config: &Config) -> Result<RolledFile, Error> {
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only, read_only: false,
+            direct_io: config.direct_io, sync: config.sync, chunk_size: config.max_file_size, page_size: PAGE_SIZE};
+        rolled.open()?;
+        Ok(rolled)
+    }
+
+    /// read and write chunks created later in units of the page size
+    pub fn with_page_size (mut self, page_size: usize) -> RolledFile {
+        self.page_size = page_size;
+        self
+    }
+
+    /// open existing chunks without permission to modify them
+    pub fn new_read_only (name: &str, extension: &str, config: &Config) -> Result<RolledFile, Error> {
+        let mut rolled = RolledFile { name: name.to_string(), extension: extension.to_string(), files: HashMap::new(), len: 0, append_only: false, read_only: true,
+            direct_io: config.direct_io, sync: config.sync, chunk_size: config.max_file_size, page_size: PAGE_SIZE};
         rolled.open()?;
         Ok(rolled)
     }
 
     fn open (&mut self) -> Result<(), Error> {
-        // interesting file names are:
-        // name.index.extension
-        // where index is a number
-        if let Some(basename) = Path::new(self.name.as_str()).file_name() {
-            let mut highest_chunk = 0;
-            if let Some(mut dir) = Path::new(&self.name).parent() {
+        let mut highest_chunk = 0;
+        for (number, path) in Self::chunk_files(self.name.as_str(), self.extension.as_str())? {
+            let filename = path.to_string_lossy().to_string();
+            let file = self.open_file(filename)?;
+            self.files.insert(number,
+                              SingleFile::new_chunk(file, number as u64 * self.chunk_size, self.chunk_size, self.page_size, self.sync)?);
+            if let Some (file) = self.files.get(&number) {
+                if file.len().unwrap() > 0 {
+                    highest_chunk = max(highest_chunk, number);
+                }
+            }
+        }
+        if let Some (file) = self.files.get(&highest_chunk) {
+            self.len = highest_chunk as u64 * self.chunk_size + file.len()?;
+        }
+        Ok(())
+    }
+
+    /// upgrade all chunks of a rolled file to the current format version
+    pub fn migrate (name: &str, extension: &str) -> Result<(), Error> {
+        for (_, path) in Self::chunk_files(name, extension)? {
+            let mut file = OpenOptions::new().read(true).open(&path)?;
+            match SingleFile::version(&SingleFile::read_header(&mut file)?) {
+                FORMAT_VERSION => {},
+                LEGACY_VERSION => {
+                    // copy content behind a new header page then replace the original
+                    let mut tmp_path = path.clone().into_os_string();
+                    tmp_path.push(".migrate");
+                    let mut tmp = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+                    // legacy files were always rolled at the default size
+                    tmp.write_all(SingleFile::header(DEFAULT_MAX_FILE_SIZE, PAGE_SIZE).as_buf())?;
+                    file.seek(SeekFrom::Start(0))?;
+                    io::copy(&mut file, &mut tmp)?;
+                    tmp.sync_all()?;
+                    fs::rename(&tmp_path, &path)?;
+                },
+                version => return Err(Error::UnsupportedVersion(version))
+            }
+        }
+        Ok(())
+    }
+
+    /// there is a chunk of a rolled file
+    pub fn exists (name: &str, extension: &str) -> Result<bool, Error> {
+        Ok(!Self::chunk_files(name, extension)?.is_empty())
+    }
+
+    /// chunk size of a rolled file, none if it has no chunk
+    pub fn chunk_size_of (name: &str, extension: &str) -> Result<Option<u64>, Error> {
+        if let Some((_, path)) = Self::chunk_files(name, extension)?.into_iter().next() {
+            let header = SingleFile::read_header(&mut OpenOptions::new().read(true).open(&path)?)?;
+            return Ok(Some(SingleFile::chunk_size(&header)));
+        }
+        Ok(None)
+    }
+
+    /// remove all chunks of a rolled file
+    pub fn remove (name: &str, extension: &str) -> Result<(), Error> {
+        for (_, path) in Self::chunk_files(name, extension)? {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    // interesting file names are:
+    // name.index.extension
+    // where index is a number
+    fn chunk_files (name: &str, extension: &str) -> Result<Vec<(u16, PathBuf)>, Error> {
+        let mut chunks = Vec::new();
+        if let Some(basename) = Path::new(name).file_name() {
+            if let Some(mut dir) = Path::new(name).parent() {
                 if dir.to_string_lossy().to_string().is_empty() {
                     dir = Path::new(".");
                 }
@@ -60,25 +146,17 @@ impl RolledFile {
                     if path.is_file() {
                         if let Some(name_index) = path.file_stem() {
                             // name.index
-                            let ni = Path::new(name_index.clone());
+                            let ni = Path::new(name_index);
                             if let Some(name) = ni.file_stem() {
                                 // compare name
                                 if name == basename {
                                     // compare extension
-                                    if let Some(extension) = path.extension() {
-                                        if extension.to_string_lossy().to_string() == self.extension {
+                                    if let Some(ext) = path.extension() {
+                                        if ext.to_string_lossy() == extension {
                                             // parse index
                                             if let Some(index) = ni.extension() {
                                                 if let Ok(number) = index.to_string_lossy().parse::<u16>() {
-                                                    let filename = path.clone().to_string_lossy().to_string();
-                                                    let file = Self::open_file(self.append_only, filename)?;
-                                                    self.files.insert(number,
-                                                                      SingleFile::new_chunk(file, number as u64 * self.chunk_size, self.chunk_size)?);
-                                                    if let Some (file) = self.files.get(&number) {
-                                                        if file.len().unwrap() > 0 {
-                                                            highest_chunk = max(highest_chunk, number);
-                                                        }
-                                                    }
+                                                    chunks.push((number, path.clone()));
                                                 }
                                             }
                                         }
@@ -89,35 +167,126 @@ impl RolledFile {
                     }
                 }
             }
-            if let Some (file) = self.files.get(&highest_chunk) {
-                self.len = highest_chunk as u64 * self.chunk_size + file.len()?;
-            }
         }
         else {
             return Err(Error::Corrupted("invalid db name".to_string()));
         }
+        Ok(chunks)
+    }
+
+    fn chunk_path (name: &str, chunk: u16, extension: &str) -> String {
+        format!("{}.{}.{}", name, chunk, extension)
+    }
+
+    // a chunk hard linked into a snapshot is replaced by a copy before it is truncated,
+    // so the snapshot keeps its content
+    #[cfg(unix)]
+    fn unshare (&mut self, chunk: u16) -> Result<(), Error> {
+        use std::os::unix::fs::MetadataExt;
+        let path = Self::chunk_path(&self.name, chunk, &self.extension);
+        if let Some(file) = self.files.get_mut(&chunk) {
+            if fs::metadata(&path)?.nlink() < 2 {
+                return Ok(());
+            }
+            file.flush()?;
+            let copy = format!("{}.unshare", path);
+            fs::copy(&path, &copy)?;
+            fs::rename(&copy, &path)?;
+        }
+        else {
+            return Ok(());
+        }
+        let file = self.open_file(path)?;
+        self.files.insert(chunk, SingleFile::new_chunk(file, chunk as u64 * self.chunk_size, self.chunk_size, self.page_size, self.sync)?);
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn unshare (&mut self, _: u16) -> Result<(), Error> {
         Ok(())
     }
 
-    fn open_file (append: bool, path: String) -> Result<File, Error> {
+    fn open_file (&self, path: String) -> Result<File, Error> {
         let mut open_mode = OpenOptions::new();
 
-        if append {
-            open_mode.read(true).append(true).create(true);
+        if self.read_only {
+            open_mode.read(true);
+        }
+        else if self.append_only {
+            Self::set_append(open_mode.read(true).create(true));
         }
         else{
             open_mode.read(true).write(true).create(true);
         };
+        if self.direct_io {
+            Self::set_direct(&mut open_mode);
+        }
+        Self::set_share(&mut open_mode);
         Ok(open_mode.open(path)?)
     }
+
+    #[cfg(not(windows))]
+    fn set_append (open_mode: &mut OpenOptions) {
+        open_mode.append(true);
+    }
+
+    // a handle opened for append only lacks the right to set the end of file, so truncate
+    // would fail. Writes are at the end of the content anyway.
+    #[cfg(windows)]
+    fn set_append (open_mode: &mut OpenOptions) {
+        open_mode.write(true);
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_direct (open_mode: &mut OpenOptions) {
+        use std::os::unix::fs::OpenOptionsExt;
+        open_mode.custom_flags(libc::O_DIRECT);
+    }
+
+    // FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH, pages are aligned to the sector size
+    #[cfg(windows)]
+    fn set_direct (open_mode: &mut OpenOptions) {
+        use std::os::windows::fs::OpenOptionsExt;
+        open_mode.custom_flags(0x2000_0000 | 0x8000_0000);
+    }
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    fn set_direct (_: &mut OpenOptions) {
+    }
+
+    // FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE, so read only tools attach to the
+    // files of a db open for writing and chunks can be removed while open
+    #[cfg(windows)]
+    fn set_share (open_mode: &mut OpenOptions) {
+        use std::os::windows::fs::OpenOptionsExt;
+        open_mode.share_mode(0x1 | 0x2 | 0x4);
+    }
+
+    #[cfg(not(windows))]
+    fn set_share (_: &mut OpenOptions) {
+    }
+
+    // number of the chunk storing pos
+    fn chunk (&self, pos: u64) -> Result<u16, Error> {
+        let chunk = pos / self.chunk_size;
+        if chunk > u16::MAX as u64 {
+            return Err(Error::DoesNotFit { what: "number of file chunks", max: u16::MAX as u64, got: chunk });
+        }
+        Ok(chunk as u16)
+    }
+
+    // the file named in IO errors
+    fn kind (&self) -> FileKind {
+        FileKind::from_extension(self.extension.as_str())
+    }
 }
 
 impl PagedFile for RolledFile {
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
         if pref.as_u64() < self.len {
-            let chunk = (pref.as_u64() / self.chunk_size) as u16;
+            let chunk = self.chunk(pref.as_u64())?;
             if let Some(file) = self.files.get(&chunk) {
-                return file.read_page(pref);
+                return file.read_page(pref).map_err(|e| e.within(self.kind(), Operation::Read, pref.as_u64()));
             }
         }
         Ok(None)
@@ -128,72 +297,133 @@ impl PagedFile for RolledFile {
     }
 
     fn truncate(&mut self, new_len: u64) -> Result<(), Error> {
-        if new_len % PAGE_SIZE as u64 != 0 {
-            return Err(Error::Corrupted(format!("truncate not to page boundary {}", new_len)));
+        if !new_len.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::Bug(format!("truncate not to page boundary {}", new_len)));
         }
-        let chunk = (new_len / self.chunk_size) as u16;
-        for (c, file) in &mut self.files {
-            if *c > chunk {
-                file.truncate(0)?;
+        let chunk = self.chunk(new_len)?;
+        let kind = self.kind();
+        let mut shortened = Vec::new();
+        for (c, file) in &self.files {
+            let keep = if *c > chunk { 0 } else if *c == chunk { new_len % self.chunk_size } else { continue };
+            if file.len()? > keep {
+                shortened.push((*c, keep));
             }
         }
-        if let Some (last) = self.files.get_mut(&chunk) {
-            last.truncate(new_len % self.chunk_size)?;
+        for (c, keep) in shortened {
+            self.unshare(c).map_err(|e| e.within(kind, Operation::Truncate, new_len))?;
+            if let Some(file) = self.files.get_mut(&c) {
+                file.truncate(keep).map_err(|e| e.within(kind, Operation::Truncate, new_len))?;
+            }
         }
         self.len = new_len;
         Ok(())
     }
 
     fn sync(&self) -> Result<(), Error> {
+        // there is nothing to sync, Windows refuses to flush a handle without write access
+        #[cfg(windows)]
+        {
+            if self.read_only {
+                return Ok(());
+            }
+        }
         for file in self.files.values() {
-            file.sync()?;
+            file.sync().map_err(|e| e.within(self.kind(), Operation::Sync, 0))?;
         }
         Ok(())
     }
 
     fn shutdown (&mut self) {}
 
+    // a snapshot is rolled at the same size, so whole chunks are shared as they are
+    #[cfg(unix)]
+    fn link_chunks (&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        if chunk_size != self.chunk_size {
+            return Ok(0);
+        }
+        let chunks = min(len, self.len) / self.chunk_size;
+        for chunk in 0 .. chunks as u16 {
+            fs::hard_link(Self::chunk_path(&self.name, chunk, &self.extension), Self::chunk_path(name, chunk, &self.extension))?;
+        }
+        Ok(chunks * self.chunk_size)
+    }
+
     fn append_page (&mut self, page: Page) -> Result<(), Error> {
-        let chunk = (self.len / self.chunk_size) as u16;
+        let chunk = self.chunk(self.len)?;
 
-        if self.len % self.chunk_size == 0 && !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, self.len, self.chunk_size)?);
+        if self.len.is_multiple_of(self.chunk_size) && !self.files.contains_key(&chunk) {
+            let file = self.open_file(Self::chunk_path(&self.name, chunk, &self.extension))?;
+            self.files.insert(chunk, SingleFile::new_chunk(file, self.len, self.chunk_size, self.page_size, self.sync)?);
         }
 
+        let kind = self.kind();
         if let Some (file) = self.files.get_mut(&chunk) {
-            file.append_page(page)?;
+            file.append_page(page).map_err(|e| e.within(kind, Operation::Write, self.len))?;
             self.len += PAGE_SIZE as u64;
         }
         else {
-            return Err(Error::Corrupted(format!("missing chunk in append {}", chunk)));
+            return Err(Error::Bug(format!("missing chunk in append {}", chunk)));
         }
         Ok(())
     }
 
+    // chunks are created and extended up to len, sparse where the file system supports it
+    fn allocate (&mut self, len: u64) -> Result<bool, Error> {
+        let kind = self.kind();
+        let mut pos = self.len;
+        while pos < len {
+            let chunk = self.chunk(pos)?;
+            let base = chunk as u64 * self.chunk_size;
+            if !self.files.contains_key(&chunk) {
+                let file = self.open_file(Self::chunk_path(&self.name, chunk, &self.extension))?;
+                self.files.insert(chunk, SingleFile::new_chunk(file, base, self.chunk_size, self.page_size, self.sync)?);
+            }
+            let end = min(len, base + self.chunk_size);
+            if let Some(file) = self.files.get_mut(&chunk) {
+                file.allocate(end - base).map_err(|e| e.within(kind, Operation::Write, pos))?;
+            }
+            pos = end;
+        }
+        self.len = max(self.len, len);
+        Ok(true)
+    }
+
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
         let n_offset = page.pref().as_u64();
-        let chunk = (n_offset / self.chunk_size) as u16;
+        let chunk = self.chunk(n_offset)?;
 
         if !self.files.contains_key(&chunk) {
-            let file = Self::open_file(self.append_only, (((self.name.clone() + ".")
-                + chunk.to_string().as_str()) + ".") + self.extension.as_str())?;
-            self.files.insert(chunk, SingleFile::new_chunk(file, (n_offset/self.chunk_size) * self.chunk_size, self.chunk_size)?);
+            let file = self.open_file(Self::chunk_path(&self.name, chunk, &self.extension))?;
+            self.files.insert(chunk, SingleFile::new_chunk(file, (n_offset/self.chunk_size) * self.chunk_size, self.chunk_size, self.page_size, self.sync)?);
         }
 
+        let kind = self.kind();
         if let Some(file) = self.files.get_mut(&chunk) {
-            self.len = max(self.len, file.update_page(page)?  + chunk as u64 * self.chunk_size);
+            self.len = max(self.len, file.update_page(page).map_err(|e| e.within(kind, Operation::Write, n_offset))?  + chunk as u64 * self.chunk_size);
             Ok(self.len)
         } else {
-            return Err(Error::Corrupted(format!("missing chunk in write {}", chunk)));
+            Err(Error::Bug(format!("missing chunk in write {}", chunk)))
         }
     }
 
     fn flush(&mut self) -> Result<(), Error> {
+        let kind = self.kind();
         for file in &mut self.files.values_mut() {
-            file.flush()?;
+            file.flush().map_err(|e| e.within(kind, Operation::Flush, 0))?;
         }
         Ok(())
     }
+
+    // the pages are split at chunk boundaries
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        let end = min(pref.as_u64() + (pages * PAGE_SIZE) as u64, self.len);
+        let mut pos = pref.as_u64();
+        while pos < end {
+            let next = min((pos / self.chunk_size + 1) * self.chunk_size, end);
+            if let Some(file) = self.chunk(pos).ok().and_then(|chunk| self.files.get(&chunk)) {
+                file.prefetch(PRef::from(pos), ((next - pos) / PAGE_SIZE as u64) as usize);
+            }
+            pos = next;
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,337 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Sharded db
+//! A db of several shards, each with data file and hash table of its own, so threads append to
+//! different shards concurrently. Keyed data is stored in the shard of the hash of its key, data
+//! without key in the first shard not in use by an other thread. The shard is encoded in the high
+//! bits of persistent references.
+//!
+
+use api::{HammersbaldAPI, persistent_with_config};
+use persistent::Persistent;
+use config::Config;
+use page::PAGE_SIZE;
+use pref::PRef;
+use error::Error;
+
+use bitcoin_hashes::siphash24;
+
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// most shards of a db
+pub const MAX_SHARDS: usize = 16;
+// bits of a persistent reference within a shard
+const SHARD_SHIFT: u32 = 44;
+const IN_SHARD: u64 = (1 << SHARD_SHIFT) - 1;
+// most bytes an envelope and the padding of its page add to key and data
+const ENVELOPE_MARGIN: u64 = 2 * PAGE_SIZE as u64;
+// data read from a shard while it is locked by an iterator
+const ITER_CHUNK: usize = 256;
+
+/// a db of shards appended to by threads concurrently
+pub struct ShardedHammersbald {
+    shards: Vec<Mutex<Box<dyn HammersbaldAPI>>>,
+    // shard tried first by the next put without key
+    next: AtomicUsize
+}
+
+impl ShardedHammersbald {
+    /// a sharded db of dbs, the number and order of shards must not change after data is stored
+    pub fn new (shards: Vec<Box<dyn HammersbaldAPI>>) -> Result<ShardedHammersbald, Error> {
+        if shards.is_empty() || shards.len() > MAX_SHARDS {
+            return Err(Error::DoesNotFit { what: "number of shards", max: MAX_SHARDS as u64, got: shards.len() as u64 });
+        }
+        Ok(ShardedHammersbald { shards: shards.into_iter().map(Mutex::new).collect(), next: AtomicUsize::new(0) })
+    }
+
+    /// open or create the persistent shards name.s0, name.s1 ...
+    /// fails with Error::Corrupted if the db exists with an other number of shards
+    pub fn open (name: &str, config: &Config, shards: usize) -> Result<ShardedHammersbald, Error> {
+        if config.page_store.is_none() {
+            let existing = (0 .. MAX_SHARDS).map(|i| Persistent::exists(&Self::shard_name(name, i), config))
+                .collect::<Result<Vec<_>, _>>()?;
+            let n = existing.iter().filter(|e| **e).count();
+            if n > 0 && (n != shards || existing.iter().take(shards).any(|e| !*e)) {
+                return Err(Error::Corrupted(format!("db {} is stored in {} shards, not {}", name, n, shards)));
+            }
+        }
+        let mut dbs = Vec::with_capacity(shards);
+        for i in 0 .. shards {
+            dbs.push(persistent_with_config(&Self::shard_name(name, i), config)?);
+        }
+        Self::new(dbs)
+    }
+
+    fn shard_name (name: &str, shard: usize) -> String {
+        format!("{}.s{}", name, shard)
+    }
+
+    /// number of shards
+    pub fn shards (&self) -> usize {
+        self.shards.len()
+    }
+
+    fn lock (&self, shard: usize) -> Result<MutexGuard<'_, Box<dyn HammersbaldAPI>>, Error> {
+        Ok(self.shards[shard].lock()?)
+    }
+
+    fn shard_of_key (&self, key: &[u8]) -> usize {
+        (siphash24::Hash::hash_to_u64_with_keys(0, 0, key) % self.shards.len() as u64) as usize
+    }
+
+    // persistent reference of the db and its shard
+    fn split (&self, pref: PRef) -> Result<(usize, PRef), Error> {
+        let shard = (pref.as_u64() >> SHARD_SHIFT) as usize;
+        if !pref.is_valid() || shard >= self.shards.len() {
+            return Err(Error::InvalidOffset);
+        }
+        Ok((shard, PRef::from(pref.as_u64() & IN_SHARD)))
+    }
+
+    // fail if data of the size might not be addressed within a shard of the length
+    fn check_room (len: u64, size: usize) -> Result<(), Error> {
+        let end = len + size as u64 + ENVELOPE_MARGIN;
+        if end > IN_SHARD {
+            return Err(Error::DoesNotFit { what: "length of a shard", max: IN_SHARD, got: end });
+        }
+        Ok(())
+    }
+
+    fn join (shard: usize, pref: PRef) -> Result<PRef, Error> {
+        if pref.as_u64() > IN_SHARD {
+            return Err(Error::DoesNotFit { what: "length of a shard", max: IN_SHARD, got: pref.as_u64() });
+        }
+        Ok(PRef::from(((shard as u64) << SHARD_SHIFT) | pref.as_u64()))
+    }
+
+    /// store data with a key in the shard of the key
+    pub fn put_keyed (&self, key: &[u8], data: &[u8]) -> Result<PRef, Error> {
+        let shard = self.shard_of_key(key);
+        let mut db = self.lock(shard)?;
+        Self::check_room(db.data_len(), key.len() + data.len())?;
+        Self::join(shard, db.put_keyed(key, data)?)
+    }
+
+    /// store data without key in the first shard not in use by an other thread, waits only if all are
+    pub fn put (&self, data: &[u8]) -> Result<PRef, Error> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0 .. self.shards.len() {
+            let shard = (start + i) % self.shards.len();
+            match self.shards[shard].try_lock() {
+                Ok(mut db) => {
+                    Self::check_room(db.data_len(), data.len())?;
+                    return Self::join(shard, db.put(data)?)
+                },
+                Err(TryLockError::WouldBlock) => continue,
+                Err(TryLockError::Poisoned(e)) => return Err(Error::from(e))
+            }
+        }
+        let shard = start % self.shards.len();
+        let mut db = self.lock(shard)?;
+        Self::check_room(db.data_len(), data.len())?;
+        Self::join(shard, db.put(data)?)
+    }
+
+    /// retrieve data with key
+    /// returns Some(persistent reference, data) or None
+    pub fn get_keyed (&self, key: &[u8]) -> Result<Option<(PRef, Vec<u8>)>, Error> {
+        let shard = self.shard_of_key(key);
+        match self.lock(shard)?.get_keyed(key)? {
+            Some((pref, data)) => Ok(Some((Self::join(shard, pref)?, data))),
+            None => Ok(None)
+        }
+    }
+
+    /// retrieve data using a persistent reference
+    /// returns (key, data)
+    pub fn get (&self, pref: PRef) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        let (shard, pref) = self.split(pref)?;
+        self.lock(shard)?.get(pref)
+    }
+
+    /// forget a key (if known)
+    pub fn forget (&self, key: &[u8]) -> Result<(), Error> {
+        self.lock(self.shard_of_key(key))?.forget(key)
+    }
+
+    /// end the current batch of each shard. Shards are batched one after the other, a crash in
+    /// between loses the batch of the later shards only.
+    pub fn batch (&self) -> Result<(), Error> {
+        for shard in 0 .. self.shards.len() {
+            self.lock(shard)?.batch()?;
+        }
+        Ok(())
+    }
+
+    /// data of each shard, the shards one after the other
+    /// returns (persistent reference, key, data)
+    pub fn iter (&self) -> ShardedIterator<'_> {
+        ShardedIterator { db: self, shard: 0, pos: PRef::from(0), items: Vec::new().into_iter(), error: None }
+    }
+
+    /// stop background writers of all shards
+    pub fn shutdown (&self) {
+        for shard in &self.shards {
+            if let Ok(mut db) = shard.lock() {
+                db.shutdown();
+            }
+        }
+    }
+}
+
+/// iterate the data of the shards one after the other, a shard is locked only while a few of its
+/// data are read, the next are read from the position after them
+pub struct ShardedIterator<'a> {
+    db: &'a ShardedHammersbald,
+    shard: usize,
+    pos: PRef,
+    items: ::std::vec::IntoIter<(PRef, Vec<u8>, Vec<u8>)>,
+    error: Option<Error>
+}
+
+impl<'a> ShardedIterator<'a> {
+    // read the next data of the current shard, move on to the next shard at its end
+    fn read (&mut self) -> Result<(), Error> {
+        let db = self.db.lock(self.shard)?;
+        let mut cursor = db.cursor_at(self.pos)?;
+        let mut items = Vec::with_capacity(ITER_CHUNK);
+        for (pref, key, data) in cursor.by_ref().take(ITER_CHUNK) {
+            items.push((ShardedHammersbald::join(self.shard, pref)?, key, data));
+        }
+        cursor.error()?;
+        if items.len() < ITER_CHUNK {
+            self.shard += 1;
+            self.pos = PRef::from(0);
+        }
+        else {
+            self.pos = cursor.position();
+        }
+        self.items = items.into_iter();
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ShardedIterator<'a> {
+    type Item = Result<(PRef, Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(error) = self.error.take() {
+                return Some(Err(error));
+            }
+            if let Some(item) = self.items.next() {
+                return Some(Ok(item));
+            }
+            if self.shard >= self.db.shards.len() {
+                return None;
+            }
+            if let Err(error) = self.read() {
+                // the shard is not read further
+                self.error = Some(error);
+                self.shard += 1;
+                self.pos = PRef::from(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use transient;
+
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_sharded () {
+        let db = Arc::new(ShardedHammersbald::new((0 .. 4).map(|_| transient(1).unwrap()).collect()).unwrap());
+        let producers = (0 .. 4u32).map(|t| {
+            let db = db.clone();
+            thread::spawn(move || {
+                (0 .. 100u32).map(|i| {
+                    let key = (t * 1000 + i).to_be_bytes();
+                    (db.put_keyed(&key, &key).unwrap(), db.put(&key).unwrap())
+                }).collect::<Vec<_>>()
+            })
+        }).collect::<Vec<_>>();
+        let stored = producers.into_iter().flat_map(|p| p.join().unwrap()).collect::<Vec<_>>();
+        db.batch().unwrap();
+
+        for (keyed, referred) in &stored {
+            let (key, data) = db.get(*keyed).unwrap();
+            assert_eq!(key, data);
+            assert_eq!(db.get_keyed(key.as_slice()).unwrap(), Some((*keyed, data.clone())));
+            assert_eq!(db.get(*referred).unwrap(), (vec!(), data));
+        }
+        assert!(stored.iter().any(|(keyed, _)| keyed.as_u64() >> SHARD_SHIFT > 0));
+        assert_eq!(db.iter().filter(|item| !item.as_ref().unwrap().1.is_empty()).count(), 400);
+        db.forget(&1u32.to_be_bytes()).unwrap();
+        assert_eq!(db.get_keyed(&1u32.to_be_bytes()).unwrap(), None);
+        assert!(db.get(PRef::from(7 << SHARD_SHIFT)).is_err());
+        assert!(ShardedHammersbald::new(vec!()).is_err());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_sharded_iter () {
+        let db = ShardedHammersbald::new((0 .. 2).map(|_| transient(1).unwrap()).collect()).unwrap();
+        for i in 0 .. 1000u32 {
+            db.put_keyed(&i.to_be_bytes(), &[1u8; 100]).unwrap();
+        }
+        db.batch().unwrap();
+        // the iterator holds no lock between its items, data stored meanwhile is read if after its position
+        let mut iter = db.iter();
+        assert!(iter.next().unwrap().is_ok());
+        for i in 1000 .. 1010u32 {
+            db.put_keyed(&i.to_be_bytes(), &[2u8; 100]).unwrap();
+        }
+        let keyed = iter.map(|item| item.unwrap()).filter(|(_, key, _)| !key.is_empty()).collect::<Vec<_>>();
+        assert_eq!(keyed.len() + 1, 1010);
+        assert_eq!(keyed.iter().filter(|(_, _, data)| data[0] == 2).count(), 10);
+        for (pref, key, data) in keyed {
+            assert_eq!(db.get(pref).unwrap(), (key, data));
+        }
+
+        assert!(ShardedHammersbald::check_room(0, 1000).is_ok());
+        assert!(ShardedHammersbald::check_room(IN_SHARD - ENVELOPE_MARGIN - 1000, 1000).is_ok());
+        assert!(ShardedHammersbald::check_room(IN_SHARD - ENVELOPE_MARGIN - 1000, 1001).is_err());
+        db.shutdown();
+    }
+
+    #[test]
+    fn test_sharded_open () {
+        let dir = env::temp_dir().join(format!("hammersbald-sharded-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config::default().directory(&dir);
+        let db = ShardedHammersbald::open("db", &config, 2).unwrap();
+        let pref = db.put_keyed(b"key", b"data").unwrap();
+        db.batch().unwrap();
+        db.shutdown();
+        drop(db);
+
+        assert!(ShardedHammersbald::open("db", &config, 3).is_err());
+        let db = ShardedHammersbald::open("db", &config, 2).unwrap();
+        assert_eq!(db.get_keyed(b"key").unwrap(), Some((pref, b"data".to_vec())));
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,36 +15,225 @@
 //
 //!
 //! # a single file
-//!
+//! Pages are read and written in units of the page size recorded in the header, a multiple
+//! of the 4k page. Appended pages are written once they fill a unit or at flush, a read
+//! reads the whole unit and keeps it for following reads. Pages of a prefetch are read on a
+//! helper thread and taken over as the unit read once a read needs them.
 //!
 
 use error::Error;
 use pagedfile::PagedFile;
 use page::{PAGE_SIZE, Page};
 use pref::PRef;
+use config::SyncMode;
+
+use byteorder::{ByteOrder, BigEndian};
 
 use std::sync::Mutex;
 use std::fs::File;
-use std::io::{Read,Write,Seek,SeekFrom};
-use std::cmp::max;
+use std::io::{self, IoSlice, IoSliceMut, Read, Write, Seek, SeekFrom};
+use std::thread::JoinHandle;
+use std::cmp::{max, min};
+
+/// magic bytes at the start of every file
+pub const MAGIC: [u8; 8] = *b"HAMMERSB";
+/// file format version written into the header page
+pub const FORMAT_VERSION: u32 = 2;
+/// the version of files created before the header page was introduced
+pub const LEGACY_VERSION: u32 = 1;
+/// the header page precedes the content of every file
+pub const HEADER_SIZE: u64 = PAGE_SIZE as u64;
+/// largest unit of reads and writes
+pub const MAX_PAGE_SIZE: usize = 1 << 16;
+
+const VERSION_POS: usize = 8;
+const CHUNK_SIZE_POS: usize = 12;
+// files created before the page size was recorded have zero here
+const PAGE_SIZE_POS: usize = 20;
 
 pub struct SingleFile {
-    file: Mutex<File>,
+    inner: Mutex<Inner>,
     base: u64,
     len: u64,
-    chunk_size: u64
+    chunk_size: u64,
+    page_size: u64,
+    sync: SyncMode
+}
+
+// position and number of pages read ahead, by a thread
+type ReadAhead = (u64, usize, JoinHandle<io::Result<Vec<Page>>>);
+
+struct Inner {
+    file: File,
+    // length written to the file, pages beyond are pending
+    written: u64,
+    pending: Vec<Page>,
+    // the unit read last, at its position
+    read: Option<(u64, Vec<Page>)>,
+    // pages being read ahead, at their position with their number
+    ahead: Option<ReadAhead>
 }
 
 impl SingleFile {
     #[allow(unused)]
-    pub fn new (mut file: File) -> Result<SingleFile, Error> {
-        let len = file.seek(SeekFrom::End(0))?;
-        Ok(SingleFile{file: Mutex::new(file), base: 0, len, chunk_size: 1 << 47})
+    pub fn new (file: File) -> Result<SingleFile, Error> {
+        Self::new_chunk(file, 0, 1 << 47, PAGE_SIZE, SyncMode::Data)
+    }
+
+    /// open a chunk of a file, the page size is that of the header if the file exists
+    pub fn new_chunk (mut file: File, base: u64, chunk_size: u64, page_size: usize, sync: SyncMode) -> Result<SingleFile, Error> {
+        let flen = file.seek(SeekFrom::End(0))?;
+        let mut page_size = page_size as u64;
+        if flen == 0 {
+            file.write_all(Self::header(chunk_size, page_size as usize).as_buf())?;
+        }
+        else {
+            let header = Self::read_header(&mut file)?;
+            let version = Self::version(&header);
+            if version != FORMAT_VERSION {
+                return Err(Error::UnsupportedVersion(version));
+            }
+            if header.read_u64(CHUNK_SIZE_POS) != chunk_size {
+                return Err(Error::Corrupted(format!("file was created with chunk size {} not {}", header.read_u64(CHUNK_SIZE_POS), chunk_size)));
+            }
+            page_size = max(BigEndian::read_u32(&header.as_buf()[PAGE_SIZE_POS .. PAGE_SIZE_POS + 4]) as u64, PAGE_SIZE as u64);
+        }
+        if !page_size.is_multiple_of(PAGE_SIZE as u64) || page_size > MAX_PAGE_SIZE as u64 {
+            return Err(Error::Corrupted(format!("invalid page size {}", page_size)));
+        }
+        let len = file.seek(SeekFrom::End(0))? - HEADER_SIZE;
+        let inner = Inner { file, written: len, pending: Vec::new(), read: None, ahead: None };
+        Ok(SingleFile{inner: Mutex::new(inner), base, len, chunk_size, page_size, sync})
+    }
+
+    /// extend the file to len without writing, the content reads as zeros
+    /// the file is sparse if the file system supports it
+    pub fn allocate (&mut self, len: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.write_pending()?;
+        if len > self.len {
+            Self::set_sparse(&inner.file);
+            inner.file.set_len(len + HEADER_SIZE)?;
+            inner.written = len;
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    // a file extended with set_len is sparse on unix file systems
+    #[cfg(not(windows))]
+    fn set_sparse (_: &File) {
+    }
+
+    // NTFS zero fills an extended file as it is written beyond the extent written before, unless it is
+    // sparse. A file system without sparse files fails the call and the file is zero filled.
+    // SetFileValidData would avoid that too, but it needs a privilege and exposes stale content of the
+    // disk, which would be read as table pages.
+    #[cfg(windows)]
+    #[allow(unsafe_code)]
+    fn set_sparse (file: &File) {
+        use std::os::windows::io::AsRawHandle;
+        use std::ptr;
+        use winapi::um::ioapiset::DeviceIoControl;
+        use winapi::um::winioctl::FSCTL_SET_SPARSE;
+        let mut returned = 0;
+        // the handle is open for the call, no buffers are passed
+        unsafe {
+            DeviceIoControl(file.as_raw_handle() as _, FSCTL_SET_SPARSE, ptr::null_mut(), 0, ptr::null_mut(), 0,
+                &mut returned, ptr::null_mut());
+        }
+    }
+
+    /// the header page of a file
+    pub fn header (chunk_size: u64, page_size: usize) -> Page {
+        let mut page = Page::new();
+        page.write(0, &MAGIC);
+        let mut version = [0u8; 4];
+        BigEndian::write_u32(&mut version, FORMAT_VERSION);
+        page.write(VERSION_POS, &version);
+        page.write_u64(CHUNK_SIZE_POS, chunk_size);
+        let mut size = [0u8; 4];
+        BigEndian::write_u32(&mut size, page_size as u32);
+        page.write(PAGE_SIZE_POS, &size);
+        page
+    }
+
+    /// read the header page of a file, an empty page if the file is shorter
+    pub fn read_header (file: &mut File) -> Result<Page, Error> {
+        let mut page = Page::new();
+        let flen = file.seek(SeekFrom::End(0))?;
+        if flen >= HEADER_SIZE {
+            file.seek(SeekFrom::Start(0))?;
+            file.read_exact(page.as_mut_buf())?;
+        }
+        Ok(page)
+    }
+
+    /// format version of a header, files without magic are of the legacy version
+    pub fn version (header: &Page) -> u32 {
+        if header.as_buf()[0..MAGIC.len()] != MAGIC {
+            return LEGACY_VERSION;
+        }
+        BigEndian::read_u32(&header.as_buf()[VERSION_POS..VERSION_POS+4])
+    }
+
+    /// chunk size of a header of the current format version
+    pub fn chunk_size (header: &Page) -> u64 {
+        header.read_u64(CHUNK_SIZE_POS)
+    }
+}
+
+impl Inner {
+    // write pending pages at the end of the written content
+    fn write_pending (&mut self) -> Result<(), Error> {
+        if !self.pending.is_empty() {
+            self.file.seek(SeekFrom::Start(self.written + HEADER_SIZE))?;
+            let mut slices = self.pending.iter().map(|p| IoSlice::new(p.as_buf())).collect::<Vec<_>>();
+            let mut slices = &mut slices[..];
+            while !slices.is_empty() {
+                let n = self.file.write_vectored(slices)?;
+                if n == 0 {
+                    return Err(Error::IO(std::io::Error::from(std::io::ErrorKind::WriteZero)));
+                }
+                IoSlice::advance_slices(&mut slices, n);
+            }
+            self.written += (self.pending.len() * PAGE_SIZE) as u64;
+            self.pending.clear();
+        }
+        Ok(())
     }
 
-    pub fn new_chunk (mut file: File, base: u64, chunk_size: u64) -> Result<SingleFile, Error> {
-        let len = file.seek(SeekFrom::End(0))?;
-        Ok(SingleFile{file: Mutex::new(file), base, len, chunk_size})
+    // read written pages starting at pos
+    fn read_pages (&mut self, pos: u64, n: usize) -> Result<Vec<Page>, Error> {
+        let mut pages = vec!(Page::new(); n);
+        self.file.seek(SeekFrom::Start(pos + HEADER_SIZE))?;
+        let mut slices = pages.iter_mut().map(|p| IoSliceMut::new(p.as_mut_buf())).collect::<Vec<_>>();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let n = self.file.read_vectored(slices)?;
+            if n == 0 {
+                return Err(Error::IO(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
+            }
+            IoSliceMut::advance_slices(&mut slices, n);
+        }
+        Ok(pages)
+    }
+
+    // take pages read ahead as the unit read if they hold pos
+    fn take_ahead (&mut self, pos: u64) -> bool {
+        if let Some((at, n, handle)) = self.ahead.take() {
+            if pos < at {
+                self.ahead = Some((at, n, handle));
+            }
+            else if pos < at + (n * PAGE_SIZE) as u64 {
+                // a failed read ahead is read again
+                if let Ok(Ok(pages)) = handle.join() {
+                    self.read = Some((at, pages));
+                    return true;
+                }
+            }
+        }
+        false
     }
 }
 
@@ -52,15 +241,27 @@ impl PagedFile for SingleFile {
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
         let o = pref.as_u64();
         if o < self.base || o >= self.base + self.chunk_size {
-            return Err(Error::Corrupted("read from wrong file".to_string()));
+            return Err(Error::Bug("read from wrong file".to_string()));
         }
         let pos = o - self.base;
         if pos < self.len {
-            let mut file = self.file.lock().unwrap();
-            file.seek(SeekFrom::Start(pos))?;
-            let mut buffer = [0u8; PAGE_SIZE];
-            file.read_exact(&mut buffer[..])?;
-            return Ok(Some(Page::from_buf(buffer)));
+            let mut inner = self.inner.lock().unwrap();
+            if pos >= inner.written {
+                return Ok(Some(inner.pending[((pos - inner.written) / PAGE_SIZE as u64) as usize].clone()));
+            }
+            let cached = match inner.read {
+                Some((at, ref pages)) => pos >= at && pos < at + (pages.len() * PAGE_SIZE) as u64,
+                None => false
+            };
+            if !cached && !inner.take_ahead(pos) {
+                let start = pos - pos % self.page_size;
+                let n = ((min(start + self.page_size, inner.written) - start) / PAGE_SIZE as u64) as usize;
+                let pages = inner.read_pages(start, n)?;
+                inner.read = Some((start, pages));
+            }
+            if let Some((at, ref pages)) = inner.read {
+                return Ok(Some(pages[((pos - at) / PAGE_SIZE as u64) as usize].clone()));
+            }
         }
         Ok(None)
     }
@@ -70,38 +271,92 @@ impl PagedFile for SingleFile {
     }
 
     fn truncate(&mut self, new_len: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.write_pending()?;
+        inner.read = None;
+        inner.ahead = None;
+        inner.file.set_len(new_len + HEADER_SIZE)?;
+        inner.written = new_len;
         self.len = new_len;
-        Ok(self.file.lock().unwrap().set_len(new_len)?)
+        Ok(())
     }
 
     fn sync(&self) -> Result<(), Error> {
-        Ok(self.file.lock().unwrap().sync_data()?)
+        let mut inner = self.inner.lock().unwrap();
+        inner.write_pending()?;
+        match self.sync {
+            SyncMode::Data => inner.file.sync_data()?,
+            SyncMode::All => inner.file.sync_all()?
+        }
+        Ok(())
     }
 
     fn shutdown (&mut self) {}
 
     fn append_page(&mut self, page: Page) -> Result<(), Error> {
-        let mut file = self.file.lock().unwrap();
-        file.write_all(&page.into_buf()[..])?;
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.push(page);
         self.len += PAGE_SIZE as u64;
+        if self.len.is_multiple_of(self.page_size) {
+            inner.write_pending()?;
+        }
         Ok(())
     }
 
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
         let o = page.pref().as_u64();
         if o < self.base || o >= self.base + self.chunk_size {
-            return Err(Error::Corrupted("write to wrong file".to_string()));
+            return Err(Error::Bug("write to wrong file".to_string()));
         }
         let pos = o - self.base;
 
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(pos))?;
-        file.write_all(&page.into_buf())?;
+        let mut inner = self.inner.lock().unwrap();
+        if pos >= inner.written && pos < self.len {
+            let i = ((pos - inner.written) / PAGE_SIZE as u64) as usize;
+            inner.pending[i] = page;
+            return Ok(self.len);
+        }
+        inner.write_pending()?;
+        inner.read = None;
+        inner.ahead = None;
+        inner.file.seek(SeekFrom::Start(pos + HEADER_SIZE))?;
+        inner.file.write_all(page.as_buf())?;
+        inner.written = max(inner.written, pos + PAGE_SIZE as u64);
         self.len = max(self.len, pos + PAGE_SIZE as u64);
         Ok(self.len)
     }
 
     fn flush(&mut self) -> Result<(), Error> {
-        Ok(self.file.lock().unwrap().flush()?)
+        let mut inner = self.inner.lock().unwrap();
+        inner.write_pending()?;
+        Ok(inner.file.flush()?)
     }
-}
\ No newline at end of file
+
+    // written pages are read with positioned reads of a handle of their own
+    #[cfg(unix)]
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        use std::os::unix::fs::FileExt;
+        use std::thread;
+
+        let o = pref.as_u64();
+        if o < self.base {
+            return;
+        }
+        let pos = o - self.base;
+        let mut inner = self.inner.lock().unwrap();
+        if pos >= inner.written {
+            return;
+        }
+        let n = min(pages as u64, (inner.written - pos) / PAGE_SIZE as u64) as usize;
+        if let Ok(file) = inner.file.try_clone() {
+            let handle = thread::spawn(move || {
+                let mut pages = vec!(Page::new(); n);
+                for (i, page) in pages.iter_mut().enumerate() {
+                    file.read_exact_at(page.as_mut_buf(), pos + HEADER_SIZE + (i * PAGE_SIZE) as u64)?;
+                }
+                Ok(pages)
+            });
+            inner.ahead = Some((pos, n, handle));
+        }
+    }
+}
@@ -17,16 +17,20 @@
 //! # Statistics for a Hammersbald db
 //!
 //!
-use api::Hammersbald;
+use api::{Hammersbald, HammersbaldRead};
 use format::Payload;
+use memtable::key_hash;
+use pref::PRef;
+use error::Error;
 
-use bitcoin_hashes::siphash24;
+use rand::{thread_rng, Rng};
+use rand::seq::SliceRandom;
 
 use std::collections::{HashMap, HashSet};
 
 /// print some statistics on a db
 #[allow(unused)]
-fn stats(db: &Hammersbald) {
+pub fn stats(db: &Hammersbald) {
     let (step, log_mod, blen, tlen, dlen, llen, sip0, sip1) = db.params();
     println!("File sizes: table: {}, data: {}, links: {}\nHash table: buckets: {}, log_mod: {}, step: {}", tlen, dlen, llen, blen, log_mod, step);
 
@@ -38,12 +42,15 @@ fn stats(db: &Hammersbald) {
     }
 
     let mut n_links = 0;
-    for (pos, envelope) in db.link_envelopes() {
+    for envelope in db.link_envelopes() {
+        let (pos, envelope) = envelope.expect("link file can not be decoded");
         match Payload::deserialize(envelope.payload()).unwrap() {
             Payload::Link(_) => {
                 n_links += 1;
                 pointer.remove (&pos);
             },
+            // padding at flush
+            Payload::Referred(_) | Payload::Padding(_) => {},
             _ => panic!("Unexpected payload type link at {}", pos)
         }
     }
@@ -56,8 +63,9 @@ fn stats(db: &Hammersbald) {
     let mut ndata = 0;
     let mut used_buckets = 0;
     for slots in db.slots() {
+        let slots = slots.expect("table can not be read");
         ndata += slots.len();
-        if slots.len() > 0 {
+        if !slots.is_empty() {
             used_buckets += 1;
         }
         for slot in slots.iter() {
@@ -66,25 +74,30 @@ fn stats(db: &Hammersbald) {
     }
     println!("Used buckets: {} {:.1} % avg. slots per bucket: {:.1}", used_buckets, 100.0*(used_buckets as f32/blen as f32), ndata as f32/used_buckets as f32);
     println!("Data: indexed: {}, hash collisions {:.2} %", ndata, (1.0-(roots.len() as f32)/(ndata as f32))*100.0);
+    let table = table_stats(db).expect("table can not be read");
+    let (p50, p90, p99, longest) = table.chain_percentiles;
+    println!("Keys per bucket: p50: {}, p90: {}, p99: {}, max: {}{}", p50, p90, p99, longest, if table.needs_resize() { ", the table needs a resize" } else { "" });
 
     let mut indexed_garbage = 0;
     let mut referred_garbage = 0;
     let mut referred = 0;
-    for (pos, envelope) in db.data_envelopes() {
+    for envelope in db.data_envelopes() {
+        let (pos, envelope) = envelope.expect("data file can not be decoded");
         match Payload::deserialize(envelope.payload()).unwrap() {
             Payload::Indexed(indexed) => {
                 if let Some(root) = roots.remove(&pos) {
                     let h = hash(indexed.key, sip0, sip1);
-                    if root.iter().any(|hash| *hash == h) == false {
+                    if !root.contains(&h) {
                         panic!("ERROR root {} points data with different key hash", pos);
                     }
                 } else {
                     indexed_garbage += 1;
                 }
             },
-            Payload::Referred(data) => {
+            Payload::Referred(_) | Payload::Linked(_) => {
                 referred += 1;
             },
+            Payload::Padding(_) => {},
             _ => panic!("Unexpected payload type in data at {}", pos)
         }
     }
@@ -95,7 +108,247 @@ fn stats(db: &Hammersbald) {
     println!("Garbage: indexed: {}, referred: {}, links: {}", indexed_garbage, referred_garbage, n_links - used_buckets);
 }
 
+/// thoroughness of verify
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyLevel {
+    /// check hash table, links, keys of live data, references between data and file lengths
+    Structure,
+    /// also decode every envelope of the data and link files, including extensions and compressed data
+    Deep
+}
+
+/// result of verify
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// buckets of the hash table
+    pub buckets: usize,
+    /// links of buckets
+    pub links: usize,
+    /// keys of live data
+    pub keys: usize,
+    /// envelopes decoded at the deep level
+    pub envelopes: usize,
+    /// inconsistencies found
+    pub problems: Vec<String>
+}
+
+impl VerifyReport {
+    /// no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// check the hash table, links, data and log of a db
+pub fn verify(db: &Hammersbald, level: VerifyLevel) -> Result<VerifyReport, Error> {
+    let (step, log_mod, buckets, table_len, data_len, link_len, sip0, sip1) = db.params();
+    let mut report = VerifyReport { buckets, ..VerifyReport::default() };
+
+    if let Some((logged_data, logged_table, logged_link)) = db.logged_lengths()? {
+        for (file, logged, len) in &[("data", logged_data, data_len), ("hash table", logged_table, table_len), ("link", logged_link, link_len)] {
+            if logged != len {
+                report.problems.push(format!("{} file is {} bytes, the log recorded {}", file, len, logged));
+            }
+        }
+    }
+
+    let mut links = HashSet::new();
+    for envelope in db.link_envelopes() {
+        let (pos, envelope) = match envelope {
+            Ok(read) => read,
+            Err(e) => {
+                report.problems.push(format!("link file can not be read: {}", e));
+                break;
+            }
+        };
+        if let Payload::Link(_) = Payload::deserialize(envelope.payload())? {
+            links.insert(pos);
+        }
+    }
+    for (bucket, link) in db.buckets().enumerate() {
+        if link.is_valid() {
+            report.links += 1;
+            if !links.contains(&link) {
+                report.problems.push(format!("bucket {} points to {} that is not a link", bucket, link));
+            }
+        }
+    }
+
+    let mut keyed = HashMap::new();
+    let mut readable = true;
+    for envelope in db.data_envelopes() {
+        let (pos, envelope) = match envelope {
+            Ok(read) => read,
+            Err(e) => {
+                report.problems.push(format!("data file can not be read: {}", e));
+                readable = false;
+                break;
+            }
+        };
+        if let Payload::Indexed(indexed) = Payload::deserialize(envelope.payload())? {
+            keyed.insert(pos, hash(indexed.key, sip0, sip1));
+        }
+    }
+    for (bucket, slots) in db.slots().enumerate() {
+        let slots = match slots {
+            Ok(slots) => slots,
+            Err(e) => {
+                report.problems.push(format!("bucket {} can not be read: {}", bucket, e));
+                break;
+            }
+        };
+        for (h, pos) in slots {
+            report.keys += 1;
+            if bucket_for_hash(h, step, log_mod) != bucket {
+                report.problems.push(format!("slot of {} is in bucket {} instead of {}", pos, bucket, bucket_for_hash(h, step, log_mod)));
+            }
+            match keyed.get(&pos) {
+                Some(stored) if *stored == h => {},
+                Some(_) => report.problems.push(format!("slot points to {} with a different key hash", pos)),
+                None => report.problems.push(format!("slot points to {} that is not keyed data", pos))
+            }
+        }
+    }
+
+    // references are checked if the data file is readable
+    if readable {
+        for (from, to) in db.verify_links()? {
+            report.problems.push(format!("data at {} refers to {} that is not stored", from, to));
+        }
+    }
+
+    if level == VerifyLevel::Deep {
+        let (data, link) = db.check_envelopes();
+        for (file, (n, failed)) in [("data", data), ("link", link)] {
+            report.envelopes += n;
+            if let Some((pos, e)) = failed {
+                report.problems.push(format!("{} file can not be decoded at {}: {}", file, pos, e));
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// load of the hash table
+#[derive(Clone, Debug, Default)]
+pub struct TableStats {
+    /// buckets of the hash table
+    pub buckets: usize,
+    /// keys of live data
+    pub keys: usize,
+    /// average keys per bucket
+    pub load_factor: f64,
+    /// average keys per bucket the table grows for
+    pub fill_target: usize,
+    /// keys in a bucket at the 50th, 90th and 99th percentile of buckets, and the most keys of a bucket
+    pub chain_percentiles: (usize, usize, usize, usize),
+    /// size of all keys, 0 if keys are of any size
+    pub fixed_key_size: usize
+}
+
+impl TableStats {
+    /// the table is undersized, gets read chains of more than twice the fill target on average
+    pub fn needs_resize(&self) -> bool {
+        self.load_factor > 2.0 * self.fill_target as f64
+    }
+}
+
+/// cost of a lookup of a key, see HammersbaldRead::probe
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Probe {
+    /// bucket of the key
+    pub bucket: usize,
+    /// the bloom filter of the bucket excludes the key, the bucket is not read
+    pub filtered: bool,
+    /// link entries of the bucket examined
+    pub slots: usize,
+    /// data read with a hash collision, stored with a different key
+    pub collisions: usize,
+    /// reference to the data last stored with the key
+    pub found: Option<PRef>
+}
+
+/// metadata of data at a persistent reference, see HammersbaldRead::entry_info
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// length of the envelope as stored, compressed and encrypted, without extensions
+    pub stored_len: usize,
+    /// length of the key, zero for data without key
+    pub key_len: usize,
+    /// references stored with the data
+    pub links: usize,
+    /// pages the envelope spans
+    pub spans_pages: u64,
+    /// data is compressed
+    pub compressed: bool,
+    /// data is encrypted
+    pub encrypted: bool,
+    /// data continues in extensions
+    pub extended: bool,
+    /// generation of the batch that stored the data, None if generations are not kept
+    pub generation: Option<u64>
+}
+
+/// measure the load of the hash table, all buckets are read
+pub fn table_stats(db: &Hammersbald) -> Result<TableStats, Error> {
+    let mut chains = db.slots().map(|slots| slots.map(|slots| slots.len())).collect::<Result<Vec<_>, _>>()?;
+    chains.sort_unstable();
+    let buckets = chains.len();
+    let keys = chains.iter().sum::<usize>();
+    let percentile = |p: usize| chains.get(buckets * p / 100).cloned().unwrap_or(0);
+    Ok(TableStats {
+        buckets, keys,
+        load_factor: if buckets > 0 { keys as f64 / buckets as f64 } else { 0.0 },
+        fill_target: db.bucket_fill_target(),
+        chain_percentiles: (percentile(50), percentile(90), percentile(99), chains.last().cloned().unwrap_or(0)),
+        fixed_key_size: db.fixed_key_size()
+    })
+}
+
+/// a random sample of up to n live keys with the length of their data
+/// buckets are visited from a random start with a random stride coprime to their number, so each is read once at most
+pub fn sample_keys(db: &Hammersbald, n: usize) -> Result<Vec<(Vec<u8>, usize)>, Error> {
+    let buckets = db.n_buckets();
+    let mut prefs = Vec::new();
+    if buckets > 0 && n > 0 {
+        let mut rng = thread_rng();
+        let start = rng.gen_range(0, buckets);
+        let mut stride = rng.gen_range(1, buckets + 1);
+        while gcd(stride, buckets) != 1 {
+            stride = rng.gen_range(1, buckets + 1);
+        }
+        for i in 0 .. buckets {
+            if prefs.len() >= n {
+                break;
+            }
+            let bucket = (start + i * stride) % buckets;
+            prefs.extend(db.bucket_slots(bucket)?.into_iter().map(|(_, pref)| pref));
+        }
+        // keys of the bucket read last are not preferred
+        prefs = prefs.partial_shuffle(&mut rng, n).0.to_vec();
+    }
+    let mut sample = Vec::with_capacity(prefs.len());
+    for pref in prefs {
+        // length of the data retrieved, not as stored compressed or encrypted
+        let (key, data) = db.get(pref)?;
+        sample.push((key, data.len()));
+    }
+    Ok(sample)
+}
+
+fn gcd (a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// bucket of a hash in a table grown to step with log_mod
+fn bucket_for_hash (hash: u32, step: usize, log_mod: u32) -> usize {
+    let bucket = (hash & (!0u32 >> (32 - log_mod))) as usize;
+    if bucket < step {
+        return (hash & (!0u32 >> (32 - log_mod - 1))) as usize;
+    }
+    bucket
+}
 
 fn hash (key: &[u8], sip0: u64, sip1: u64) -> u32 {
-    siphash24::Hash::hash_to_u64_with_keys(sip0, sip1, key) as u32
+    key_hash(key, sip0, sip1)
 }
\ No newline at end of file
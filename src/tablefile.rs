@@ -23,7 +23,7 @@ use std::cmp::max;
 use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
 use pagedfile::PagedFile;
 use memtable::MemTable;
-use error::Error;
+use error::{Error, FileKind};
 use pref::PRef;
 
 pub const FIRST_PAGE_HEAD:usize = 28;
@@ -34,13 +34,15 @@ pub const BUCKETS_FIRST_PAGE:usize = (PAGE_PAYLOAD_SIZE - FIRST_PAGE_HEAD)/BUCKE
 /// The key file
 pub struct TableFile {
     file: Box<dyn PagedFile>,
-    initialized_until: PRef
+    initialized_until: PRef,
+    // invalid offsets, as read from a page allocated but not written yet
+    unwritten: Box<Page>
 }
 
 impl TableFile {
     pub fn new (file: Box<dyn PagedFile>) -> Result<TableFile, Error> {
         let initialized_until = PRef::from(file.len()?);
-        Ok(TableFile {file, initialized_until})
+        Ok(TableFile {file, initialized_until, unwritten: Box::new(MemTable::invalid_offsets_page(PRef::from(PAGE_SIZE as u64)))})
     }
 
     pub fn table_offset (bucket: usize) -> PRef {
@@ -57,6 +59,17 @@ impl TableFile {
     pub fn iter<'a>(&'a self) -> impl Iterator<Item=PRef> +'a {
         BucketIterator{file: self, n:0}
     }
+
+    // a page allocated but not written yet reads as zeros, it is replaced by invalid offsets
+    // the page is changed in place, as a page is large for the stack
+    fn unwritten(&self, page: &mut Page, pref: PRef) -> bool {
+        if page.as_buf() != &[0u8; PAGE_SIZE][..] {
+            return false;
+        }
+        page.as_mut_buf().copy_from_slice(self.unwritten.as_buf());
+        page.write_pref(PAGE_PAYLOAD_SIZE, pref);
+        true
+    }
 }
 
 impl PagedFile for TableFile {
@@ -80,10 +93,10 @@ impl PagedFile for TableFile {
     fn shutdown (&mut self) {}
 
     fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
-        let result = self.file.read_page(pref)?;
-        if let Some(ref page) = result {
-            if page.pref() != pref {
-                return Err(Error::Corrupted(format!("table page {} does not have the pref of its position", pref)));
+        let mut result = self.file.read_page(pref)?;
+        if let Some(ref mut page) = result {
+            if page.pref() != pref && !self.unwritten(page, pref) {
+                return Err(Error::CorruptedAt { file: FileKind::Table, pos: pref.as_u64(), reason: "table page does not have the pref of its position".to_string() });
             }
         }
         Ok(result)
@@ -94,7 +107,8 @@ impl PagedFile for TableFile {
     }
 
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
-        if page.pref().as_u64() >= self.len()? {
+        // pages skipped are allocated without writing them if the file can, they read as invalid offsets
+        if page.pref().as_u64() >= self.len()? && page.pref() > self.initialized_until && !self.file.allocate(page.pref().as_u64())? {
             while page.pref() > self.initialized_until {
                 self.file.update_page(MemTable::invalid_offsets_page(self.initialized_until))?;
                 self.initialized_until = self.initialized_until.add_pages(1);
@@ -0,0 +1,416 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Crash injection
+//! Page writes of the files of a db fail or are lost from a chosen write on, as if the process
+//! crashed there. A db is then reopened, so recovery has to bring it to a consistent state.
+//! Writes of a page are assumed to be atomic, they are not torn, except with Fault::Torn.
+//!
+
+use api::{HammersbaldAPI, persistent_with_config};
+use pagedfile::PagedFile;
+use config::Config;
+use stats::VerifyLevel;
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
+use pref::PRef;
+use error::Error;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const DB_NAME: &str = "db";
+
+/// what happens to page writes from the fault on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// writes fail with an IO error
+    Fail,
+    /// writes are lost as if the files were cut at the write, syncs and flushes succeed
+    Truncate,
+    /// writes fail as on a full disk, with Error::OutOfSpace
+    Full,
+    /// a page updated in place at the write reaches the file only with its first half, as a torn log page,
+    /// an append at the write and later writes are lost
+    Torn
+}
+
+/// faults shared by the files of a db, page writes are counted across all files
+#[derive(Clone, Default)]
+pub struct Faults {
+    state: Arc<Mutex<FaultState>>
+}
+
+#[derive(Default)]
+struct FaultState {
+    // page write number and fault from there on
+    at: Option<(u64, Fault)>,
+    writes: u64
+}
+
+// what to do with a write
+enum Write {
+    Pass,
+    Fail,
+    Lose,
+    Full,
+    Tear
+}
+
+impl Faults {
+    /// no faults until injected
+    pub fn new () -> Faults {
+        Faults::default()
+    }
+
+    /// inject the fault at the page write with the number, counted from zero since the faults were created
+    pub fn inject (&self, at: u64, fault: Fault) {
+        self.state.lock().unwrap().at = Some((at, fault));
+    }
+
+    /// page writes so far
+    pub fn writes (&self) -> u64 {
+        self.state.lock().unwrap().writes
+    }
+
+    /// a fault was injected and its write reached
+    pub fn triggered (&self) -> bool {
+        let state = self.state.lock().unwrap();
+        match state.at {
+            Some((at, _)) => state.writes > at,
+            None => false
+        }
+    }
+
+    fn next_write (&self) -> Write {
+        let mut state = self.state.lock().unwrap();
+        let n = state.writes;
+        state.writes += 1;
+        match state.at {
+            Some((at, Fault::Fail)) if n >= at => Write::Fail,
+            Some((at, Fault::Truncate)) if n >= at => Write::Lose,
+            Some((at, Fault::Full)) if n >= at => Write::Full,
+            Some((at, Fault::Torn)) if n == at => Write::Tear,
+            Some((at, Fault::Torn)) if n > at => Write::Lose,
+            _ => Write::Pass
+        }
+    }
+
+    // writes other than of pages fail or are lost after the fault
+    fn other_write (&self) -> Write {
+        let state = self.state.lock().unwrap();
+        match state.at {
+            Some((at, Fault::Fail)) if state.writes > at => Write::Fail,
+            Some((at, Fault::Truncate)) if state.writes > at => Write::Lose,
+            Some((at, Fault::Full)) if state.writes > at => Write::Full,
+            Some((at, Fault::Torn)) if state.writes > at => Write::Lose,
+            _ => Write::Pass
+        }
+    }
+}
+
+fn injected () -> Error {
+    Error::IO(io::Error::other("injected fault"))
+}
+
+fn full () -> Error {
+    Error::from(io::Error::from(io::ErrorKind::StorageFull))
+}
+
+/// a paged file with injected faults
+pub struct FaultyFile {
+    file: Box<dyn PagedFile>,
+    faults: Faults
+}
+
+impl FaultyFile {
+    /// inject faults into writes of the file
+    pub fn new (file: Box<dyn PagedFile>, faults: Faults) -> FaultyFile {
+        FaultyFile { file, faults }
+    }
+
+    // the first half of the page over the page in the file, the position at the end of the page is kept
+    fn torn (&self, page: Page) -> Result<Page, Error> {
+        let mut torn = self.file.read_page(page.pref())?.unwrap_or_default();
+        torn.write(0, &page.as_buf()[0 .. PAGE_SIZE / 2]);
+        torn.write(PAGE_PAYLOAD_SIZE, &page.as_buf()[PAGE_PAYLOAD_SIZE ..]);
+        Ok(torn)
+    }
+}
+
+impl PagedFile for FaultyFile {
+    fn read_page(&self, pref: PRef) -> Result<Option<Page>, Error> {
+        self.file.read_page(pref)
+    }
+
+    fn len(&self) -> Result<u64, Error> {
+        self.file.len()
+    }
+
+    fn truncate(&mut self, new_len: u64) -> Result<(), Error> {
+        match self.faults.other_write() {
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            Write::Pass => self.file.truncate(new_len),
+            _ => Ok(())
+        }
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        match self.faults.other_write() {
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            _ => self.file.sync()
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.file.shutdown()
+    }
+
+    fn append_page(&mut self, page: Page) -> Result<(), Error> {
+        match self.faults.next_write() {
+            Write::Pass => self.file.append_page(page),
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            Write::Lose | Write::Tear => Ok(())
+        }
+    }
+
+    fn update_page(&mut self, page: Page) -> Result<u64, Error> {
+        match self.faults.next_write() {
+            Write::Pass => self.file.update_page(page),
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            Write::Tear => { let torn = self.torn(page)?; self.file.update_page(torn) },
+            Write::Lose => self.file.len()
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.faults.other_write() {
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            _ => self.file.flush()
+        }
+    }
+
+    fn prefetch(&self, pref: PRef, pages: usize) {
+        self.file.prefetch(pref, pages)
+    }
+
+    fn link_chunks(&self, name: &str, len: u64, chunk_size: u64) -> Result<u64, Error> {
+        self.file.link_chunks(name, len, chunk_size)
+    }
+
+    // a lost allocation leaves the pages to be written
+    fn allocate(&mut self, len: u64) -> Result<bool, Error> {
+        match self.faults.other_write() {
+            Write::Fail => Err(injected()),
+            Write::Full => Err(full()),
+            Write::Pass => self.file.allocate(len),
+            _ => Ok(false)
+        }
+    }
+}
+
+/// open the db after a crash, so it recovers, and verify it deeply
+/// fails with Error::Corrupted listing the problems found
+pub fn reopen_and_verify (name: &str, config: &Config) -> Result<Box<dyn HammersbaldAPI>, Error> {
+    let mut config = config.clone();
+    config.faults = None;
+    let mut db = persistent_with_config(name, &config)?;
+    let report = db.verify(VerifyLevel::Deep)?;
+    if !report.is_ok() {
+        db.shutdown();
+        return Err(Error::Corrupted(report.problems.join(", ")));
+    }
+    Ok(db)
+}
+
+/// run the workload on a new db for each page write it causes, with the fault injected at that write.
+/// The db is reopened after the workload, verified and passed to check with the number of the faulted write.
+/// Each db is created in a directory of its own below dir and removed thereafter.
+/// returns the number of page writes of the workload, including those of creating the db
+pub fn crash_test<W, C> (dir: &Path, config: &Config, fault: Fault, workload: W, check: C) -> Result<u64, Error>
+    where W: Fn(&mut dyn HammersbaldAPI) -> Result<(), Error>, C: Fn(&dyn HammersbaldAPI, u64) -> Result<(), Error> {
+    let writes = run(&dir.join("count"), config, None, &workload)?;
+    fs::remove_dir_all(dir.join("count"))?;
+    for at in 0 .. writes {
+        let run_dir = dir.join(at.to_string());
+        run(&run_dir, config, Some((at, fault)), &workload)?;
+        let config = config.clone().directory(&run_dir);
+        let mut db = reopen_and_verify(DB_NAME, &config)
+            .map_err(|e| Error::Corrupted(format!("after a fault at page write {}: {}", at, e)))?;
+        let checked = check(db.as_ref(), at);
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&run_dir)?;
+        checked?;
+    }
+    Ok(writes)
+}
+
+// run the workload on a new db in dir, errors after the fault are expected, returns the page writes
+fn run<W> (dir: &Path, config: &Config, fault: Option<(u64, Fault)>, workload: &W) -> Result<u64, Error>
+    where W: Fn(&mut dyn HammersbaldAPI) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    let faults = Faults::new();
+    if let Some((at, fault)) = fault {
+        faults.inject(at, fault);
+    }
+    let config = config.clone().directory(dir).faults(faults.clone());
+    match persistent_with_config(DB_NAME, &config) {
+        Ok(mut db) => {
+            let done = workload(db.as_mut());
+            if fault.is_none() {
+                done?;
+                db.shutdown();
+            }
+            // dropped without shutdown as in a crash
+        },
+        Err(e) => if fault.is_none() {
+            return Err(e);
+        }
+    }
+    Ok(faults.writes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+
+    // the keys of a batch are stored with it or not at all
+    fn check_batches (db: &dyn HammersbaldAPI) -> Result<(), Error> {
+        let mut complete = true;
+        for batch in 0 .. 3u8 {
+            let stored = (0 .. 20u8).filter(|i| db.get_keyed(&[batch, *i]).unwrap().is_some()).count();
+            if stored != 0 && (stored != 20 || !complete) {
+                return Err(Error::Corrupted(format!("batch {} is stored partially or without the batch before", batch)));
+            }
+            complete = stored == 20;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_crash () {
+        let workload = |db: &mut dyn HammersbaldAPI| -> Result<(), Error> {
+            for batch in 0 .. 3u8 {
+                for i in 0 .. 20u8 {
+                    db.put_keyed(&[batch, i], &[i; 1000])?;
+                }
+                db.batch()?;
+            }
+            Ok(())
+        };
+        let dir = env::temp_dir().join(format!("hammersbald-crash-{}", rand::random::<u64>()));
+        for fault in [Fault::Fail, Fault::Truncate, Fault::Full].iter() {
+            let writes = crash_test(&dir, &Config::default(), *fault, workload, |db, _| check_batches(db)).unwrap();
+            assert!(writes > 15);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_full () {
+        let dir = env::temp_dir().join(format!("hammersbald-full-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let faults = Faults::new();
+        let config = Config::default().directory(&dir).faults(faults.clone());
+        let mut db = persistent_with_config(DB_NAME, &config).unwrap();
+        for i in 0 .. 20u8 {
+            db.put_keyed(&[0, i], &[i; 1000]).unwrap();
+        }
+        db.batch().unwrap();
+
+        // the disk fills within the next batch, it fails as out of space and recovers to the batch before
+        faults.inject(faults.writes() + 2, Fault::Full);
+        let mut stored = Ok(());
+        for i in 0 .. 20u8 {
+            stored = stored.and_then(|_| db.put_keyed(&[1, i], &[i; 1000]).map(|_| ()));
+        }
+        match stored.and_then(|_| db.batch()) {
+            Err(Error::OutOfSpace { source: Some(_), .. }) => {},
+            other => panic!("expected out of space, got {:?}", other)
+        }
+        drop(db);
+        let mut db = reopen_and_verify(DB_NAME, &config).unwrap();
+        check_batches(db.as_ref()).unwrap();
+        assert!(db.get_keyed(&[0, 19]).unwrap().is_some());
+        db.shutdown();
+        drop(db);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_group () {
+        // groups are only logged, not batched
+        let workload = |db: &mut dyn HammersbaldAPI| -> Result<(), Error> {
+            for group in 0 .. 3u8 {
+                let data = vec!(group; 1000);
+                let keys = (0 .. 20u8).map(|i| [group, i]).collect::<Vec<_>>();
+                let entries = keys.iter().map(|key| (&key[..], data.as_slice())).collect::<Vec<_>>();
+                db.put_group(entries.as_slice())?;
+            }
+            Ok(())
+        };
+        let dir = env::temp_dir().join(format!("hammersbald-crash-group-{}", rand::random::<u64>()));
+        let config = Config::default().write_ahead_log(true);
+        crash_test(&dir, &config, Fault::Truncate, workload, |db, _| check_batches(db)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_crash_torn_log () {
+        // a torn record of the log is not replayed, nor are the records after it
+        let dir = env::temp_dir().join(format!("hammersbald-crash-torn-{}", rand::random::<u64>()));
+        for at in 0 .. {
+            let run_dir = dir.join(at.to_string());
+            fs::create_dir_all(&run_dir).unwrap();
+            let faults = Faults::new();
+            let config = Config::default().directory(&run_dir).write_ahead_log(true);
+            let mut db = persistent_with_config(DB_NAME, &config.clone().faults(faults.clone())).unwrap();
+            // only puts after the db is created are logged, none is batched
+            faults.inject(faults.writes() + at, Fault::Torn);
+            for i in 0 .. 20u8 {
+                if db.put_keyed(&[i], &[i + 1; 600]).is_err() {
+                    break;
+                }
+            }
+            if !faults.triggered() {
+                // all writes of the puts were done
+                assert!(at > 20);
+                db.shutdown();
+                break;
+            }
+            drop(db);
+            let mut db = reopen_and_verify(DB_NAME, &config).unwrap();
+            let mut complete = true;
+            for i in 0 .. 20u8 {
+                match db.get_keyed(&[i]).unwrap() {
+                    Some((_, data)) => assert!(complete && data == vec!(i + 1; 600), "key {} replayed wrong after torn write {}", i, at),
+                    None => complete = false
+                }
+            }
+            db.shutdown();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
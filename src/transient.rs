@@ -20,6 +20,7 @@
 
 use error::Error;
 use logfile::LogFile;
+use bloomfile::BloomFile;
 use api::{Hammersbald, HammersbaldAPI};
 use tablefile::TableFile;
 use datafile::DataFile;
@@ -50,14 +51,15 @@ struct Inner {
 
 impl Transient {
     /// create a new file
-    fn new (append: bool) -> Transient {
+    pub fn new (append: bool) -> Transient {
         Transient {inner: Mutex::new(Inner{data: Vec::new(), pos: 0, append})}
     }
 
     pub fn new_db (_name: &str, cached_data_pages: usize, bucket_fill_target: usize) -> Result<Box<dyn HammersbaldAPI>, Error> {
         let log = LogFile::new(
             Box::new(AsyncFile::new(
-            Box::new(Transient::new(true)))?));
+            Box::new(Transient::new(true)))?))
+            .with_checkpoints(Box::new(Transient::new(true)));
         let table = TableFile::new(
             Box::new(CachedFile::new(
             Box::new(Transient::new(false)), cached_data_pages)?))?;
@@ -69,7 +71,9 @@ impl Transient {
             Box::new(CachedFile::new(
                 Box::new(AsyncFile::new(Box::new(Transient::new(true)))?),
                 cached_data_pages)?))?;
-        Ok(Box::new(Hammersbald::new(log, table, data, link, bucket_fill_target)?))
+        let bloom = BloomFile::new(Box::new(Transient::new(false)));
+        let factory = Box::new(move |_: &str| Self::new_db("", cached_data_pages, bucket_fill_target));
+        Ok(Box::new(Hammersbald::new(log, table, data, link, bloom, bucket_fill_target)?.with_index_factory(factory)))
     }
 }
 
@@ -80,7 +84,7 @@ impl PagedFile for Transient {
         if pref.as_u64() < len {
             inner.seek(SeekFrom::Start(pref.as_u64()))?;
             let mut buffer = [0u8; PAGE_SIZE];
-            inner.read(&mut buffer)?;
+            inner.read_exact(&mut buffer)?;
             return Ok(Some(Page::from_buf(buffer)));
         }
         Ok(None)
@@ -92,8 +96,8 @@ impl PagedFile for Transient {
     }
 
     fn truncate(&mut self, len: u64) -> Result<(), Error> {
-        if len % PAGE_SIZE as u64 != 0 {
-            return Err(Error::Corrupted(format!("truncate not to page boundary {}", len)));
+        if !len.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::Bug(format!("truncate not to page boundary {}", len)));
         }
         let mut inner = self.inner.lock().unwrap();
         inner.data.truncate(len as usize);
@@ -107,14 +111,14 @@ impl PagedFile for Transient {
 
     fn append_page(&mut self, page: Page) -> Result<(), Error> {
         let mut inner = self.inner.lock().unwrap();
-        inner.write(&page.clone().into_buf())?;
+        inner.write_all(&page.clone().into_buf())?;
         Ok(())
     }
 
     fn update_page(&mut self, page: Page) -> Result<u64, Error> {
         let mut inner = self.inner.lock().unwrap();
         inner.seek(SeekFrom::Start(page.pref().as_u64()))?;
-        inner.write(&page.into_buf())?;
+        inner.write_all(&page.into_buf())?;
         Ok(inner.data.len() as u64)
     }
 
@@ -149,7 +153,7 @@ impl Write for Inner {
             }
         }
         self.pos += buflen;
-        Ok(self.pos)
+        Ok(buflen)
     }
 
     fn flush(&mut self) -> Result<(), io::Error> {
@@ -0,0 +1,330 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Tuning harness
+//! Synthetic workloads timed on a db: a load of uniform random 32 byte keys, reads of them with
+//! uniform and zipfian popularity and a mix of puts and gets, with batches in between. Throughput
+//! and latency percentiles of each tell regressions of the hash table, data file and batches, the
+//! pages read and keys stored suggest the page cache and the size of the hash table.
+//!
+
+use api::{HammersbaldAPI, persistent_with_config, transient};
+use config::Config;
+use error::Error;
+use page::PAGE_SIZE;
+use pref::PRef;
+use stats::TableStats;
+
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: tuner [options]
+options:
+  --keys <n>          keys loaded, default 100000
+  --data-len <n>      bytes of data of a key, default 100
+  --reads <n>         reads of each read workload and operations of the mixed workload, default 100000
+  --batch <n>         puts between batches, default 10000
+  --zipf <s>          exponent of the zipfian popularity of keys, default 1.0
+  --fill-target <n>   bucket fill target of the dbs, default that of Config
+  --seed <n>          seed of the keys and workloads, default 0
+  --dir <path>        directory of the persistent db, default the temporary directory";
+
+// share of zipfian reads the recommended page cache serves
+const CACHE_HITS: f64 = 0.9;
+
+/// sizes of the synthetic workloads
+#[derive(Clone, Debug)]
+pub struct Workloads {
+    /// keys loaded
+    pub keys: usize,
+    /// bytes of data of a key
+    pub data_len: usize,
+    /// reads of each read workload and operations of the mixed workload
+    pub reads: usize,
+    /// puts between batches
+    pub batch_every: usize,
+    /// exponent of the zipfian popularity of keys, 0 is uniform
+    pub zipf_exponent: f64,
+    /// seed of the keys and workloads, the same seed repeats a run
+    pub seed: u64
+}
+
+impl Default for Workloads {
+    fn default() -> Self {
+        Workloads { keys: 100000, data_len: 100, reads: 100000, batch_every: 10000, zipf_exponent: 1.0, seed: 0 }
+    }
+}
+
+/// timing of a workload
+#[derive(Clone, Debug)]
+pub struct Measurement {
+    /// name of the workload
+    pub workload: &'static str,
+    /// operations timed
+    pub ops: usize,
+    /// time of all operations
+    pub elapsed: Duration,
+    /// latency of an operation at the 50th, 90th and 99th percentile and the slowest
+    pub percentiles: (Duration, Duration, Duration, Duration)
+}
+
+impl Measurement {
+    fn new (workload: &'static str, mut latencies: Vec<Duration>) -> Measurement {
+        latencies.sort_unstable();
+        let elapsed = latencies.iter().sum();
+        let at = |p: usize| latencies.get(latencies.len().saturating_sub(1) * p / 100).cloned().unwrap_or_default();
+        Measurement { workload, ops: latencies.len(), elapsed, percentiles: (at(50), at(90), at(99), at(100)) }
+    }
+
+    /// operations per second
+    pub fn throughput (&self) -> f64 {
+        self.ops as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+/// measurements of the workloads and settings they suggest
+#[derive(Clone, Debug)]
+pub struct Report {
+    /// measurements in the order the workloads ran
+    pub measurements: Vec<Measurement>,
+    /// pages of the data file with data of the keys loaded
+    pub data_pages: usize,
+    /// fewest data pages that serve 90% of the zipfian reads, a page cache for the hot keys
+    pub cached_data_pages: usize,
+    /// hash table after the workloads
+    pub table: TableStats,
+    /// buckets for the keys at the fill target of the table, see HammersbaldAPI::resize
+    pub buckets: usize
+}
+
+// popularity of keys by rank, rank 0 is the most popular
+struct Zipf {
+    cumulative: Vec<f64>
+}
+
+impl Zipf {
+    fn new (n: usize, exponent: f64) -> Zipf {
+        let mut total = 0.0;
+        Zipf { cumulative: (1 ..= n).map(|rank| { total += 1.0 / (rank as f64).powf(exponent); total }).collect() }
+    }
+
+    fn sample<R: Rng> (&self, rng: &mut R) -> usize {
+        let u = rng.gen::<f64>() * self.cumulative.last().cloned().unwrap_or_default();
+        self.cumulative.partition_point(|c| *c <= u).min(self.cumulative.len().saturating_sub(1))
+    }
+}
+
+/// run the workloads on a db, it should be empty, at least one key is loaded
+pub fn tune (db: &mut dyn HammersbaldAPI, workloads: &Workloads) -> Result<Report, Error> {
+    let mut rng = StdRng::seed_from_u64(workloads.seed);
+    let mut data = vec!(0u8; workloads.data_len);
+    let mut measurements = Vec::new();
+    let mut batches = Vec::new();
+
+    let mut keys = Vec::with_capacity(workloads.keys);
+    let mut prefs = Vec::with_capacity(workloads.keys);
+    let mut latencies = Vec::with_capacity(workloads.keys);
+    for n in 1 ..= workloads.keys.max(1) {
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        rng.fill_bytes(&mut data);
+        let start = Instant::now();
+        prefs.push(db.put_keyed(&key, &data)?);
+        latencies.push(start.elapsed());
+        keys.push(key);
+        if n % workloads.batch_every.max(1) == 0 {
+            batches.push(timed(|| db.batch())?);
+        }
+    }
+    batches.push(timed(|| db.batch())?);
+    measurements.push(Measurement::new("put", latencies));
+
+    let mut latencies = Vec::with_capacity(workloads.reads);
+    for _ in 0 .. workloads.reads {
+        let key = &keys[rng.gen_range(0, keys.len())];
+        latencies.push(timed(|| db.get_keyed(key))?);
+    }
+    measurements.push(Measurement::new("get uniform", latencies));
+
+    // popular keys are spread over the data file, not the first loaded
+    let zipf = Zipf::new(keys.len(), workloads.zipf_exponent);
+    let mut ranked = (0 .. keys.len()).collect::<Vec<_>>();
+    for i in (1 .. ranked.len()).rev() {
+        ranked.swap(i, rng.gen_range(0, i + 1));
+    }
+    let mut page_reads = vec!(0usize; (prefs.iter().map(PRef::as_u64).max().unwrap_or(0) / PAGE_SIZE as u64) as usize + 1);
+    let mut latencies = Vec::with_capacity(workloads.reads);
+    for _ in 0 .. workloads.reads {
+        let i = ranked[zipf.sample(&mut rng)];
+        page_reads[(prefs[i].as_u64() / PAGE_SIZE as u64) as usize] += 1;
+        latencies.push(timed(|| db.get_keyed(&keys[i]))?);
+    }
+    measurements.push(Measurement::new("get zipfian", latencies));
+
+    // half replace data of zipfian keys, half read them
+    let mut latencies = Vec::with_capacity(workloads.reads);
+    for n in 1 ..= workloads.reads {
+        let i = ranked[zipf.sample(&mut rng)];
+        if rng.gen() {
+            rng.fill_bytes(&mut data);
+            latencies.push(timed(|| db.put_keyed(&keys[i], &data))?);
+        } else {
+            latencies.push(timed(|| db.get_keyed(&keys[i]))?);
+        }
+        if n % workloads.batch_every.max(1) == 0 {
+            batches.push(timed(|| db.batch())?);
+        }
+    }
+    batches.push(timed(|| db.batch())?);
+    measurements.push(Measurement::new("mixed", latencies));
+    measurements.push(Measurement::new("batch", batches));
+
+    let mut data_pages = prefs.iter().map(|pref| pref.as_u64() / PAGE_SIZE as u64).collect::<Vec<_>>();
+    data_pages.dedup();
+    let data_pages = data_pages.len();
+    page_reads.sort_unstable_by(|a, b| b.cmp(a));
+    let mut served = 0;
+    let cached_data_pages = page_reads.iter().take_while(|r| {
+        let hot = (served as f64) < CACHE_HITS * workloads.reads as f64;
+        served += **r;
+        hot
+    }).count().max(1);
+    let table = db.table_stats()?;
+    let buckets = table.keys.div_ceil(table.fill_target.max(1)).max(table.buckets);
+    Ok(Report { measurements, data_pages, cached_data_pages, table, buckets })
+}
+
+fn timed<T, F: FnMut() -> Result<T, Error>> (mut f: F) -> Result<Duration, Error> {
+    let start = Instant::now();
+    f()?;
+    Ok(start.elapsed())
+}
+
+/// write the measurements of a report as a table
+pub fn write_report (out: &mut dyn Write, backend: &str, report: &Report) -> Result<(), Error> {
+    writeln!(out, "{}", backend)?;
+    writeln!(out, "{:>14} {:>10} {:>12} {:>10} {:>10} {:>10} {:>10}", "workload", "ops", "ops/s", "p50 us", "p90 us", "p99 us", "max us")?;
+    let us = |d: Duration| d.as_secs_f64() * 1e6;
+    for m in &report.measurements {
+        let (p50, p90, p99, max) = m.percentiles;
+        writeln!(out, "{:>14} {:>10} {:>12.0} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+                 m.workload, m.ops, m.throughput(), us(p50), us(p90), us(p99), us(max))?;
+    }
+    Ok(())
+}
+
+/// run the tuner binary, args exclude the program name
+pub fn run_tuner (args: &[String], out: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+    let mut workloads = Workloads::default();
+    let mut fill_target = Config::default().bucket_fill_target;
+    let mut dir = env::temp_dir();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, value] => match flag.as_str() {
+                "--keys" => workloads.keys = value.parse()?,
+                "--data-len" => workloads.data_len = value.parse()?,
+                "--reads" => workloads.reads = value.parse()?,
+                "--batch" => workloads.batch_every = value.parse()?,
+                "--zipf" => workloads.zipf_exponent = value.parse()?,
+                "--fill-target" => fill_target = value.parse()?,
+                "--seed" => workloads.seed = value.parse()?,
+                "--dir" => dir = value.into(),
+                _ => return Err(USAGE.into())
+            },
+            _ => return Err(USAGE.into())
+        }
+    }
+
+    let mut db = transient(fill_target)?;
+    let report = tune(db.as_mut(), &workloads)?;
+    write_report(out, "transient", &report)?;
+
+    let dir = dir.join(format!("hammersbald-tuner-{}", ::rand::random::<u64>()));
+    fs::create_dir_all(&dir)?;
+    let mut db = persistent_with_config("tuner", &Config::default().directory(&dir).bucket_fill_target(fill_target))?;
+    let report = tune(db.as_mut(), &workloads);
+    db.shutdown();
+    fs::remove_dir_all(&dir)?;
+    let report = report?;
+    write_report(out, "persistent", &report)?;
+
+    writeln!(out, "recommended settings")?;
+    writeln!(out, "  cached_data_pages {}: {:.0}% of zipfian reads are of {} of {} data pages",
+             report.cached_data_pages, CACHE_HITS * 100.0, report.cached_data_pages, report.data_pages)?;
+    writeln!(out, "  resize to {} buckets: {} keys at fill target {}, the table has {} buckets at load factor {:.1}{}",
+             report.buckets, report.table.keys, report.table.fill_target, report.table.buckets, report.table.load_factor,
+             if report.table.needs_resize() { " and needs a resize" } else { "" })?;
+    writeln!(out, "  bulk_load({}) before a load of as many keys sizes the table upfront", report.table.keys)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tune () {
+        let workloads = Workloads { keys: 2000, reads: 2000, batch_every: 500, ..Workloads::default() };
+        let mut db = transient(1).unwrap();
+        let report = tune(db.as_mut(), &workloads).unwrap();
+        assert_eq!(report.measurements.iter().map(|m| m.workload).collect::<Vec<_>>(),
+                   vec!("put", "get uniform", "get zipfian", "mixed", "batch"));
+        assert_eq!(report.measurements[0].ops, 2000);
+        // four batches of the load, four of the mixed and one after each
+        assert_eq!(report.measurements[4].ops, 10);
+        for m in &report.measurements {
+            let (p50, p90, p99, max) = m.percentiles;
+            assert!(p50 <= p90 && p90 <= p99 && p99 <= max);
+        }
+        // zipfian reads are of a hot set
+        assert!(report.cached_data_pages < report.data_pages);
+        assert_eq!(report.table.keys, 2000);
+        assert!(report.buckets >= 2000);
+    }
+
+    #[test]
+    fn test_zipf () {
+        let zipf = Zipf::new(1000, 1.0);
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut counts = vec!(0; 1000);
+        for _ in 0 .. 100000 {
+            counts[zipf.sample(&mut rng)] += 1;
+        }
+        assert!(counts[0] > counts[1] && counts[1] > counts[10] && counts[10] > counts[999]);
+        // a uniform popularity with exponent 0
+        let uniform = Zipf::new(10, 0.0);
+        let mut counts = [0; 10];
+        for _ in 0 .. 10000 {
+            counts[uniform.sample(&mut rng)] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 800));
+    }
+
+    #[test]
+    fn test_run_tuner () {
+        let mut out = Vec::new();
+        let args = ["--keys", "1000", "--reads", "1000", "--batch", "300"].iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        run_tuner(&args, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("transient") && out.contains("persistent") && out.contains("cached_data_pages"));
+        assert!(run_tuner(&["--keys".to_string()], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Typed Hammersbald
+//! Stores serde serializable keys and values. Values are stored together with their key
+//! in CBOR encoding, the key of the underlying db is the hash of the encoded key.
+//!
+
+use PRef;
+use HammersbaldAPI;
+use HammersbaldIterator;
+
+use bitcoin_hashes::{sha256, Hash};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use std::error::Error;
+use std::marker::PhantomData;
+
+/// Typed wrapper of a db storing values of type V with keys of type K
+pub struct TypedHammersbald<K, V> {
+    hammersbald: Box<dyn HammersbaldAPI>,
+    types: PhantomData<(K, V)>
+}
+
+impl<K, V> TypedHammersbald<K, V>
+    where K: Serialize + DeserializeOwned + PartialEq, V: Serialize + DeserializeOwned {
+    /// Create a new typed wrapper
+    pub fn new (hammersbald: Box<dyn HammersbaldAPI>) -> TypedHammersbald<K, V> {
+        TypedHammersbald { hammersbald, types: PhantomData }
+    }
+
+    /// the wrapped db
+    pub fn inner (&mut self) -> &mut dyn HammersbaldAPI {
+        self.hammersbald.as_mut()
+    }
+
+    /// Store a value with its key
+    pub fn put_typed (&mut self, key: &K, value: &V) -> Result<PRef, Box<dyn Error>> {
+        let encoded_key = serde_cbor::to_vec(key)?;
+        let data = serde_cbor::to_vec(&(key, value))?;
+        Ok(self.hammersbald.put_keyed(&Self::hash_key(encoded_key.as_slice())[..], data.as_slice())?)
+    }
+
+    /// Retrieve a value with its key
+    pub fn get_typed (&self, key: &K) -> Result<Option<(PRef, V)>, Box<dyn Error>> {
+        let encoded_key = serde_cbor::to_vec(key)?;
+        if let Some((pref, data)) = self.hammersbald.get_keyed(&Self::hash_key(encoded_key.as_slice())[..])? {
+            let (stored, value): (K, V) = serde_cbor::from_slice(data.as_slice())?;
+            if stored == *key {
+                return Ok(Some((pref, value)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Forget a key
+    pub fn forget_typed (&mut self, key: &K) -> Result<(), Box<dyn Error>> {
+        let encoded_key = serde_cbor::to_vec(key)?;
+        Ok(self.hammersbald.forget(&Self::hash_key(encoded_key.as_slice())[..])?)
+    }
+
+    /// end current batch and start a new batch
+    pub fn batch (&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(self.hammersbald.batch()?)
+    }
+
+    /// stop background writer
+    pub fn shutdown (&mut self) {
+        self.hammersbald.shutdown()
+    }
+
+    /// iterate over current (key, value) pairs
+    pub fn iter_typed (&self) -> TypedIterator<'_, K, V> {
+        TypedIterator { inner: self.hammersbald.iter_live(), types: PhantomData }
+    }
+
+    fn hash_key (encoded_key: &[u8]) -> sha256::Hash {
+        sha256::Hash::hash(encoded_key)
+    }
+}
+
+/// Iterator of typed (key, value) pairs
+pub struct TypedIterator<'a, K, V> {
+    inner: HammersbaldIterator<'a>,
+    types: PhantomData<(K, V)>
+}
+
+impl<'a, K, V> TypedIterator<'a, K, V> {
+    /// fail with the error that ended iteration before the end of the data, see HammersbaldIterator::error
+    pub fn error(&mut self) -> Result<(), ::Error> {
+        self.inner.error()
+    }
+}
+
+impl<'a, K, V> Iterator for TypedIterator<'a, K, V>
+    where K: DeserializeOwned, V: DeserializeOwned {
+    type Item = (PRef, K, V);
+
+    fn next(&mut self) -> Option<<Self as Iterator>::Item> {
+        for (pref, _, data) in &mut self.inner {
+            if let Ok((key, value)) = serde_cbor::from_slice::<(K, V)>(data.as_slice()) {
+                return Some((pref, key, value));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use api::test::temp_db_name;
+    use persistent;
+    use transient;
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn typed_test () {
+        let mut db = TypedHammersbald::<String, (u64, Vec<u8>)>::new(transient(1).unwrap());
+        let one = db.put_typed(&"one".to_string(), &(1, vec!(1))).unwrap();
+        db.put_typed(&"two".to_string(), &(2, vec!(2, 2))).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_typed(&"one".to_string()).unwrap(), Some((one, (1, vec!(1)))));
+        assert_eq!(db.get_typed(&"three".to_string()).unwrap(), None);
+
+        db.forget_typed(&"two".to_string()).unwrap();
+        let mut all = db.iter_typed().map(|(_, k, v)| (k, v)).collect::<Vec<_>>();
+        all.sort();
+        assert_eq!(all, vec!(("one".to_string(), (1, vec!(1)))));
+        db.shutdown();
+    }
+
+    #[test]
+    fn typed_mismatch_test () {
+        let name = temp_db_name("typed_mismatch");
+        let key = (7u32, "seven".to_string());
+        let mut db = TypedHammersbald::<(u32, String), Vec<(u64, bool)>>::new(persistent(&name, 10, 1).unwrap());
+        let pref = db.put_typed(&key, &vec!((7, true), (8, false))).unwrap();
+        db.batch().unwrap();
+        assert_eq!(db.get_typed(&key).unwrap(), Some((pref, vec!((7, true), (8, false)))));
+        assert_eq!(db.get_typed(&(7u32, "eight".to_string())).unwrap(), None);
+        db.shutdown();
+        drop(db);
+
+        // the value stored does not decode as an other type
+        let mut db = TypedHammersbald::<(u32, String), String>::new(persistent(&name, 10, 1).unwrap());
+        assert!(db.get_typed(&key).is_err());
+        assert_eq!(db.iter_typed().count(), 0);
+        assert_eq!(db.inner().iter_keys().count(), 1);
+        db.shutdown();
+        fs::remove_dir_all(Path::new(&name).parent().unwrap()).unwrap();
+    }
+}
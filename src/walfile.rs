@@ -0,0 +1,328 @@
+//
+// Copyright 2018-2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # The write ahead log
+//! Redo records of puts since the last batch. A record is synced before the put returns,
+//! so puts are durable without a batch. The log starts with the data file length of the batch
+//! it extends, records are only replayed on top of that batch. Each record is followed by a CRC32 of it,
+//! replay stops at the first record that does not match, as at a torn write at the end of the log.
+//!
+
+use page::{Page, PAGE_SIZE, PAGE_PAYLOAD_SIZE};
+use pagedfile::{PagedFile, PagedFileIterator};
+use error::Error;
+use pref::PRef;
+use format::LinkedData;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use std::cmp::min;
+
+// record length that does not fit into three bytes, followed by four bytes of length
+const LONG_RECORD: u32 = 0xFF_FFFF;
+
+// CRC32 (IEEE) of a record
+fn crc32 (buf: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in buf {
+        crc ^= *b as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// a redo record
+pub enum Redo {
+    /// put_keyed(key, data)
+    Keyed(Vec<u8>, Vec<u8>),
+    /// put(data)
+    Referred(Vec<u8>),
+    /// forget(key)
+    Forget(Vec<u8>),
+    /// put_with_links(data, links)
+    Linked(Vec<u8>, Vec<PRef>),
+    /// put_group(entries), replayed completely or not at all
+    Group(Vec<(Vec<u8>, Vec<u8>)>),
+    /// put_keyed_also(key, data)
+    KeyedAlso(Vec<u8>, Vec<u8>),
+    /// put_durable(key, data) that stored the data at the reference
+    Durable(PRef, Vec<u8>, Vec<u8>)
+}
+
+impl Redo {
+    fn serialize (&self, result: &mut Vec<u8>) {
+        match self {
+            Redo::Keyed(key, data) | Redo::KeyedAlso(key, data) => {
+                result.push(if let Redo::Keyed(..) = self { 0 } else { 5 });
+                result.push(key.len() as u8);
+                result.extend_from_slice(key.as_slice());
+                result.extend_from_slice(data.as_slice());
+            },
+            Redo::Referred(data) => {
+                result.push(1);
+                result.extend_from_slice(data.as_slice());
+            },
+            Redo::Forget(key) => {
+                result.push(2);
+                result.extend_from_slice(key.as_slice());
+            },
+            Redo::Linked(data, links) => {
+                result.push(3);
+                let mut n_links = [0u8; 3];
+                BigEndian::write_u24(&mut n_links, links.len() as u32);
+                result.extend_from_slice(&n_links);
+                result.extend_from_slice(LinkedData::from_prefs(links.as_slice()).as_slice());
+                result.extend_from_slice(data.as_slice());
+            },
+            Redo::Durable(pref, key, data) => {
+                result.push(6);
+                let mut pos = [0u8; 6];
+                BigEndian::write_u48(&mut pos, pref.as_u64());
+                result.extend_from_slice(&pos);
+                result.push(key.len() as u8);
+                result.extend_from_slice(key.as_slice());
+                result.extend_from_slice(data.as_slice());
+            },
+            Redo::Group(entries) => {
+                result.push(4);
+                let mut len = [0u8; 4];
+                BigEndian::write_u32(&mut len, entries.len() as u32);
+                result.extend_from_slice(&len);
+                for (key, data) in entries {
+                    result.push(key.len() as u8);
+                    result.extend_from_slice(key.as_slice());
+                    BigEndian::write_u32(&mut len, data.len() as u32);
+                    result.extend_from_slice(&len);
+                    result.extend_from_slice(data.as_slice());
+                }
+            }
+        }
+    }
+
+    fn deserialize (record: &[u8]) -> Option<Redo> {
+        match record.split_first() {
+            Some((kind @ (0 | 5), rest)) => {
+                let (key_len, rest) = rest.split_first()?;
+                if rest.len() < *key_len as usize {
+                    return None;
+                }
+                let (key, data) = rest.split_at(*key_len as usize);
+                if *kind == 0 {
+                    Some(Redo::Keyed(key.to_vec(), data.to_vec()))
+                } else {
+                    Some(Redo::KeyedAlso(key.to_vec(), data.to_vec()))
+                }
+            },
+            Some((1, data)) => Some(Redo::Referred(data.to_vec())),
+            Some((2, key)) => Some(Redo::Forget(key.to_vec())),
+            Some((6, rest)) => {
+                if rest.len() < 7 {
+                    return None;
+                }
+                let pref = PRef::from(BigEndian::read_u48(&rest[0..6]));
+                let key_len = rest[6] as usize;
+                if rest.len() < 7 + key_len {
+                    return None;
+                }
+                let (key, data) = rest[7..].split_at(key_len);
+                Some(Redo::Durable(pref, key.to_vec(), data.to_vec()))
+            },
+            Some((3, rest)) => {
+                if rest.len() < 3 {
+                    return None;
+                }
+                let links_len = 6 * BigEndian::read_u24(&rest[0..3]) as usize;
+                if rest.len() < 3 + links_len {
+                    return None;
+                }
+                let (links, data) = rest[3..].split_at(links_len);
+                Some(Redo::Linked(data.to_vec(), links.chunks(6).map(|c| PRef::from(BigEndian::read_u48(c))).collect()))
+            },
+            Some((4, rest)) => {
+                if rest.len() < 4 {
+                    return None;
+                }
+                let n = BigEndian::read_u32(&rest[0..4]);
+                let mut rest = &rest[4..];
+                let mut entries = Vec::new();
+                for _ in 0 .. n {
+                    let (key_len, after) = rest.split_first()?;
+                    if after.len() < *key_len as usize + 4 {
+                        return None;
+                    }
+                    let (key, after) = after.split_at(*key_len as usize);
+                    let data_len = BigEndian::read_u32(&after[0..4]) as usize;
+                    if after.len() < 4 + data_len {
+                        return None;
+                    }
+                    let (data, after) = after[4..].split_at(data_len);
+                    entries.push((key.to_vec(), data.to_vec()));
+                    rest = after;
+                }
+                Some(Redo::Group(entries))
+            },
+            _ => None
+        }
+    }
+}
+
+/// the write ahead log
+pub struct WalFile {
+    file: Box<dyn PagedFile>,
+    page: Page,
+    // position in the stream of page payloads
+    pos: u64,
+    // the file is known to hold a log without records
+    empty: bool,
+    // data file length of the batch a log without records is rewritten for before the next record
+    rebase: Option<u64>
+}
+
+impl WalFile {
+    pub fn new (file: Box<dyn PagedFile>) -> WalFile {
+        WalFile { file, page: Page::new_table_page(PRef::from(0)), pos: 0, empty: false, rebase: None }
+    }
+
+    /// data file length of the batch the log extends and its records
+    /// records from the first torn or not matching its CRC on are ignored
+    pub fn records (&self) -> Result<(Option<PRef>, Vec<Redo>), Error> {
+        let mut stream = Vec::new();
+        for page in PagedFileIterator::new(self.file.as_ref(), PRef::from(0)) {
+            stream.extend_from_slice(&page.as_buf()[0..PAGE_PAYLOAD_SIZE]);
+        }
+        if stream.len() < 6 {
+            return Ok((None, vec!()));
+        }
+        let base = PRef::from(BigEndian::read_u48(&stream[0..6]));
+        let mut records = Vec::new();
+        let mut pos = 6;
+        while pos + 3 <= stream.len() {
+            let mut len = BigEndian::read_u24(&stream[pos .. pos + 3]) as usize;
+            pos += 3;
+            if len == LONG_RECORD as usize {
+                if pos + 4 > stream.len() {
+                    break;
+                }
+                len = BigEndian::read_u32(&stream[pos .. pos + 4]) as usize;
+                pos += 4;
+            }
+            if len == 0 || pos + len + 4 > stream.len() {
+                break;
+            }
+            let record = &stream[pos .. pos + len];
+            if BigEndian::read_u32(&stream[pos + len .. pos + len + 4]) != crc32(record) {
+                break;
+            }
+            if let Some(redo) = Redo::deserialize(record) {
+                records.push(redo);
+            }
+            else {
+                break;
+            }
+            pos += len + 4;
+        }
+        Ok((Some(base), records))
+    }
+
+    /// append a record and sync it to disk
+    pub fn append (&mut self, redo: &Redo) -> Result<(), Error> {
+        self.append_all(std::slice::from_ref(redo))
+    }
+
+    /// append records and sync them to disk once
+    pub fn append_all (&mut self, redos: &[Redo]) -> Result<(), Error> {
+        if let Some(data_len) = self.rebase.take() {
+            self.rewrite(data_len)?;
+        }
+        for redo in redos {
+            self.write_record(redo)?;
+        }
+        self.empty = false;
+        self.commit()
+    }
+
+    fn write_record (&mut self, redo: &Redo) -> Result<(), Error> {
+        let mut record = vec!();
+        redo.serialize(&mut record);
+        let mut len = [0u8; 7];
+        if record.len() >= LONG_RECORD as usize {
+            BigEndian::write_u24(&mut len[0..3], LONG_RECORD);
+            BigEndian::write_u32(&mut len[3..7], record.len() as u32);
+            self.write(&len)?;
+        }
+        else {
+            BigEndian::write_u24(&mut len[0..3], record.len() as u32);
+            self.write(&len[0..3])?;
+        }
+        self.write(record.as_slice())?;
+        let mut crc = [0u8; 4];
+        BigEndian::write_u32(&mut crc, crc32(record.as_slice()));
+        self.write(&crc)
+    }
+
+    /// start an empty log extending the batch with the given data file length
+    /// a log without records is only rewritten before the next record, so a batch without logged puts does not sync it
+    pub fn reset (&mut self, data_len: u64) -> Result<(), Error> {
+        if self.empty {
+            self.rebase = Some(data_len);
+            return Ok(());
+        }
+        self.rewrite(data_len)
+    }
+
+    fn rewrite (&mut self, data_len: u64) -> Result<(), Error> {
+        self.file.truncate(0)?;
+        self.page = Page::new_table_page(PRef::from(0));
+        self.pos = 0;
+        let mut base = [0u8; 6];
+        BigEndian::write_u48(&mut base, data_len);
+        self.write(&base)?;
+        self.commit()?;
+        self.empty = true;
+        Ok(())
+    }
+
+    pub fn shutdown (&mut self) {
+        self.file.shutdown()
+    }
+
+    fn write (&mut self, buf: &[u8]) -> Result<(), Error> {
+        let mut wrote = 0;
+        while wrote < buf.len() {
+            let in_page = (self.pos % PAGE_PAYLOAD_SIZE as u64) as usize;
+            let space = min(PAGE_PAYLOAD_SIZE - in_page, buf.len() - wrote);
+            self.page.write(in_page, &buf[wrote .. wrote + space]);
+            wrote += space;
+            self.pos += space as u64;
+            if self.pos.is_multiple_of(PAGE_PAYLOAD_SIZE as u64) {
+                self.file.update_page(self.page.clone())?;
+                let next = self.pos / PAGE_PAYLOAD_SIZE as u64 * PAGE_SIZE as u64;
+                self.page = Page::new_table_page(PRef::from(next));
+            }
+        }
+        Ok(())
+    }
+
+    fn commit (&mut self) -> Result<(), Error> {
+        if !self.pos.is_multiple_of(PAGE_PAYLOAD_SIZE as u64) {
+            self.file.update_page(self.page.clone())?;
+        }
+        self.file.flush()?;
+        self.file.sync()
+    }
+}